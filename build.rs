@@ -0,0 +1,24 @@
+use std::process::Command;
+
+fn main() {
+    // Expose the commit the binary has been built from, for the status endpoint.
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=GIT_COMMIT={}", git_commit);
+    // HEAD only names the branch, the commit moves in its ref, loose or packed
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+
+    // The gRPC admin service, built with the protoc shipped with the crate rather than one of the system
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform");
+    std::env::set_var("PROTOC", protoc);
+    tonic_build::compile_protos("proto/registry_admin.proto").expect("Unable to compile the gRPC admin service");
+}
@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+use crate::configuration::repository_matches;
+
+/// Scope of the admin routes, as asked to the token server and granted to API keys and signing keys
+pub const ADMIN_SCOPE: &str = "registry:admin:*";
+const ADMIN_RESOURCE_TYPE: &str = "registry";
+const ADMIN_RESOURCE_NAME: &str = "admin";
+
+/// One line of the access control list from the configuration file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AclEntry {
+    /// Account name this entry applies to. `*` matches any authenticated account,
+    /// `anonymous` matches requests without credentials.
+    pub account: String,
+    /// Repository name this entry applies to. A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    pub actions: Vec<String>
+}
+
+impl AclEntry {
+    fn matches(&self, account: Option<&str>, repository: &str) -> bool {
+        let account_matches = match account {
+            Some(account) => self.account == "*" || self.account == account,
+            None => self.account == "anonymous"
+        };
+
+        account_matches && repository_matches(&self.repository, repository)
+    }
+}
+
+/// A resource and the actions on it, as found in the `scope` parameter of a token request
+/// and in the `access` claim of a token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResourceAccess {
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    pub actions: Vec<String>
+}
+
+impl ResourceAccess {
+    /// Parses a scope like `repository:samalba/my-app:pull,push`. Repository names may contain
+    /// colons (registries with ports), so the actions are whatever is after the last one.
+    pub fn from_scope(scope: &str) -> Option<Self> {
+        let (resource_type, rest) = scope.split_once(':')?;
+        let (name, actions) = rest.rsplit_once(':')?;
+
+        Some(Self {
+            resource_type: resource_type.to_string(),
+            name: name.to_string(),
+            actions: actions.split(',')
+                .filter(|action| !action.is_empty())
+                .map(|action| action.to_string())
+                .collect()
+        })
+    }
+
+    /// Whether the access covers the admin routes
+    pub fn allows_admin(&self) -> bool {
+        self.allows(ADMIN_RESOURCE_TYPE, ADMIN_RESOURCE_NAME, "*")
+    }
+
+    /// Whether the access covers the action on the resource. A trailing `*` in the name matches any
+    /// resource with this prefix, which API key scopes use to cover whole namespaces.
+    pub fn allows(&self, resource_type: &str, name: &str, action: &str) -> bool {
+        let name_matches = match self.name.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => self.name == name
+        };
+
+        self.resource_type == resource_type
+            && name_matches
+            && self.actions.iter().any(|granted| granted == action || granted == "*")
+    }
+}
+
+pub struct AccessControlList {
+    entries: Vec<AclEntry>,
+    namespace_ownership: bool,
+    admins: Vec<String>
+}
+
+impl AccessControlList {
+    pub fn new(entries: Vec<AclEntry>, namespace_ownership: bool, admins: Vec<String>) -> Self {
+        Self { entries, namespace_ownership, admins }
+    }
+
+    /// Whether the account is one of the admins, allowed on the admin routes
+    pub fn is_admin(&self, account: Option<&str>) -> bool {
+        account.is_some_and(|account| self.admins.iter().any(|admin| admin == account))
+    }
+
+    /// With namespace ownership, authenticated users may pull anything and push or delete
+    /// anything under `<username>/`.
+    fn implicit_actions(&self, account: Option<&str>, repository: &str) -> &'static [&'static str] {
+        match account {
+            Some(account) if self.namespace_ownership => {
+                let owns_namespace = repository
+                    .strip_prefix(account)
+                    .map(|rest| rest.starts_with('/'))
+                    .unwrap_or(false);
+
+                if owns_namespace {
+                    &["pull", "push", "delete"]
+                } else {
+                    &["pull"]
+                }
+            },
+            _ => &[]
+        }
+    }
+
+    /// Actions out of the requested ones that the account is allowed to perform on the repository.
+    pub fn granted_actions(&self, account: Option<&str>, repository: &str, requested: &[String]) -> Vec<String> {
+        let allowed = self.entries.iter()
+            .filter(|entry| entry.matches(account, repository))
+            .flat_map(|entry| entry.actions.iter().map(|action| action.as_str()))
+            .chain(self.implicit_actions(account, repository).iter().copied())
+            .collect::<Vec<_>>();
+
+        requested.iter()
+            .filter(|action| allowed.iter().any(|allowed| *allowed == action.as_str() || *allowed == "*"))
+            .cloned()
+            .collect()
+    }
+
+    /// Restricts the requested access to what the account has been granted.
+    pub fn authorize(&self, account: Option<&str>, requested: &ResourceAccess) -> ResourceAccess {
+        let actions = if requested.resource_type == "repository" {
+            self.granted_actions(account, &requested.name, &requested.actions)
+        } else if requested.resource_type == ADMIN_RESOURCE_TYPE && requested.name == ADMIN_RESOURCE_NAME && self.is_admin(account) {
+            requested.actions.clone()
+        } else {
+            Vec::new()
+        };
+
+        ResourceAccess {
+            actions,
+            ..requested.clone()
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::{path::{Path, PathBuf}, sync::RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+use eyre::Context;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+use super::acl::ResourceAccess;
+
+/// Marks the secrets that are API keys rather than passwords or JSON web tokens
+const API_KEY_PREFIX: &str = "rk_";
+
+/// Long-lived credentials of a robot account, as saved on disk. Only the hash of the secret is kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    /// Account name of the requests made with the key
+    pub name: String,
+    pub scopes: Vec<ResourceAccess>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    secret_hash: String,
+}
+
+impl ApiKey {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Restricts the access requested from the token server to the scopes of the key
+    pub fn restrict(&self, requested: &ResourceAccess) -> ResourceAccess {
+        ResourceAccess {
+            actions: requested.actions
+                .iter()
+                .filter(|action| self.scopes.iter().any(|scope| scope.allows(&requested.resource_type, &requested.name, action)))
+                .cloned()
+                .collect(),
+            ..requested.clone()
+        }
+    }
+
+    /// Same key, without the hash of its secret
+    pub fn summary(&self) -> Self {
+        Self {
+            secret_hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// API keys of the registry, kept in memory and written to a JSON file on every change
+pub struct ApiKeys {
+    path: PathBuf,
+    keys: RwLock<Vec<ApiKey>>,
+    /// Serializes the writes of the file
+    file: Mutex<()>,
+}
+
+impl ApiKeys {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let keys = match std::fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("Unable to parse the API keys in {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Unable to read the API keys in {:?}", path))
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys: RwLock::new(keys),
+            file: Mutex::new(()),
+        })
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.read().unwrap().iter().map(ApiKey::summary).collect()
+    }
+
+    /// Creates a key, returning it along with its secret. The secret can't be found again afterwards.
+    pub async fn create(&self, name: String, scopes: Vec<ResourceAccess>, lifetime: Option<Duration>) -> eyre::Result<(ApiKey, String)> {
+        let id = Uuid::new_v4().simple().to_string();
+        let mut random = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut random);
+        let secret = base16ct::lower::encode_string(&random);
+
+        let created_at = Utc::now();
+        let key = ApiKey {
+            id: id.clone(),
+            name,
+            scopes,
+            created_at,
+            expires_at: lifetime.map(|lifetime| created_at + lifetime),
+            secret_hash: hash_secret(&secret),
+        };
+
+        let _file = self.file.lock().await;
+        self.keys.write().unwrap().push(key.clone());
+        self.save().await?;
+
+        info!("Created API key {} for {}", key.id, key.name);
+        Ok((key.summary(), format!("{}{}.{}", API_KEY_PREFIX, id, secret)))
+    }
+
+    /// Removes a key, returning whether it existed
+    pub async fn revoke(&self, id: &str) -> eyre::Result<bool> {
+        let _file = self.file.lock().await;
+        let revoked = {
+            let mut keys = self.keys.write().unwrap();
+            let count = keys.len();
+            keys.retain(|key| key.id != id);
+            keys.len() != count
+        };
+
+        if revoked {
+            self.save().await?;
+            info!("Revoked API key {}", id);
+        }
+
+        Ok(revoked)
+    }
+
+    /// Whether a secret presented by a client is meant to be an API key, rather than a password or a token
+    pub fn is_api_key(presented: &str) -> bool {
+        presented.starts_with(API_KEY_PREFIX)
+    }
+
+    /// Key matching a secret presented by a client, unless it was revoked or has expired
+    pub fn verify(&self, presented: &str) -> Option<ApiKey> {
+        let (id, secret) = presented.strip_prefix(API_KEY_PREFIX)?.split_once('.')?;
+
+        self.keys.read().unwrap()
+            .iter()
+            .find(|key| key.id == id && key.secret_hash == hash_secret(secret) && !key.is_expired())
+            .map(ApiKey::summary)
+    }
+
+    async fn save(&self) -> eyre::Result<()> {
+        let content = serde_json::to_vec_pretty(&*self.keys.read().unwrap())?;
+
+        // Written aside first, so a crash doesn't leave a truncated file
+        let temporary_path = self.path.with_extension("tmp");
+        tokio::fs::write(&temporary_path, content).await?;
+        tokio::fs::rename(&temporary_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    base16ct::lower::encode_string(&Sha256::digest(secret.as_bytes()))
+}
@@ -0,0 +1,199 @@
+use axum::{http::{Request, Method, StatusCode}, middleware::Next, response::{Response, IntoResponse}, extract::State};
+use percent_encoding::percent_decode_str;
+use tracing::{info, warn};
+
+use crate::{ApplicationState, configuration::AuthenticationConfiguration, controllers::{RegistryHttpError, images::split_image_path}, repository_path::RepositoryPath, requests::{self, ForwardedInfo}};
+
+use super::{AuthenticationError, Authenticator, Identity, acl::ADMIN_SCOPE, opa::AuthorizationInput};
+
+/// Routes of the administration API, only open to the admins
+const ADMIN_ROUTES_PREFIX: &str = "/admin/";
+
+/// Routes of the images API, on `<repository>/<digest>/<resource>`
+const IMAGES_API_ROUTES_PREFIX: &str = "/api/images/";
+
+/// Repository targeted by a request, as it would appear in a token scope.
+pub fn requested_repository(path: &str) -> Option<String> {
+    if let Some(image_resource) = path.strip_prefix(IMAGES_API_ROUTES_PREFIX) {
+        return images_api_repository(image_resource);
+    }
+
+    RepositoryPath::parse(path).map(|repository_path| repository_path.repository())
+}
+
+/// Repository of an images API route, read from the path decoded as the handlers get it, so an encoded
+/// digest names the same image for both
+fn images_api_repository(image_resource: &str) -> Option<String> {
+    let image_resource = percent_decode_str(image_resource).decode_utf8().ok()?;
+    let (image, _) = image_resource.trim_start_matches('/').rsplit_once('/')?;
+
+    split_image_path(image).ok().map(|(container_ref, _)| container_ref)
+}
+
+pub fn requested_action(method: &Method) -> &'static str {
+    match *method {
+        Method::GET | Method::HEAD => "pull",
+        Method::DELETE => "delete",
+        _ => "push"
+    }
+}
+
+/// Rejects requests on repositories whose credentials don't grant the action derived from the route.
+/// The identity of the caller is made available to the handlers through the request extensions.
+pub async fn authorize_repository_access<B>(
+    State(app): State<ApplicationState>,
+    mut req: Request<B>,
+    next: Next<B>
+) -> Response {
+    let is_admin_route = req.uri().path().starts_with(ADMIN_ROUTES_PREFIX);
+    let (authentication, authenticator) = match (&app.conf.authentication, &app.authenticator) {
+        (Some(authentication), Some(authenticator)) => (authentication, authenticator),
+        _ if is_admin_route && !app.conf.server.unauthenticated_admin => {
+            warn!("Refused {} {}, the admin routes need the authentication to be configured", req.method(), req.uri().path());
+            return RegistryHttpError::denied("The admin routes need the authentication to be configured").into_response();
+        },
+        _ => return next.run(req).await
+    };
+
+    // Nothing to protect, OPTIONS only tells which methods are available
+    if req.method() == Method::OPTIONS {
+        return next.run(req).await;
+    }
+
+    let repository = match requested_repository(req.uri().path()) {
+        Some(repository) => repository,
+        None if is_admin_route => return authorize_admin_access(authentication, authenticator, req, next).await,
+        // Never handed to the handlers unchecked, whatever they would make of the path
+        None if req.uri().path().starts_with(IMAGES_API_ROUTES_PREFIX) => return StatusCode::NOT_FOUND.into_response(),
+        None => return next.run(req).await
+    };
+    let action = requested_action(req.method());
+    let scope = format!("repository:{}:{}", repository, action);
+    let origin = req.extensions().get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.origin());
+    let client_ip = req.extensions().get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.client_ip);
+
+    let identity = match authenticate_request(authentication, authenticator, &req, &scope).await {
+        Ok(identity) => identity,
+        Err(response) => return response
+    };
+
+    let allowed = match &authenticator.opa {
+        // Bearer tokens still restrict the access to their scopes, the policy engine replaces the ACL only
+        Some(opa) if identity.token_access.is_none() || authenticator.is_allowed(&identity, &repository, action) => {
+            let input = AuthorizationInput {
+                account: identity.account.clone(),
+                repository: repository.clone(),
+                action: action.to_string(),
+                method: req.method().to_string(),
+                path: req.uri().path().to_string(),
+                client_ip
+            };
+
+            match opa.decide(&input).await {
+                Ok(decision) => {
+                    if let (false, Some(reason)) = (decision.allowed, &decision.reason) {
+                        info!("Policy engine refused {}: {}", scope, reason);
+                    }
+                    decision.allowed
+                },
+                Err(e) => {
+                    warn!("Unable to get a policy decision for {}: {}", scope, e);
+                    return RegistryHttpError::ServiceUnavailable.into_response();
+                }
+            }
+        },
+        Some(_) => false,
+        None => authenticator.is_allowed(&identity, &repository, action)
+    };
+
+    if !allowed {
+        // Anonymous clients get a chance to log in, authenticated ones simply don't have the rights.
+        if identity.is_anonymous() {
+            info!("Anonymous access to {} refused, challenging the client", scope);
+            return RegistryHttpError::unauthorized(authentication.challenge(Some(&scope), origin.as_deref())).into_response();
+        }
+
+        warn!("Access to {} denied for {:?}", scope, identity.account);
+        return RegistryHttpError::denied(scope).into_response();
+    }
+
+    requests::record_identity(&identity);
+    req.extensions_mut().insert(identity);
+    next.run(req).await
+}
+
+/// Identifies the client of a request, unless it was by its signature already. Clients failing to
+/// authenticate are challenged for the scope.
+async fn authenticate_request<B>(
+    authentication: &AuthenticationConfiguration,
+    authenticator: &Authenticator,
+    req: &Request<B>,
+    scope: &str
+) -> Result<Identity, Response> {
+    let origin = req.extensions().get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.origin());
+    let client_ip = req.extensions().get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.client_ip);
+
+    // Signed requests have been identified by the signature verification already
+    let authenticated = match req.extensions().get::<Identity>().cloned() {
+        Some(identity) => Ok(identity),
+        None => authenticator.authenticate(authentication.method, req.headers(), client_ip).await
+    };
+
+    match authenticated {
+        Ok(identity) => Ok(identity),
+        Err(AuthenticationError::LockedOut(retry_after)) => Err(RegistryHttpError::locked_out(retry_after).into_response()),
+        Err(e) => {
+            warn!("Authentication failed: {}", e);
+            Err(RegistryHttpError::unauthorized(authentication.challenge(Some(scope), origin.as_deref())).into_response())
+        }
+    }
+}
+
+/// Lets the admins through to the admin routes: the configured admin accounts, and the tokens, API keys
+/// and signing keys granted the admin scope.
+async fn authorize_admin_access<B>(
+    authentication: &AuthenticationConfiguration,
+    authenticator: &Authenticator,
+    mut req: Request<B>,
+    next: Next<B>
+) -> Response {
+    let identity = match authenticate_request(authentication, authenticator, &req, ADMIN_SCOPE).await {
+        Ok(identity) => identity,
+        Err(response) => return response
+    };
+
+    if !authenticator.is_admin(&identity) {
+        let origin = req.extensions().get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.origin());
+        if identity.is_anonymous() {
+            info!("Anonymous access to {} refused, challenging the client", req.uri().path());
+            return RegistryHttpError::unauthorized(authentication.challenge(Some(ADMIN_SCOPE), origin.as_deref())).into_response();
+        }
+
+        warn!("Access to {} {} denied for {:?}", req.method(), req.uri().path(), identity.account);
+        return RegistryHttpError::denied(ADMIN_SCOPE).into_response();
+    }
+
+    requests::record_identity(&identity);
+    req.extensions_mut().insert(identity);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::requested_repository;
+
+    #[test]
+    fn images_api_repositories_with_encoded_digests() {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        for path in [
+            format!("/api/images/team/app/{}/scan", digest),
+            format!("/api/images/team/app/{}/sbom", digest.replace(':', "%3A")),
+            format!("/api/images/team%2Fapp/{}/scan", digest.replace(':', "%3a"))
+        ] {
+            assert_eq!(requested_repository(&path).as_deref(), Some("team/app"), "{}", path);
+        }
+
+        assert_eq!(requested_repository("/api/images/team/app/latest/scan"), None);
+        assert_eq!(requested_repository("/api/images/scan"), None);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::HashMap, path::Path};
+
+use eyre::{Context, ContextCompat};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Users loaded from an Apache htpasswd file. Only bcrypt hashes are supported,
+/// which is what `htpasswd -B` produces.
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    /// Checked against for the unknown users, so they take as long to reject as a wrong password
+    dummy_hash: String
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read htpasswd file {:?}", path))?;
+
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> eyre::Result<Self> {
+        let mut users = HashMap::new();
+
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (username, hash) = line
+                .split_once(':')
+                .with_context(|| format!("Malformed htpasswd line {}", line_number + 1))?;
+
+            if !hash.starts_with("$2") {
+                warn!("Ignoring user {}: only bcrypt hashes are supported", username);
+                continue;
+            }
+
+            users.insert(username.to_string(), hash.to_string());
+        }
+
+        // Same cost as the hashes of the file, `htpasswd -B` uses 5 unless told otherwise
+        let cost = users
+            .values()
+            .find_map(|hash| hash.split('$').nth(2).and_then(|cost| cost.parse().ok()))
+            .unwrap_or(bcrypt::DEFAULT_COST);
+        let dummy_hash = bcrypt::hash(Uuid::new_v4().to_string(), cost).context("Unable to hash the dummy password")?;
+
+        Ok(Self { users, dummy_hash })
+    }
+
+    /// Checks the password of the user on the blocking threads, a bcrypt hash takes long enough to stall the runtime
+    pub async fn verify(&self, username: &str, password: &str) -> bool {
+        let (hash, known_user) = match self.users.get(username) {
+            Some(hash) => (hash.clone(), true),
+            None => (self.dummy_hash.clone(), false)
+        };
+        let password = password.to_string();
+
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        known_user && verified
+    }
+}
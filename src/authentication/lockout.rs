@@ -0,0 +1,154 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::{Duration, Instant}};
+
+use tracing::warn;
+
+use crate::configuration::LockoutConfiguration;
+
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>
+}
+
+/// Failed logins by username from a client IP address and by address alone, slowing down and then
+/// locking out whoever keeps guessing credentials. A username is never locked out on its own, failures
+/// coming from elsewhere don't keep its owner from logging in.
+pub struct LoginThrottle {
+    configuration: LockoutConfiguration,
+    failures: Mutex<HashMap<String, FailedLogins>>
+}
+
+impl LoginThrottle {
+    pub fn new(configuration: &LockoutConfiguration) -> Self {
+        Self {
+            configuration: configuration.clone(),
+            failures: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// The username from an unknown address is throttled as a whole, there is nothing else to tell the clients apart
+    fn user_key(username: &str, client_ip: Option<IpAddr>) -> String {
+        match client_ip {
+            Some(client_ip) => format!("user:{}@ip:{}", username, client_ip),
+            None => format!("user:{}", username)
+        }
+    }
+
+    fn keys(username: Option<&str>, client_ip: Option<IpAddr>) -> Vec<String> {
+        username.map(|username| Self::user_key(username, client_ip))
+            .into_iter()
+            .chain(client_ip.map(|client_ip| format!("ip:{}", client_ip)))
+            .collect()
+    }
+
+    /// Time left before the username from the address, or the address, may try to log in again
+    pub fn locked_out(&self, username: Option<&str>, client_ip: Option<IpAddr>) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+
+        Self::keys(username, client_ip)
+            .iter()
+            .filter_map(|key| failures.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max()
+    }
+
+    /// Counts a failed login, returning how long to wait before answering it
+    pub fn record_failure(&self, username: Option<&str>, client_ip: Option<IpAddr>) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let now = Instant::now();
+        let window = Duration::from_secs(self.configuration.window_seconds);
+        let mut count = 0;
+
+        for key in Self::keys(username, client_ip) {
+            let failed_logins = failures.entry(key.clone()).or_insert(FailedLogins {
+                count: 0,
+                last_failure: now,
+                locked_until: None
+            });
+
+            // Failures older than the window are forgiven
+            if now.duration_since(failed_logins.last_failure) > window {
+                failed_logins.count = 0;
+            }
+            failed_logins.count += 1;
+            failed_logins.last_failure = now;
+
+            if failed_logins.count >= self.configuration.max_failures {
+                failed_logins.locked_until = Some(now + Duration::from_secs(self.configuration.lockout_seconds));
+                warn!(target: "audit", "Locking out {} for {} seconds after {} failed logins", key, self.configuration.lockout_seconds, failed_logins.count);
+            }
+            count = count.max(failed_logins.count);
+        }
+
+        warn!(target: "audit", "Failed login of {} from {:?}, {} failures in a row", username.unwrap_or("unknown user"), client_ip, count);
+
+        let delay = Duration::from_millis(self.configuration.initial_delay_milliseconds)
+            .saturating_mul(2u32.saturating_pow(count.saturating_sub(1)));
+        delay.min(Duration::from_secs(self.configuration.max_delay_seconds))
+    }
+
+    /// Forgets the failures of a username from the address once it logged in. Those of the address are kept,
+    /// so a single valid account doesn't let the address guess the passwords of the others.
+    pub fn record_success(&self, username: &str, client_ip: Option<IpAddr>) {
+        self.failures.lock().unwrap().remove(&Self::user_key(username, client_ip));
+    }
+
+    /// Forgets about the failures that are forgiven and the lockouts that are over
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.configuration.window_seconds);
+
+        self.failures.lock().unwrap().retain(|_, failed_logins| {
+            now.duration_since(failed_logins.last_failure) <= window
+                || failed_logins.locked_until.is_some_and(|locked_until| locked_until > now)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::LoginThrottle;
+    use crate::configuration::LockoutConfiguration;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(&LockoutConfiguration {
+            max_failures: 3,
+            window_seconds: 600,
+            lockout_seconds: 900,
+            initial_delay_milliseconds: 0,
+            max_delay_seconds: 0
+        })
+    }
+
+    #[test]
+    fn usernames_are_locked_out_from_the_guessing_address_only() {
+        let throttle = throttle();
+        let (attacker, owner) = ("192.0.2.1".parse::<IpAddr>().unwrap(), "198.51.100.7".parse::<IpAddr>().unwrap());
+
+        for _ in 0..3 {
+            throttle.record_failure(Some("alice"), Some(attacker));
+        }
+        assert!(throttle.locked_out(Some("alice"), Some(attacker)).is_some());
+        assert!(throttle.locked_out(Some("bob"), Some(attacker)).is_some());
+        assert!(throttle.locked_out(Some("alice"), Some(owner)).is_none());
+    }
+
+    #[test]
+    fn logging_in_forgets_the_failures_from_the_address() {
+        let throttle = throttle();
+        let (first, second) = ("192.0.2.1".parse::<IpAddr>().unwrap(), "192.0.2.2".parse::<IpAddr>().unwrap());
+
+        for _ in 0..2 {
+            throttle.record_failure(Some("alice"), Some(first));
+            throttle.record_failure(Some("alice"), Some(second));
+        }
+        throttle.record_success("alice", Some(first));
+        throttle.record_failure(Some("alice"), Some(second));
+        assert!(throttle.locked_out(Some("alice"), Some(second)).is_some());
+        assert!(throttle.locked_out(Some("alice"), Some(first)).is_none());
+    }
+}
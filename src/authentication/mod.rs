@@ -0,0 +1,250 @@
+use std::{net::IpAddr, time::Duration};
+
+use axum::http::HeaderMap;
+use eyre::ContextCompat;
+
+use crate::configuration::{AuthenticationConfiguration, AuthenticationMethod};
+
+use self::{api_keys::ApiKeys, htpasswd::Htpasswd, lockout::LoginThrottle, acl::{AccessControlList, ResourceAccess}, opa::OpaAuthorizer, request_signing::RequestVerifier, revocation::RevocationList, token::TokenIssuer};
+
+pub mod acl;
+pub mod api_keys;
+pub mod authorization;
+pub mod htpasswd;
+pub mod lockout;
+pub mod opa;
+pub mod request_signing;
+pub mod revocation;
+pub mod token;
+
+/// Who is behind a request, once their credentials have been checked.
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    /// None for anonymous requests
+    pub account: Option<String>,
+    /// Access granted by a bearer token. None if the access has to be looked up in the ACL.
+    pub token_access: Option<Vec<ResourceAccess>>
+}
+
+impl Identity {
+    pub fn is_anonymous(&self) -> bool {
+        self.account.is_none()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthenticationError {
+    #[error("Invalid username or password")]
+    BadCredentials,
+
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("The token has been revoked")]
+    RevokedToken,
+
+    #[error("Too many failed logins, retry in {} seconds", .0.as_secs())]
+    LockedOut(Duration),
+
+    #[error("Bearer tokens are not usable without a configured token server")]
+    NoTokenIssuer,
+}
+
+/// Everything needed to authenticate and authorize downstream clients, loaded once
+/// from the configuration at startup.
+pub struct Authenticator {
+    pub htpasswd: Option<Htpasswd>,
+    pub acl: AccessControlList,
+    pub token_issuer: Option<TokenIssuer>,
+    /// Tokens refused before their expiration, available along with the token issuer
+    pub revocations: Option<RevocationList>,
+    pub opa: Option<OpaAuthorizer>,
+    pub api_keys: Option<ApiKeys>,
+    pub login_throttle: Option<LoginThrottle>,
+    pub request_verifier: Option<RequestVerifier>
+}
+
+impl Authenticator {
+    pub fn load(configuration: &AuthenticationConfiguration) -> eyre::Result<Self> {
+        let htpasswd = configuration.htpasswd
+            .as_deref()
+            .map(Htpasswd::load)
+            .transpose()?;
+
+        let (token_issuer, revocations) = match &configuration.token {
+            Some(token_configuration) => {
+                let audience = configuration.service
+                    .as_deref()
+                    .context("The token issuer needs a service name to use as audience")?;
+                let revocations = RevocationList::load(
+                    token_configuration.revocation_list.as_deref(),
+                    Duration::from_secs(token_configuration.expiration_seconds)
+                )?;
+                (Some(TokenIssuer::load(token_configuration, audience)?), Some(revocations))
+            },
+            None => (None, None)
+        };
+
+        Ok(Self {
+            htpasswd,
+            acl: AccessControlList::new(configuration.acl.clone(), configuration.namespace_ownership, configuration.admins.clone()),
+            token_issuer,
+            revocations,
+            opa: configuration.opa.as_ref().map(OpaAuthorizer::new).transpose()?,
+            api_keys: configuration.api_keys.as_deref().map(ApiKeys::load).transpose()?,
+            login_throttle: configuration.lockout.as_ref().map(LoginThrottle::new),
+            request_verifier: configuration.request_signing.as_ref().map(RequestVerifier::new).transpose()?
+        })
+    }
+
+    /// Identifies the client of a request, keeping track of the failed logins when a lockout is configured.
+    /// Failed logins are answered after a delay growing with the failures.
+    pub async fn authenticate(&self, method: AuthenticationMethod, headers: &HeaderMap, client_ip: Option<IpAddr>) -> Result<Identity, AuthenticationError> {
+        let username = basic_credentials(headers).map(|(username, _)| username);
+        self.check_lockout(username.as_deref(), client_ip)?;
+
+        match self.identify(method, headers).await {
+            Err(AuthenticationError::BadCredentials) => {
+                self.login_failed(username.as_deref(), client_ip).await;
+                Err(AuthenticationError::BadCredentials)
+            },
+            Ok(identity) => {
+                if let Some(username) = &username {
+                    self.login_succeeded(username, client_ip);
+                }
+                Ok(identity)
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn check_lockout(&self, username: Option<&str>, client_ip: Option<IpAddr>) -> Result<(), AuthenticationError> {
+        match self.login_throttle.as_ref().and_then(|login_throttle| login_throttle.locked_out(username, client_ip)) {
+            Some(retry_after) => Err(AuthenticationError::LockedOut(retry_after)),
+            None => Ok(())
+        }
+    }
+
+    pub async fn login_failed(&self, username: Option<&str>, client_ip: Option<IpAddr>) {
+        if let Some(login_throttle) = &self.login_throttle {
+            tokio::time::sleep(login_throttle.record_failure(username, client_ip)).await;
+        }
+    }
+
+    pub fn login_succeeded(&self, username: &str, client_ip: Option<IpAddr>) {
+        if let Some(login_throttle) = &self.login_throttle {
+            login_throttle.record_success(username, client_ip);
+        }
+    }
+
+    /// Finds out who is behind a request from its Authorization header. Requests without
+    /// credentials are anonymous, requests with invalid credentials are errors.
+    pub async fn identify(&self, method: AuthenticationMethod, headers: &HeaderMap) -> Result<Identity, AuthenticationError> {
+        match method {
+            AuthenticationMethod::Basic => match basic_credentials(headers) {
+                Some((_, password)) if self.api_keys.is_some() && ApiKeys::is_api_key(&password) => self.api_key_identity(&password),
+                Some((username, password)) => match self.verify_credentials(&username, &password).await {
+                    true => Ok(Identity {
+                        account: Some(username),
+                        token_access: None
+                    }),
+                    false => Err(AuthenticationError::BadCredentials)
+                },
+                None => Ok(Identity::default())
+            },
+
+            AuthenticationMethod::Bearer => match bearer_token(headers) {
+                Some(token) if self.api_keys.is_some() && ApiKeys::is_api_key(&token) => self.api_key_identity(&token),
+                Some(token) => {
+                    let token_issuer = self.token_issuer.as_ref().ok_or(AuthenticationError::NoTokenIssuer)?;
+                    let claims = token_issuer.verify(&token)?;
+                    if self.revocations.as_ref().is_some_and(|revocations| revocations.is_revoked(&claims)) {
+                        return Err(AuthenticationError::RevokedToken);
+                    }
+
+                    Ok(Identity {
+                        account: Some(claims.sub).filter(|sub| !sub.is_empty()),
+                        token_access: Some(claims.access)
+                    })
+                },
+                // Without a token, nothing is allowed: the client has to go fetch one
+                None => Ok(Identity {
+                    account: None,
+                    token_access: Some(Vec::new())
+                })
+            }
+        }
+    }
+
+    /// Requests made with an API key act as the account named after the key, restricted to its scopes like a token
+    fn api_key_identity(&self, presented: &str) -> Result<Identity, AuthenticationError> {
+        let key = self.api_keys
+            .as_ref()
+            .and_then(|api_keys| api_keys.verify(presented))
+            .ok_or(AuthenticationError::BadCredentials)?;
+
+        Ok(Identity {
+            account: Some(key.name),
+            token_access: Some(key.scopes)
+        })
+    }
+
+    /// Whether the identity is allowed to perform the action on the repository.
+    pub fn is_allowed(&self, identity: &Identity, repository: &str, action: &str) -> bool {
+        match &identity.token_access {
+            Some(access) => access.iter().any(|access| access.allows("repository", repository, action)),
+            None => !self.acl
+                .granted_actions(identity.account.as_deref(), repository, &[action.to_string()])
+                .is_empty()
+        }
+    }
+
+    /// Whether the identity is allowed on the admin routes, either as an admin account or with the admin scope
+    pub fn is_admin(&self, identity: &Identity) -> bool {
+        match &identity.token_access {
+            Some(access) => access.iter().any(|access| access.allows_admin()),
+            None => self.acl.is_admin(identity.account.as_deref())
+        }
+    }
+
+    /// Checks a username and password against the configured users.
+    pub async fn verify_credentials(&self, username: &str, password: &str) -> bool {
+        match &self.htpasswd {
+            Some(htpasswd) => htpasswd.verify(username, password).await,
+            None => false
+        }
+    }
+}
+
+/// Extracts the username and password from a `Basic` Authorization header.
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let (scheme, encoded) = headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Extracts the token from a `Bearer` Authorization header.
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let (scheme, token) = headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    Some(token.trim().to_string())
+}
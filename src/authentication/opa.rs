@@ -0,0 +1,112 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::configuration::OpaConfiguration;
+
+/// What the policy engine gets to decide on, sent as the `input` document
+#[derive(Serialize)]
+pub struct AuthorizationInput {
+    /// None for anonymous requests
+    pub account: Option<String>,
+    pub repository: String,
+    pub action: String,
+    pub method: String,
+    pub path: String,
+    pub client_ip: Option<IpAddr>,
+}
+
+#[derive(Serialize)]
+struct DecisionRequest<'a> {
+    input: &'a AuthorizationInput,
+}
+
+#[derive(Deserialize)]
+struct DecisionResponse {
+    /// Missing when the rule is undefined, which OPA does when no rule matches
+    result: Option<DecisionResult>,
+}
+
+/// Rules either evaluate to a boolean or to an object with the verdict and its reason
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DecisionResult {
+    Allowed(bool),
+    Detailed {
+        #[serde(default)]
+        allow: bool,
+        reason: Option<String>,
+    },
+}
+
+/// Account, repository, action, method, path and client IP address of the requests sharing a decision.
+/// Policies may tell the requests apart by anything they are given.
+type DecisionKey = (Option<String>, String, String, String, String, Option<IpAddr>);
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub reason: Option<String>,
+}
+
+/// Asks an Open Policy Agent server whether requests are allowed, remembering the answers for a little while
+pub struct OpaAuthorizer {
+    http_client: reqwest::Client,
+    url: String,
+    cache_duration: Duration,
+    decisions: Arc<RwLock<HashMap<DecisionKey, (Decision, Instant)>>>,
+}
+
+impl OpaAuthorizer {
+    pub fn new(configuration: &OpaConfiguration) -> eyre::Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(configuration.timeout_seconds))
+            .build()?;
+
+        Ok(Self {
+            http_client,
+            url: configuration.url.clone(),
+            cache_duration: Duration::from_secs(configuration.cache_seconds),
+            decisions: Default::default(),
+        })
+    }
+
+    pub async fn decide(&self, input: &AuthorizationInput) -> eyre::Result<Decision> {
+        let key: DecisionKey = (
+            input.account.clone(), input.repository.clone(), input.action.clone(),
+            input.method.clone(), input.path.clone(), input.client_ip
+        );
+
+        if let Some((decision, decided_at)) = self.decisions.read().await.get(&key) {
+            if decided_at.elapsed() < self.cache_duration {
+                return Ok(decision.clone());
+            }
+        }
+
+        let response = self.http_client
+            .post(&self.url)
+            .json(&DecisionRequest { input })
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<DecisionResponse>()
+            .await?;
+
+        let decision = match response.result {
+            Some(DecisionResult::Allowed(allowed)) => Decision { allowed, reason: None },
+            Some(DecisionResult::Detailed { allow, reason }) => Decision { allowed: allow, reason },
+            None => Decision { allowed: false, reason: Some("No policy decision".to_string()) }
+        };
+        debug!("Policy decision for {:?} on {} {}: {:?}", input.account, input.action, input.repository, decision);
+
+        if !self.cache_duration.is_zero() {
+            let mut decisions = self.decisions.write().await;
+            decisions.retain(|_, (_, decided_at)| decided_at.elapsed() < self.cache_duration);
+            decisions.insert(key, (decision.clone(), Instant::now()));
+        }
+
+        Ok(decision)
+    }
+}
@@ -0,0 +1,381 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::Mutex};
+
+use axum::{body::{Body, HttpBody}, extract::State, http::{HeaderMap, Request, Uri}, middleware::Next, response::{IntoResponse, Response}};
+use chrono::Utc;
+use eyre::ContextCompat;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{ApplicationState, configuration::RequestSigningConfiguration, controllers::RegistryHttpError, requests};
+
+use super::{Identity, acl::ResourceAccess};
+
+/// Authorization scheme of the signed requests, as in
+/// `Authorization: HMAC-SHA256 key=ci-puller, timestamp=1700000000, nonce=<random>, signature=<hex>`
+pub const SIGNATURE_SCHEME: &str = "HMAC-SHA256";
+
+/// Longest nonce accepted, 128 bits in hex or base64 fit with room to spare
+const MAX_NONCE_LENGTH: usize = 64;
+
+struct SigningKey {
+    secret: Vec<u8>,
+    account: String,
+    scopes: Vec<ResourceAccess>
+}
+
+/// Checks the requests signed by internal services with a key shared with the registry
+pub struct RequestVerifier {
+    keys: HashMap<String, SigningKey>,
+    max_skew_seconds: i64,
+    max_body_bytes: usize,
+    seen_nonces: Mutex<SeenNonces>
+}
+
+/// Nonces of the signatures accepted, by key, for as long as their timestamp is within the allowed skew
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<(String, String)>,
+    /// When each nonce can be forgotten, oldest first
+    expirations: VecDeque<(i64, (String, String))>
+}
+
+impl SeenNonces {
+    /// Records the nonce, false if it has already been used
+    fn insert(&mut self, key: &str, nonce: &str, now: i64, forget_at: i64) -> bool {
+        while self.expirations.front().is_some_and(|(expiration, _)| *expiration < now) {
+            if let Some((_, seen)) = self.expirations.pop_front() {
+                self.nonces.remove(&seen);
+            }
+        }
+
+        let seen = (key.to_string(), nonce.to_string());
+        if !self.nonces.insert(seen.clone()) {
+            return false;
+        }
+        // Expirations are mostly in order, a signature dated ahead is remembered for a bit longer than needed
+        let forget_at = self.expirations.back().map_or(forget_at, |(last, _)| forget_at.max(*last));
+        self.expirations.push_back((forget_at, seen));
+        true
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureError {
+    #[error("Malformed signature parameters")]
+    Malformed,
+
+    #[error("Unknown signing key {0}")]
+    UnknownKey(String),
+
+    #[error("The signature timestamp is too far from the current time")]
+    Expired,
+
+    #[error("The body is too large to be signed")]
+    BodyTooLarge,
+
+    #[error("The signature doesn't match the request")]
+    Mismatch,
+
+    #[error("The nonce of the signature has already been used")]
+    Replayed,
+}
+
+struct SignatureParameters {
+    key: String,
+    timestamp: i64,
+    nonce: String,
+    signature: Vec<u8>
+}
+
+impl RequestVerifier {
+    pub fn new(configuration: &RequestSigningConfiguration) -> eyre::Result<Self> {
+        let mut keys = HashMap::new();
+        for key in &configuration.keys {
+            let scopes = key.scopes
+                .iter()
+                .map(|scope| ResourceAccess::from_scope(scope).with_context(|| format!("Invalid scope {} for the signing key {}", scope, key.id)))
+                .collect::<eyre::Result<Vec<_>>>()?;
+
+            keys.insert(key.id.clone(), SigningKey {
+                secret: key.secret.as_bytes().to_vec(),
+                account: key.account.clone().unwrap_or_else(|| key.id.clone()),
+                scopes
+            });
+        }
+
+        Ok(Self {
+            keys,
+            max_skew_seconds: configuration.max_skew_seconds as i64,
+            max_body_bytes: configuration.max_body_bytes,
+            seen_nonces: Mutex::new(SeenNonces::default())
+        })
+    }
+
+    /// Identity of the service behind a signed request. The string to sign is made of the method, the canonical
+    /// path, the canonical query string, the timestamp, the nonce and the hex SHA-256 of the body, separated by
+    /// new lines. A nonce is only accepted once while its timestamp is within the allowed skew.
+    fn verify(&self, method: &str, uri: &Uri, headers: &HeaderMap, body: &[u8]) -> Result<Identity, SignatureError> {
+        let parameters = signature_parameters(headers).ok_or(SignatureError::Malformed)?;
+        let key = self.keys.get(&parameters.key).ok_or_else(|| SignatureError::UnknownKey(parameters.key.clone()))?;
+
+        let now = Utc::now().timestamp();
+        if (now - parameters.timestamp).abs() > self.max_skew_seconds {
+            return Err(SignatureError::Expired);
+        }
+
+        let body_hash = base16ct::lower::encode_string(&Sha256::digest(body));
+        let signed = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, canonical_path(uri.path()), canonical_query(uri.query().unwrap_or_default()), parameters.timestamp, parameters.nonce, body_hash
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret).map_err(|_| SignatureError::Malformed)?;
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&parameters.signature).map_err(|_| SignatureError::Mismatch)?;
+
+        // Only genuine signatures record their nonce, others can't use up the nonces of a key
+        let forget_at = parameters.timestamp + self.max_skew_seconds;
+        if !self.seen_nonces.lock().unwrap().insert(&parameters.key, &parameters.nonce, now, forget_at) {
+            return Err(SignatureError::Replayed);
+        }
+
+        Ok(Identity {
+            account: Some(key.account.clone()),
+            token_access: Some(key.scopes.clone())
+        })
+    }
+}
+
+fn signature_parameters(headers: &HeaderMap) -> Option<SignatureParameters> {
+    let (scheme, parameters) = headers
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case(SIGNATURE_SCHEME) {
+        return None;
+    }
+
+    let parameters = parameters
+        .split(',')
+        .filter_map(|parameter| parameter.trim().split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim().trim_matches('"')))
+        .collect::<HashMap<_, _>>();
+
+    let nonce = parameters.get("nonce").filter(|nonce| !nonce.is_empty() && nonce.len() <= MAX_NONCE_LENGTH)?;
+
+    Some(SignatureParameters {
+        key: parameters.get("key")?.to_string(),
+        timestamp: parameters.get("timestamp")?.parse().ok()?,
+        nonce: nonce.to_string(),
+        signature: base16ct::mixed::decode_vec(parameters.get("signature")?).ok()?
+    })
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986, after decoding what already was.
+/// Clients and proxies encoding the same URL differently end up with the same canonical form.
+fn canonical_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = (bytes[index] == b'%')
+            .then(|| bytes.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            },
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    decoded.iter()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (*byte as char).to_string(),
+            _ => format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+/// Each segment of the path in its canonical form. Encoded slashes stay encoded, they are part of their segment.
+fn canonical_path(path: &str) -> String {
+    path.split('/').map(canonical_component).collect::<Vec<_>>().join("/")
+}
+
+/// Parameters of the query in their canonical form, sorted by name and then by value
+fn canonical_query(query: &str) -> String {
+    let mut parameters = query
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .map(|parameter| {
+            let (name, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (canonical_component(name), canonical_component(value))
+        })
+        .collect::<Vec<_>>();
+    parameters.sort();
+
+    parameters.iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn is_signed(headers: &HeaderMap) -> bool {
+    headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case(SIGNATURE_SCHEME))
+}
+
+/// Checks the signature of the signed requests, whose body is read in memory to be hashed.
+/// The identity of the signing service is left in the request extensions for the authorization middleware.
+pub async fn verify_request_signature(
+    State(app): State<ApplicationState>,
+    req: Request<Body>,
+    next: Next<Body>
+) -> Response {
+    let verifier = match app.authenticator.as_deref().and_then(|authenticator| authenticator.request_verifier.as_ref()) {
+        Some(verifier) if is_signed(req.headers()) => verifier,
+        _ => return next.run(req).await
+    };
+
+    let (mut parts, mut body) = req.into_parts();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if content.len() + chunk.len() <= verifier.max_body_bytes => content.extend_from_slice(&chunk),
+            Ok(_) => {
+                warn!("Rejected signed request: {}", SignatureError::BodyTooLarge);
+                return RegistryHttpError::denied(SignatureError::BodyTooLarge).into_response();
+            },
+            Err(e) => return RegistryHttpError::from(axum::Error::new(e)).into_response()
+        }
+    }
+
+    match verifier.verify(parts.method.as_str(), &parts.uri, &parts.headers, &content) {
+        Ok(identity) => {
+            requests::record_identity(&identity);
+            parts.extensions.insert(identity);
+            next.run(Request::from_parts(parts, Body::from(content))).await
+        },
+        Err(e) => {
+            warn!("Rejected signed request: {}", e);
+            let challenge = app.conf.authentication
+                .as_ref()
+                .map(|authentication| authentication.challenge(None, None))
+                .unwrap_or_default();
+            RegistryHttpError::unauthorized(challenge).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, Uri};
+    use chrono::Utc;
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    use super::{RequestVerifier, SignatureError, canonical_path, canonical_query};
+    use crate::configuration::{RequestSigningConfiguration, SigningKeyConfiguration};
+
+    fn verifier() -> RequestVerifier {
+        RequestVerifier::new(&RequestSigningConfiguration {
+            max_skew_seconds: 300,
+            max_body_bytes: 1024,
+            keys: vec![SigningKeyConfiguration {
+                id: "ci-puller".to_string(),
+                secret: "change-me".to_string(),
+                account: None,
+                scopes: vec!["repository:proxy/*:pull".to_string()]
+            }]
+        }).unwrap()
+    }
+
+    fn signed_headers(signed: &str, timestamp: i64, nonce: &str) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"change-me").unwrap();
+        mac.update(signed.as_bytes());
+        let signature = base16ct::lower::encode_string(&mac.finalize().into_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            format!("HMAC-SHA256 key=ci-puller, timestamp={}, nonce={}, signature={}", timestamp, nonce, signature).parse().unwrap()
+        );
+        headers
+    }
+
+    fn signed_string(path: &str, query: &str, timestamp: i64, nonce: &str) -> String {
+        let body_hash = base16ct::lower::encode_string(&Sha256::digest(b""));
+        format!("GET\n{}\n{}\n{}\n{}\n{}", path, query, timestamp, nonce, body_hash)
+    }
+
+    #[test]
+    fn canonical_forms() {
+        assert_eq!(canonical_path("/v2/team/app/manifests/latest"), "/v2/team/app/manifests/latest");
+        assert_eq!(canonical_path("/v2/team%2fapp/%7Euser/a b"), "/v2/team%2Fapp/~user/a%20b");
+        assert_eq!(canonical_path("/v2/%zz/100%"), "/v2/%25zz/100%25");
+        assert_eq!(canonical_query(""), "");
+        assert_eq!(canonical_query("n=100&last=3.18&last=3%2E1"), "last=3.1&last=3.18&n=100");
+        assert_eq!(canonical_query("scope=repository:a/b:pull&flag"), "flag=&scope=repository%3Aa%2Fb%3Apull");
+    }
+
+    #[test]
+    fn differently_encoded_requests_match_their_signature() {
+        let verifier = verifier();
+        let timestamp = Utc::now().timestamp();
+        let headers = signed_headers(&signed_string("/v2/proxy/tags/list", "last=a%3Ab&n=10", timestamp, "first"), timestamp, "first");
+
+        let uri = Uri::from_static("/v2/proxy/tag%73/list?n=10&last=a:b");
+        let identity = verifier.verify("GET", &uri, &headers, b"").unwrap();
+        assert_eq!(identity.account.as_deref(), Some("ci-puller"));
+
+        // Decoding an encoded slash would change the repository the request is about
+        let headers = signed_headers(&signed_string("/v2/proxy/tags/list", "", timestamp, "second"), timestamp, "second");
+        let uri = Uri::from_static("/v2/proxy%2Ftags/list");
+        assert!(matches!(verifier.verify("GET", &uri, &headers, b""), Err(SignatureError::Mismatch)));
+    }
+
+    #[test]
+    fn replayed_signatures_are_refused() {
+        let verifier = verifier();
+        let timestamp = Utc::now().timestamp();
+        let uri = Uri::from_static("/v2/");
+
+        let headers = signed_headers(&signed_string("/v2/", "", timestamp, "once"), timestamp, "once");
+        assert!(verifier.verify("GET", &uri, &headers, b"").is_ok());
+        assert!(matches!(verifier.verify("GET", &uri, &headers, b""), Err(SignatureError::Replayed)));
+
+        let headers = signed_headers(&signed_string("/v2/", "", timestamp, "twice"), timestamp, "twice");
+        assert!(verifier.verify("GET", &uri, &headers, b"").is_ok());
+
+        // A forged signature doesn't use up the nonce
+        let mut forged = signed_headers("forged", timestamp, "later");
+        assert!(matches!(verifier.verify("GET", &uri, &forged, b""), Err(SignatureError::Mismatch)));
+        forged = signed_headers(&signed_string("/v2/", "", timestamp, "later"), timestamp, "later");
+        assert!(verifier.verify("GET", &uri, &forged, b"").is_ok());
+    }
+
+    #[test]
+    fn signatures_need_a_nonce() {
+        let verifier = verifier();
+        let timestamp = Utc::now().timestamp();
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", format!("HMAC-SHA256 key=ci-puller, timestamp={}, signature=00", timestamp).parse().unwrap());
+        assert!(matches!(verifier.verify("GET", &Uri::from_static("/v2/"), &headers, b""), Err(SignatureError::Malformed)));
+
+        let nonce = "n".repeat(65);
+        let headers = signed_headers(&signed_string("/v2/", "", timestamp, &nonce), timestamp, &nonce);
+        assert!(matches!(verifier.verify("GET", &Uri::from_static("/v2/"), &headers, b""), Err(SignatureError::Malformed)));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::RwLock, time::Duration};
+
+use chrono::Utc;
+use eyre::Context;
+use serde::{Serialize, Deserialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::token::TokenClaims;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RevokedTokens {
+    /// Expiration time of the revoked tokens by token ID, after which they don't need to be remembered
+    pub tokens: HashMap<String, i64>,
+    /// Time of revocation of all the tokens issued to an account until then
+    pub accounts: HashMap<String, i64>,
+}
+
+/// Tokens that must not be accepted anymore, although they haven't expired yet.
+/// Written to a file on every change when one is configured, kept in memory otherwise.
+pub struct RevocationList {
+    path: Option<PathBuf>,
+    /// Lifetime of the tokens, after which the revocations of whole accounts are forgotten
+    token_lifetime: Duration,
+    revoked: RwLock<RevokedTokens>,
+    /// Serializes the writes of the file
+    file: Mutex<()>,
+}
+
+impl RevocationList {
+    pub fn load(path: Option<&Path>, token_lifetime: Duration) -> eyre::Result<Self> {
+        let revoked = match path.map(std::fs::read) {
+            Some(Ok(content)) => serde_json::from_slice(&content)
+                .with_context(|| format!("Unable to parse the revoked tokens in {:?}", path))?,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Unable to read the revoked tokens in {:?}", path));
+            },
+            _ => RevokedTokens::default()
+        };
+
+        Ok(Self {
+            path: path.map(Path::to_path_buf),
+            token_lifetime,
+            revoked: RwLock::new(revoked),
+            file: Mutex::new(()),
+        })
+    }
+
+    pub fn is_revoked(&self, claims: &TokenClaims) -> bool {
+        let revoked = self.revoked.read().unwrap();
+
+        revoked.tokens.contains_key(&claims.jti)
+            || revoked.accounts.get(&claims.sub).is_some_and(|revoked_at| claims.iat <= *revoked_at)
+    }
+
+    pub fn list(&self) -> RevokedTokens {
+        self.revoked.read().unwrap().clone()
+    }
+
+    /// Revokes a single token, remembered until it expires
+    pub async fn revoke_token(&self, jti: String, expires_at: i64) -> eyre::Result<()> {
+        info!("Revoking token {}", jti);
+        self.update(|revoked| {
+            revoked.tokens.insert(jti, expires_at);
+        }).await
+    }
+
+    /// Revokes all the tokens issued to an account so far
+    pub async fn revoke_account(&self, account: String) -> eyre::Result<()> {
+        info!("Revoking the tokens of {}", account);
+        self.update(|revoked| {
+            revoked.accounts.insert(account, Utc::now().timestamp());
+        }).await
+    }
+
+    async fn update<F: FnOnce(&mut RevokedTokens)>(&self, change: F) -> eyre::Result<()> {
+        let _file = self.file.lock().await;
+        let content = {
+            let mut revoked = self.revoked.write().unwrap();
+            change(&mut revoked);
+
+            // Expired tokens are refused anyway
+            let now = Utc::now().timestamp();
+            let token_lifetime = self.token_lifetime.as_secs() as i64;
+            revoked.tokens.retain(|_, expires_at| *expires_at > now);
+            revoked.accounts.retain(|_, revoked_at| *revoked_at + token_lifetime > now);
+
+            serde_json::to_vec_pretty(&*revoked)?
+        };
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+
+        // Written aside first, so a crash doesn't leave a truncated file
+        let temporary_path = path.with_extension("tmp");
+        tokio::fs::write(&temporary_path, content).await?;
+        tokio::fs::rename(&temporary_path, path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use eyre::Context;
+use jsonwebtoken::{Algorithm, EncodingKey, DecodingKey, Header, Validation};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::configuration::{TokenConfiguration, TokenAlgorithm};
+
+use super::acl::ResourceAccess;
+
+/// Claims of the tokens we hand out, following the Docker registry token specification.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub nbf: i64,
+    pub iat: i64,
+    pub jti: String,
+    pub access: Vec<ResourceAccess>
+}
+
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_in: Duration,
+    pub issued_at: chrono::DateTime<Utc>
+}
+
+pub struct TokenIssuer {
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    issuer: String,
+    audience: String,
+    lifetime: Duration
+}
+
+impl TokenIssuer {
+    pub fn load(configuration: &TokenConfiguration, audience: &str) -> eyre::Result<Self> {
+        let private_key = std::fs::read(&configuration.private_key)
+            .with_context(|| format!("Unable to read token signing key {:?}", configuration.private_key))?;
+        let public_key = std::fs::read(&configuration.public_key)
+            .with_context(|| format!("Unable to read token verification key {:?}", configuration.public_key))?;
+
+        let (algorithm, encoding_key, decoding_key) = match configuration.algorithm {
+            TokenAlgorithm::RS256 => (
+                Algorithm::RS256,
+                EncodingKey::from_rsa_pem(&private_key)?,
+                DecodingKey::from_rsa_pem(&public_key)?
+            ),
+            TokenAlgorithm::ES256 => (
+                Algorithm::ES256,
+                EncodingKey::from_ec_pem(&private_key)?,
+                DecodingKey::from_ec_pem(&public_key)?
+            ),
+        };
+
+        Ok(Self {
+            algorithm,
+            encoding_key,
+            decoding_key,
+            issuer: configuration.issuer.clone(),
+            audience: audience.to_string(),
+            lifetime: Duration::from_secs(configuration.expiration_seconds)
+        })
+    }
+
+    pub fn issue(&self, account: Option<&str>, access: Vec<ResourceAccess>) -> eyre::Result<IssuedToken> {
+        self.issue_for(account, access, self.lifetime)
+    }
+
+    /// Issues a token expiring sooner than the others, never later
+    pub fn issue_for(&self, account: Option<&str>, access: Vec<ResourceAccess>, lifetime: Duration) -> eyre::Result<IssuedToken> {
+        let lifetime = lifetime.min(self.lifetime);
+        let issued_at = Utc::now();
+        let claims = TokenClaims {
+            iss: self.issuer.clone(),
+            sub: account.unwrap_or_default().to_string(),
+            aud: self.audience.clone(),
+            exp: issued_at.timestamp() + lifetime.as_secs() as i64,
+            nbf: issued_at.timestamp(),
+            iat: issued_at.timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            access,
+        };
+
+        let token = jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.encoding_key)?;
+
+        Ok(IssuedToken {
+            token,
+            expires_in: lifetime,
+            issued_at
+        })
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, jsonwebtoken::errors::Error> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+
+        jsonwebtoken::decode::<TokenClaims>(token, &self.decoding_key, &validation)
+            .map(|data| data.claims)
+    }
+}
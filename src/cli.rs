@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use tracing::info;
+
+use crate::{configuration::Configuration, data::proxy_cache};
+
+/// Runs the command given on the command line, if any. Returns false when the server should start.
+pub fn run(configuration: &Configuration, args: &[String]) -> eyre::Result<bool> {
+    match args {
+        [] => Ok(false),
+
+        [command, archive, repositories @ ..] if command == "export" => {
+            let archive_file = std::fs::File::create(archive)?;
+            let exported = proxy_cache::export_repositories(&configuration.proxy_storage, repositories, archive_file)?;
+            info!("Exported {} repositories to {}", exported.len(), archive);
+            Ok(true)
+        },
+
+        [command, archive] if command == "import" => {
+            let archive_file = std::fs::File::open(Path::new(archive))?;
+            let imported = proxy_cache::import_repositories(&configuration.proxy_storage, archive_file)?;
+            info!("Imported {} repositories from {}", imported.len(), archive);
+            Ok(true)
+        },
+
+        _ => Err(eyre::eyre!(
+            "Usage: {} [export <archive> [repository...] | import <archive>]",
+            env!("CARGO_PKG_NAME")
+        ))
+    }
+}
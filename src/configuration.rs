@@ -1,9 +1,1066 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration, collections::HashMap, net::IpAddr, num::NonZeroUsize};
+use ipnet::IpNet;
 use serde::Deserialize;
 
-#[derive(Deserialize, Debug)]
+use crate::{authentication::acl::AclEntry, data::helpers::resolve_upstream_registry};
+
+#[derive(Deserialize, Debug, Default)]
 pub struct Configuration {
     pub registry_storage: PathBuf,
     pub temporary_registry_storage: PathBuf,
-    pub proxy_storage: PathBuf
+    pub proxy_storage: PathBuf,
+    pub authentication: Option<AuthenticationConfiguration>,
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfiguration,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfiguration,
+    #[serde(default)]
+    pub server: ServerConfiguration,
+    #[serde(default)]
+    pub logging: LoggingConfiguration,
+    #[serde(default)]
+    pub runtime: RuntimeConfiguration,
+    #[serde(default)]
+    pub upstream: UpstreamConfiguration,
+    #[serde(default)]
+    pub cache: CacheConfiguration,
+    #[serde(default)]
+    pub storage: StorageConfiguration,
+    pub scanner: Option<ScannerConfiguration>,
+    pub notifications: Option<NotificationsConfiguration>,
+    /// Admin API for orchestration tooling, served with gRPC on a port of its own
+    pub grpc: Option<GrpcConfiguration>,
+    /// Conditions images have to meet to be pulled, the first rule matching the repository applies
+    #[serde(default)]
+    pub policies: Vec<PolicyRule>,
+    /// Teams served by this instance with storage of their own. Requests belonging to no tenant use the
+    /// storage roots above.
+    #[serde(default)]
+    pub tenants: Vec<TenantConfiguration>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrpcConfiguration {
+    #[serde(default = "default_grpc_address")]
+    pub address: IpAddr,
+    pub port: u16,
+    /// Bearer tokens accepted from the callers, independently from the downstream authentication
+    pub tokens: Vec<String>
+}
+
+fn default_grpc_address() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TenantConfiguration {
+    pub name: String,
+    pub registry_storage: PathBuf,
+    pub temporary_registry_storage: PathBuf,
+    pub proxy_storage: PathBuf,
+    /// Repositories of the tenant, like `team-a/*`. Proxied repositories start with `proxy/` and are named after
+    /// their upstream registry. A trailing `*` matches any repository with this prefix.
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Accounts whose requests go to this tenant when the repository belongs to no tenant
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Most bytes the registry storage of the tenant may hold. Pushes are refused beyond that.
+    pub quota_bytes: Option<u64>,
+    /// Upstream registry settings of the tenant, credentials for instance, taking over the global ones
+    #[serde(default)]
+    pub upstream_registries: HashMap<String, UpstreamRegistryConfiguration>
+}
+
+impl TenantConfiguration {
+    pub fn owns_repository(&self, repository: &str) -> bool {
+        self.repositories.iter().any(|pattern| repository_matches(pattern, repository))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Unknown,
+    Negligible,
+    Low,
+    Medium,
+    High,
+    Critical
+}
+
+impl Severity {
+    /// Severities are named a bit differently by each scanner
+    pub fn parse(severity: &str) -> Self {
+        match severity.to_lowercase().as_str() {
+            "negligible" => Severity::Negligible,
+            "low" => Severity::Low,
+            "medium" | "moderate" => Severity::Medium,
+            "high" | "important" => Severity::High,
+            "critical" => Severity::Critical,
+            _ => Severity::Unknown
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PolicyRule {
+    /// Repository name this rule applies to, starting with `proxy/` for the proxy cache.
+    /// A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    /// A cosign or Notation signature of the manifest has to be found
+    #[serde(default)]
+    pub require_signature: bool,
+    /// Highest severity of the vulnerabilities found by the scanner. Images without a scan report are denied.
+    pub max_severity: Option<Severity>,
+    /// Upstream registries proxied images may come from
+    pub allowed_registries: Option<Vec<String>>,
+    /// Images built longer ago than this are denied
+    pub max_age_days: Option<i64>
+}
+
+impl Configuration {
+    /// First policy rule matching the repository, if any.
+    pub fn policy_rule(&self, repository: &str) -> Option<&PolicyRule> {
+        self.policies.iter().find(|rule| repository_matches(&rule.repository, repository))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScannerKind {
+    /// Clair v4 indexer and matcher APIs
+    Clair,
+    /// Any service taking the image reference and answering with a Trivy JSON report
+    Http
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScannerConfiguration {
+    pub kind: ScannerKind,
+    /// Base URL of the scanner API
+    pub url: String,
+    /// URL the scanner reaches this registry at, to download the images
+    pub registry_url: String,
+    /// Repositories whose images are scanned, all of them if empty. Proxied repositories start with `proxy/`.
+    /// A trailing `*` matches any repository with this prefix.
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Time given to the scanner for each image, in seconds
+    #[serde(default = "default_scan_timeout")]
+    pub timeout: u64
+}
+
+fn default_scan_timeout() -> u64 {
+    300
+}
+
+impl ScannerConfiguration {
+    pub fn scans(&self, repository: &str) -> bool {
+        self.repositories.is_empty() || self.repositories.iter().any(|pattern| repository_matches(pattern, repository))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotificationsConfiguration {
+    /// Where the events waiting to be delivered and the dead letters are kept
+    pub directory: PathBuf,
+    pub endpoints: Vec<NotificationEndpoint>,
+    /// Deliveries given up after this many attempts end up in the dead letters
+    #[serde(default = "default_notification_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, in seconds, doubled after every failed attempt
+    #[serde(default = "default_notification_backoff")]
+    pub initial_backoff: u64,
+    #[serde(default = "default_notification_max_backoff")]
+    pub max_backoff: u64,
+    /// Time given to the endpoints to answer, in seconds
+    #[serde(default = "default_notification_timeout")]
+    pub timeout: u64,
+    /// Deliveries attempted at the same time, so a slow endpoint doesn't hold up the others
+    #[serde(default = "default_notification_concurrency")]
+    pub max_concurrent_deliveries: usize
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EndpointKind {
+    /// HTTP POST of the events
+    #[default]
+    Webhook,
+    /// Published on a NATS subject, through the core NATS protocol over connections kept open
+    Nats,
+    /// Produced to a Kafka topic, through a Confluent REST Proxy
+    Kafka
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    /// Events as the registry describes them
+    #[default]
+    Json,
+    /// Events wrapped in CloudEvents 1.0 structured mode
+    CloudEvents
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotificationEndpoint {
+    /// Unique name, used to keep track of the deliveries
+    pub name: String,
+    #[serde(default)]
+    pub kind: EndpointKind,
+    /// Webhook URL, `nats://[user:password@]host:port` server (`tls://` to require TLS), or base URL of the Kafka REST Proxy
+    pub url: String,
+    /// NATS subject or Kafka topic the events are published to
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub format: EventFormat,
+    /// Sent along with each webhook or Kafka REST Proxy request, for authentication for instance
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Repositories whose events are sent, all of them if empty. A trailing `*` matches any repository with this prefix.
+    #[serde(default)]
+    pub repositories: Vec<String>
+}
+
+fn default_notification_attempts() -> u32 {
+    10
+}
+
+fn default_notification_backoff() -> u64 {
+    1
+}
+
+fn default_notification_max_backoff() -> u64 {
+    600
+}
+
+fn default_notification_timeout() -> u64 {
+    10
+}
+
+fn default_notification_concurrency() -> usize {
+    8
+}
+
+impl NotificationsConfiguration {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Delay before the next attempt of a delivery that already failed `attempts` times
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let backoff = self.initial_backoff.saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)));
+        Duration::from_secs(backoff.min(self.max_backoff))
+    }
+}
+
+impl NotificationEndpoint {
+    pub fn notifies(&self, repository: &str) -> bool {
+        self.repositories.is_empty() || self.repositories.iter().any(|pattern| repository_matches(pattern, repository))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct StorageConfiguration {
+    /// Repositories whose pushed blobs are stored compressed with zstd
+    #[serde(default)]
+    pub compression: Vec<CompressionRule>,
+    /// Encrypts the blobs and manifests pushed to the registry
+    pub encryption: Option<EncryptionConfiguration>,
+    /// Keeps the whole storage in memory instead of the storage roots, for tests and throwaway registries
+    pub memory: Option<MemoryStorageConfiguration>,
+    /// How often the blobs of the proxy cache also pushed to the registry are replaced with hard links to
+    /// the pushed copy. Not done without it.
+    pub deduplication_interval_seconds: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MemoryStorageConfiguration {
+    /// Pushes are refused once the registry, the uploads and the proxy cache hold this much
+    pub max_bytes: u64
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EncryptionConfiguration {
+    /// Base64 encoded 256 bits AES key
+    pub key: Option<String>,
+    /// File holding the base64 encoded key, such as a mounted secret
+    pub key_file: Option<PathBuf>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CompressionRule {
+    /// Repository name this rule applies to. A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    #[serde(default = "default_compression_level")]
+    pub level: i32
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+impl StorageConfiguration {
+    /// First compression rule matching the repository, if any.
+    pub fn compression_rule(&self, repository: &str) -> Option<&CompressionRule> {
+        self.compression.iter().find(|rule| repository_matches(&rule.repository, repository))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CacheConfiguration {
+    /// Proxied repositories whose cached blobs are hashed again before being served
+    #[serde(default)]
+    pub verification: Vec<CacheVerificationRule>,
+    /// Proxied repositories whose blobs are streamed from upstream without being cached. A trailing `*`
+    /// matches any repository with this prefix.
+    #[serde(default)]
+    pub pass_through: Vec<String>,
+    /// How long proxied tags are served from the cache before asking upstream whether they moved.
+    /// Without it, upstream is asked on every pull.
+    pub manifest_ttl_seconds: Option<u64>,
+    /// Free space kept on the filesystem of the proxy storage, cached blobs are evicted to stay over it
+    pub free_space_watermark: Option<FreeSpaceWatermark>,
+    /// Cached blobs kept open for their ranged reads, as lazy-pulling snapshotters do many of them
+    pub open_blobs: Option<usize>,
+    /// Images a prefetch or prewarm fetches at the same time at most, whatever the caller asks for. 16 by default.
+    pub max_prefetch_parallelism: Option<usize>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FreeSpaceWatermark {
+    /// Free bytes under which the least recently used blobs are evicted and the blobs passed through
+    pub min_free_bytes: Option<u64>,
+    /// Same, for the free inodes
+    pub min_free_inodes: Option<u64>,
+    /// How often the free space is checked
+    #[serde(default = "default_watermark_check_interval")]
+    pub check_interval_seconds: u64
+}
+
+fn default_watermark_check_interval() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheVerificationMode {
+    /// Every cache hit is verified
+    Full,
+    /// A random share of the cache hits is verified
+    Sampled
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CacheVerificationRule {
+    /// Repository name this rule applies to. A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    pub mode: CacheVerificationMode,
+    /// Share of the cache hits verified in the sampled mode, between 0 and 1
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64
+}
+
+fn default_sample_rate() -> f64 {
+    0.1
+}
+
+/// Whether a repository pattern of the configuration names the repository. A trailing `*` matches any
+/// repository with this prefix.
+pub fn repository_matches(pattern: &str, repository: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => repository.starts_with(prefix),
+        None => pattern == repository
+    }
+}
+
+impl CacheConfiguration {
+    /// First verification rule matching the repository, if any.
+    pub fn verification_rule(&self, repository: &str) -> Option<&CacheVerificationRule> {
+        self.verification.iter().find(|rule| repository_matches(&rule.repository, repository))
+    }
+
+    pub fn manifest_ttl(&self) -> Option<Duration> {
+        self.manifest_ttl_seconds.map(Duration::from_secs)
+    }
+
+    pub fn max_prefetch_parallelism(&self) -> usize {
+        self.max_prefetch_parallelism.unwrap_or(16)
+    }
+
+    pub fn passes_through(&self, repository: &str) -> bool {
+        self.pass_through.iter().any(|pattern| repository_matches(pattern, repository))
+    }
+}
+
+impl CacheVerificationRule {
+    /// Whether the cache hit being served should be verified
+    pub fn should_verify(&self) -> bool {
+        match self.mode {
+            CacheVerificationMode::Full => true,
+            CacheVerificationMode::Sampled => rand::random::<f64>() < self.sample_rate
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpstreamConfiguration {
+    /// Maximum number of redirects followed on a single upstream request
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: usize,
+    /// Maximum number of simultaneous requests sent to a single upstream registry
+    pub max_concurrent_requests_per_registry: Option<usize>,
+    /// Clients of the upstream repositories are built again after this long, authentication included
+    #[serde(default = "default_client_ttl_seconds")]
+    pub client_ttl_seconds: u64,
+    /// How long the authentication challenge of a registry is reused by its new clients before asking again
+    #[serde(default = "default_challenge_ttl_seconds")]
+    pub challenge_ttl_seconds: u64,
+    /// Downloads not matching their digest tolerated from a registry before it is reported as broken
+    #[serde(default)]
+    pub digest_mismatch_budget: DigestMismatchBudget,
+    /// Appended to the User-Agent sent upstream, for instance to give a contact address
+    pub user_agent_suffix: Option<String>,
+    /// Addresses to reach upstream hosts at, instead of asking the DNS
+    #[serde(default)]
+    pub dns_overrides: HashMap<String, Vec<IpAddr>>,
+    /// Sibling proxies asked for blobs missing from the cache before the upstream registry, as in "http://proxy-b:8000"
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Docker-style config.json to take the credentials saved by `docker login` from
+    pub docker_config: Option<PathBuf>,
+    /// Proxied repositories whose images need a valid cosign signature to be cached and served
+    #[serde(default)]
+    pub signature_verification: Vec<SignatureVerificationRule>,
+    /// Repositories of the registry whose pushes are sent on to an upstream registry
+    #[serde(default)]
+    pub push_through: Vec<PushThroughRule>,
+    /// Settings of specific upstream registries, by host name
+    #[serde(default)]
+    pub registries: HashMap<String, UpstreamRegistryConfiguration>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignatureVerificationRule {
+    /// Proxied repository name this rule applies to. A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    /// PEM public keys the images may be signed with, as made by `cosign generate-key-pair`
+    pub public_keys: Vec<PathBuf>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PushThroughRule {
+    /// Repository of the registry this rule applies to. A trailing `*` matches any repository with this prefix.
+    pub repository: String,
+    /// Registry, and namespace if any, the pushes go to, as in `registry.example.com/mirror`. The repository
+    /// of the registry is appended to it.
+    pub upstream: String,
+    /// Upstream repositories the blobs are mounted from when they already hold them, shared base images for instance
+    #[serde(default)]
+    pub mount_from: Vec<String>,
+}
+
+impl PushThroughRule {
+    /// Upstream repository the pushes to a repository of the registry go to
+    pub fn upstream_repository(&self, container_ref: &str) -> String {
+        format!("{}/{}", self.upstream.trim_end_matches('/'), container_ref)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpstreamRegistryConfiguration {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// OAuth2 refresh token, such as the identity tokens of ACR
+    pub identity_token: Option<String>,
+    /// Authenticate to ACR with the Azure managed identity of the machine
+    #[serde(default)]
+    pub azure_managed_identity: bool,
+    /// Client ID of the user-assigned managed identity to use, if not the system-assigned one
+    pub azure_client_id: Option<String>,
+    /// Authenticate to ECR with the AWS credentials of the environment
+    #[serde(default)]
+    pub aws_ecr: bool,
+    /// Region of the ECR registry, taken from its host name by default
+    pub aws_region: Option<String>,
+    /// Docker credential helper giving the credentials, like "ecr-login" for docker-credential-ecr-login
+    pub credential_helper: Option<String>,
+    /// How long the credentials given by the helper are used before running it again
+    #[serde(default = "default_credential_helper_cache_seconds")]
+    pub credential_helper_cache_seconds: u64,
+    /// Proxy the registry is reached through, such as "socks5h://proxy.internal:1080"
+    pub proxy: Option<String>
+}
+
+fn default_credential_helper_cache_seconds() -> u64 {
+    900
+}
+
+impl UpstreamConfiguration {
+    /// Settings of an upstream registry, whichever of its names the configuration uses
+    pub fn registry(&self, registry: &str) -> Option<&UpstreamRegistryConfiguration> {
+        self.registries
+            .iter()
+            .find(|(name, _)| resolve_upstream_registry(name) == registry)
+            .map(|(_, registry_configuration)| registry_configuration)
+    }
+
+    /// First push-through rule matching the repository of the registry
+    pub fn push_through_rule(&self, container_ref: &str) -> Option<&PushThroughRule> {
+        self.push_through.iter().find(|rule| repository_matches(&rule.repository, container_ref))
+    }
+
+    pub fn user_agent(&self) -> String {
+        let user_agent = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+        match &self.user_agent_suffix {
+            Some(suffix) => format!("{} {}", user_agent, suffix),
+            None => user_agent
+        }
+    }
+}
+
+impl Default for UpstreamConfiguration {
+    fn default() -> Self {
+        Self {
+            max_redirects: default_max_redirects(),
+            max_concurrent_requests_per_registry: None,
+            client_ttl_seconds: default_client_ttl_seconds(),
+            challenge_ttl_seconds: default_challenge_ttl_seconds(),
+            digest_mismatch_budget: DigestMismatchBudget::default(),
+            user_agent_suffix: None,
+            dns_overrides: HashMap::new(),
+            peers: Vec::new(),
+            docker_config: None,
+            push_through: Vec::new(),
+            signature_verification: Vec::new(),
+            registries: HashMap::new()
+        }
+    }
+}
+
+fn default_max_redirects() -> usize {
+    5
+}
+
+fn default_client_ttl_seconds() -> u64 {
+    3600
+}
+
+fn default_challenge_ttl_seconds() -> u64 {
+    3600
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DigestMismatchBudget {
+    /// Mismatches within the window over which the registry is reported
+    #[serde(default = "default_max_digest_mismatches")]
+    pub max_mismatches: usize,
+    #[serde(default = "default_digest_mismatch_window_seconds")]
+    pub window_seconds: u64
+}
+
+impl Default for DigestMismatchBudget {
+    fn default() -> Self {
+        Self {
+            max_mismatches: default_max_digest_mismatches(),
+            window_seconds: default_digest_mismatch_window_seconds()
+        }
+    }
+}
+
+fn default_max_digest_mismatches() -> usize {
+    3
+}
+
+fn default_digest_mismatch_window_seconds() -> u64 {
+    3600
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RuntimeConfiguration {
+    /// Threads running the requests, one per CPU core by default. The counts can't be 0, which the runtime
+    /// would panic on, or which would leave every hash waiting.
+    pub worker_threads: Option<NonZeroUsize>,
+    /// Threads doing the file work, hashing included, 512 by default
+    pub max_blocking_threads: Option<NonZeroUsize>,
+    /// Idle blocking threads are stopped after this long, 10 seconds by default
+    pub blocking_thread_keep_alive_seconds: Option<u64>,
+    /// Files being hashed or waiting for a blocking thread to be hashed. Other hashes wait for a slot
+    /// before being queued, so large pushes don't starve the rest of the file work. Unlimited by default.
+    pub max_blocking_queue: Option<NonZeroUsize>
+}
+
+impl RuntimeConfiguration {
+    pub fn build_runtime(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads.get());
+        }
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads.get());
+        }
+        if let Some(keep_alive) = self.blocking_thread_keep_alive_seconds {
+            builder.thread_keep_alive(Duration::from_secs(keep_alive));
+        }
+
+        builder.build()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct LoggingConfiguration {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Level of everything the modules below don't mention. `RUST_LOG` takes precedence over the whole section.
+    pub level: Option<String>,
+    /// Level by module, like `tower_http = "debug"`
+    #[serde(default)]
+    pub modules: HashMap<String, String>,
+    /// Logs are written to the standard output unless a file is configured
+    pub file: Option<LogFileConfiguration>
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event
+    #[default]
+    Full,
+    /// Several lines per event, for humans
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LogFileConfiguration {
+    pub path: PathBuf,
+    /// The file is rotated once it holds this much
+    pub max_bytes: Option<u64>,
+    /// The file is also rotated when the hour or the day changes
+    pub rotation: Option<LogRotation>,
+    /// Rotated files kept next to the current one, the oldest are deleted
+    #[serde(default = "default_kept_log_files")]
+    pub keep: usize
+}
+
+fn default_kept_log_files() -> usize {
+    7
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    Daily
+}
+
+impl LoggingConfiguration {
+    /// Directives of the log filter, when `RUST_LOG` isn't set
+    pub fn filter_directives(&self) -> String {
+        if self.level.is_none() && self.modules.is_empty() {
+            return "info,tower_http=debug,docker_storage_proxy_registry=debug".to_string();
+        }
+
+        std::iter::once(self.level.clone().unwrap_or_else(|| "info".to_string()))
+            .chain(self.modules.iter().map(|(module, level)| format!("{}={}", module, level)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ServerConfiguration {
+    /// Accept HTTP/2 connections (h2c with prior knowledge, as sent by reverse proxies) next to HTTP/1.1
+    #[serde(default = "default_true")]
+    pub http2: bool,
+    /// Maximum number of simultaneous client connections
+    pub max_connections: Option<usize>,
+    /// Maximum number of requests a single HTTP/2 connection can run at the same time
+    pub max_concurrent_streams: Option<u32>,
+    /// Maximum number of requests being handled at the same time. Requests above the cap get a 503.
+    pub max_in_flight_requests: Option<usize>,
+    /// Time a client has to send the headers of its request (HTTP/1 only)
+    pub header_read_timeout_seconds: Option<u64>,
+    /// Time a client has to send each chunk of a blob or manifest upload
+    pub body_chunk_timeout_seconds: Option<u64>,
+    /// Connections without any traffic for this long are closed
+    pub idle_timeout_seconds: Option<u64>,
+    /// Largest manifest accepted, pushed or pulled from upstream. Larger ones are refused before being stored.
+    #[serde(default = "default_max_manifest_bytes")]
+    pub max_manifest_bytes: u64,
+    /// Receive the chunks of an upload side by side when they tell their range with Content-Range
+    #[serde(default)]
+    pub parallel_chunk_uploads: bool,
+    /// Reverse proxies whose X-Forwarded-* headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Binds the port with SO_REUSEPORT, so a new instance can start listening before the old one stops
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Process ID of the running instance. A new instance asks the one it names to drain once it listens.
+    pub pid_file: Option<PathBuf>,
+    /// Time given to the requests in progress to complete once the server is asked to stop
+    pub drain_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub deadlines: DeadlinesConfiguration,
+    /// Leaves the admin routes open to anyone when no authentication is configured, for registries only
+    /// reachable from a trusted network. They are refused otherwise.
+    #[serde(default)]
+    pub unauthenticated_admin: bool,
+    /// Sends the chunks of an upload to the replica holding its session, for replicas behind a load
+    /// balancer that don't share their temporary storage
+    pub upload_affinity: Option<UploadAffinityConfiguration>
+}
+
+/// Time the handlers of each kind of route have to answer, unbounded when not set. Streamed responses
+/// only have to start within the deadline.
+#[derive(Deserialize, Debug, Default)]
+pub struct DeadlinesConfiguration {
+    /// Manifests, pushed and pulled, proxied or not
+    pub manifests_seconds: Option<u64>,
+    /// Blob pulls, including the wait for the upstream registry
+    pub blobs_seconds: Option<u64>,
+    /// Cancellation of the upload sessions. Starting them, their chunks and their completion carry blob data
+    /// and are never bounded.
+    pub uploads_seconds: Option<u64>,
+    /// Base endpoint, tokens, admin and images API, metrics. The proxy cache export, import and prefetch
+    /// are never bounded.
+    pub api_seconds: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UploadAffinityConfiguration {
+    /// Name of this replica among the nodes
+    pub node_id: String,
+    /// Shared by the replicas, signs the node named in the upload URLs
+    pub secret: String,
+    /// Base URL each replica is reachable at by the others, as in `http://registry-0.registry:8000`
+    #[serde(default)]
+    pub nodes: HashMap<String, String>,
+    #[serde(default)]
+    pub mode: UploadAffinityMode
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadAffinityMode {
+    /// The replica receiving the chunk forwards it to the one holding the session
+    #[default]
+    Proxy,
+    /// The client is redirected to the replica holding the session with a 307, which it has to be able
+    /// to reach
+    Redirect
+}
+
+impl DeadlinesConfiguration {
+    pub fn manifests(&self) -> Option<Duration> {
+        self.manifests_seconds.map(Duration::from_secs)
+    }
+
+    pub fn blobs(&self) -> Option<Duration> {
+        self.blobs_seconds.map(Duration::from_secs)
+    }
+
+    pub fn uploads(&self) -> Option<Duration> {
+        self.uploads_seconds.map(Duration::from_secs)
+    }
+
+    pub fn api(&self) -> Option<Duration> {
+        self.api_seconds.map(Duration::from_secs)
+    }
+}
+
+impl ServerConfiguration {
+    pub fn header_read_timeout(&self) -> Option<Duration> {
+        self.header_read_timeout_seconds.map(Duration::from_secs)
+    }
+
+    pub fn body_chunk_timeout(&self) -> Option<Duration> {
+        self.body_chunk_timeout_seconds.map(Duration::from_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_seconds.map(Duration::from_secs)
+    }
+
+    pub fn drain_timeout(&self) -> Option<Duration> {
+        self.drain_timeout_seconds.map(Duration::from_secs)
+    }
+}
+
+impl Default for ServerConfiguration {
+    fn default() -> Self {
+        Self {
+            http2: true,
+            max_connections: None,
+            max_concurrent_streams: None,
+            max_in_flight_requests: None,
+            header_read_timeout_seconds: None,
+            body_chunk_timeout_seconds: None,
+            idle_timeout_seconds: None,
+            max_manifest_bytes: default_max_manifest_bytes(),
+            parallel_chunk_uploads: false,
+            trusted_proxies: Vec::new(),
+            reuse_port: false,
+            pid_file: None,
+            drain_timeout_seconds: None,
+            deadlines: DeadlinesConfiguration::default(),
+            unauthenticated_admin: false,
+            upload_affinity: None
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// As suggested by the distribution spec
+fn default_max_manifest_bytes() -> u64 {
+    4 * 1024 * 1024
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BandwidthConfiguration {
+    /// Cap for each blob response
+    pub per_connection_bytes_per_second: Option<u64>,
+    /// Cap shared by all the blob responses of a client (IP address or account)
+    pub per_client_bytes_per_second: Option<u64>
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct RateLimitsConfiguration {
+    /// Applied per client IP address to requests without credentials
+    pub anonymous: Option<RateLimit>,
+    /// Applied per account to authenticated requests
+    pub authenticated: Option<RateLimit>,
+    /// Applied per repository on top of the client limits, the first rule matching the repository applies
+    #[serde(default)]
+    pub repositories: Vec<RepositoryRateLimit>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per_seconds: u64
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RepositoryRateLimit {
+    /// Repository as found in the URLs, starting with `proxy/` for the proxy cache. A trailing `*` matches
+    /// any repository with this prefix, each of them getting its own limits.
+    pub repository: String,
+    /// Requests allowed on the repository every `per_seconds`, by all the clients together
+    pub requests: Option<u32>,
+    #[serde(default = "default_repository_rate_period")]
+    pub per_seconds: u64,
+    /// Cap shared by all the blob responses of the repository
+    pub bytes_per_second: Option<u64>
+}
+
+fn default_repository_rate_period() -> u64 {
+    1
+}
+
+impl RateLimitsConfiguration {
+    pub fn repository_rule(&self, repository: &str) -> Option<&RepositoryRateLimit> {
+        self.repositories.iter().find(|rule| repository_matches(&rule.repository, repository))
+    }
+}
+
+impl RepositoryRateLimit {
+    pub fn request_limit(&self) -> Option<RateLimit> {
+        self.requests.map(|requests| RateLimit {
+            requests,
+            per_seconds: self.per_seconds
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthenticationMethod {
+    Basic,
+    Bearer
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthenticationConfiguration {
+    pub method: AuthenticationMethod,
+    /// For Bearer, the URL of the token server. For Basic, the name of the protection space.
+    pub realm: String,
+    pub service: Option<String>,
+    /// Users allowed to log in, in the Apache htpasswd format (bcrypt only).
+    pub htpasswd: Option<PathBuf>,
+    /// Built-in token server settings. Required to serve /token.
+    pub token: Option<TokenConfiguration>,
+    #[serde(default)]
+    pub acl: Vec<AclEntry>,
+    /// Authenticated users can push under `<username>/...` and pull everywhere else,
+    /// on top of what the ACL allows.
+    #[serde(default)]
+    pub namespace_ownership: bool,
+    /// External policy engine taking the authorization decisions instead of the ACL.
+    pub opa: Option<OpaConfiguration>,
+    /// File keeping the API keys created through the admin endpoints. API keys are disabled without it.
+    pub api_keys: Option<PathBuf>,
+    /// Slows down and then locks out the usernames and addresses failing to log in
+    pub lockout: Option<LockoutConfiguration>,
+    /// Keys shared with internal services signing their requests instead of logging in
+    pub request_signing: Option<RequestSigningConfiguration>,
+    /// Accounts allowed on the admin routes. Tokens, API keys and signing keys need the `registry:admin:*` scope.
+    #[serde(default)]
+    pub admins: Vec<String>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RequestSigningConfiguration {
+    /// Largest difference between the timestamp of a signature and the current time
+    #[serde(default = "default_signature_max_skew")]
+    pub max_skew_seconds: u64,
+    /// Bodies of signed requests are read in memory to be hashed, up to this size
+    #[serde(default = "default_signature_max_body")]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub keys: Vec<SigningKeyConfiguration>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SigningKeyConfiguration {
+    pub id: String,
+    pub secret: String,
+    /// Account name of the signed requests, the key ID by default
+    pub account: Option<String>,
+    /// Scopes as in token requests, like `repository:proxy/*:pull`
+    pub scopes: Vec<String>
+}
+
+fn default_signature_max_skew() -> u64 {
+    300
+}
+
+fn default_signature_max_body() -> usize {
+    4 * 1024 * 1024
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LockoutConfiguration {
+    /// Failed logins of a username from an address, or of an address, before it is locked out
+    #[serde(default = "default_lockout_max_failures")]
+    pub max_failures: u32,
+    /// Failed logins are forgiven after this long without another one
+    #[serde(default = "default_lockout_window")]
+    pub window_seconds: u64,
+    #[serde(default = "default_lockout_duration")]
+    pub lockout_seconds: u64,
+    /// Wait before answering the first failed login, doubled on every following one
+    #[serde(default = "default_lockout_initial_delay")]
+    pub initial_delay_milliseconds: u64,
+    #[serde(default = "default_lockout_max_delay")]
+    pub max_delay_seconds: u64
+}
+
+fn default_lockout_max_failures() -> u32 {
+    10
+}
+
+fn default_lockout_window() -> u64 {
+    600
+}
+
+fn default_lockout_duration() -> u64 {
+    900
+}
+
+fn default_lockout_initial_delay() -> u64 {
+    250
+}
+
+fn default_lockout_max_delay() -> u64 {
+    5
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OpaConfiguration {
+    /// Data API endpoint of the decision, like `http://opa:8181/v1/data/registry/allow`
+    pub url: String,
+    #[serde(default = "default_opa_timeout")]
+    pub timeout_seconds: u64,
+    /// How long a decision is reused for the same account, repository, action, method, path and client. 0 disables the cache.
+    #[serde(default = "default_opa_cache")]
+    pub cache_seconds: u64
+}
+
+fn default_opa_timeout() -> u64 {
+    5
+}
+
+fn default_opa_cache() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenAlgorithm {
+    RS256,
+    ES256
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenConfiguration {
+    pub algorithm: TokenAlgorithm,
+    /// PEM encoded key used to sign the tokens (PKCS#8 for ES256)
+    pub private_key: PathBuf,
+    /// PEM encoded key used to verify the tokens
+    pub public_key: PathBuf,
+    pub issuer: String,
+    #[serde(default = "default_token_expiration")]
+    pub expiration_seconds: u64,
+    /// File keeping the tokens revoked before their expiration, so the revocations survive restarts
+    pub revocation_list: Option<PathBuf>
+}
+
+fn default_token_expiration() -> u64 {
+    300
+}
+
+impl AuthenticationConfiguration {
+    /// Value of the WWW-Authenticate header sent to clients that need to authenticate.
+    /// A realm configured as a path is made absolute with the origin the client used to reach us.
+    pub fn challenge(&self, scope: Option<&str>, origin: Option<&str>) -> String {
+        let realm = match origin {
+            Some(origin) if self.realm.starts_with('/') => format!("{}{}", origin, self.realm),
+            _ => self.realm.clone()
+        };
+
+        match self.method {
+            AuthenticationMethod::Basic => format!("Basic realm=\"{}\"", realm),
+            AuthenticationMethod::Bearer => {
+                let mut challenge = format!("Bearer realm=\"{}\"", realm);
+                if let Some(service) = &self.service {
+                    challenge.push_str(&format!(",service=\"{}\"", service));
+                }
+                if let Some(scope) = scope {
+                    challenge.push_str(&format!(",scope=\"{}\"", scope));
+                }
+
+                challenge
+            }
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        match self.method {
+            AuthenticationMethod::Basic => "Basic",
+            AuthenticationMethod::Bearer => "Bearer",
+        }
+    }
 }
@@ -0,0 +1,400 @@
+use std::{time::Instant, path::PathBuf, sync::Arc};
+
+use axum::{extract::{State, Path}, Json, response::{IntoResponse, Response}, http::StatusCode};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{ApplicationState, authentication::{Authenticator, acl::ResourceAccess, api_keys::{ApiKey, ApiKeys}}, notifications::Delivery, docker_client::{client::DockerClientError, clients_store::UpstreamClientSummary}, data::{proxy_cache::{RepositorySummary, RepositoryDetails, summarize_repositories_async, repository_details_async}, cache_stats::RepositoryCacheCounters, uploads::UploadSummary, helpers::{reject_invalid_container_refs, resolve_upstream_container_ref}}};
+
+use crate::tenants::{AdminTenant, StorageSizes};
+
+use super::RegistryHttpError;
+
+#[derive(Serialize)]
+pub struct StorageRoots {
+    pub registry_storage: PathBuf,
+    pub temporary_registry_storage: PathBuf,
+    pub proxy_storage: PathBuf,
+}
+
+#[derive(Serialize)]
+pub struct ServerStatus {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub uptime_seconds: u64,
+    /// None for the storage roots at the top of the configuration
+    pub tenant: Option<String>,
+    pub storage: StorageRoots,
+    pub active_uploads: usize,
+    pub cached_clients: usize,
+    /// Counted at most once a minute
+    pub cache_size: StorageSizes,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamHealth {
+    /// None for the storage roots at the top of the configuration
+    pub tenant: Option<String>,
+    /// Key of the cached client checked, or the registry itself for the registries of the configuration
+    pub client_key: String,
+    pub registry: String,
+    pub reachable: bool,
+    pub authentication_valid: bool,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TenantUpstreamClient {
+    /// None for the clients of the requests belonging to no tenant
+    pub tenant: Option<String>,
+    #[serde(flatten)]
+    pub client: UpstreamClientSummary,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamClientsList {
+    pub clients: Vec<TenantUpstreamClient>,
+}
+
+#[derive(Serialize)]
+pub struct ProxyCacheCatalog {
+    pub repositories: Vec<RepositorySummary>,
+}
+
+#[derive(Serialize)]
+pub struct UploadsList {
+    pub uploads: Vec<UploadSummary>,
+}
+
+#[derive(Serialize)]
+pub struct RepositoryCacheStats {
+    #[serde(flatten)]
+    pub details: RepositoryDetails,
+    #[serde(flatten)]
+    pub counters: RepositoryCacheCounters,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn status(State(app): State<ApplicationState>, AdminTenant(tenant): AdminTenant) -> Result<Json<ServerStatus>, RegistryHttpError> {
+    let cache_size = tenant.storage_sizes().await?;
+
+    Ok(Json(ServerStatus {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("GIT_COMMIT"),
+        uptime_seconds: app.started_at.elapsed().as_secs(),
+        tenant: tenant.name.clone(),
+        storage: StorageRoots {
+            registry_storage: tenant.registry_storage.clone(),
+            temporary_registry_storage: tenant.temporary_registry_storage.clone(),
+            proxy_storage: tenant.proxy_storage.clone(),
+        },
+        active_uploads: app.uploads.len().await,
+        cached_clients: tenant.docker_clients.len().await,
+        cache_size,
+    }))
+}
+
+/// Health of an upstream registry from the outcome of a request to its base endpoint
+fn upstream_health(tenant: Option<String>, client_key: String, registry: String, started_at: Instant, result: Result<(), DockerClientError>) -> UpstreamHealth {
+    let latency_ms = started_at.elapsed().as_millis();
+
+    let (reachable, authentication_valid, error) = match result {
+        Ok(_) => (true, true, None),
+        // The registry answered, but doesn't like our credentials (anymore)
+        Err(e @ (DockerClientError::UnexpectedStatusCode(401 | 403) | DockerClientError::BadAuthenticationCredentials)) => (true, false, Some(e.to_string())),
+        Err(e @ DockerClientError::UnexpectedStatusCode(_)) => (true, true, Some(e.to_string())),
+        Err(e) => (false, false, Some(e.to_string()))
+    };
+
+    UpstreamHealth {
+        tenant,
+        client_key,
+        registry,
+        reachable,
+        authentication_valid,
+        latency_ms,
+        error,
+    }
+}
+
+/// Checks the registries of the configuration of every tenant, whether they were used yet or not, along with
+/// the clients cached for their repositories
+#[tracing::instrument(skip_all)]
+pub async fn upstreams_health(State(app): State<ApplicationState>) -> Json<Vec<UpstreamHealth>> {
+    let mut registry_checks = Vec::new();
+    let mut client_checks = Vec::new();
+    for tenant in app.tenants.all() {
+        for registry in tenant.docker_clients.configured_registries() {
+            let tenant = Arc::clone(tenant);
+            registry_checks.push(async move {
+                let started_at = Instant::now();
+                // Building the client asks the base endpoint whether the credentials are accepted
+                let result = tenant.docker_clients.registry_client(&registry).await.map(|_| ());
+                upstream_health(tenant.name.clone(), registry.clone(), registry, started_at, result)
+            });
+        }
+
+        for (client_key, client) in tenant.docker_clients.clients().await {
+            let tenant_name = tenant.name.clone();
+            client_checks.push(async move {
+                let started_at = Instant::now();
+                let result = client.query_base().await;
+                upstream_health(tenant_name, client_key, client.registry().to_string(), started_at, result)
+            });
+        }
+    }
+    info!("Checking health of {} upstream registries and {} upstream clients", registry_checks.len(), client_checks.len());
+
+    let (mut health, client_health) = futures::future::join(
+        futures::future::join_all(registry_checks),
+        futures::future::join_all(client_checks)
+    ).await;
+    health.extend(client_health);
+
+    Json(health)
+}
+
+/// Upstream clients cached by every tenant, to check which credentials are in effect
+#[tracing::instrument(skip_all)]
+pub async fn upstream_clients(State(app): State<ApplicationState>) -> Json<UpstreamClientsList> {
+    let mut clients = Vec::new();
+    for tenant in app.tenants.all() {
+        clients.extend(tenant.docker_clients.summaries().await.into_iter().map(|client| TenantUpstreamClient {
+            tenant: tenant.name.clone(),
+            client
+        }));
+    }
+
+    Json(UpstreamClientsList { clients })
+}
+
+#[derive(Serialize)]
+pub struct UpstreamClientsFlush {
+    pub flushed: usize,
+}
+
+/// Drops the cached clients and credentials of an upstream registry, after its credentials were rotated
+#[tracing::instrument(skip_all, fields(registry = registry))]
+pub async fn flush_upstream_clients(
+    Path(registry): Path<String>,
+    State(app): State<ApplicationState>
+) -> Json<UpstreamClientsFlush> {
+    let mut flushed = 0;
+    for tenant in app.tenants.all() {
+        flushed += tenant.docker_clients.flush_registry(&registry).await;
+    }
+
+    Json(UpstreamClientsFlush { flushed })
+}
+
+/// Upstream repositories currently in the proxy cache
+#[tracing::instrument(skip_all)]
+pub async fn proxy_cache_repositories(AdminTenant(tenant): AdminTenant) -> Result<Json<ProxyCacheCatalog>, RegistryHttpError> {
+    let repositories = summarize_repositories_async(tenant.proxy_storage.clone()).await??;
+    info!("{} repositories in the proxy cache", repositories.len());
+
+    Ok(Json(ProxyCacheCatalog { repositories }))
+}
+
+/// Cache statistics of an upstream repository, on /admin/proxy-cache/<repository>/stats
+#[tracing::instrument(skip_all, fields(path = path))]
+pub async fn proxy_cache_repository_stats(
+    Path(path): Path<String>,
+    State(app): State<ApplicationState>
+) -> Result<Response, RegistryHttpError> {
+    let container_ref = match path.trim_start_matches('/').strip_suffix("/stats") {
+        Some(container_ref) => container_ref.to_string(),
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+    reject_invalid_container_refs(&container_ref)?;
+    let container_ref = resolve_upstream_container_ref(&container_ref);
+
+    let counters = app.cache_stats.get(&container_ref).await;
+    let proxy_storage = app.tenants.for_repository(&format!("proxy/{}", container_ref)).proxy_storage.clone();
+    let details = match repository_details_async(proxy_storage, container_ref.clone()).await?? {
+        Some(details) => details,
+        None => return Err(RegistryHttpError::manifest_not_found(&container_ref, "*"))
+    };
+
+    Ok(Json(RepositoryCacheStats { details, counters }).into_response())
+}
+
+/// Upload sessions in progress, to spot the stuck ones
+#[tracing::instrument(skip_all)]
+pub async fn uploads(State(app): State<ApplicationState>) -> Result<Json<UploadsList>, RegistryHttpError> {
+    let uploads = app.uploads.list().await?;
+    info!("{} uploads in progress", uploads.len());
+
+    Ok(Json(UploadsList { uploads }))
+}
+
+/// Aborts an upload session, whatever its client is doing
+#[tracing::instrument(skip_all, fields(upload_id = raw_upload_uuid))]
+pub async fn abort_upload(
+    Path(raw_upload_uuid): Path<String>,
+    State(app): State<ApplicationState>
+) -> Result<Response, RegistryHttpError> {
+    let upload_id = raw_upload_uuid.parse()?;
+
+    if !app.uploads.abort_upload(upload_id).await? {
+        return Err(RegistryHttpError::upload_id_not_found(&raw_upload_uuid));
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Serialize)]
+pub struct DeadLetters {
+    pub dead_letters: Vec<Delivery>,
+}
+
+#[derive(Serialize)]
+pub struct DeadLettersReplay {
+    pub replayed: usize,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn dead_letters(State(app): State<ApplicationState>) -> Result<Response, RegistryHttpError> {
+    let notifier = match &app.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    Ok(Json(DeadLetters {
+        dead_letters: notifier.dead_letters().await?
+    }).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn replay_dead_letters(State(app): State<ApplicationState>) -> Result<Response, RegistryHttpError> {
+    let notifier = match &app.notifier {
+        Some(notifier) => notifier,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    Ok(Json(DeadLettersReplay {
+        replayed: notifier.replay_dead_letters().await?
+    }).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyRequest {
+    pub name: String,
+    /// Scopes as in token requests, like `repository:ci/*:pull,push`
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyList {
+    pub api_keys: Vec<ApiKey>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    /// Only ever shown in this response
+    pub secret: String,
+}
+
+fn api_keys(app: &ApplicationState) -> Option<&ApiKeys> {
+    app.authenticator.as_deref().and_then(|authenticator| authenticator.api_keys.as_ref())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list_api_keys(State(app): State<ApplicationState>) -> Result<Response, RegistryHttpError> {
+    let api_keys = match api_keys(&app) {
+        Some(api_keys) => api_keys,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    Ok(Json(ApiKeyList {
+        api_keys: api_keys.list()
+    }).into_response())
+}
+
+#[tracing::instrument(skip_all, fields(name = request.name))]
+pub async fn create_api_key(
+    State(app): State<ApplicationState>,
+    Json(request): Json<ApiKeyRequest>
+) -> Result<Response, RegistryHttpError> {
+    let api_keys = match api_keys(&app) {
+        Some(api_keys) => api_keys,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    let mut scopes = Vec::new();
+    for scope in &request.scopes {
+        match ResourceAccess::from_scope(scope) {
+            Some(access) if !access.actions.is_empty() => scopes.push(access),
+            _ => return Ok((StatusCode::BAD_REQUEST, format!("Invalid scope {}", scope)).into_response())
+        }
+    }
+
+    let lifetime = request.expires_in_days.map(chrono::Duration::days);
+    let (api_key, secret) = api_keys.create(request.name, scopes, lifetime).await?;
+
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { api_key, secret })).into_response())
+}
+
+#[tracing::instrument(skip_all, fields(id = id))]
+pub async fn revoke_api_key(
+    Path(id): Path<String>,
+    State(app): State<ApplicationState>
+) -> Result<Response, RegistryHttpError> {
+    let api_keys = match api_keys(&app) {
+        Some(api_keys) => api_keys,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    match api_keys.revoke(&id).await? {
+        true => Ok(StatusCode::NO_CONTENT.into_response()),
+        false => Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+/// Token to revoke, either as is or by its ID, or all the tokens of an account
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenRevocation {
+    Token(String),
+    Jti(String),
+    Account(String),
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn revoked_tokens(State(app): State<ApplicationState>) -> Result<Response, RegistryHttpError> {
+    match app.authenticator.as_deref().and_then(|authenticator| authenticator.revocations.as_ref()) {
+        Some(revocations) => Ok(Json(revocations.list()).into_response()),
+        None => Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn revoke_token(
+    State(app): State<ApplicationState>,
+    Json(revocation): Json<TokenRevocation>
+) -> Result<Response, RegistryHttpError> {
+    let (token_issuer, revocations) = match app.authenticator.as_deref() {
+        Some(Authenticator { token_issuer: Some(token_issuer), revocations: Some(revocations), .. }) => (token_issuer, revocations),
+        _ => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+
+    match revocation {
+        TokenRevocation::Token(token) => match token_issuer.verify(&token) {
+            Ok(claims) => revocations.revoke_token(claims.jti, claims.exp).await?,
+            Err(e) => return Ok((StatusCode::BAD_REQUEST, format!("Invalid token: {}", e)).into_response())
+        },
+        // Whenever the token was issued, it's expired once a token lifetime has passed
+        TokenRevocation::Jti(jti) => {
+            let expires_at = Utc::now().timestamp() + token_issuer.lifetime().as_secs() as i64;
+            revocations.revoke_token(jti, expires_at).await?
+        },
+        TokenRevocation::Account(account) => revocations.revoke_account(account).await?
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
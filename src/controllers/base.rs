@@ -1,9 +1,59 @@
-use axum::http::StatusCode;
+use axum::{http::{StatusCode, HeaderMap}, extract::State, response::IntoResponse, BoxError};
+use tracing::warn;
+
+use crate::{ApplicationState, authentication::{AuthenticationError, bearer_token}, requests::ForwardedInfo};
+
+use super::{RegistryHttpResult, RegistryHttpError};
+
+/// Turns the errors of the in-flight requests limiting layers into registry errors.
+pub async fn handle_overload(error: BoxError) -> RegistryHttpError {
+    if error.is::<tower::load_shed::error::Overloaded>() {
+        RegistryHttpError::ServiceUnavailable
+    } else {
+        RegistryHttpError::RegistryInternalError(eyre::eyre!("Unhandled middleware error: {}", error))
+    }
+}
+
+/// Turns the errors of the route deadlines into registry errors.
+pub async fn handle_deadline(error: BoxError) -> RegistryHttpError {
+    if error.is::<tower::timeout::error::Elapsed>() {
+        warn!("Request cut after its deadline");
+        RegistryHttpError::DeadlineExceeded
+    } else {
+        RegistryHttpError::RegistryInternalError(eyre::eyre!("Unhandled middleware error: {}", error))
+    }
+}
 
 pub async fn root() -> StatusCode {
     StatusCode::OK
 }
 
-pub async fn registry_base() -> &'static str {
-    "{}"
-}
\ No newline at end of file
+pub async fn registry_base(
+    State(app): State<ApplicationState>,
+    forwarded_info: ForwardedInfo,
+    headers: HeaderMap
+) -> RegistryHttpResult {
+    // Docker clients only run their login flow when the base endpoint tells them to, so
+    // this is where we have to challenge them when authentication is enabled.
+    if let (Some(authentication), Some(authenticator)) = (&app.conf.authentication, &app.authenticator) {
+        // Anonymous tokens from the token server are good enough to access the base endpoint.
+        let authenticated = match authenticator.authenticate(authentication.method, &headers, forwarded_info.client_ip).await {
+            Ok(identity) => !identity.is_anonymous() || bearer_token(&headers).is_some(),
+            Err(AuthenticationError::LockedOut(retry_after)) => return Err(RegistryHttpError::locked_out(retry_after)),
+            Err(_) => false
+        };
+
+        if !authenticated {
+            return Err(RegistryHttpError::unauthorized(authentication.challenge(None, forwarded_info.origin().as_deref())));
+        }
+    }
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", "application/json"),
+            ("Docker-Distribution-Api-Version", "registry/2.0")
+        ],
+        "{}"
+    ).into_response())
+}
@@ -1,80 +1,396 @@
-use std::{io, os::unix::prelude::MetadataExt};
+use std::{ops::Range, time::SystemTime, sync::Arc, path::PathBuf};
 
-use axum::{http::{StatusCode, Method}, extract::{Path, State}, response::IntoResponse, body::StreamBody};
-use futures::stream::{self, StreamExt};
-use tokio::io::AsyncWriteExt;
+use axum::{http::{StatusCode, Method, HeaderValue, HeaderMap}, extract::State, response::IntoResponse, body::{StreamBody, Bytes}};
+use futures::{Stream, stream::{self, StreamExt}};
+use tokio::{io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt}, sync::OwnedSemaphorePermit};
+use sha2::{Digest, Sha256};
 use tokio_util::io::ReaderStream;
-use tracing::info;
+use tracing::{info, warn};
+use uuid::Uuid;
 
-use crate::{data::helpers::{reject_invalid_container_refs, RegistryPathsHelper, self, reject_invalid_tags_refs}, ApplicationState, docker_client::client::DockerClientError};
+use crate::{tenants::{CurrentTenant, Tenant}, data::{cache_watermark, open_blobs, proxy_cache::BlobMetadata, blob_storage::StoredBlob, helpers::{reject_invalid_container_refs, RegistryPathsHelper, self, reject_invalid_tags_refs, resolve_upstream_container_ref}}, ApplicationState, docker_client::{client::{DockerClientError, DockerClient}, peers::PEER_REQUEST_HEADER}};
 use crate::controllers::RegistryHttpResult;
+use crate::repository_path::RepositoryPath;
+use crate::requests::ClientKey;
 
 use super::RegistryHttpError;
 
 struct FileWritingStreamHelper<S> {
     file: tokio::fs::File,
     inner_stream: S,
+    /// Accounts the downloaded bytes to the upstream registry, when the blob comes from there
+    docker_client: Option<Arc<DockerClient>>,
+    // Released once the whole blob went through
+    _connection_permit: Option<OwnedSemaphorePermit>,
+    /// The blob is hashed on its way to the cache, and only lands there if it matches its digest
+    hasher: Sha256,
+    size: u64,
+    /// Size announced to the client. The response ends as soon as this much is sent, without waiting
+    /// for the end of the inner stream, so the blob is checked along with its last chunk.
+    expected_size: Option<u64>,
+    temporary_path: PathBuf,
+    proxy_storage: PathBuf,
+    container_ref: String,
+    digest: String,
+    /// Docker-Content-Digest the upstream registry announced, which the content must match as well
+    advertised_digest: Option<String>,
+    finished: bool,
 }
 
-#[tracing::instrument(skip_all, fields(container_ref = container_ref))]
+impl<S> FileWritingStreamHelper<S> {
+    async fn new(tenant: &Tenant, container_ref: &str, digest: &str, inner_stream: S, expected_size: Option<u64>) -> Result<Self, RegistryHttpError> {
+        let temporary_path = RegistryPathsHelper::temporary_blob_path(&tenant.temporary_registry_storage, Uuid::new_v4());
+        tokio::fs::create_dir_all(temporary_path.parent().unwrap()).await?;
+
+        Ok(Self {
+            file: tokio::fs::File::create(&temporary_path).await?,
+            inner_stream,
+            docker_client: None,
+            _connection_permit: None,
+            hasher: Sha256::new(),
+            size: 0,
+            expected_size,
+            temporary_path,
+            proxy_storage: tenant.proxy_storage.clone(),
+            container_ref: container_ref.to_string(),
+            digest: digest.to_string(),
+            advertised_digest: None,
+            finished: false
+        })
+    }
+
+    /// Moves the downloaded blob to the cache once its digest is checked.
+    async fn finish(&mut self) -> Result<(), RegistryHttpError> {
+        self.finished = true;
+        self.file.flush().await?;
+
+        let actual_digest = format!("sha256:{}", base16ct::lower::encode_string(&std::mem::take(&mut self.hasher).finalize()));
+        // Only SHA-256 digests are computed by the registry
+        let mismatched_digest = [Some(self.digest.as_str()), self.advertised_digest.as_deref()]
+            .into_iter()
+            .flatten()
+            .filter(|digest| digest.starts_with("sha256:"))
+            .find(|digest| *digest != actual_digest)
+            .map(|digest| digest.to_string());
+        if let Some(expected_digest) = mismatched_digest {
+            warn!("Downloaded blob doesn't match the digest {} (got {}), not caching it", expected_digest, actual_digest);
+            if let Some(docker_client) = &self.docker_client {
+                docker_client.record_digest_mismatch(&expected_digest, &actual_digest);
+            }
+            tokio::fs::remove_file(&self.temporary_path).await?;
+            return Err(eyre::eyre!("Blob {} doesn't match the digest {}, got {}", self.digest, expected_digest, actual_digest).into());
+        }
+        let verified = self.digest.starts_with("sha256:");
+
+        let blob_path = RegistryPathsHelper::blob_path(&self.proxy_storage, &self.container_ref, &self.digest);
+        tokio::fs::create_dir_all(blob_path.parent().unwrap()).await?;
+        tokio::fs::rename(&self.temporary_path, &blob_path).await?;
+
+        if verified {
+            BlobMetadata::verified(&self.digest, self.size).save(&self.proxy_storage, &self.container_ref).await?;
+        }
+        info!("Blob {} cached ({} bytes)", self.digest, self.size);
+
+        Ok(())
+    }
+}
+
+impl<S> Drop for FileWritingStreamHelper<S> {
+    fn drop(&mut self) {
+        // The download didn't complete, for instance because the client went away
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+/// Blobs are addressed by their digest, so they never change: downstream HTTP caches can keep them forever.
+fn blob_cache_headers(digest: &str, last_modified: SystemTime) -> [(&'static str, String); 3] {
+    let last_modified = chrono::DateTime::<chrono::Utc>::from(last_modified);
+
+    [
+        ("Cache-Control", "public, max-age=31536000, immutable".to_string()),
+        ("ETag", format!("\"{}\"", digest)),
+        ("Last-Modified", last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+    ]
+}
+
+/// What the Range header of a blob request asks for
+enum RequestedRange {
+    Whole,
+    Part(Range<u64>),
+    /// The range starts past the end of the blob
+    Unsatisfiable
+}
+
+impl RequestedRange {
+    /// Whether less than the whole blob of `size` bytes is sent. Ranges like `bytes=0-` cover all of it.
+    fn is_partial(&self, size: u64) -> bool {
+        match self {
+            RequestedRange::Whole => false,
+            RequestedRange::Part(range) => *range != (0..size),
+            RequestedRange::Unsatisfiable => true
+        }
+    }
+}
+
+/// Reads the Range header of a blob request, out of a blob of `size` bytes. Only single byte ranges are
+/// served, the whole blob is sent for the others as HTTP allows.
+fn requested_range(headers: &HeaderMap, size: u64) -> RequestedRange {
+    let byte_range = match headers.get("Range").and_then(|range| range.to_str().ok()).and_then(|range| range.trim().strip_prefix("bytes=")) {
+        Some(byte_range) if !byte_range.contains(',') => byte_range,
+        _ => return RequestedRange::Whole
+    };
+    let (start, end) = match byte_range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return RequestedRange::Whole
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last bytes of the blob
+        (Err(_), Ok(suffix_length)) if start.is_empty() && suffix_length > 0 => size.saturating_sub(suffix_length)..size,
+        (Err(_), Ok(_)) if start.is_empty() => return RequestedRange::Unsatisfiable,
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        // The end is inclusive, and may be past the end of the blob
+        (Ok(start), Ok(end)) if end >= start => start..size.min(end.saturating_add(1)),
+        _ => return RequestedRange::Whole
+    };
+
+    if range.start >= size {
+        return RequestedRange::Unsatisfiable;
+    }
+
+    RequestedRange::Part(range)
+}
+
+/// Content-Range header of a 206 Partial Content response carrying `range` of a blob of `size` bytes
+fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, size)
+}
+
+fn range_not_satisfiable(size: u64) -> axum::response::Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [("Content-Range", format!("bytes */{}", size))]
+    ).into_response()
+}
+
+/// The magic that will allow us to write a file and send a response at the same time. Since
+/// axum's StreamBody takes an implementation of stream, we can pass an unfold stream that will wrap
+/// the underlying stream. The effect is like the `tee` command, but on streams.
+fn write_while_streaming<S>(stream_helper: FileWritingStreamHelper<S>) -> impl Stream<Item = Result<Bytes, RegistryHttpError>>
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Unpin
+{
+    stream::unfold(
+        stream_helper,
+        |mut state| async move {
+            if state.finished {
+                return None;
+            }
+
+            let next_chunk = state.inner_stream.next().await;
+
+            match next_chunk {
+                // There is a chunk of response to dump into a file and it has been extracted successfully.
+                Some(Ok(chunk)) => {
+                    if let Some(docker_client) = &state.docker_client {
+                        docker_client.record_downloaded_bytes(chunk.len() as u64);
+                    }
+                    state.hasher.update(&chunk);
+                    state.size += chunk.len() as u64;
+                    let result = state
+                        .file
+                        .write_all(&chunk)
+                        .await
+                        // We convert a successful write into the chunk so axum can
+                        // write it in the response, and a write error into a registry
+                        // error.
+                        .map(|_| chunk)
+                        .map_err(RegistryHttpError::from);
+
+                    let result = match result {
+                        Ok(chunk) if Some(state.size) == state.expected_size => state.finish().await.map(|_| chunk),
+                        result => result
+                    };
+                    Some((result, state))
+                }
+
+                // There is a chunk but the extraction failed. Convert the failure into a registry error and
+                // return it.
+                Some(Err(error)) => {
+                    Some((Err(RegistryHttpError::from(error)), state))
+                }
+
+                // There's no more chunk to extract. The blob goes to the cache if it matches its digest,
+                // otherwise the client gets an error instead of the end of the stream.
+                None => match state.finish().await {
+                    Ok(()) => None,
+                    Err(e) => Some((Err(e), state))
+                }
+            }
+    })
+}
+
+/// Relays a blob without caching it, still accounting the downloaded bytes to the upstream registry.
+fn pass_through<S>(inner_stream: S, docker_client: Option<Arc<DockerClient>>, connection_permit: Option<OwnedSemaphorePermit>) -> impl Stream<Item = Result<Bytes, RegistryHttpError>>
+where
+    S: Stream<Item = Result<Bytes, reqwest::Error>> + Unpin
+{
+    inner_stream.map(move |chunk| {
+        // Released once the whole blob went through
+        let _connection_permit = &connection_permit;
+        let chunk = chunk?;
+        if let Some(docker_client) = &docker_client {
+            docker_client.record_downloaded_bytes(chunk.len() as u64);
+        }
+        Ok(chunk)
+    })
+}
+
+/// Hashes a cached blob again when the configuration asks for it. A blob not matching its digest
+/// is removed from the cache so it gets downloaded again.
+async fn cached_blob_is_intact(app: &ApplicationState, container_ref: &str, digest: &str, blob_path: &std::path::Path) -> Result<bool, RegistryHttpError> {
+    let rule = match app.conf.cache.verification_rule(container_ref) {
+        Some(rule) if rule.should_verify() => rule,
+        _ => return Ok(true)
+    };
+
+    // Only SHA-256 digests are computed by the registry
+    let expected_hash = match digest.strip_prefix("sha256:") {
+        Some(hash) => hash,
+        None => return Ok(true)
+    };
+
+    info!("Verifying the cached blob ({:?} mode)", rule.mode);
+    let actual_hash = helpers::file256sum_async(blob_path.to_path_buf()).await??;
+    let intact = actual_hash == expected_hash;
+    app.cache_stats.record_verification(container_ref, intact).await;
+
+    if !intact {
+        warn!("Cached blob is corrupted (got sha256:{}), removing it from the cache", actual_hash);
+        tokio::fs::remove_file(blob_path).await?;
+        if let Some(open_blobs) = &app.open_blobs {
+            open_blobs.forget(blob_path);
+        }
+    }
+
+    Ok(intact)
+}
+
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), digest = %repository_path.reference()))]
 pub async fn check_blob_exists(
-    Path((container_ref, digest)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     http_method: Method,
-    State(app): State<ApplicationState>
+    State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
+    client: ClientKey,
+    headers: HeaderMap
 ) -> RegistryHttpResult {
+    let (container_ref, digest) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
 
     let (_algo, hash) = digest
         .split_once(':')
         .ok_or(RegistryHttpError::invalid_hash_format(&digest))?;
 
-    let file_path = RegistryPathsHelper::blob_path(&app.conf.registry_storage, &container_ref, hash);
+    let file_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, &container_ref, hash);
     info!("Checking if path [{:?}] exists", file_path);
-    let blob_file = match tokio::fs::File::open(&file_path).await {
-        Ok(f) => {
-            info!("File exists and is accessible"); 
-            f
+    let blob = match StoredBlob::open(&file_path, app.storage_cipher.clone()).await? {
+        Some(blob) => {
+            info!("File exists and is accessible");
+            blob
         },
-        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        None => {
             info!("File not found, returning 404");
             return Ok((StatusCode::NOT_FOUND).into_response())
         }
-        Err(e) => return Err(e.into())
     };
 
-    let blob_size = blob_file.metadata().await?.size();
+    let blob_size = blob.size;
+    let cache_headers = blob_cache_headers(&format!("sha256:{}", hash), blob.modified);
 
     if http_method == Method::HEAD {
         return Ok((
             StatusCode::OK,
             [
                 ("Content-Length", blob_size.to_string()),
-                ("Docker-Content-Digest", format!("sha256:{}", hash))
-            ]
+                ("Docker-Content-Digest", format!("sha256:{}", hash)),
+                ("Accept-Ranges", "bytes".to_string())
+            ],
+            cache_headers
         ).into_response());
     }
 
+    // Parts of the blob are read as stored, hashing the whole blob for each of them would be too much
+    match requested_range(&headers, blob_size) {
+        RequestedRange::Whole => (),
+        RequestedRange::Unsatisfiable => return Ok(range_not_satisfiable(blob_size)),
+        RequestedRange::Part(range) => {
+            info!("Sending bytes {:?} of the blob", range);
+            let response_body = StreamBody::new(
+                app.bandwidth_limiter.throttle(&client.key, &container_ref, ReaderStream::new(blob.range_reader(range.clone()).await?)).await
+            );
+
+            return Ok((
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    ("Content-Type", "application/octet-stream".to_string()),
+                    ("Content-Length", (range.end - range.start).to_string()),
+                    ("Content-Range", content_range(&range, blob_size)),
+                    ("Docker-Content-Digest", format!("sha256:{}", hash)),
+                    ("Accept-Ranges", "bytes".to_string())
+                ],
+                cache_headers,
+                response_body
+            ).into_response());
+        }
+    }
+
     // The client really wants the blob, send it away and calculate the real hash !
-    let blob_sha256 = helpers::file256sum_async(file_path.clone()).await??;
-    let response_body = StreamBody::new(tokio_util::io::ReaderStream::new(blob_file));
+    let blob_sha256 = blob.sha256().await?;
+    let response_body = StreamBody::new(
+        app.bandwidth_limiter.throttle(&client.key, &container_ref, tokio_util::io::ReaderStream::new(blob.reader().await?)).await
+    );
 
     Ok((
         StatusCode::OK,
         [
             ("Content-Type", "application/octet-stream".to_string()),
             ("Content-Length", blob_size.to_string()),
-            ("Docker-Content-Digest", format!("sha256:{}", blob_sha256))
+            ("Docker-Content-Digest", format!("sha256:{}", blob_sha256)),
+            ("Accept-Ranges", "bytes".to_string())
         ],
+        cache_headers,
         response_body
     ).into_response())
 }
 
-#[tracing::instrument(skip_all, fields(container_ref = container_ref, digest = digest))]
+/// Whether a blob of this size, if known, can still be cached. Blobs are passed through instead once the
+/// memory storage is full.
+async fn fits_in_cache(tenant: &Tenant, content_length: Option<u64>) -> bool {
+    match tenant.check_proxy_fill(content_length.unwrap_or(0)).await {
+        Ok(()) => true,
+        Err(e) => {
+            info!("Not caching the blob: {}", e);
+            false
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), digest = %repository_path.reference()))]
 pub async fn proxy_blob(
-    Path((container_ref, digest)): Path<(String, String)>,
+    repository_path: RepositoryPath,
+    http_method: Method,
     State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
+    client: ClientKey,
+    headers: HeaderMap
 ) -> RegistryHttpResult {
+    let (container_ref, digest) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
+    let container_ref = resolve_upstream_container_ref(&container_ref);
+    let proxy_repository = format!("proxy/{}", container_ref);
     reject_invalid_tags_refs(&digest)?;
 
     // Check if we already have the blob file in our cache if we do, send it away
@@ -88,86 +404,198 @@ pub async fn proxy_blob(
     // far.
 
     info!("Checking if there is a cached blob");
-    let blob_path = RegistryPathsHelper::blob_path(&app.conf.proxy_storage, &container_ref, &digest);
-    if blob_path.is_file() {
+    let blob_path = RegistryPathsHelper::blob_path(&tenant.proxy_storage, &container_ref, &digest);
+    // Lazy-pulling snapshotters read layers in many small ranges at once, hashing the whole blob for each
+    // of them would stall the pull. Partial reads are left to the verification of the whole ones.
+    let cached_blob_size = match tokio::fs::metadata(&blob_path).await {
+        Ok(metadata) if metadata.is_file() => Some(metadata.len()),
+        _ => None
+    };
+    let partial = cached_blob_size.is_some_and(|size| requested_range(&headers, size).is_partial(size));
+    if cached_blob_size.is_some() && (partial || cached_blob_is_intact(&app, &container_ref, &digest, &blob_path).await?) {
         info!("Blob is cached, sending cached version");
-        let blob_file = tokio::fs::File::open(&blob_path).await?;
-        let blob_size = blob_file.metadata().await?.size();
+        app.cache_stats.record_blob(&container_ref, true).await;
+        let open_blob = match &app.open_blobs {
+            Some(open_blobs) if partial => Some(open_blobs.open(&blob_path).await?),
+            _ => None
+        };
+        let blob_metadata = match &open_blob {
+            Some((_, blob_metadata)) => blob_metadata.clone(),
+            None => tokio::fs::metadata(&blob_path).await?
+        };
+        let blob_size = blob_metadata.len();
 
-        let body_stream = StreamBody::from(ReaderStream::new(blob_file));
-        return Ok((
-            StatusCode::OK,
+        let (status, range) = match requested_range(&headers, blob_size) {
+            RequestedRange::Whole => (StatusCode::OK, 0..blob_size),
+            RequestedRange::Part(range) => {
+                info!("Sending bytes {:?} of the cached blob", range);
+                (StatusCode::PARTIAL_CONTENT, range)
+            },
+            RequestedRange::Unsatisfiable => return Ok(range_not_satisfiable(blob_size))
+        };
+        let blob_stream = match open_blob {
+            Some((blob_file, _)) => open_blobs::read_range(blob_file, range.clone()).boxed(),
+            None => {
+                let mut blob_file = tokio::fs::File::open(&blob_path).await?;
+                blob_file.seek(std::io::SeekFrom::Start(range.start)).await?;
+                ReaderStream::new(blob_file.take(range.end - range.start)).boxed()
+            }
+        };
+
+        let body_stream = StreamBody::from(
+            app.bandwidth_limiter.throttle(&client.key, &proxy_repository, blob_stream).await
+        );
+        let mut response = (
+            status,
             [
                 ("Content-Type", "application/octet-stream".to_string()),
-                ("Content-Length", blob_size.to_string()),
+                ("Content-Length", (range.end - range.start).to_string()),
+                ("Accept-Ranges", "bytes".to_string()),
                 ("Proxy-Docker-Cache", "HIT".to_string())
             ],
+            (status == StatusCode::PARTIAL_CONTENT).then(|| [("Content-Range", content_range(&range, blob_size))]),
+            blob_cache_headers(&digest, blob_metadata.modified()?),
             body_stream
-        ).into_response());
+        ).into_response();
+        // Blobs checked against their digest when they were cached
+        if let Some(metadata) = BlobMetadata::load(&tenant.proxy_storage, &container_ref, &digest).await {
+            if let Ok(digest) = HeaderValue::from_str(&metadata.digest) {
+                response.headers_mut().insert("Docker-Content-Digest", digest);
+            }
+        }
+
+        return Ok(response);
+    }
+
+    // Sibling proxies only want what we already have
+    if headers.contains_key(PEER_REQUEST_HEADER) {
+        info!("Cache miss on a peer request");
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    app.cache_stats.record_blob(&container_ref, false).await;
+    let pass_through_repository = app.conf.cache.passes_through(&container_ref)
+        || cache_watermark::fills_suspended(&tenant.proxy_storage);
+    let peers = tenant.docker_clients.peers();
+    if http_method == Method::GET && !peers.is_empty() {
+        info!("Cache miss, asking peers about the blob");
+        if let Some(peer_response) = peers.query_blob(&container_ref, &digest).await {
+            let content_length = peer_response.content_length();
+            let downstream_response_stream = if pass_through_repository || !fits_in_cache(&tenant, content_length).await {
+                pass_through(peer_response.bytes_stream(), None, None).boxed()
+            } else {
+                let stream_helper = FileWritingStreamHelper::new(&tenant, &container_ref, &digest, peer_response.bytes_stream(), content_length).await?;
+                write_while_streaming(stream_helper).boxed()
+            };
+
+            let mut response = (
+                StatusCode::OK,
+                [
+                    ("Content-Type", "application/octet-stream".to_string()),
+                    ("Proxy-Docker-Cache", "PEER".to_string())
+                ],
+                blob_cache_headers(&digest, SystemTime::now()),
+                StreamBody::new(app.bandwidth_limiter.throttle(&client.key, &proxy_repository, downstream_response_stream).await)
+            ).into_response();
+            if let Some(content_length) = content_length {
+                response.headers_mut().insert("Content-Length", HeaderValue::from(content_length));
+            }
+
+            return Ok(response);
+        }
     }
 
-    info!("Cache miss, downloading and sending blob");
-    // Prepare the file system structure to received the blobs to cache
-    tokio::fs::create_dir_all(blob_path.parent().unwrap()).await?;
+    info!("Cache miss, asking upstream about the blob");
+    let docker_client = tenant.docker_clients.get_client(&container_ref).await?;
 
-    let docker_client = app.docker_clients.get_client(&container_ref).await?;
+    // Learn about the blob before starting the download. Some registries don't answer HEAD
+    // requests on blobs, in which case we go straight for the GET.
+    let blob_head = match docker_client.query_blob_head(&digest).await {
+        Ok(blob_head) => Some(blob_head),
+        Err(DockerClientError::UnexpectedStatusCode(404)) => {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        },
+        Err(e @ DockerClientError::RateLimited { .. }) => return Err(e.into()),
+        Err(e) => {
+            warn!("Upstream HEAD on the blob failed, falling back to GET: {}", e);
+            None
+        }
+    };
+
+    // The client only wants to know if the blob exists, no need to download it for that
+    if http_method == Method::HEAD {
+        if let Some(blob_head) = blob_head {
+            info!("Blob has {:?} bytes upstream", blob_head.content_length);
+            let mut response = (
+                StatusCode::OK,
+                [
+                    ("Content-Type", "application/octet-stream".to_string()),
+                    ("Proxy-Docker-Cache", "MISS".to_string())
+                ]
+            ).into_response();
+            if let Some(content_length) = blob_head.content_length {
+                response.headers_mut().insert("Content-Length", HeaderValue::from(content_length));
+            }
+            // Blobs are addressed by their digest, which is all some registries tell
+            let hash = blob_head.hash.unwrap_or_else(|| digest.clone());
+            if let Ok(hash) = HeaderValue::from_str(&hash) {
+                response.headers_mut().insert("Docker-Content-Digest", hash);
+            }
+            // Ranges are served once the blob is cached, the first GET brings it in whole
+            if !pass_through_repository {
+                response.headers_mut().insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+            }
+
+            return Ok(response);
+        }
+    }
+
+    info!("Downloading and sending blob");
     match docker_client.query_blob(&digest).await {
         Ok(response) => {
             // Since we can't write a file with the existing methods on the streams because
             // mutables don't mix very well with them, we will need a helper structure that will keep
             // some state for each chunk of the response. While this could have been a simple tuple,
             // I'd rather not mix my pens and stumble on myself.
-            let stream_helper = FileWritingStreamHelper {
-                file: tokio::fs::File::create(&blob_path).await?,
-                inner_stream: response
-                    .raw_response
-                    .bytes_stream()
-            };
+            // Chunked responses have no size, the download then goes on until the end of the stream
+            let content_length = blob_head
+                .as_ref()
+                .and_then(|blob_head| blob_head.content_length)
+                .or(response.content_length);
+            let upstream_hash = response.hash
+                .clone()
+                .or_else(|| blob_head.and_then(|blob_head| blob_head.hash));
 
-            // The magic that will allow us to write a file and send a response at the same time. Since
-            // axum's StreamBody takes an implementation of stream, we can pass an unfold stream that will wrap
-            // the underlying stream. The effect is like the `tee` command, but on streams.
-            let downstream_response_stream = stream::unfold(
-                stream_helper,
-                |mut state| async move {
-                    let next_chunk = state.inner_stream.next().await;
-
-                    match next_chunk {
-                        // There is a chunk of response to dump into a file and it has been extracted successfully.
-                        Some(Ok(chunk)) => {
-                            let result = state
-                                .file
-                                .write_all(&chunk)
-                                .await
-                                // We convert a successful write into the chunk so axum can
-                                // write it in the response, and a write error into a registry
-                                // error.
-                                .map(|_| chunk)
-                                .map_err(|e| RegistryHttpError::from(e));
-                            Some((result, state))
-                        }
-
-                        // There is a chunk but the extraction failed. Convert the failure into a registry error and
-                        // return it.
-                        Some(Err(error)) => {
-                            Some((Err(RegistryHttpError::from(error)), state))
-                        }
-
-                        // There's no more chunk to extract, we send None so axum is signaled that the stream
-                        // has been exhausted.
-                        None => None
-                    }
-            });
+            let (downstream_response_stream, cache_status) = if pass_through_repository || !fits_in_cache(&tenant, content_length).await {
+                info!("Repository is passed through, not caching the blob");
+                let stream = pass_through(response.raw_response.bytes_stream(), Some(Arc::clone(&docker_client)), response.connection_permit);
+                (stream.boxed(), "BYPASS")
+            } else {
+                let mut stream_helper = FileWritingStreamHelper::new(&tenant, &container_ref, &digest, response.raw_response.bytes_stream(), content_length).await?;
+                stream_helper.docker_client = Some(Arc::clone(&docker_client));
+                stream_helper.advertised_digest = upstream_hash.clone();
+                stream_helper._connection_permit = response.connection_permit;
+                (write_while_streaming(stream_helper).boxed(), "MISS")
+            };
 
-            return Ok((
+            let mut response = (
                 StatusCode::OK,
                 [
                     ("Content-Type", "application/octet-stream".to_string()),
-                    ("Content-Length", response.content_length.to_string()),
-                    ("Proxy-Docker-Cache", "MISS".to_string())
+                    ("Proxy-Docker-Cache", cache_status.to_string())
                 ],
-                StreamBody::new(downstream_response_stream)
-            ).into_response())
+                // The cached copy is being written right now
+                blob_cache_headers(&digest, SystemTime::now()),
+                StreamBody::new(app.bandwidth_limiter.throttle(&client.key, &proxy_repository, downstream_response_stream).await)
+            ).into_response();
+            if let Some(content_length) = content_length {
+                response.headers_mut().insert("Content-Length", HeaderValue::from(content_length));
+            }
+            if let Some(hash) = upstream_hash.and_then(|hash| HeaderValue::from_str(&hash).ok()) {
+                response.headers_mut().insert("Docker-Content-Digest", hash);
+            }
+
+            return Ok(response)
         },
 
         Err(DockerClientError::UnexpectedStatusCode(404)) => {
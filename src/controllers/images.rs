@@ -0,0 +1,52 @@
+use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, Json};
+
+use crate::{ApplicationState, data::{helpers::{is_sha256_digest, reject_invalid_container_refs, resolve_upstream_container_ref}, scans::ScanReport}, tenants::{CurrentTenant, Tenant}};
+
+use super::{sbom, RegistryHttpError, RegistryHttpResult};
+
+/// Splits `<repository>/<digest>`, repositories containing slashes themselves
+pub fn split_image_path(image: &str) -> Result<(String, String), RegistryHttpError> {
+    let (container_ref, digest) = image
+        .rsplit_once('/')
+        .ok_or_else(|| RegistryHttpError::invalid_repository_name(image))?;
+
+    reject_invalid_container_refs(container_ref)?;
+    if !is_sha256_digest(digest) {
+        return Err(RegistryHttpError::invalid_hash_format(digest));
+    }
+
+    Ok((container_ref.to_string(), digest.to_string()))
+}
+
+/// Everything known about an image besides its content, on GET /api/images/<repository>/<digest>/<resource>
+#[tracing::instrument(skip_all, fields(path = path))]
+pub async fn fetch_image_resource(
+    Path(path): Path<String>,
+    State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant
+) -> RegistryHttpResult {
+    let (image, resource) = match path.trim_start_matches('/').rsplit_once('/') {
+        Some(image_resource) => image_resource,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+    let (container_ref, image_digest) = split_image_path(image)?;
+
+    match resource {
+        "sbom" => sbom::fetch_sbom(&app, &tenant, container_ref, image_digest).await,
+        "scan" => fetch_scan_report(&tenant, container_ref, image_digest).await,
+        _ => Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+/// Latest vulnerability scan of an image. Images of the proxy cache are found under `proxy/<registry>/<repository>`.
+async fn fetch_scan_report(tenant: &Tenant, container_ref: String, image_digest: String) -> RegistryHttpResult {
+    let (storage_root, container_ref) = match container_ref.strip_prefix("proxy/") {
+        Some(proxied_container_ref) => (&tenant.proxy_storage, resolve_upstream_container_ref(proxied_container_ref)),
+        None => (&tenant.registry_storage, container_ref)
+    };
+
+    match ScanReport::load(storage_root, &container_ref, &image_digest).await? {
+        Some(report) => Ok(Json(report).into_response()),
+        None => Err(RegistryHttpError::manifest_not_found(&container_ref, format!("{}/scan", image_digest)))
+    }
+}
@@ -1,36 +1,79 @@
-use std::os::unix::prelude::MetadataExt;
-
-use axum::{response::IntoResponse, extract::{Path, BodyStream, State}, TypedHeader, headers, http::StatusCode, body::StreamBody};
 
+use axum::{response::IntoResponse, extract::{BodyStream, State}, TypedHeader, headers, http::StatusCode, body::{StreamBody, Bytes}};
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
 use tokio_util::io::ReaderStream;
 use tracing::{info, warn};
 
-use crate::{data::{helpers::{reject_invalid_container_refs, RegistryPathsHelper, reject_invalid_tags_refs}, manifests::{Manifest, ManifestMetadata}}, ApplicationState, docker_client::client::DockerClientError};
+use crate::{data::{helpers::{reject_invalid_container_refs, RegistryPathsHelper, reject_invalid_tags_refs, resolve_upstream_container_ref}, manifests::{Manifest, ManifestMetadata, resolve_manifest_reference_async, described_manifest, listed_by_index, read_manifest_metadata, tag_linked_at}, encryption}, ApplicationState, docker_client::client::{DockerClient, DockerClientError}};
 use crate::controllers::RegistryHttpResult;
+use crate::requests::ClientKey;
+use crate::repository_path::RepositoryPath;
+use crate::policy::{self, PolicyImage};
+use crate::notifications::{Event, EventAction, EventTarget};
+use crate::scanner::ScannedImage;
+use crate::push_through;
+use crate::tenants::{CurrentTenant, Tenant};
 
 use super::RegistryHttpError;
 
-#[tracing::instrument(skip_all, fields(container_ref = container_ref, manifest_ref = manifest_ref))]
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), manifest_ref = %repository_path.reference()))]
 pub async fn upload_manifest(
-    Path((container_ref, manifest_ref)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     TypedHeader(content_type): TypedHeader<headers::ContentType>,
     State(app): State<ApplicationState>,
-    mut body: BodyStream
+    CurrentTenant(tenant): CurrentTenant,
+    client: ClientKey,
+    content_length: Option<TypedHeader<headers::ContentLength>>,
+    body: BodyStream
 ) -> RegistryHttpResult {
+    let (container_ref, manifest_ref) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
     reject_invalid_tags_refs(&manifest_ref)?;
 
+    let max_manifest_bytes = app.conf.server.max_manifest_bytes;
+    reject_large_manifests(content_length.map(|TypedHeader(content_length)| content_length.0), max_manifest_bytes)?;
+    let manifest_content = read_manifest(body, max_manifest_bytes).await?;
+    tenant.check_quota_for(manifest_content.len() as u64).await?;
+
     let mut manifest = Manifest::new(
-        &app.conf.registry_storage, 
-        &app.conf.temporary_registry_storage,
+        &tenant.registry_storage, 
+        &tenant.temporary_registry_storage,
         &container_ref, 
         &manifest_ref
-    );
+    )
+        .with_encryption(app.storage_cipher.clone())
+        .with_pusher(client.key.clone());
 
+    let tag_lock = manifest.lock_tag().await;
     info!("Saving manifest");
-    manifest.save_manifest((&mut body).into()).await?;
+    manifest.save_manifest(manifest_content.as_slice().into()).await?;
     info!("Saving metadata");
     manifest.save_manifest_metadata(&content_type.to_string()).await?;
+    manifest.link_tag().await?;
+    drop(tag_lock);
+    tenant.record_stored(manifest_content.len() as u64);
+    push_through::forward_manifest(&app, &tenant, &container_ref, &manifest_ref, &content_type.to_string(), &manifest_content).await?;
+
+    if let Some(scanner) = &app.scanner {
+        scanner.schedule(ScannedImage {
+            storage_root: tenant.registry_storage.clone(),
+            container_ref: container_ref.clone(),
+            repository: container_ref.clone(),
+            digest: manifest.docker_hash()?.clone(),
+        });
+    }
+
+    if let Some(notifier) = &app.notifier {
+        let target = EventTarget {
+            repository: container_ref.clone(),
+            digest: manifest.docker_hash()?.clone(),
+            tag: Some(manifest_ref.clone()).filter(|manifest_ref| !manifest_ref.starts_with("sha256:")),
+            media_type: Some(content_type.to_string()),
+            size: Some(manifest.size()),
+        };
+        notifier.notify(Event::new(EventAction::Push, target).with_actor(client.key)).await;
+    }
 
     Ok((
         StatusCode::CREATED,
@@ -41,61 +84,99 @@ pub async fn upload_manifest(
     ).into_response())
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), manifest_ref = %repository_path.reference()))]
 pub async fn fetch_manifest(
-    Path((container_ref, manifest_ref)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> RegistryHttpResult {
+    let (container_ref, manifest_ref) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
     reject_invalid_tags_refs(&manifest_ref)?;
 
-    let manifest_path = RegistryPathsHelper::manifest_path(&app.conf.registry_storage, &container_ref, &manifest_ref);
-    let manifest_file = match tokio::fs::File::open(&manifest_path).await {
-        Ok(f) => f,
+    let manifest_digest = match resolve_manifest_reference_async(tenant.registry_storage.clone(), container_ref.clone(), manifest_ref.clone()).await?? {
+        Some(manifest_digest) => manifest_digest,
+        None => return Err(RegistryHttpError::manifest_not_found(&container_ref, &manifest_ref))
+    };
+
+    let manifest_path = RegistryPathsHelper::manifest_path(&tenant.registry_storage, &container_ref, &manifest_digest);
+    let manifest_content = match tokio::fs::read(&manifest_path).await {
+        Ok(manifest_content) => encryption::decrypt_manifest(app.storage_cipher.as_deref(), &manifest_digest, manifest_content)?,
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
             return Err(RegistryHttpError::manifest_not_found(&container_ref, &manifest_ref));
         }
         Err(e) => return Err(e.into())
     };
-    let manifest_size = manifest_file.metadata().await?.size();
+    let manifest_size = manifest_content.len();
 
-    let manifest_meta_path = RegistryPathsHelper::manifest_meta(&app.conf.registry_storage, &container_ref, &manifest_ref);
-    let manifest_meta = tokio::fs::read_to_string(&manifest_meta_path).await?;
-    let manifest_meta = serde_json::from_str::<ManifestMetadata>(&manifest_meta).unwrap();
+    let (docker_hash, content_type) = read_manifest_metadata(&tenant.registry_storage, &container_ref, &manifest_digest, &manifest_content).await?;
 
-    let manifest_stream = StreamBody::new(tokio_util::io::ReaderStream::new(manifest_file));
+    policy::enforce(&app, &PolicyImage {
+        storage_root: tenant.registry_storage.clone(),
+        container_ref: &container_ref,
+        repository: container_ref.clone(),
+        digest: &docker_hash,
+        reference: &manifest_ref,
+        manifest: &manifest_content,
+        upstream: None,
+    }).await?;
 
     Ok((
         StatusCode::OK,
         [
-            ("Docker-Content-Digest", format!("sha256:{}", manifest_meta.hash)),
-            ("Content-Type", manifest_meta.content_type.to_string()),
+            ("Docker-Content-Digest", docker_hash),
+            ("Content-Type", content_type),
             ("Content-Length", manifest_size.to_string())
         ],
-        manifest_stream
+        manifest_content
     ).into_response())
 }
 
-#[tracing::instrument(skip_all, fields(container_ref = container_ref, manifest_ref = manifest_ref))]
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), manifest_ref = %repository_path.reference()))]
 pub async fn proxy_fetch_manifest(
-    Path((container_ref, manifest_ref)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
 ) -> RegistryHttpResult {
+    let (container_ref, manifest_ref) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
+    let container_ref = resolve_upstream_container_ref(&container_ref);
     reject_invalid_tags_refs(&manifest_ref)?;
 
+    // Manifests addressed by digest never change, tags are trusted until their TTL expires
+    let cached_digest = resolve_manifest_reference_async(tenant.proxy_storage.clone(), container_ref.clone(), manifest_ref.clone())
+        .await??
+        .filter(|cached_digest| RegistryPathsHelper::manifest_path(&tenant.proxy_storage, &container_ref, cached_digest).is_file());
+    if let (Some(manifest_ttl), Some(_)) = (app.conf.cache.manifest_ttl(), &cached_digest) {
+        let fresh = manifest_ref.starts_with("sha256:") || tag_linked_at(&tenant.proxy_storage, &container_ref, &manifest_ref)
+            .await
+            .and_then(|linked_at| linked_at.elapsed().ok())
+            .is_some_and(|age| age < manifest_ttl);
+        if fresh {
+            if let Some(response) = cached_proxy_manifest(&app, &tenant, &container_ref, &manifest_ref, "HIT").await? {
+                return Ok(response);
+            }
+        }
+    }
+
     // TODO: Rearrange code to support offline proxying, that is if the upstream proxy did send 429 or any 5xx HTTP code
-    let client = app.docker_clients.get_client(&container_ref).await?;
+    let client = tenant.docker_clients.get_client(&container_ref).await?;
     info!("Querying upstream HEAD to fetch the most manifest related to the tag");
 
-    let (proxy_hash, content_length, content_type) = match client.query_manifest(&manifest_ref, true).await {
+    let upstream_manifest = match &cached_digest {
+        Some(cached_digest) => client.revalidate_manifest(&manifest_ref, cached_digest).await,
+        None => client.query_manifest(&manifest_ref, true).await
+    };
+    let (proxy_hash, content_length, content_type) = match upstream_manifest {
         // The ideal case: the server returns a 200 on the HEAD HTTP request
         Ok(proxy_response_head) => {
             info!("Upstream returned 200 on the HEAD. Checking for cached hash file {}", proxy_response_head.hash);
+            app.cache_stats.record_refresh(&container_ref).await;
 
             // Check if we have the same copy of the manifest somewhere in our files before sending a GET request
             // to the upstream respository.
-            let proxy_manifest_hash_path = RegistryPathsHelper::manifest_path(&app.conf.proxy_storage, &container_ref, &proxy_response_head.hash);
+            let proxy_manifest_hash_path = RegistryPathsHelper::manifest_path(&tenant.proxy_storage, &container_ref, &proxy_response_head.hash);
+            app.cache_stats.record_manifest(&container_ref, proxy_manifest_hash_path.is_file()).await;
             if !proxy_manifest_hash_path.is_file() {
                 info!("File does not exist. Querying and caching the upstream manifest");
                 // We don't have the manifest, GET the manifest referenced by the hash sent by the server
@@ -105,44 +186,226 @@ pub async fn proxy_fetch_manifest(
                 // something other than a 200 is either rate limiting or server errors.
                 //
                 // Instead of bailing out, we could consider sending a stale version of the manifest. Later.
-                let mut proxy_manifest = client.query_manifest(&proxy_response_head.hash, false).await?;
+                let proxy_manifest = client.query_manifest(&proxy_response_head.hash, false).await?;
+                let max_manifest_bytes = app.conf.server.max_manifest_bytes;
+                reject_large_manifests(proxy_manifest.content_length, max_manifest_bytes)?;
+                let proxy_manifest_content = read_manifest(proxy_manifest.raw_response.bytes_stream(), max_manifest_bytes).await?;
+                client.record_downloaded_bytes(proxy_manifest_content.len() as u64);
+                drop(proxy_manifest.connection_permit);
+
+                // Checked before anything is written, a manifest not matching its digest never enters the cache
+                let proxy_manifest_digest = format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(&proxy_manifest_content)));
+                if proxy_manifest_digest != proxy_response_head.hash {
+                    warn!("Upstream manifest doesn't match its digest, got {}", proxy_manifest_digest);
+                    client.record_digest_mismatch(&proxy_response_head.hash, &proxy_manifest_digest);
+                    return Err(eyre::eyre!("Manifest {} doesn't match its digest, got {}", proxy_response_head.hash, proxy_manifest_digest).into());
+                }
+
+                // Only signed images enter the cache of the repositories needing signatures
+                if let Some(violation) = signature_violation(&tenant, &client, &container_ref, &manifest_ref, &proxy_response_head.hash, &proxy_manifest_content).await? {
+                    return Err(RegistryHttpError::PolicyViolation {
+                        repository: format!("proxy/{}", container_ref),
+                        digest: proxy_response_head.hash,
+                        detail: serde_json::json!({
+                            "policy": "signature_verification",
+                            "violations": [{ "rule": "signature_verification", "message": violation }]
+                        })
+                    });
+                }
+
+                // Served from the cache once written, a manifest that doesn't fit in the memory storage can't be served
+                tenant.check_proxy_fill(proxy_manifest_content.len() as u64).await?;
 
                 tokio::fs::create_dir_all(&proxy_manifest_hash_path.parent().unwrap()).await?;
-                let proxy_manifest_meta_hash_path = RegistryPathsHelper::manifest_meta(&app.conf.proxy_storage, &container_ref, &proxy_response_head.hash);
+                let proxy_manifest_meta_hash_path = RegistryPathsHelper::manifest_meta(&tenant.proxy_storage, &container_ref, &proxy_response_head.hash);
                 tokio::fs::create_dir_all(proxy_manifest_meta_hash_path.parent().unwrap()).await?;
-                let mut manifest_file = Manifest::new(&app.conf.proxy_storage, &app.conf.temporary_registry_storage, &container_ref, &manifest_ref);
+                let mut manifest_file = Manifest::new(&tenant.proxy_storage, &tenant.temporary_registry_storage, &container_ref, &manifest_ref);
 
                 // And write all the things. The function will be in charge of writing the docker image manifest and its
                 // related metadata, while making sure to not do stupid stuff such as overwriting the hash file with an
                 // empty version of itself.
-                manifest_file.save_manifest((&mut proxy_manifest.raw_response).into()).await?;
+                let tag_lock = manifest_file.lock_tag().await;
+                manifest_file.save_manifest(proxy_manifest_content.as_slice().into()).await?;
                 manifest_file.save_manifest_metadata(&proxy_response_head.content_type).await?;
+                manifest_file.link_tag().await?;
+                drop(tag_lock);
+
+                if let Some(scanner) = &app.scanner {
+                    scanner.schedule(ScannedImage {
+                        storage_root: tenant.proxy_storage.clone(),
+                        container_ref: container_ref.clone(),
+                        repository: format!("proxy/{}", container_ref),
+                        digest: proxy_response_head.hash.clone(),
+                    });
+                }
             } else {
                 info!("Manifest is already cached");
+                // Upstream confirmed the tag, which is fresh again
+                Manifest::new(&tenant.proxy_storage, &tenant.temporary_registry_storage, &container_ref, &manifest_ref)
+                    .with_docker_hash(proxy_response_head.hash.clone())
+                    .link_tag()
+                    .await?;
             }
 
             (proxy_response_head.hash, proxy_response_head.content_length, proxy_response_head.content_type)
         },
 
+        // The cached manifest is still the upstream one, no need to download it again
+        Err(e @ DockerClientError::UnexpectedStatusCode(304)) => {
+            info!("Upstream manifest didn't change");
+            app.cache_stats.record_refresh(&container_ref).await;
+            if let Some(cached_digest) = cached_digest {
+                Manifest::new(&tenant.proxy_storage, &tenant.temporary_registry_storage, &container_ref, &manifest_ref)
+                    .with_docker_hash(cached_digest)
+                    .link_tag()
+                    .await?;
+            }
+            return match cached_proxy_manifest(&app, &tenant, &container_ref, &manifest_ref, "REVALIDATED").await? {
+                Some(response) => Ok(response),
+                None => Err(e.into())
+            };
+        }
+
         // Not ideal but easy to deal with: 404 Not Found
-        Err(DockerClientError::UnexpectedStatusCode(code)) if code == 404 => {
+        Err(DockerClientError::UnexpectedStatusCode(404)) => {
             warn!("Upstream sent 404 Not Found");
             return Ok(StatusCode::NOT_FOUND.into_response())
         }
 
+        // The upstream registry is rate limiting us: the cached manifest may be stale, but it beats no manifest at all.
+        Err(e @ DockerClientError::RateLimited { .. }) => {
+            warn!("Upstream is rate limiting us, looking for a cached manifest");
+            return match cached_proxy_manifest(&app, &tenant, &container_ref, &manifest_ref, "STALE").await? {
+                Some(response) => Ok(response),
+                None => Err(e.into())
+            };
+        }
+
         Err(e) => return Err(e.into())
     };
 
-    let proxy_manifest_hash_path = RegistryPathsHelper::manifest_path(&app.conf.proxy_storage, &container_ref, &manifest_ref);
-    let body = StreamBody::new(ReaderStream::new(tokio::fs::File::open(&proxy_manifest_hash_path).await?));
+    // The bytes sent are always the ones of the digest announced, whatever the tag pointed to before
+    let proxy_manifest_hash_path = RegistryPathsHelper::manifest_path(&tenant.proxy_storage, &container_ref, &proxy_hash);
+
+    let repository = format!("proxy/{}", container_ref);
+    if app.conf.policy_rule(&repository).is_some() {
+        policy::enforce(&app, &PolicyImage {
+            storage_root: tenant.proxy_storage.clone(),
+            container_ref: &container_ref,
+            repository,
+            digest: &proxy_hash,
+            reference: &manifest_ref,
+            manifest: &tokio::fs::read(&proxy_manifest_hash_path).await?,
+            upstream: Some(&client),
+        }).await?;
+    }
+
+    let manifest_file = tokio::fs::File::open(&proxy_manifest_hash_path).await?;
+    let manifest_size = manifest_file.metadata().await?.len();
+    if let Some(content_length) = content_length.filter(|content_length| *content_length != manifest_size) {
+        warn!("Upstream announced {} bytes for manifest {}, serving the {} bytes cached", content_length, proxy_hash, manifest_size);
+    }
+    let body = StreamBody::new(ReaderStream::new(manifest_file));
 
     Ok((
         StatusCode::OK,
         [
             ("Content-Type", content_type.clone()),
             ("Docker-Content-Digest", proxy_hash.clone()),
-            ("Content-Length", content_length.to_string())
+            ("Content-Length", manifest_size.to_string())
         ],
         body
     ).into_response())
-}
\ No newline at end of file
+}
+/// Why a proxied manifest can't enter the cache, None if it can. Images need a trusted signature unless an index
+/// already admitted into the cache lists them. Signatures, SBOMs and attestations are admitted along with the
+/// image they describe.
+async fn signature_violation(tenant: &Tenant, client: &DockerClient, container_ref: &str, manifest_ref: &str, digest: &str, manifest: &[u8]) -> eyre::Result<Option<String>> {
+    let signature_verifier = tenant.docker_clients.signature_verifier();
+    if !signature_verifier.requires_signature(container_ref) {
+        return Ok(None);
+    }
+
+    let image_digest = described_manifest(manifest, manifest_ref).unwrap_or_else(|| digest.to_string());
+    if listed_by_index(&tenant.proxy_storage, container_ref, &image_digest).await? {
+        return Ok(None);
+    }
+
+    signature_verifier.verify(client, container_ref, &image_digest).await
+}
+
+/// Cached version of a proxied manifest, served without asking the upstream registry whether it changed.
+/// The cache status tells the client why: still fresh, confirmed by upstream, or stale.
+async fn cached_proxy_manifest(app: &ApplicationState, tenant: &Tenant, container_ref: &str, manifest_ref: &str, cache_status: &str) -> Result<Option<axum::response::Response>, RegistryHttpError> {
+    let manifest_digest = match resolve_manifest_reference_async(tenant.proxy_storage.clone(), container_ref.to_string(), manifest_ref.to_string()).await?? {
+        Some(manifest_digest) => manifest_digest,
+        None => return Ok(None)
+    };
+
+    let manifest_path = RegistryPathsHelper::manifest_path(&tenant.proxy_storage, container_ref, &manifest_digest);
+    let manifest_meta_path = RegistryPathsHelper::manifest_meta(&tenant.proxy_storage, container_ref, &manifest_digest);
+    if !manifest_path.is_file() || !manifest_meta_path.is_file() {
+        return Ok(None);
+    }
+
+    let manifest_file = tokio::fs::File::open(&manifest_path).await?;
+    let manifest_size = manifest_file.metadata().await?.len();
+    let manifest_meta = tokio::fs::read_to_string(&manifest_meta_path).await?;
+    let manifest_meta = serde_json::from_str::<ManifestMetadata>(&manifest_meta).unwrap();
+
+    let repository = format!("proxy/{}", container_ref);
+    if app.conf.policy_rule(&repository).is_some() {
+        policy::enforce(app, &PolicyImage {
+            storage_root: tenant.proxy_storage.clone(),
+            container_ref,
+            repository,
+            digest: &format!("sha256:{}", manifest_meta.hash),
+            reference: manifest_ref,
+            manifest: &tokio::fs::read(&manifest_path).await?,
+            upstream: None,
+        }).await?;
+    }
+
+    info!("Serving the cached manifest");
+    app.cache_stats.record_manifest(container_ref, true).await;
+    Ok(Some((
+        StatusCode::OK,
+        [
+            ("Content-Type", manifest_meta.content_type.to_string()),
+            ("Docker-Content-Digest", format!("sha256:{}", manifest_meta.hash)),
+            ("Content-Length", manifest_size.to_string()),
+            ("Proxy-Docker-Cache", cache_status.to_string())
+        ],
+        StreamBody::new(ReaderStream::new(manifest_file))
+    ).into_response()))
+}
+
+/// Refuses manifests announced larger than the limit before anything is read
+fn reject_large_manifests(content_length: Option<u64>, max_manifest_bytes: u64) -> Result<(), RegistryHttpError> {
+    match content_length {
+        Some(content_length) if content_length > max_manifest_bytes => {
+            warn!("Manifest of {} bytes refused, over the limit of {} bytes", content_length, max_manifest_bytes);
+            Err(RegistryHttpError::ManifestTooLarge { max_bytes: max_manifest_bytes })
+        },
+        _ => Ok(())
+    }
+}
+
+/// Reads a manifest in memory, giving up as soon as it goes over the limit, whatever its Content-Length said
+async fn read_manifest<S, E>(mut body: S, max_manifest_bytes: u64) -> Result<Vec<u8>, RegistryHttpError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    RegistryHttpError: From<E>
+{
+    let mut content = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if (content.len() + chunk.len()) as u64 > max_manifest_bytes {
+            warn!("Manifest refused once over the limit of {} bytes", max_manifest_bytes);
+            return Err(RegistryHttpError::ManifestTooLarge { max_bytes: max_manifest_bytes });
+        }
+        content.extend_from_slice(&chunk);
+    }
+
+    Ok(content)
+}
@@ -0,0 +1,29 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+
+use crate::{ApplicationState, data::{cache_watermark, deduplication, helpers, operation_metrics}, docker_client::clients_store};
+
+/// Exposes the proxy metrics in the Prometheus text format.
+pub async fn metrics(State(app): State<ApplicationState>) -> impl IntoResponse {
+    let mut output = String::new();
+    app.docker_clients.metrics().render(&mut output);
+    app.docker_clients.digest_mismatches().render(&mut output);
+    let mut upstream_clients = Vec::new();
+    for tenant in app.tenants.all() {
+        upstream_clients.extend(tenant.docker_clients.summaries().await);
+    }
+    clients_store::render_client_metrics(&upstream_clients, &mut output);
+    app.cache_stats.render(&mut output).await;
+    helpers::render_hashing_metrics(&mut output);
+    deduplication::render_deduplication_metrics(&mut output);
+    cache_watermark::render_watermark_metrics(&mut output);
+    if let Some(open_blobs) = &app.open_blobs {
+        open_blobs.render(&mut output);
+    }
+    operation_metrics::render_operation_metrics(&mut output);
+
+    (
+        StatusCode::OK,
+        [("Content-Type", "text/plain; version=0.0.4")],
+        output
+    )
+}
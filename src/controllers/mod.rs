@@ -1,14 +1,28 @@
-use axum::{response::{Response, IntoResponse}, http::StatusCode};
+use axum::{response::{Response, IntoResponse}, http::{StatusCode, HeaderValue}};
 use tracing::{error, log::warn};
 use crate::{data::json_registry_error::RegistryJsonErrorReprWrapper, docker_client};
 
+pub mod admin;
 pub mod base;
 pub mod blobs;
+pub mod images;
 pub mod manifests;
+pub mod metrics;
+pub mod openapi;
+pub mod prefetch;
+pub mod sbom;
+pub mod token;
+pub mod transfer;
 pub mod uploads;
 
 pub type RegistryHttpResult = Result<Response, RegistryHttpError>;
 
+/// Codes found in the bodies of the errors, those of the distribution specification and UPSTREAM_ERROR for the proxy
+pub const ERROR_CODES: &[&str] = &[
+    "NAME_INVALID", "TAG_INVALID", "UNSUPPORTED", "BLOB_UPLOAD_UNKNOWN", "BLOB_UPLOAD_INVALID", "SIZE_INVALID", "DIGEST_INVALID",
+    "NAME_UNKNOWN", "UNAUTHORIZED", "DENIED", "TOOMANYREQUESTS", "UNAVAILABLE", "UPSTREAM_ERROR", "UNKNOWN"
+];
+
 #[derive(thiserror::Error, Debug)]
 pub enum RegistryHttpError {
     #[error("Invalid repository name {0}")]
@@ -24,12 +38,66 @@ pub enum RegistryHttpError {
     #[error("Upload ID {0} not found or invalid")]
     UploadIdNotFound(String),
 
+    #[error("Invalid blob upload: {0}")]
+    BlobUploadInvalid(String),
+
+    #[error("Manifests are limited to {max_bytes} bytes")]
+    ManifestTooLarge { max_bytes: u64 },
+
+    #[error("Invalid or unsupported digest {0}")]
+    DigestInvalid(String),
+
+    #[error("The uploaded content doesn't match the digest {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+
     // #[error("Multiple registry errors: {0:?}")]
     // MultipleErrors(Vec<Self>),
 
     #[error("Manifest {manifest} in layer {container} not found")]
     ManifestNotFound { container: String, manifest: String },
 
+    #[error("Authentication required")]
+    Unauthorized { challenge: String },
+
+    #[error("Requested access to the resource is denied: {0}")]
+    Denied(String),
+
+    #[error("Manifest {digest} of {repository} is denied by policy")]
+    PolicyViolation { repository: String, digest: String, detail: serde_json::Value },
+
+    #[error("Storage quota of the tenant {tenant} exceeded")]
+    QuotaExceeded { tenant: String, used_bytes: u64, quota_bytes: u64 },
+
+    #[error("The memory storage is full")]
+    StorageFull { used_bytes: u64, max_bytes: u64 },
+
+    #[error("Too many requests, retry in {retry_after} seconds")]
+    TooManyRequests { retry_after: u64, detail: serde_json::Value },
+
+    #[error("The request didn't complete within its deadline")]
+    DeadlineExceeded,
+
+    #[error("Media type {0} is not supported on this endpoint")]
+    UnsupportedMediaType(String),
+
+    #[error("Method {0} is not supported on this endpoint")]
+    MethodNotAllowed(String),
+
+    #[error("{0} is not enabled on this registry")]
+    Unsupported(String),
+
+    #[error("The registry is overloaded, try again later")]
+    ServiceUnavailable,
+
+    #[error("The upstream registry failed: {0}")]
+    UpstreamError(String),
+
+    #[error("The upstream registry didn't answer in time: {0}")]
+    UpstreamTimeout(String),
+
+    #[error("The upstream registry denied access: {reason}")]
+    UpstreamDenied { status: Option<u16>, reason: String },
+
     #[error("Internal server error: {0}")]
     RegistryInternalError(eyre::Report),
 }
@@ -48,26 +116,67 @@ impl RegistryHttpError {
     registry_error_constructor!(invalid_tag_name, InvalidTagName);
     registry_error_constructor!(invalid_hash_format, InvalidHashFormat);
     registry_error_constructor!(upload_id_not_found, UploadIdNotFound);
+    registry_error_constructor!(blob_upload_invalid, BlobUploadInvalid);
+    registry_error_constructor!(digest_invalid, DigestInvalid);
+    registry_error_constructor!(denied, Denied);
+    registry_error_constructor!(method_not_allowed, MethodNotAllowed);
+    registry_error_constructor!(unsupported_media_type, UnsupportedMediaType);
+    registry_error_constructor!(unsupported, Unsupported);
     pub fn manifest_not_found<C: ToString, M: ToString>(container: C, manifest_ref: M) -> Self {
         Self::ManifestNotFound { container: container.to_string(), manifest: manifest_ref.to_string() }
     }
-}
+    pub fn too_many_requests(retry_after: u64, detail: serde_json::Value) -> Self {
+        Self::TooManyRequests { retry_after, detail }
+    }
+    /// Failed logins went over the limit of the lockout
+    pub fn locked_out(retry_after: std::time::Duration) -> Self {
+        Self::TooManyRequests {
+            retry_after: retry_after.as_secs() + 1,
+            detail: serde_json::json!({ "limit": "authentication" })
+        }
+    }
+    pub fn unauthorized<C: ToString>(challenge: C) -> Self {
+        Self::Unauthorized { challenge: challenge.to_string() }
+    }
 
-impl IntoResponse for RegistryHttpError {
-    fn into_response(self) -> Response {
-        warn!("HTTP error: {:?}", self);
-        let (http_code, registry_error) = match self {
+    /// Status of the response and code of the error in its body, one of `ERROR_CODES`
+    pub fn status_and_code(&self) -> (StatusCode, &'static str) {
+        match self {
             RegistryHttpError::InvalidRepositoryName(_) => (StatusCode::BAD_REQUEST, "NAME_INVALID"),
             RegistryHttpError::InvalidTagName(_) => (StatusCode::BAD_REQUEST, "TAG_INVALID"),
             RegistryHttpError::InvalidHashFormat(_) => (StatusCode::BAD_REQUEST, "UNSUPPORTED"),
             RegistryHttpError::UploadIdNotFound(_) => (StatusCode::NOT_FOUND, "BLOB_UPLOAD_UNKNOWN"),
-            RegistryHttpError::RegistryInternalError(ref report) => {
-                error!("Internal server error: {:#?}", report);
-                (StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN")
-            },
+            RegistryHttpError::BlobUploadInvalid(_) => (StatusCode::BAD_REQUEST, "BLOB_UPLOAD_INVALID"),
+            RegistryHttpError::ManifestTooLarge {..} => (StatusCode::PAYLOAD_TOO_LARGE, "SIZE_INVALID"),
+            RegistryHttpError::DigestInvalid(_) => (StatusCode::BAD_REQUEST, "DIGEST_INVALID"),
+            RegistryHttpError::DigestMismatch {..} => (StatusCode::BAD_REQUEST, "DIGEST_INVALID"),
+            RegistryHttpError::RegistryInternalError(_) => (StatusCode::INTERNAL_SERVER_ERROR, "UNKNOWN"),
             RegistryHttpError::ManifestNotFound {..} => (StatusCode::NOT_FOUND, "NAME_UNKNOWN"),
-            // RegistryHttpError::MultipleErrors(_) => (StatusCode::BAD_REQUEST, ""),
-        };
+            RegistryHttpError::Unauthorized {..} => (StatusCode::UNAUTHORIZED, "UNAUTHORIZED"),
+            RegistryHttpError::Denied(_) => (StatusCode::FORBIDDEN, "DENIED"),
+            RegistryHttpError::PolicyViolation {..} => (StatusCode::FORBIDDEN, "DENIED"),
+            RegistryHttpError::QuotaExceeded {..} => (StatusCode::FORBIDDEN, "DENIED"),
+            RegistryHttpError::StorageFull {..} => (StatusCode::FORBIDDEN, "DENIED"),
+            RegistryHttpError::TooManyRequests {..} => (StatusCode::TOO_MANY_REQUESTS, "TOOMANYREQUESTS"),
+            RegistryHttpError::ServiceUnavailable => (StatusCode::SERVICE_UNAVAILABLE, "UNAVAILABLE"),
+            RegistryHttpError::DeadlineExceeded => (StatusCode::SERVICE_UNAVAILABLE, "UNAVAILABLE"),
+            RegistryHttpError::UpstreamError(_) => (StatusCode::BAD_GATEWAY, "UPSTREAM_ERROR"),
+            RegistryHttpError::UpstreamTimeout(_) => (StatusCode::GATEWAY_TIMEOUT, "UPSTREAM_ERROR"),
+            RegistryHttpError::UpstreamDenied {..} => (StatusCode::FORBIDDEN, "DENIED"),
+            RegistryHttpError::MethodNotAllowed(_) => (StatusCode::METHOD_NOT_ALLOWED, "UNSUPPORTED"),
+            RegistryHttpError::UnsupportedMediaType(_) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "UNSUPPORTED"),
+            RegistryHttpError::Unsupported(_) => (StatusCode::NOT_FOUND, "UNSUPPORTED"),
+        }
+    }
+}
+
+impl IntoResponse for RegistryHttpError {
+    fn into_response(self) -> Response {
+        warn!("HTTP error: {:?}", self);
+        if let RegistryHttpError::RegistryInternalError(ref report) = self {
+            error!("Internal server error: {:#?}", report);
+        }
+        let (http_code, registry_error) = self.status_and_code();
 
         let json_representaiton = match self {
             // RegistryHttpError::MultipleErrors(errors) => {
@@ -77,17 +186,59 @@ impl IntoResponse for RegistryHttpError {
             RegistryHttpError::InvalidTagName(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
             RegistryHttpError::InvalidHashFormat(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
             RegistryHttpError::UploadIdNotFound(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::BlobUploadInvalid(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::ManifestTooLarge { max_bytes } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), serde_json::json!({
+                "max_bytes": max_bytes
+            })),
+            RegistryHttpError::DigestInvalid(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::DigestMismatch {..} => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
             RegistryHttpError::RegistryInternalError(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
-            RegistryHttpError::ManifestNotFound {..} => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), "")
+            RegistryHttpError::ManifestNotFound {..} => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::Unauthorized {..} => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::Denied(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::PolicyViolation { ref detail, .. } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), detail.clone()),
+            RegistryHttpError::QuotaExceeded { used_bytes, quota_bytes, .. } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), serde_json::json!({
+                "used_bytes": used_bytes,
+                "quota_bytes": quota_bytes
+            })),
+            RegistryHttpError::StorageFull { used_bytes, max_bytes } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), serde_json::json!({
+                "used_bytes": used_bytes,
+                "max_bytes": max_bytes
+            })),
+            RegistryHttpError::TooManyRequests { ref detail, .. } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), detail.clone()),
+            RegistryHttpError::ServiceUnavailable => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::DeadlineExceeded => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::UpstreamError(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::UpstreamTimeout(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::UpstreamDenied { status, .. } => RegistryJsonErrorReprWrapper::single_with_detail(registry_error, self.to_string(), serde_json::json!({
+                "upstream_status": status
+            })),
+            RegistryHttpError::MethodNotAllowed(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::UnsupportedMediaType(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), ""),
+            RegistryHttpError::Unsupported(_) => RegistryJsonErrorReprWrapper::single(registry_error, self.to_string(), "")
         };
 
         let body = serde_json::to_string_pretty(&json_representaiton).unwrap();
 
-        (
+        let mut response = (
             http_code,
             [("Content-Type", "application/json")],
             body
-        ).into_response()
+        ).into_response();
+
+        match &self {
+            RegistryHttpError::Unauthorized { challenge } => {
+                if let Ok(challenge) = HeaderValue::from_str(challenge) {
+                    response.headers_mut().insert("WWW-Authenticate", challenge);
+                }
+            },
+            RegistryHttpError::TooManyRequests { retry_after, .. } => {
+                response.headers_mut().insert("Retry-After", HeaderValue::from(*retry_after));
+            },
+            _ => {}
+        }
+
+        response
     }
 }
 
@@ -107,9 +258,113 @@ impl From<uuid::Error> for RegistryHttpError {
     }
 }
 
+/// Failures of the upstream registry are told apart from the ones of the proxy: 502 when it fails or
+/// answers something unexpected, 504 when it doesn't answer in time, 403 when it refuses our credentials.
+impl From<docker_client::client::DockerClientError> for RegistryHttpError {
+    fn from(value: docker_client::client::DockerClientError) -> Self {
+        use docker_client::client::DockerClientError;
+
+        match value {
+            // Pass the upstream rate limiting on, rather than failing with a 500
+            DockerClientError::RateLimited { retry_after } => {
+                Self::TooManyRequests {
+                    retry_after: retry_after.as_secs() + 1,
+                    detail: serde_json::json!({ "limit": "upstream" })
+                }
+            },
+            DockerClientError::UnexpectedStatusCode(status @ (401 | 403)) => {
+                Self::UpstreamDenied { status: Some(status), reason: value.to_string() }
+            },
+            DockerClientError::BadAuthenticationCredentials => {
+                Self::UpstreamDenied { status: None, reason: value.to_string() }
+            },
+            DockerClientError::ReqwestError(e) => e.into(),
+            DockerClientError::InvalidContainerRef(e) => Self::InvalidRepositoryName(e.container_ref().to_string()),
+            DockerClientError::UnexpectedStatusCode(_)
+            | DockerClientError::MissingProxyHeader(_)
+            | DockerClientError::InvalidProxyHeader(_)
+            | DockerClientError::WwwAuthenticateParseError(_)
+            | DockerClientError::TooManyRedirects(_)
+            | DockerClientError::InvalidUrl(_) => Self::UpstreamError(value.to_string()),
+            value => Self::RegistryInternalError(value.into())
+        }
+    }
+}
+
+/// Requests only go to the upstream registries
+impl From<reqwest::Error> for RegistryHttpError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_timeout() {
+            Self::UpstreamTimeout(value.to_string())
+        } else if value.is_builder() {
+            Self::RegistryInternalError(value.into())
+        } else {
+            Self::UpstreamError(value.to_string())
+        }
+    }
+}
+
 impl_from!(std::io::Error);
 impl_from!(axum::Error);
 impl_from!(tokio::task::JoinError);
 impl_from!(eyre::Report);
-impl_from!(docker_client::client::DockerClientError);
-impl_from!(reqwest::Error);
\ No newline at end of file
+
+#[cfg(test)]
+mod tests {
+    use super::{ERROR_CODES, RegistryHttpError};
+
+    /// One error of each kind
+    fn every_error() -> Vec<RegistryHttpError> {
+        vec![
+            RegistryHttpError::invalid_repository_name("name"),
+            RegistryHttpError::invalid_tag_name("tag"),
+            RegistryHttpError::invalid_hash_format("hash"),
+            RegistryHttpError::upload_id_not_found("uuid"),
+            RegistryHttpError::blob_upload_invalid("upload"),
+            RegistryHttpError::ManifestTooLarge { max_bytes: 1 },
+            RegistryHttpError::digest_invalid("digest"),
+            RegistryHttpError::DigestMismatch { expected: "expected".to_string(), actual: "actual".to_string() },
+            RegistryHttpError::manifest_not_found("repository", "tag"),
+            RegistryHttpError::unauthorized("Basic"),
+            RegistryHttpError::denied("denied"),
+            RegistryHttpError::PolicyViolation { repository: "repository".to_string(), digest: "digest".to_string(), detail: serde_json::Value::Null },
+            RegistryHttpError::QuotaExceeded { tenant: "tenant".to_string(), used_bytes: 2, quota_bytes: 1 },
+            RegistryHttpError::StorageFull { used_bytes: 2, max_bytes: 1 },
+            RegistryHttpError::too_many_requests(1, serde_json::Value::Null),
+            RegistryHttpError::DeadlineExceeded,
+            RegistryHttpError::unsupported_media_type("text/plain"),
+            RegistryHttpError::method_not_allowed("TRACE"),
+            RegistryHttpError::unsupported("token server"),
+            RegistryHttpError::ServiceUnavailable,
+            RegistryHttpError::UpstreamError("upstream".to_string()),
+            RegistryHttpError::UpstreamTimeout("upstream".to_string()),
+            RegistryHttpError::UpstreamDenied { status: Some(403), reason: "reason".to_string() },
+            RegistryHttpError::RegistryInternalError(eyre::eyre!("internal")),
+        ]
+    }
+
+    #[test]
+    fn error_codes_are_listed() {
+        let errors = every_error();
+        for error in &errors {
+            // Fails to compile once a kind of error is added, for it to be added to the errors above as well
+            match error {
+                RegistryHttpError::InvalidRepositoryName(_) | RegistryHttpError::InvalidTagName(_) | RegistryHttpError::InvalidHashFormat(_)
+                | RegistryHttpError::UploadIdNotFound(_) | RegistryHttpError::BlobUploadInvalid(_) | RegistryHttpError::ManifestTooLarge {..}
+                | RegistryHttpError::DigestInvalid(_) | RegistryHttpError::DigestMismatch {..} | RegistryHttpError::ManifestNotFound {..}
+                | RegistryHttpError::Unauthorized {..} | RegistryHttpError::Denied(_) | RegistryHttpError::PolicyViolation {..}
+                | RegistryHttpError::QuotaExceeded {..} | RegistryHttpError::StorageFull {..} | RegistryHttpError::TooManyRequests {..}
+                | RegistryHttpError::DeadlineExceeded | RegistryHttpError::UnsupportedMediaType(_) | RegistryHttpError::MethodNotAllowed(_)
+                | RegistryHttpError::Unsupported(_) | RegistryHttpError::ServiceUnavailable | RegistryHttpError::UpstreamError(_) | RegistryHttpError::UpstreamTimeout(_)
+                | RegistryHttpError::UpstreamDenied {..} | RegistryHttpError::RegistryInternalError(_) => ()
+            }
+
+            let (_, code) = error.status_and_code();
+            assert!(ERROR_CODES.contains(&code), "{} isn't listed", code);
+        }
+
+        for code in ERROR_CODES {
+            assert!(errors.iter().any(|error| error.status_and_code().1 == *code), "{} is listed but never used", code);
+        }
+    }
+}
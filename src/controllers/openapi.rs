@@ -0,0 +1,611 @@
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+
+use super::ERROR_CODES;
+
+static OPENAPI_DOCUMENT: Lazy<Value> = Lazy::new(openapi_document);
+
+/// A route of the HTTP surface, with the parameters found in its path
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    operation_id: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    query: &'static [(&'static str, &'static str)],
+    request_body: Option<(&'static str, Value)>,
+    /// Successful responses, errors are added from the tag
+    responses: Vec<(u16, &'static str, Option<Value>)>
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn routes() -> Vec<Route> {
+    let binary = || json!({ "type": "string", "format": "binary" });
+    let manifest = || json!({ "type": "object", "description": "Image manifest or index, in the media type of the Content-Type header" });
+
+    vec![
+        Route {
+            method: "get", path: "/v2/", operation_id: "base", tag: "registry",
+            summary: "Checks that the registry implements the V2 API, and challenges the clients that have to log in",
+            query: &[], request_body: None,
+            responses: vec![(200, "The client may use the registry", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "get", path: "/token", operation_id: "token_issue", tag: "registry",
+            summary: "Issues a bearer token for the requested scopes, restricted to what the credentials are granted",
+            query: &[("service", "Audience of the token"), ("scope", "Requested access like `repository:samalba/my-app:pull,push`, may be repeated")],
+            request_body: None,
+            responses: vec![(200, "Issued token", Some(json_content(schema_ref("TokenResponse"))))]
+        },
+        Route {
+            method: "get", path: "/v2/{name}/manifests/{reference}", operation_id: "manifest_get", tag: "registry",
+            summary: "Fetches a manifest by tag or digest",
+            query: &[], request_body: None,
+            responses: vec![(200, "The manifest, with its digest in Docker-Content-Digest", Some(json_content(manifest())))]
+        },
+        Route {
+            method: "head", path: "/v2/{name}/manifests/{reference}", operation_id: "manifest_head", tag: "registry",
+            summary: "Checks that a manifest exists",
+            query: &[], request_body: None,
+            responses: vec![(200, "The manifest exists", None)]
+        },
+        Route {
+            method: "put", path: "/v2/{name}/manifests/{reference}", operation_id: "manifest_put", tag: "registry",
+            summary: "Pushes a manifest under a tag or its digest",
+            query: &[], request_body: Some(("application/vnd.oci.image.manifest.v1+json", manifest())),
+            responses: vec![(201, "The manifest was saved", None)]
+        },
+        Route {
+            method: "get", path: "/v2/{name}/blobs/{digest}", operation_id: "blob_get", tag: "registry",
+            summary: "Fetches a blob",
+            query: &[], request_body: None,
+            responses: vec![(200, "The blob content", Some(json!({ "application/octet-stream": { "schema": binary() } })))]
+        },
+        Route {
+            method: "head", path: "/v2/{name}/blobs/{digest}", operation_id: "blob_head", tag: "registry",
+            summary: "Checks that a blob exists",
+            query: &[], request_body: None,
+            responses: vec![(200, "The blob exists, with its size in Content-Length", None)]
+        },
+        Route {
+            method: "post", path: "/v2/{name}/blobs/uploads/", operation_id: "blob_upload_start", tag: "registry",
+            summary: "Starts a blob upload session, uploads a whole blob at once when its digest is given, or mounts a blob of another repository",
+            query: &[
+                ("digest", "Digest of the blob sent in the body, for a monolithic upload"),
+                ("mount", "Digest of the blob to mount from another repository"),
+                ("from", "Repository to mount the blob from, pushed or proxied like `proxy/docker.io/library/alpine`")
+            ],
+            request_body: Some(("application/octet-stream", binary())),
+            responses: vec![
+                (202, "The upload session was created, its URL is in the Location header", None),
+                (201, "The blob sent in the body was saved, or the blob was mounted", None)
+            ]
+        },
+        Route {
+            method: "patch", path: "/v2/{name}/blobs/uploads/{uuid}", operation_id: "blob_upload_chunk", tag: "registry",
+            summary: "Sends a chunk of a blob",
+            query: &[], request_body: Some(("application/octet-stream", binary())),
+            responses: vec![(202, "The chunk was received, the Range header tells what the registry has", None)]
+        },
+        Route {
+            method: "put", path: "/v2/{name}/blobs/uploads/{uuid}", operation_id: "blob_upload_finish", tag: "registry",
+            summary: "Completes a blob upload, with an optional last chunk",
+            query: &[("digest", "Digest of the whole blob, checked against what was received")],
+            request_body: Some(("application/octet-stream", binary())),
+            responses: vec![(201, "The blob was saved", None)]
+        },
+        Route {
+            method: "delete", path: "/v2/{name}/blobs/uploads/{uuid}", operation_id: "blob_upload_cancel", tag: "registry",
+            summary: "Cancels a blob upload",
+            query: &[], request_body: None,
+            responses: vec![(204, "The upload was cancelled", None)]
+        },
+        Route {
+            method: "get", path: "/v2/proxy/{name}/manifests/{reference}", operation_id: "proxy_manifest_get", tag: "proxy",
+            summary: "Fetches a manifest from the upstream registry named in the repository, through the proxy cache",
+            query: &[], request_body: None,
+            responses: vec![(200, "The manifest, with its digest in Docker-Content-Digest", Some(json_content(manifest())))]
+        },
+        Route {
+            method: "get", path: "/v2/proxy/{name}/blobs/{digest}", operation_id: "proxy_blob", tag: "proxy",
+            summary: "Fetches a blob from the upstream registry named in the repository, through the proxy cache",
+            query: &[], request_body: None,
+            responses: vec![(200, "The blob content", Some(json!({ "application/octet-stream": { "schema": binary() } })))]
+        },
+        Route {
+            method: "get", path: "/api/images/{name}/{digest}/{resource}", operation_id: "image_resource_get", tag: "images",
+            summary: "Fetches the SBOM or the vulnerability report of an image, `resource` being `sbom` or `scan`",
+            query: &[], request_body: None,
+            responses: vec![(200, "The SBOM or the scan report", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "put", path: "/api/images/{name}/{digest}/{resource}", operation_id: "sbom_put", tag: "images",
+            summary: "Attaches an SBOM to an image, `resource` being `sbom`",
+            query: &[], request_body: Some(("application/json", json!({ "type": "object" }))),
+            responses: vec![(201, "The SBOM was saved", None)]
+        },
+        Route {
+            method: "get", path: "/", operation_id: "root", tag: "observability",
+            summary: "Answers as long as the registry is up, for load balancers and liveness probes",
+            query: &[], request_body: None,
+            responses: vec![(200, "The registry is up", None)]
+        },
+        Route {
+            method: "get", path: "/metrics", operation_id: "metrics", tag: "observability",
+            summary: "Metrics in the Prometheus text format",
+            query: &[], request_body: None,
+            responses: vec![(200, "The metrics", Some(json!({ "text/plain": { "schema": { "type": "string" } } })))]
+        },
+        Route {
+            method: "get", path: "/openapi.json", operation_id: "openapi", tag: "observability",
+            summary: "This document",
+            query: &[], request_body: None,
+            responses: vec![(200, "The OpenAPI description of the HTTP surface", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "get", path: "/admin/status", operation_id: "admin_status", tag: "admin",
+            summary: "Version, uptime, storage usage and activity of the registry",
+            query: &[("tenant", "Tenant whose storage is used, the storage roots at the top of the configuration without it")], request_body: None,
+            responses: vec![(200, "Status of the registry", Some(json_content(schema_ref("ServerStatus"))))]
+        },
+        Route {
+            method: "get", path: "/admin/upstreams/health", operation_id: "admin_upstreams_health", tag: "admin",
+            summary: "Checks that the upstream registries are reachable and the credentials valid",
+            query: &[], request_body: None,
+            responses: vec![(200, "Health of the configured upstream registries and of the cached clients", Some(json_content(json!({ "type": "array", "items": schema_ref("UpstreamHealth") }))))]
+        },
+        Route {
+            method: "get", path: "/admin/upstreams/clients", operation_id: "admin_upstream_clients", tag: "admin",
+            summary: "Lists the cached upstream clients, with their authentication strategy, token expiration and last use",
+            query: &[], request_body: None,
+            responses: vec![(200, "Cached upstream clients", Some(json_content(json!({
+                "type": "object",
+                "properties": { "clients": { "type": "array", "items": schema_ref("UpstreamClientSummary") } }
+            }))))]
+        },
+        Route {
+            method: "delete", path: "/admin/upstreams/{registry}/clients", operation_id: "admin_flush_upstream_clients", tag: "admin",
+            summary: "Drops the cached clients and credentials of an upstream registry, after its credentials were rotated",
+            query: &[], request_body: None,
+            responses: vec![(200, "Number of dropped clients", Some(json_content(json!({
+                "type": "object",
+                "properties": { "flushed": { "type": "integer" } }
+            }))))]
+        },
+        Route {
+            method: "get", path: "/admin/uploads", operation_id: "admin_uploads", tag: "admin",
+            summary: "Lists the upload sessions in progress",
+            query: &[], request_body: None,
+            responses: vec![(200, "Uploads in progress", Some(json_content(json!({
+                "type": "object",
+                "properties": { "uploads": { "type": "array", "items": schema_ref("UploadSummary") } }
+            }))))]
+        },
+        Route {
+            method: "delete", path: "/admin/uploads/{uuid}", operation_id: "admin_abort_upload", tag: "admin",
+            summary: "Aborts an upload session, whatever its client is doing",
+            query: &[], request_body: None,
+            responses: vec![(204, "The upload was aborted", None)]
+        },
+        Route {
+            method: "get", path: "/admin/proxy-cache/repositories", operation_id: "admin_proxy_cache_repositories", tag: "admin",
+            summary: "Lists the repositories of the proxy cache with their size",
+            query: &[("tenant", "Tenant whose storage is used, the storage roots at the top of the configuration without it")], request_body: None,
+            responses: vec![(200, "Cached repositories", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "get", path: "/admin/proxy-cache/{name}/stats", operation_id: "admin_proxy_cache_repository_stats", tag: "admin",
+            summary: "Cached tags and cache hits of a proxied repository",
+            query: &[], request_body: None,
+            responses: vec![(200, "Repository statistics", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "post", path: "/admin/proxy-cache/prefetch", operation_id: "admin_prefetch", tag: "admin",
+            summary: "Caches a list of images ahead of their first pull",
+            query: &[("parallelism", "Images fetched at the same time"), ("platform", "Platform picked in multi-platform images, as in `linux/arm64`")],
+            request_body: Some(("text/plain", json!({ "type": "string", "description": "Image references, one per line or as a YAML list" }))),
+            responses: vec![(200, "Result for every image", Some(json_content(json!({ "type": "array", "items": schema_ref("PrefetchResult") }))))]
+        },
+        Route {
+            method: "get", path: "/admin/proxy-cache/export", operation_id: "admin_proxy_cache_export", tag: "admin",
+            summary: "Exports proxied repositories as a tar archive",
+            query: &[("repository", "Repository to export, may be repeated. The whole proxy cache without it"), ("tenant", "Tenant whose storage is used, the storage roots at the top of the configuration without it")],
+            request_body: None,
+            responses: vec![(200, "The archive", Some(json!({ "application/x-tar": { "schema": binary() } })))]
+        },
+        Route {
+            method: "post", path: "/admin/proxy-cache/import", operation_id: "admin_proxy_cache_import", tag: "admin",
+            summary: "Imports proxied repositories from a tar archive made by the export",
+            query: &[("tenant", "Tenant whose storage is used, the storage roots at the top of the configuration without it")], request_body: Some(("application/x-tar", binary())),
+            responses: vec![(200, "Imported repositories", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "get", path: "/admin/notifications/dead-letters", operation_id: "admin_dead_letters", tag: "admin",
+            summary: "Lists the events whose delivery failed for good",
+            query: &[], request_body: None,
+            responses: vec![(200, "Dead letters", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "post", path: "/admin/notifications/dead-letters/replay", operation_id: "admin_replay_dead_letters", tag: "admin",
+            summary: "Queues the dead letters for delivery again",
+            query: &[], request_body: None,
+            responses: vec![(200, "Number of replayed events", Some(json_content(json!({
+                "type": "object",
+                "properties": { "replayed": { "type": "integer" } }
+            }))))]
+        },
+        Route {
+            method: "get", path: "/admin/api-keys", operation_id: "admin_list_api_keys", tag: "admin",
+            summary: "Lists the API keys, without their secrets",
+            query: &[], request_body: None,
+            responses: vec![(200, "API keys", Some(json_content(json!({
+                "type": "object",
+                "properties": { "api_keys": { "type": "array", "items": schema_ref("ApiKey") } }
+            }))))]
+        },
+        Route {
+            method: "post", path: "/admin/api-keys", operation_id: "admin_create_api_key", tag: "admin",
+            summary: "Creates an API key, whose secret is only returned in this response",
+            query: &[], request_body: Some(("application/json", schema_ref("ApiKeyRequest"))),
+            responses: vec![(201, "The key and its secret", Some(json_content(schema_ref("CreatedApiKey"))))]
+        },
+        Route {
+            method: "delete", path: "/admin/api-keys/{id}", operation_id: "admin_revoke_api_key", tag: "admin",
+            summary: "Revokes an API key",
+            query: &[], request_body: None,
+            responses: vec![(204, "The key was revoked", None)]
+        },
+        Route {
+            method: "get", path: "/admin/tokens/revoked", operation_id: "admin_revoked_tokens", tag: "admin",
+            summary: "Lists the tokens revoked before their expiration",
+            query: &[], request_body: None,
+            responses: vec![(200, "Revoked tokens and accounts", Some(json_content(json!({ "type": "object" }))))]
+        },
+        Route {
+            method: "post", path: "/admin/tokens/revoke", operation_id: "admin_revoke_token", tag: "admin",
+            summary: "Revokes a token, or all the tokens issued to an account so far",
+            query: &[], request_body: Some(("application/json", schema_ref("TokenRevocation"))),
+            responses: vec![(204, "The revocation was recorded", None)]
+        },
+    ]
+}
+
+/// Error responses of the routes, by tag. The admin routes answer with plain status codes.
+fn error_responses(tag: &str) -> Vec<(u16, &'static str)> {
+    match tag {
+        "admin" | "observability" => vec![
+            (404, "The feature is not configured, or the resource doesn't exist"),
+            (500, "Internal error")
+        ],
+        _ => vec![
+            (400, "Invalid repository name, tag or digest"),
+            (401, "Authentication required, the WWW-Authenticate header tells how"),
+            (403, "The credentials don't grant the access, or a policy refused it"),
+            (404, "The repository, manifest, blob or upload doesn't exist"),
+            (429, "Rate limited, the Retry-After header tells when to try again"),
+            (503, "Overloaded, or the authorization can't be decided"),
+            (500, "Internal error")
+        ]
+    }
+}
+
+fn path_parameters(path: &str) -> Vec<Value> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            let description = match name {
+                "name" => "Repository name, which may contain slashes",
+                "reference" => "Tag or digest",
+                "digest" => "Digest like `sha256:...`",
+                "uuid" => "Upload session ID",
+                _ => ""
+            };
+            json!({ "name": name, "in": "path", "required": true, "description": description, "schema": { "type": "string" } })
+        })
+        .collect()
+}
+
+fn schemas() -> Value {
+    json!({
+        "RegistryErrors": {
+            "type": "object",
+            "description": "Body of the errors of the registry routes",
+            "required": ["errors"],
+            "properties": { "errors": { "type": "array", "items": schema_ref("RegistryError") } }
+        },
+        "RegistryError": {
+            "type": "object",
+            "required": ["code", "message", "detail"],
+            "properties": {
+                "code": { "type": "string", "enum": ERROR_CODES },
+                "message": { "type": "string" },
+                "detail": { "description": "Either a message or an object, such as the limit of a 429 or the used and allowed bytes of a quota" }
+            }
+        },
+        "TokenResponse": {
+            "type": "object",
+            "properties": {
+                "token": { "type": "string" },
+                "access_token": { "type": "string" },
+                "expires_in": { "type": "integer" },
+                "issued_at": { "type": "string", "format": "date-time" }
+            }
+        },
+        "ServerStatus": {
+            "type": "object",
+            "properties": {
+                "version": { "type": "string" },
+                "git_commit": { "type": "string" },
+                "uptime_seconds": { "type": "integer" },
+                "tenant": { "type": "string", "nullable": true },
+                "storage": { "type": "object", "additionalProperties": { "type": "string" } },
+                "active_uploads": { "type": "integer" },
+                "cached_clients": { "type": "integer" },
+                "cache_size": { "type": "object", "additionalProperties": { "type": "integer" } }
+            }
+        },
+        "UpstreamHealth": {
+            "type": "object",
+            "properties": {
+                "tenant": { "type": "string", "nullable": true },
+                "client_key": { "type": "string" },
+                "registry": { "type": "string" },
+                "reachable": { "type": "boolean" },
+                "authentication_valid": { "type": "boolean" },
+                "latency_ms": { "type": "integer" },
+                "error": { "type": "string", "nullable": true }
+            }
+        },
+        "UpstreamClientSummary": {
+            "type": "object",
+            "properties": {
+                "tenant": { "type": "string", "nullable": true },
+                "client_key": { "type": "string" },
+                "registry": { "type": "string" },
+                "repository": { "type": "string" },
+                "auth_strategy": { "type": "string", "enum": ["anonymous", "basic", "bearer"], "nullable": true },
+                "token_expires_at": { "type": "string", "format": "date-time", "nullable": true },
+                "created_at": { "type": "string", "format": "date-time" },
+                "last_used": { "type": "string", "format": "date-time" },
+                "idle_seconds": { "type": "integer" },
+                "rejected": { "type": "boolean" }
+            }
+        },
+        "UploadSummary": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "string" },
+                "repository": { "type": "string" },
+                "bytes_received": { "type": "integer" },
+                "temporary_file_path": { "type": "string" },
+                "created_at": { "type": "string", "format": "date-time" },
+                "age_seconds": { "type": "integer" },
+                "last_activity": { "type": "string", "format": "date-time" },
+                "writing": { "type": "boolean" }
+            }
+        },
+        "PrefetchResult": {
+            "type": "object",
+            "properties": {
+                "image": { "type": "string" },
+                "cached": { "type": "boolean" },
+                "digest": { "type": "string", "nullable": true },
+                "blobs": { "type": "integer" },
+                "error": { "type": "string", "nullable": true }
+            }
+        },
+        "ResourceAccess": {
+            "type": "object",
+            "properties": {
+                "type": { "type": "string" },
+                "name": { "type": "string" },
+                "actions": { "type": "array", "items": { "type": "string" } }
+            }
+        },
+        "ApiKey": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "string" },
+                "name": { "type": "string" },
+                "scopes": { "type": "array", "items": schema_ref("ResourceAccess") },
+                "created_at": { "type": "string", "format": "date-time" },
+                "expires_at": { "type": "string", "format": "date-time", "nullable": true }
+            }
+        },
+        "ApiKeyRequest": {
+            "type": "object",
+            "required": ["name", "scopes"],
+            "properties": {
+                "name": { "type": "string" },
+                "scopes": { "type": "array", "items": { "type": "string" }, "example": ["repository:ci/*:pull,push"] },
+                "expires_in_days": { "type": "integer", "nullable": true }
+            }
+        },
+        "CreatedApiKey": {
+            "allOf": [
+                schema_ref("ApiKey"),
+                { "type": "object", "properties": { "secret": { "type": "string" } } }
+            ]
+        },
+        "TokenRevocation": {
+            "oneOf": [
+                { "type": "object", "required": ["token"], "properties": { "token": { "type": "string" } } },
+                { "type": "object", "required": ["jti"], "properties": { "jti": { "type": "string" } } },
+                { "type": "object", "required": ["account"], "properties": { "account": { "type": "string" } } }
+            ]
+        }
+    })
+}
+
+fn openapi_document() -> Value {
+    let mut paths = Map::new();
+
+    for route in routes() {
+        let mut parameters = path_parameters(route.path);
+        parameters.extend(route.query.iter().map(|(name, description)| json!({
+            "name": name, "in": "query", "required": false, "description": description, "schema": { "type": "string" }
+        })));
+
+        let mut responses = Map::new();
+        for (status, description, content) in &route.responses {
+            let mut response = json!({ "description": description });
+            if let Some(content) = content {
+                response["content"] = content.clone();
+            }
+            responses.insert(status.to_string(), response);
+        }
+        for (status, description) in error_responses(route.tag) {
+            let mut response = json!({ "description": description });
+            if route.tag != "admin" && route.tag != "observability" {
+                response["content"] = json_content(schema_ref("RegistryErrors"));
+            }
+            responses.entry(status.to_string()).or_insert(response);
+        }
+
+        let mut operation = json!({
+            "operationId": route.operation_id,
+            "tags": [route.tag],
+            "summary": route.summary,
+            "parameters": parameters,
+            "responses": responses
+        });
+        if let Some((media_type, schema)) = route.request_body {
+            operation["requestBody"] = json!({ "content": { media_type: { "schema": schema } } });
+        }
+
+        paths.entry(route.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .unwrap()
+            .insert(route.method.to_string(), operation);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Docker registry and proxy",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "tags": [
+            { "name": "registry", "description": "Docker Registry HTTP API V2, for the images pushed to this registry" },
+            { "name": "proxy", "description": "Pull-through cache of the upstream registries, under `/v2/proxy/<registry>/<repository>`" },
+            { "name": "images", "description": "SBOMs and vulnerability reports of the images" },
+            { "name": "admin", "description": "Administration of the registry" },
+            { "name": "observability", "description": "Metrics and API description" }
+        ],
+        "paths": paths,
+        "components": { "schemas": schemas() }
+    })
+}
+
+/// OpenAPI description of the registry, proxy and admin routes
+pub async fn openapi() -> Json<Value> {
+    Json(OPENAPI_DOCUMENT.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::{Method, Request, StatusCode}};
+    use regex::Regex;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::routes;
+    use crate::{RegistryServer, repository_path::{RepositoryPath, RepositoryRoute}};
+
+    /// OpenAPI template of a path of the router, `:parameter` becoming `{parameter}`
+    fn openapi_template(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some("container_ref") => "{name}".to_string(),
+                Some(parameter) => format!("{{{}}}", parameter),
+                None => segment.to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// A path matching the template, whose upstream registries can't be reached
+    fn sample_path(template: &str) -> String {
+        let name = if template.starts_with("/v2/proxy/") { "localhost:1/team/app" } else { "team/app" };
+        template
+            .replace("{name}", name)
+            .replace("{reference}", "latest")
+            .replace("{digest}", &format!("sha256:{}", "0".repeat(64)))
+            .replace("{uuid}", &Uuid::new_v4().to_string())
+            .replace("{registry}", "localhost:1")
+            .replace("{id}", "key")
+            .replace("{resource}", "sbom")
+    }
+
+    #[test]
+    fn repository_routes_are_documented() {
+        let documented = routes().into_iter().map(|route| route.path).collect::<Vec<_>>();
+        let repository_routes = [
+            RepositoryRoute::Manifest, RepositoryRoute::Blob, RepositoryRoute::UploadStart,
+            RepositoryRoute::Upload, RepositoryRoute::ProxyManifest, RepositoryRoute::ProxyBlob
+        ];
+
+        for route in repository_routes {
+            // Fails to compile once a route is added, for it to be added to the list above as well
+            match route {
+                RepositoryRoute::Manifest | RepositoryRoute::Blob | RepositoryRoute::UploadStart
+                | RepositoryRoute::Upload | RepositoryRoute::ProxyManifest | RepositoryRoute::ProxyBlob => ()
+            }
+            let template = openapi_template(route.template());
+            assert!(documented.contains(&template.as_str()), "{} isn't documented", template);
+        }
+    }
+
+    #[test]
+    fn router_paths_are_documented() {
+        let documented = routes().into_iter().map(|route| route.path).collect::<Vec<_>>();
+        let route_path = Regex::new(r#"\.route\(\s*"([^"]+)""#).unwrap();
+
+        for path in route_path.captures_iter(include_str!("../lib.rs")).map(|captures| captures[1].to_string()) {
+            let template = openapi_template(&path);
+            let is_documented = match template.split_once('*') {
+                // Wildcards are matched by the handlers, at least one of their paths has to be documented
+                Some((prefix, _)) => documented.iter().any(|documented| documented.starts_with(prefix) && documented.len() > prefix.len()),
+                None => documented.contains(&template.as_str())
+            };
+            assert!(is_documented, "{} is served but not documented", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn documented_routes_are_served() {
+        let storage = std::env::temp_dir().join(format!("openapi-{}", Uuid::new_v4()));
+        let server = RegistryServer::builder()
+            .registry_storage(storage.join("registry"))
+            .temporary_registry_storage(storage.join("tmp"))
+            .proxy_storage(storage.join("proxy"))
+            .build()
+            .await
+            .unwrap();
+        let router = server.router().fallback(|| async { StatusCode::IM_A_TEAPOT });
+
+        for route in routes() {
+            let path = sample_path(route.path);
+            if path.starts_with("/v2/") && path != "/v2/" {
+                assert!(RepositoryPath::parse(&path).and_then(|repository_path| repository_path.route()).is_some(), "{} isn't a repository route", route.path);
+            }
+
+            let method = Method::from_bytes(route.method.to_uppercase().as_bytes()).unwrap();
+            let request = Request::builder().method(method).uri(&path).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_ne!(response.status(), StatusCode::IM_A_TEAPOT, "{} {} isn't routed", route.method, route.path);
+            assert_ne!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {} isn't routed", route.method, route.path);
+        }
+
+        std::fs::remove_dir_all(&storage).unwrap();
+    }
+}
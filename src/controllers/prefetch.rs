@@ -0,0 +1,226 @@
+use axum::{extract::{Query, State}, http::{Method, StatusCode, HeaderMap}, Json, response::Response, body::HttpBody};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{ApplicationState, data::{cache_watermark, helpers::resolve_upstream_container_ref}, repository_path::RepositoryPath, requests::ClientKey, tenants::CurrentTenant};
+
+use super::{blobs::proxy_blob, manifests::proxy_fetch_manifest, RegistryHttpError};
+
+/// Registry of the images named without one, as Docker does
+const DEFAULT_REGISTRY: &str = "registry-1.docker.io";
+const DEFAULT_PARALLELISM: usize = 4;
+const DEFAULT_PLATFORM: &str = "linux/amd64";
+
+#[derive(Deserialize)]
+pub struct PrefetchParameters {
+    /// Number of images fetched at the same time, capped by the configuration
+    parallelism: Option<usize>,
+    /// Platform picked in multi-platform images, as in `linux/arm64`
+    platform: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PrefetchResult {
+    pub image: String,
+    pub cached: bool,
+    pub digest: Option<String>,
+    pub blobs: usize,
+    pub error: Option<String>,
+}
+
+/// Splits `nginx:1.25` or `ghcr.io/org/app@sha256:...` into the proxied repository and the manifest reference.
+fn parse_image_reference(image: &str) -> Option<(String, String)> {
+    let (repository, reference) = match image.split_once('@') {
+        Some((repository, digest)) => (repository, digest.to_string()),
+        None => match image.rsplit_once(':') {
+            // A colon after the last slash separates the tag, otherwise it's the port of the registry
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag.to_string()),
+            _ => (image, "latest".to_string())
+        }
+    };
+
+    if repository.is_empty() || reference.is_empty() {
+        return None;
+    }
+
+    let first_component = repository.split('/').next().unwrap_or_default();
+    let has_registry = repository.contains('/')
+        && (first_component.contains('.') || first_component.contains(':') || first_component == "localhost");
+
+    let repository = match (has_registry, repository.contains('/')) {
+        (true, _) => repository.to_string(),
+        (false, true) => format!("{}/{}", DEFAULT_REGISTRY, repository),
+        (false, false) => format!("{}/library/{}", DEFAULT_REGISTRY, repository),
+    };
+
+    Some((repository, reference))
+}
+
+/// Image references, one per line, either as plain text or as a YAML list. Comments and blank lines are skipped.
+fn parse_image_list(body: &str) -> Vec<String> {
+    body.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .map(|line| line.strip_prefix('-').unwrap_or(line).trim())
+        .map(|line| line.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
+
+async fn read_body(response: Response) -> Result<Vec<u8>, RegistryHttpError> {
+    let mut body = response.into_body();
+    let mut content = Vec::new();
+    while let Some(chunk) = body.data().await {
+        content.extend_from_slice(&chunk?);
+    }
+
+    Ok(content)
+}
+
+fn check_status(response: &Response, what: &str) -> Result<(), RegistryHttpError> {
+    if response.status() != StatusCode::OK {
+        return Err(eyre::eyre!("Got status {} on {}", response.status(), what).into());
+    }
+
+    Ok(())
+}
+
+fn proxy_repository_path(repository: &str, object: &str, reference: &str) -> Result<RepositoryPath, RegistryHttpError> {
+    RepositoryPath::parse(&format!("/v2/proxy/{}/{}/{}", repository, object, reference))
+        .ok_or_else(|| RegistryHttpError::invalid_repository_name(repository))
+}
+
+async fn fetch_manifest(app: &ApplicationState, repository: &str, reference: &str) -> Result<(Option<String>, serde_json::Value), RegistryHttpError> {
+    // Boxed, the handler futures are too deeply nested to be laid out inline in the prefetch stream
+    let response = Box::pin(proxy_fetch_manifest(
+        proxy_repository_path(repository, "manifests", reference)?,
+        State(app.clone()),
+        CurrentTenant(app.tenants.for_repository(&format!("proxy/{}", repository)))
+    )).await?;
+    check_status(&response, &format!("manifest {}", reference))?;
+
+    let digest = response.headers()
+        .get("Docker-Content-Digest")
+        .and_then(|digest| digest.to_str().ok())
+        .map(|digest| digest.to_string());
+    let manifest = serde_json::from_slice(&read_body(response).await?)
+        .map_err(|e| eyre::eyre!("Invalid manifest {}: {}", reference, e))?;
+
+    Ok((digest, manifest))
+}
+
+/// Goes through the blob proxy, which writes the blob in the cache as it is read.
+async fn fetch_blob(app: &ApplicationState, repository: &str, digest: &str) -> Result<(), RegistryHttpError> {
+    let response = Box::pin(proxy_blob(
+        proxy_repository_path(repository, "blobs", digest)?,
+        Method::GET,
+        State(app.clone()),
+        CurrentTenant(app.tenants.for_repository(&format!("proxy/{}", repository))),
+        ClientKey { key: "prefetch".to_string(), authenticated: true },
+        HeaderMap::new()
+    )).await?;
+    check_status(&response, &format!("blob {}", digest))?;
+    read_body(response).await?;
+
+    Ok(())
+}
+
+fn manifest_blobs(manifest: &serde_json::Value) -> Vec<String> {
+    let config = manifest.get("config").into_iter();
+    let layers = manifest.get("layers").and_then(|layers| layers.as_array()).into_iter().flatten();
+
+    config.chain(layers)
+        .filter_map(|descriptor| descriptor.get("digest")?.as_str())
+        .map(|digest| digest.to_string())
+        .collect()
+}
+
+fn platform_matches(descriptor: &serde_json::Value, platform: &str) -> bool {
+    let descriptor_platform = match descriptor.get("platform") {
+        Some(descriptor_platform) => descriptor_platform,
+        None => return false
+    };
+    let field = |name: &str| descriptor_platform.get(name).and_then(|value| value.as_str()).unwrap_or_default();
+
+    let mut wanted = platform.split('/');
+    let (os, architecture, variant) = (wanted.next(), wanted.next(), wanted.next());
+
+    Some(field("os")) == os
+        && Some(field("architecture")) == architecture
+        && variant.map(|variant| field("variant") == variant).unwrap_or(true)
+}
+
+async fn prefetch_image(app: &ApplicationState, image: &str, platform: &str) -> Result<(Option<String>, usize), RegistryHttpError> {
+    let (repository, reference) = parse_image_reference(image)
+        .ok_or_else(|| RegistryHttpError::invalid_repository_name(image))?;
+
+    let (digest, manifest) = fetch_manifest(app, &repository, &reference).await?;
+
+    // Multi-platform images: only the manifest of the requested platform is fetched
+    let manifests = match manifest.get("manifests").and_then(|manifests| manifests.as_array()) {
+        Some(descriptors) => {
+            let mut manifests = Vec::new();
+            for descriptor in descriptors.iter().filter(|descriptor| platform_matches(descriptor, platform)) {
+                if let Some(child_digest) = descriptor.get("digest").and_then(|digest| digest.as_str()) {
+                    manifests.push(fetch_manifest(app, &repository, child_digest).await?.1);
+                }
+            }
+            manifests
+        },
+        None => vec![manifest]
+    };
+
+    // Blobs passed through are never cached, downloading them would be for nothing
+    let tenant = app.tenants.for_repository(&format!("proxy/{}", repository));
+    if app.conf.cache.passes_through(&resolve_upstream_container_ref(&repository))
+        || cache_watermark::fills_suspended(&tenant.proxy_storage)
+        || tenant.check_proxy_fill(0).await.is_err()
+    {
+        return Ok((digest, 0));
+    }
+
+    let mut blobs = 0;
+    for blob in manifests.iter().flat_map(manifest_blobs) {
+        fetch_blob(app, &repository, &blob).await?;
+        blobs += 1;
+    }
+
+    Ok((digest, blobs))
+}
+
+/// Caches the images, a few at a time, through the proxy routes. Images that can't be fetched are reported
+/// in the results rather than failing the others. The parallelism asked for is capped by the configuration.
+pub async fn prefetch(app: &ApplicationState, images: Vec<String>, platform: Option<String>, parallelism: Option<usize>) -> Vec<PrefetchResult> {
+    let parallelism = parallelism.unwrap_or(DEFAULT_PARALLELISM).min(app.conf.cache.max_prefetch_parallelism()).max(1);
+    let platform = platform.unwrap_or_else(|| DEFAULT_PLATFORM.to_string());
+    info!("Prefetching {} images for {}, {} at a time", images.len(), platform, parallelism);
+
+    stream::iter(images)
+        .map(|image| {
+            let app = app.clone();
+            let platform = platform.clone();
+            async move {
+                match prefetch_image(&app, &image, &platform).await {
+                    Ok((digest, blobs)) => PrefetchResult { image, cached: true, digest, blobs, error: None },
+                    Err(e) => {
+                        warn!("Unable to prefetch {}: {}", image, e);
+                        PrefetchResult { image, cached: false, digest: None, blobs: 0, error: Some(e.to_string()) }
+                    }
+                }
+            }
+        })
+        .buffered(parallelism)
+        .collect::<Vec<_>>()
+        .await
+}
+
+/// Caches every image of the list, such as the images of a Kubernetes cluster, ahead of their first pull.
+#[tracing::instrument(skip_all)]
+pub async fn prefetch_images(
+    State(app): State<ApplicationState>,
+    Query(parameters): Query<PrefetchParameters>,
+    body: String
+) -> Json<Vec<PrefetchResult>> {
+    Json(prefetch(&app, parse_image_list(&body), parameters.platform, parameters.parallelism).await)
+}
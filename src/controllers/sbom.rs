@@ -0,0 +1,183 @@
+use axum::{body::{Bytes, StreamBody}, extract::{Path, State}, headers, http::StatusCode, response::IntoResponse, TypedHeader};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{ApplicationState, requests::ClientKey, tenants::{CurrentTenant, Tenant}, data::{blob_storage::StoredBlob, encryption, helpers::{is_sha256_digest, RegistryPathsHelper}, manifests::{list_referrers_async, Manifest, ManifestMetadata}}};
+
+use super::{images::split_image_path, RegistryHttpError, RegistryHttpResult};
+
+/// Artifact types of the SBOM formats accepted on the images API
+const SBOM_MEDIA_TYPES: [&str; 4] = [
+    "application/spdx+json",
+    "text/spdx",
+    "application/vnd.cyclonedx+json",
+    "application/vnd.cyclonedx+xml",
+];
+
+const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const EMPTY_CONFIG: &[u8] = b"{}";
+const CREATED_ANNOTATION: &str = "org.opencontainers.image.created";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SbomManifest {
+    layers: Vec<SbomLayer>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SbomLayer {
+    media_type: String,
+    digest: String,
+}
+
+fn sha256_digest(content: &[u8]) -> String {
+    format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(content)))
+}
+
+/// Stores content produced by the registry as a blob of the repository
+async fn save_blob(app: &ApplicationState, tenant: &Tenant, container_ref: &str, content: &[u8]) -> Result<String, RegistryHttpError> {
+    let digest = sha256_digest(content);
+    let mut blob_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, container_ref, digest.trim_start_matches("sha256:"));
+    if StoredBlob::open(&blob_path, app.storage_cipher.clone()).await?.is_some() {
+        return Ok(digest);
+    }
+
+    let mut temporary_path = tenant.temporary_registry_storage.join(Uuid::new_v4().to_string());
+    tokio::fs::write(&temporary_path, content).await?;
+    if let Some(storage_cipher) = &app.storage_cipher {
+        temporary_path = storage_cipher.encrypt_file_async(temporary_path).await??;
+        blob_path = encryption::encrypted_path(&blob_path);
+    }
+
+    tokio::fs::create_dir_all(blob_path.parent().unwrap()).await?;
+    tokio::fs::rename(&temporary_path, &blob_path).await?;
+
+    Ok(digest)
+}
+
+/// Attaches an SBOM to an image, on PUT /api/images/<repository>/<digest>/sbom. The SBOM is stored as an OCI
+/// artifact whose subject is the image manifest, so registry clients find it through the referrers as well.
+#[tracing::instrument(skip_all, fields(path = path))]
+pub async fn upload_sbom(
+    Path(path): Path<String>,
+    TypedHeader(content_type): TypedHeader<headers::ContentType>,
+    State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
+    client: ClientKey,
+    body: Bytes
+) -> RegistryHttpResult {
+    let (container_ref, image_digest) = match path.trim_start_matches('/').strip_suffix("/sbom") {
+        Some(image) => split_image_path(image)?,
+        None => return Ok(StatusCode::NOT_FOUND.into_response())
+    };
+    let sbom_media_type = content_type.to_string();
+    if !SBOM_MEDIA_TYPES.contains(&sbom_media_type.as_str()) {
+        return Err(RegistryHttpError::unsupported_media_type(sbom_media_type));
+    }
+
+    // The subject descriptor needs the media type and size of the image manifest
+    let image_meta_path = RegistryPathsHelper::manifest_meta(&tenant.registry_storage, &container_ref, &image_digest);
+    let image_meta = match tokio::fs::read_to_string(&image_meta_path).await {
+        Ok(image_meta) => image_meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(RegistryHttpError::manifest_not_found(&container_ref, &image_digest)),
+        Err(e) => return Err(e.into())
+    };
+    let image_meta = serde_json::from_str::<ManifestMetadata>(&image_meta).map_err(eyre::Report::from)?;
+    let image_manifest = tokio::fs::read(RegistryPathsHelper::manifest_path(&tenant.registry_storage, &container_ref, &image_digest)).await?;
+    let image_manifest = encryption::decrypt_manifest(app.storage_cipher.as_deref(), &image_digest, image_manifest)?;
+
+    let config_digest = save_blob(&app, &tenant, &container_ref, EMPTY_CONFIG).await?;
+    let sbom_digest = save_blob(&app, &tenant, &container_ref, &body).await?;
+
+    let sbom_manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": sbom_media_type,
+        "config": {
+            "mediaType": EMPTY_CONFIG_MEDIA_TYPE,
+            "digest": config_digest,
+            "size": EMPTY_CONFIG.len()
+        },
+        "layers": [{
+            "mediaType": sbom_media_type,
+            "digest": sbom_digest,
+            "size": body.len()
+        }],
+        "subject": {
+            "mediaType": image_meta.content_type,
+            "digest": image_digest,
+            "size": image_manifest.len()
+        },
+        "annotations": {
+            CREATED_ANNOTATION: Utc::now().to_rfc3339()
+        }
+    })).map_err(eyre::Report::from)?;
+    let sbom_manifest_digest = sha256_digest(&sbom_manifest);
+
+    let mut manifest = Manifest::new(
+        &tenant.registry_storage,
+        &tenant.temporary_registry_storage,
+        &container_ref,
+        &sbom_manifest_digest
+    )
+        .with_encryption(app.storage_cipher.clone())
+        .with_pusher(client.key);
+    manifest.save_manifest(sbom_manifest.as_slice().into()).await?;
+    manifest.save_manifest_metadata("application/vnd.oci.image.manifest.v1+json").await?;
+    info!("SBOM of {} stored as {}", image_digest, sbom_manifest_digest);
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            ("Location", format!("/v2/{}/manifests/{}", container_ref, sbom_manifest_digest)),
+            ("Docker-Content-Digest", sbom_manifest_digest)
+        ]
+    ).into_response())
+}
+
+/// Latest SBOM attached to an image, on GET /api/images/<repository>/<digest>/sbom
+pub async fn fetch_sbom(app: &ApplicationState, tenant: &Tenant, container_ref: String, image_digest: String) -> RegistryHttpResult {
+    let referrers = list_referrers_async(tenant.registry_storage.clone(), container_ref.clone(), image_digest.clone()).await??;
+    let sbom_referrer = referrers
+        .into_iter()
+        .filter(|referrer| matches!(&referrer.artifact_type, Some(artifact_type) if SBOM_MEDIA_TYPES.contains(&artifact_type.as_str())))
+        // RFC 3339 dates in UTC sort chronologically
+        .max_by_key(|referrer| referrer.annotations.as_ref().and_then(|annotations| annotations.get(CREATED_ANNOTATION)).cloned());
+    let sbom_referrer = match sbom_referrer {
+        Some(sbom_referrer) => sbom_referrer,
+        None => return Err(RegistryHttpError::manifest_not_found(&container_ref, format!("{}/sbom", image_digest)))
+    };
+
+    let sbom_manifest = tokio::fs::read(RegistryPathsHelper::manifest_path(&tenant.registry_storage, &container_ref, &sbom_referrer.digest)).await?;
+    let sbom_manifest = encryption::decrypt_manifest(app.storage_cipher.as_deref(), &sbom_referrer.digest, sbom_manifest)?;
+    let sbom_layer = serde_json::from_slice::<SbomManifest>(&sbom_manifest)
+        .map_err(eyre::Report::from)?
+        .layers
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre::eyre!("SBOM manifest {} has no layer", sbom_referrer.digest))?;
+
+    // The manifest was pushed by a client, its layer digest ends up in a path
+    if !is_sha256_digest(&sbom_layer.digest) {
+        return Err(RegistryHttpError::digest_invalid(&sbom_layer.digest));
+    }
+    let blob_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, &container_ref, sbom_layer.digest.trim_start_matches("sha256:"));
+    let blob = StoredBlob::open(&blob_path, app.storage_cipher.clone())
+        .await?
+        .ok_or_else(|| eyre::eyre!("Blob {} of SBOM manifest {} is missing", sbom_layer.digest, sbom_referrer.digest))?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", sbom_layer.media_type),
+            ("Content-Length", blob.size.to_string()),
+            ("Docker-Content-Digest", sbom_layer.digest)
+        ],
+        StreamBody::new(tokio_util::io::ReaderStream::new(blob.reader().await?))
+    ).into_response())
+}
@@ -0,0 +1,87 @@
+use axum::{extract::{State, RawQuery}, http::HeaderMap, Json};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{ApplicationState, authentication::{AuthenticationError, basic_credentials, acl::ResourceAccess, api_keys::ApiKeys}, requests::ForwardedInfo};
+
+use super::RegistryHttpError;
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub access_token: String,
+    pub expires_in: u64,
+    pub issued_at: String
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn issue_token(
+    State(app): State<ApplicationState>,
+    RawQuery(query): RawQuery,
+    forwarded_info: ForwardedInfo,
+    headers: HeaderMap
+) -> Result<Json<TokenResponse>, RegistryHttpError> {
+    let (authenticator, token_issuer) = match app.authenticator.as_deref() {
+        Some(authenticator @ crate::authentication::Authenticator { token_issuer: Some(token_issuer), .. }) => (authenticator, token_issuer),
+        _ => return Err(RegistryHttpError::unsupported("The token server"))
+    };
+
+    let unauthorized = || {
+        let realm = app.conf.authentication.as_ref().map(|auth| auth.realm.as_str()).unwrap_or_default();
+        RegistryHttpError::unauthorized(format!("Basic realm=\"{}\"", realm))
+    };
+
+    // No credentials means an anonymous token, which the ACL may still allow to pull things.
+    // Robot accounts log in with an API key as password, and get tokens restricted to the scopes of the key.
+    let (account, api_key) = match basic_credentials(&headers) {
+        Some((username, password)) => {
+            if let Err(AuthenticationError::LockedOut(retry_after)) = authenticator.check_lockout(Some(&username), forwarded_info.client_ip) {
+                return Err(RegistryHttpError::locked_out(retry_after));
+            }
+
+            let login = match &authenticator.api_keys {
+                Some(api_keys) if ApiKeys::is_api_key(&password) => api_keys
+                    .verify(&password)
+                    .map(|api_key| (Some(api_key.name.clone()), Some(api_key))),
+                _ => authenticator
+                    .verify_credentials(&username, &password)
+                    .await
+                    .then(|| (Some(username.clone()), None))
+            };
+
+            match login {
+                Some(login) => {
+                    authenticator.login_succeeded(&username, forwarded_info.client_ip);
+                    login
+                },
+                None => {
+                    warn!("Rejected credentials for user {}", username);
+                    authenticator.login_failed(Some(&username), forwarded_info.client_ip).await;
+                    return Err(unauthorized());
+                }
+            }
+        },
+        None => (None, None)
+    };
+
+    // Clients may send the scope parameter multiple times, which the Query extractor can't deal with.
+    let query = query.unwrap_or_default();
+    let access = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == "scope")
+        .flat_map(|(_, scope)| scope.split(' ').filter_map(ResourceAccess::from_scope).collect::<Vec<_>>())
+        .map(|requested| match &api_key {
+            Some(api_key) => api_key.restrict(&requested),
+            None => authenticator.acl.authorize(account.as_deref(), &requested)
+        })
+        .collect::<Vec<_>>();
+
+    info!("Issuing token for {} with access {:?}", account.as_deref().unwrap_or("anonymous"), access);
+    let issued = token_issuer.issue(account.as_deref(), access)?;
+
+    Ok(Json(TokenResponse {
+        access_token: issued.token.clone(),
+        token: issued.token,
+        expires_in: issued.expires_in.as_secs(),
+        issued_at: issued.issued_at.to_rfc3339()
+    }))
+}
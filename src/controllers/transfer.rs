@@ -0,0 +1,100 @@
+use axum::{extract::{RawQuery, BodyStream}, http::StatusCode, response::IntoResponse, body::StreamBody, Json};
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{data::{proxy_cache, helpers::RegistryPathsHelper}, tenants::AdminTenant};
+
+use super::{RegistryHttpResult, RegistryHttpError};
+
+#[derive(Serialize)]
+pub struct ImportResult {
+    pub repositories: Vec<String>,
+}
+
+/// Exports the repositories given with the `repository` parameters, or the whole proxy cache of the tenant, as a tar archive.
+#[tracing::instrument(skip_all)]
+pub async fn export_proxy_cache(
+    AdminTenant(tenant): AdminTenant,
+    RawQuery(query): RawQuery
+) -> RegistryHttpResult {
+    let repositories = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "repository")
+        .map(|(_, repository)| repository.to_string())
+        .collect::<Vec<_>>();
+
+    // The archive is built on the disk first, so a missing repository is reported before sending anything
+    let archive_path = RegistryPathsHelper::temporary_blob_path(&tenant.temporary_registry_storage, Uuid::new_v4());
+    tokio::fs::create_dir_all(archive_path.parent().unwrap()).await?;
+
+    let proxy_storage = tenant.proxy_storage.clone();
+    let exported = {
+        let archive_path = archive_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let archive_file = std::fs::File::create(&archive_path)?;
+            proxy_cache::export_repositories(&proxy_storage, &repositories, archive_file)
+        }).await?
+    };
+
+    let exported = match exported {
+        Ok(exported) => exported,
+        Err(e) => {
+            tokio::fs::remove_file(&archive_path).await.ok();
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok((StatusCode::NOT_FOUND, e.to_string()).into_response());
+            }
+            return Err(e.into());
+        }
+    };
+    info!("Exporting {} repositories", exported.len());
+
+    // The open file stays readable once unlinked, no cleanup needed after the download
+    let archive_file = tokio::fs::File::open(&archive_path).await?;
+    let archive_size = archive_file.metadata().await?.len();
+    tokio::fs::remove_file(&archive_path).await?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            ("Content-Type", "application/x-tar".to_string()),
+            ("Content-Length", archive_size.to_string()),
+            ("Content-Disposition", "attachment; filename=\"proxy-cache.tar\"".to_string())
+        ],
+        StreamBody::new(ReaderStream::new(archive_file))
+    ).into_response())
+}
+
+/// Imports an archive made by the export endpoint into the proxy cache of the tenant.
+#[tracing::instrument(skip_all)]
+pub async fn import_proxy_cache(
+    AdminTenant(tenant): AdminTenant,
+    mut body: BodyStream
+) -> Result<Json<ImportResult>, RegistryHttpError> {
+    let archive_path = RegistryPathsHelper::temporary_blob_path(&tenant.temporary_registry_storage, Uuid::new_v4());
+    tokio::fs::create_dir_all(archive_path.parent().unwrap()).await?;
+
+    let mut archive_file = tokio::fs::File::create(&archive_path).await?;
+    while let Some(chunk) = body.next().await {
+        archive_file.write_all(&chunk?).await?;
+    }
+    archive_file.flush().await?;
+    drop(archive_file);
+
+    let proxy_storage = tenant.proxy_storage.clone();
+    let imported = {
+        let archive_path = archive_path.clone();
+        tokio::task::spawn_blocking(move || {
+            let archive_file = std::fs::File::open(&archive_path)?;
+            proxy_cache::import_repositories(&proxy_storage, archive_file)
+        }).await?
+    };
+    tokio::fs::remove_file(&archive_path).await?;
+
+    let repositories = imported?;
+    info!("Imported {} repositories", repositories.len());
+
+    Ok(Json(ImportResult { repositories }))
+}
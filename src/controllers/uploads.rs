@@ -1,9 +1,12 @@
-use axum::{http::StatusCode, extract::{Path, State, Query, BodyStream}, response::IntoResponse};
+use std::{ops::Range, path::PathBuf, sync::Arc};
+
+use axum::{http::{StatusCode, HeaderMap}, extract::{State, Query, BodyStream}, response::{IntoResponse, Response}, Extension};
 use serde::Deserialize;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::{data::helpers::reject_invalid_container_refs, ApplicationState};
+use crate::{authentication::Identity, data::{helpers::{self, reject_invalid_container_refs, resolve_upstream_container_ref, RegistryPathsHelper}, uploads::{ChunkOutcome, Upload, UploadStoreItem}, proxy_cache::BlobMetadata, compression, blob_storage::stored_blob_paths}, tenants::{CurrentTenant, Tenant}, push_through, ApplicationState};
 use crate::controllers::RegistryHttpResult;
+use crate::repository_path::RepositoryPath;
 
 use super::RegistryHttpError;
 
@@ -12,27 +15,65 @@ pub struct DigestQueryString {
     pub digest: String
 }
 
-#[tracing::instrument(skip_all)]
+/// Cross-repository blob mount, from a repository the client can pull
+#[derive(Deserialize)]
+pub struct MountQueryString {
+    pub mount: String,
+    pub from: String
+}
+
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref()))]
 pub async fn initiate_upload(
-    Path(container_ref): Path<String>,
+    repository_path: RepositoryPath,
     State(application): State<ApplicationState>,
-    query_string: Option<Query<DigestQueryString>>
+    CurrentTenant(tenant): CurrentTenant,
+    identity: Option<Extension<Identity>>,
+    query_string: Option<Query<DigestQueryString>>,
+    mount_query_string: Option<Query<MountQueryString>>,
+    mut layer: BodyStream
 ) -> RegistryHttpResult {
+    let container_ref = repository_path.container_ref();
     reject_invalid_container_refs(&container_ref)?;
+    tenant.check_quota().await?;
+
+    // Blobs that can't be mounted are uploaded as usual, as the specification asks
+    let mut announced_blob = None;
+    if let Some(Query(mount)) = mount_query_string {
+        let identity = identity.map(|Extension(identity)| identity);
+        match mount_blob(&application, &tenant, identity.as_ref(), &container_ref, &mount).await? {
+            Some(response) => return Ok(response),
+            None => info!("Unable to mount blob {} from {}, starting an upload", mount.mount, mount.from)
+        }
 
-    if query_string.is_some() {
-        // Monolithic uploads are not implemented
-        return Ok((StatusCode::NOT_IMPLEMENTED).into_response());
+        // The blob to come is the one that couldn't be mounted
+        if helpers::is_sha256_digest(&mount.mount) {
+            announced_blob = Some((known_blob_size(&tenant, &mount).await, mount.mount));
+        }
     }
 
     let upload_lock = application.uploads.create_upload(
-        &container_ref, &application.conf.temporary_registry_storage,
-        &application.conf.registry_storage
+        &container_ref, &tenant.temporary_registry_storage,
+        &tenant.registry_storage
     ).await;
-    let upload = upload_lock.read().await;
+
+    // Monolithic upload: the whole blob comes with the request
+    if let Some(Query(DigestQueryString { digest: docker_digest })) = query_string {
+        let mut upload = upload_lock.write().await;
+        info!("Monolithic upload for [{}] blob {}", container_ref, docker_digest);
+
+        upload.create_directory().await?;
+        return complete_upload(&application, &tenant, &container_ref, &mut upload, docker_digest, &mut layer).await;
+    }
+
+    let mut upload = upload_lock.write().await;
     info!("Initiating upload for [{}] blob {}", container_ref, upload.id);
 
-    upload.create_parent_directory().await?;
+    if let Some((size, digest)) = announced_blob {
+        info!("Upload {} announced as blob {} ({:?} bytes)", upload.id, digest, size);
+        upload.announce(&digest, size);
+    }
+    upload.create_directory().await?;
+    upload.save_session().await?;
 
     Ok((
         StatusCode::ACCEPTED,
@@ -44,17 +85,113 @@ pub async fn initiate_upload(
     ).into_response())
 }
 
-#[tracing::instrument(skip_all)]
+/// Links a blob of another repository of the tenant, whether pushed or in the proxy cache, into the repository.
+/// Base layers pulled through the proxy are then pushed without being uploaded again.
+async fn mount_blob(
+    app: &ApplicationState,
+    tenant: &Arc<Tenant>,
+    identity: Option<&Identity>,
+    container_ref: &str,
+    mount: &MountQueryString
+) -> Result<Option<axum::response::Response>, RegistryHttpError> {
+    let hash = match mount.mount.strip_prefix("sha256:") {
+        Some(hash) if helpers::is_sha256_digest(&mount.mount) => hash,
+        _ => return Ok(None)
+    };
+    if reject_invalid_container_refs(&mount.from).is_err() {
+        return Ok(None);
+    }
+
+    // Pushing to the repository doesn't mean the source can be read
+    if let Some(authenticator) = &app.authenticator {
+        match identity {
+            Some(identity) if authenticator.is_allowed(identity, &mount.from, "pull") => (),
+            _ => return Ok(None)
+        }
+    }
+
+    // Blobs don't cross tenants
+    let source_tenant = app.tenants.select(Some(&mount.from), identity.and_then(|identity| identity.account.as_deref()));
+    if !source_tenant.is_ok_and(|source_tenant| Arc::ptr_eq(&source_tenant, tenant)) {
+        return Ok(None);
+    }
+
+    let source_path = match mount_source(tenant, &mount.from, &mount.mount, hash) {
+        Some(source_path) => source_path,
+        None => return Ok(None)
+    };
+    let mut destination_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, container_ref, hash);
+    // Pushed blobs keep their format, it is told from the extensions of the file name
+    if !mount.from.starts_with("proxy/") {
+        destination_path.set_file_name(source_path.file_name().unwrap_or_default());
+    }
+
+    info!("Mounting blob {} from {} into {}", mount.mount, mount.from, container_ref);
+    helpers::link_or_copy(&source_path, &destination_path).await?;
+    let blob_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, container_ref, hash);
+    push_through::forward_blob(app, tenant, container_ref, &mount.mount, &blob_path).await?;
+
+    Ok(Some((
+        StatusCode::CREATED,
+        [
+            ("Location", format!("/v2/{}/blobs/{}", container_ref, mount.mount)),
+            ("Docker-Content-Digest", mount.mount.clone())
+        ]
+    ).into_response()))
+}
+
+/// Size of a blob the proxy cache of the tenant holds, from its verified metadata. Pushed blobs may be
+/// stored compressed or encrypted, their size isn't told by their file.
+async fn known_blob_size(tenant: &Tenant, mount: &MountQueryString) -> Option<u64> {
+    let proxied_container_ref = mount.from.strip_prefix("proxy/")?;
+    let container_ref = resolve_upstream_container_ref(proxied_container_ref);
+
+    BlobMetadata::load(&tenant.proxy_storage, &container_ref, &mount.mount)
+        .await
+        .map(|metadata| metadata.size)
+}
+
+/// File holding a blob of a repository of the tenant. Proxied blobs are stored by digest, pushed ones by hash.
+fn mount_source(tenant: &Tenant, repository: &str, digest: &str, hash: &str) -> Option<PathBuf> {
+    match repository.strip_prefix("proxy/") {
+        Some(proxied_container_ref) => {
+            let container_ref = resolve_upstream_container_ref(proxied_container_ref);
+            Some(RegistryPathsHelper::blob_path(&tenant.proxy_storage, &container_ref, digest))
+                .filter(|blob_path| blob_path.is_file())
+        },
+        None => {
+            let blob_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, repository, hash);
+            stored_blob_paths(&blob_path)
+                .into_iter()
+                .map(|(blob_path, _, _)| blob_path)
+                .find(|blob_path| blob_path.is_file())
+        }
+    }
+}
+
+/// Upload of this instance, or one started by the instance it replaced on the same storage
+async fn find_upload(app: &ApplicationState, tenant: &Tenant, container_ref: &str, raw_upload_uuid: &str) -> Result<UploadStoreItem, RegistryHttpError> {
+    let upload_id = raw_upload_uuid.parse()?;
+    if let Some(upload) = app.uploads.fetch_upload(upload_id).await {
+        return Ok(upload);
+    }
+
+    app.uploads
+        .resume_upload(upload_id, container_ref, &tenant.temporary_registry_storage, &tenant.registry_storage)
+        .await?
+        .ok_or_else(|| RegistryHttpError::upload_id_not_found(raw_upload_uuid))
+}
+
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), upload_id = %repository_path.reference()))]
 pub async fn delete_upload(
-    Path((container_ref, raw_upload_uuid)): Path<(String, String)>,
-    State(app): State<ApplicationState>
+    repository_path: RepositoryPath,
+    State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant
 ) -> RegistryHttpResult {
+    let (container_ref, raw_upload_uuid) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
 
-    let upload_lock = app.uploads
-        .fetch_upload_string_uuid(&raw_upload_uuid)
-        .await?
-        .ok_or_else(|| RegistryHttpError::upload_id_not_found(&raw_upload_uuid))?;
+    let upload_lock = find_upload(&app, &tenant, &container_ref, &raw_upload_uuid).await?;
 
     let upload = upload_lock.read().await;
 
@@ -64,26 +201,93 @@ pub async fn delete_upload(
     Ok((StatusCode::NO_CONTENT, "").into_response())
 }
 
-#[tracing::instrument(skip_all)]
+/// Start offset of a chunk from its Content-Range header, which is `<start>-<end>` for chunked uploads,
+/// along with the range it covers when the end is given. The end is inclusive.
+fn chunk_content_range(headers: &HeaderMap) -> Option<(u64, Option<Range<u64>>)> {
+    let content_range = headers.get("Content-Range")?.to_str().ok()?.trim();
+    let content_range = content_range.strip_prefix("bytes").unwrap_or(content_range).trim_start_matches([' ', '=']);
+    let (start, end) = content_range.split_once('-')?;
+    let start = start.trim().parse().ok()?;
+    let range = end.trim().parse::<u64>().ok()
+        .filter(|end| *end >= start)
+        .map(|end| start..end.saturating_add(1));
+
+    Some((start, range))
+}
+
+fn range_not_satisfiable(upload: &Upload, upload_size: u64) -> Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [
+            ("Range", Upload::committed_range(upload_size)),
+            ("Docker-Upload-UUID", upload.id.to_string()),
+            ("Location", upload.http_upload_uri())
+        ]
+    ).into_response()
+}
+
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), upload_id = %repository_path.reference()))]
 pub async fn process_blob_chunk_upload(
-    Path((container_ref, raw_upload_uuid)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
+    headers: HeaderMap,
     mut layer: BodyStream
 ) -> RegistryHttpResult {
+    let (container_ref, raw_upload_uuid) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
 
-    let upload_lock = app.uploads
-        .fetch_upload_string_uuid(&raw_upload_uuid)
-        .await?
-        .ok_or_else(|| RegistryHttpError::upload_id_not_found(&raw_upload_uuid))?;
+    let upload_lock = find_upload(&app, &tenant, &container_ref, &raw_upload_uuid).await?;
 
-    let mut upload = upload_lock.write().await;
-    let seek_position = upload.write_blob(&mut layer).await?;
+    let content_range = chunk_content_range(&headers);
+    let chunk_start = content_range.as_ref().map(|(start, _)| *start);
+    // Chunks telling the whole range they cover can be received side by side, as long as the ranges don't
+    // overlap. The others are received one at a time.
+    let parallel_range = content_range
+        .and_then(|(_, range)| range)
+        .filter(|_| app.conf.server.parallel_chunk_uploads);
+
+    let (shared_upload, exclusive_upload);
+    let upload: &Upload = if parallel_range.is_some() {
+        shared_upload = upload_lock.read().await;
+        &shared_upload
+    } else {
+        exclusive_upload = upload_lock.write().await;
+        &exclusive_upload
+    };
+
+    // A chunk may be sent again, or ahead of one still on its way, but not over the middle of the chunks
+    // already received. Tell the client what we have so it can resume from there.
+    if let Some(chunk_start) = chunk_start {
+        if !upload.accepts_chunk_at(chunk_start).await? {
+            let upload_size = upload.size().await?;
+            warn!("Chunk for upload {} starts at {}, expected {}", upload.id, chunk_start, upload_size);
+            return Ok(range_not_satisfiable(upload, upload_size));
+        }
+    }
+
+    let reservation = match parallel_range {
+        Some(range) => match upload.reserve_chunk(range.clone()) {
+            Some(reservation) => Some(reservation),
+            None => {
+                warn!("Chunk {:?} for upload {} overlaps a chunk being received", range, upload.id);
+                return Ok(range_not_satisfiable(upload, upload.size().await?));
+            }
+        },
+        None => None
+    };
+
+    // A failed chunk is left out, the client can send it again
+    let seek_position = match upload.write_blob(&mut layer, app.conf.server.body_chunk_timeout(), chunk_start, reservation.as_ref()).await? {
+        ChunkOutcome::Staged(upload_size) => upload_size,
+        ChunkOutcome::WrongSize => return Err(RegistryHttpError::blob_upload_invalid("The chunk doesn't have the size of its Content-Range")),
+        ChunkOutcome::PastAnnouncedSize => return Err(RegistryHttpError::blob_upload_invalid("The chunk goes past the size of the announced blob"))
+    };
 
     Ok((
         StatusCode::ACCEPTED,
         [
-            ("Range", format!("0-{}", seek_position)),
+            ("Range", Upload::committed_range(seek_position)),
             ("Docker-Upload-UUID", upload.id.to_string()),
             ("Location", upload.http_upload_uri()),
             ("Docker-Distribution-Api-Version", "registry/2.0".to_string())
@@ -91,30 +295,100 @@ pub async fn process_blob_chunk_upload(
     ).into_response())
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(skip_all, fields(container_ref = %repository_path.container_ref(), upload_id = %repository_path.reference()))]
 pub async fn finalize_blob_upload(
-    Path((container_ref, raw_upload_uuid)): Path<(String, String)>,
+    repository_path: RepositoryPath,
     State(app): State<ApplicationState>,
+    CurrentTenant(tenant): CurrentTenant,
     Query(DigestQueryString { digest: docker_digest }): Query<DigestQueryString>,
     mut layer: BodyStream
 ) -> RegistryHttpResult {
+    let (container_ref, raw_upload_uuid) = (repository_path.container_ref(), repository_path.reference());
     reject_invalid_container_refs(&container_ref)?;
 
-    let (_, hash) = docker_digest
-        .split_once(':')
-        .ok_or_else(|| RegistryHttpError::invalid_hash_format(&docker_digest))?;
+    let upload_lock = find_upload(&app, &tenant, &container_ref, &raw_upload_uuid).await?;
+    let mut upload = upload_lock.write().await;
 
-    let upload_lock = app.uploads
-        .fetch_upload_string_uuid(&raw_upload_uuid)
-        .await?
-        .ok_or_else(|| RegistryHttpError::upload_id_not_found(&raw_upload_uuid))?;
+    // The body may hold the last chunk, the whole blob when no chunk was sent, or nothing at all
+    complete_upload(&app, &tenant, &container_ref, &mut upload, docker_digest, &mut layer).await
+}
 
-    let mut upload = upload_lock.write().await;
-    upload.write_blob(&mut layer).await?;
-    upload.finalize_upload(hash).await?;
+/// Writes the rest of the blob, checks it against its digest and moves it to the registry storage
+async fn complete_upload(
+    app: &ApplicationState,
+    tenant: &Tenant,
+    container_ref: &str,
+    upload: &mut Upload,
+    docker_digest: String,
+    layer: &mut BodyStream
+) -> RegistryHttpResult {
+    // The blob is stored under its hash, which has to be one the registry can check
+    if !helpers::is_sha256_digest(&docker_digest) {
+        return Err(RegistryHttpError::digest_invalid(&docker_digest));
+    }
+    let hash = docker_digest.trim_start_matches("sha256:").to_string();
+    let hash = hash.as_str();
+
+    // Completing under another digest than the announced one, the content can't be the expected blob
+    if let Some(announced_digest) = upload.announced_digest().filter(|announced_digest| *announced_digest != docker_digest) {
+        warn!("Upload {} was announced as {}, completed as {}, discarding the upload", upload.id, announced_digest, docker_digest);
+        let mismatch = RegistryHttpError::DigestMismatch {
+            expected: announced_digest.to_string(),
+            actual: docker_digest
+        };
+        app.uploads.schedule_discard(upload.id);
+        return Err(mismatch);
+    }
+
+    // A failed last chunk is left out like the others, the client can send it again
+    if let ChunkOutcome::PastAnnouncedSize = upload.write_blob(layer, app.conf.server.body_chunk_timeout(), None, None).await? {
+        return Err(RegistryHttpError::blob_upload_invalid("The chunk goes past the size of the announced blob"));
+    }
+
+    // Counted against the quota once whole, the client may have sent more than it asked for at the start
+    let blob_size = upload.size().await?;
+    if let Err(e) = tenant.check_quota_for(blob_size).await {
+        warn!("Upload {} doesn't fit in the quota of the tenant, discarding the upload", upload.id);
+        app.uploads.schedule_discard(upload.id);
+        return Err(e);
+    }
+
+    // Chunks that don't make up the blob won't do any better next time, the client has to start over.
+    // Other failures, like a full disk, leave the chunks in place so the client can complete the upload again.
+    let write_result = match upload.assemble().await {
+        Ok(actual_hash) => verify_upload_digest(hash, &actual_hash),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(e.into()),
+        Err(e) => {
+            warn!("Unable to put upload {} together, keeping its chunks: {}", upload.id, e);
+            return Err(e.into());
+        }
+    };
+    if let Err(e) = write_result {
+        warn!("Upload {} doesn't make up its blob, discarding the upload: {}", upload.id, e);
+        app.uploads.schedule_discard(upload.id);
+        return Err(e);
+    }
+
+    // Compressed and encrypted before it's moved to the registry storage, where it's never found in the clear
+    let prepared_blob_path = match prepare_blob(app, container_ref, &docker_digest, upload.assembled_blob_path()).await {
+        Ok(prepared_blob_path) => prepared_blob_path,
+        Err(e) => {
+            warn!("Unable to encrypt upload {}, keeping its chunks: {}", upload.id, e);
+            return Err(e.into());
+        }
+    };
+
+    if let Err(e) = upload.finalize_upload(hash, &prepared_blob_path).await {
+        warn!("Unable to move upload {} to the registry storage, keeping its chunks: {}", upload.id, e);
+        return Err(e.into());
+    }
 
     let upload_id = upload.id;
     app.uploads.delete_upload(upload_id).await;
+    tenant.record_stored(blob_size);
+
+    let blob_path = RegistryPathsHelper::blob_path(&tenant.registry_storage, container_ref, hash);
+    push_through::forward_blob(app, tenant, container_ref, &docker_digest, &blob_path).await?;
 
     Ok((
         StatusCode::CREATED,
@@ -123,4 +397,41 @@ pub async fn finalize_blob_upload(
             ("Docker-Content-Digest", docker_digest.clone())
         ]
     ).into_response())
-}
\ No newline at end of file
+}
+
+/// Compresses the assembled blob when its repository asks for it, then encrypts it when the storage is.
+/// Returns where the blob ended up in the temporary storage.
+async fn prepare_blob(app: &ApplicationState, container_ref: &str, docker_digest: &str, assembled_blob_path: PathBuf) -> std::io::Result<PathBuf> {
+    let mut blob_path = assembled_blob_path;
+
+    // A blob that can't be compressed is stored as-is, it just takes more space than it could
+    if let Some(rule) = app.conf.storage.compression_rule(container_ref) {
+        match compression::compress_blob_async(blob_path.clone(), rule.level).await {
+            Ok(Ok(true)) => {
+                info!("Blob {} stored compressed", docker_digest);
+                blob_path = compression::compressed_blob_path(&blob_path);
+            },
+            Ok(Ok(false)) => info!("Blob {} doesn't compress well, stored as-is", docker_digest),
+            Ok(Err(e)) => warn!("Unable to compress blob {}: {}", docker_digest, e),
+            Err(e) => warn!("Compression of blob {} didn't complete: {}", docker_digest, e)
+        }
+    }
+
+    if let Some(storage_cipher) = &app.storage_cipher {
+        info!("Encrypting blob {}", docker_digest);
+        blob_path = storage_cipher.encrypt_file_async(blob_path).await??;
+    }
+
+    Ok(blob_path)
+}
+
+fn verify_upload_digest(hash: &str, actual_hash: &str) -> Result<(), RegistryHttpError> {
+    if actual_hash != hash {
+        return Err(RegistryHttpError::DigestMismatch {
+            expected: format!("sha256:{}", hash),
+            actual: format!("sha256:{}", actual_hash)
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::{io::{self, Read}, ops::Range, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use axum::body::Bytes;
+use futures::{SinkExt, Stream};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::StreamReader;
+
+use super::{compression, encryption::{self, StorageCipher}, helpers};
+
+/// Size of the chunks read from blobs decrypted on a blocking thread
+const PLAINTEXT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A blob of the registry storage, whether it is stored compressed, encrypted or as-is.
+pub struct StoredBlob {
+    path: PathBuf,
+    compressed: bool,
+    encrypted: bool,
+    cipher: Option<Arc<StorageCipher>>,
+    /// Size of the blob itself, not of the file holding it
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl StoredBlob {
+    /// Finds the file holding a blob, if it is stored at all. Its format is told by the extensions of its name.
+    pub async fn open(blob_path: &Path, cipher: Option<Arc<StorageCipher>>) -> io::Result<Option<Self>> {
+        let blob_path = blob_path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || {
+            // In the order they are written: a blob is only removed once the next version of it is in place
+            for (path, compressed, encrypted) in stored_blob_paths(&blob_path) {
+                let file = match std::fs::File::open(&path) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e)
+                };
+                let metadata = file.metadata()?;
+
+                let mut blob = Self { path, compressed, encrypted, cipher, size: metadata.len(), modified: metadata.modified()? };
+                blob.size = match (compressed, encrypted) {
+                    (false, false) => metadata.len(),
+                    (false, true) => encryption::plaintext_size(metadata.len()),
+                    // The decompressed size is in the header of the zstd frame
+                    (true, _) => {
+                        let mut header = Vec::new();
+                        blob.decrypted_reader()?.take(compression::FRAME_HEADER_MAX_SIZE as u64).read_to_end(&mut header)?;
+                        compression::frame_content_size(&header)?
+                    }
+                };
+
+                return Ok(Some(blob));
+            }
+
+            Ok(None)
+        }).await?
+    }
+
+    fn cipher(&self) -> io::Result<&StorageCipher> {
+        self.cipher
+            .as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "The blob is encrypted but no encryption key is configured"))
+    }
+
+    /// The file, decrypted if needed but not decompressed
+    fn decrypted_reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let file = std::fs::File::open(&self.path)?;
+        if self.encrypted {
+            Ok(Box::new(self.cipher()?.decrypting_reader(file)?))
+        } else {
+            Ok(Box::new(file))
+        }
+    }
+
+    /// The content of the blob, on a blocking thread
+    fn plaintext_reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        let reader = self.decrypted_reader()?;
+        if self.compressed {
+            Ok(Box::new(zstd::Decoder::new(reader)?))
+        } else {
+            Ok(reader)
+        }
+    }
+
+    /// Hash of the content of the blob
+    pub async fn sha256(&self) -> io::Result<String> {
+        if !self.compressed && !self.encrypted {
+            return helpers::file256sum_async(self.path.clone()).await?;
+        }
+
+        let mut reader = self.plaintext_reader()?;
+
+        tokio::task::spawn_blocking(move || {
+            let mut hasher = Sha256::new();
+            io::copy(&mut reader, &mut hasher)?;
+            Ok(base16ct::lower::encode_string(&hasher.finalize()))
+        }).await?
+    }
+
+    /// The content of the blob, to be streamed
+    pub async fn reader(&self) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        match (self.compressed, self.encrypted) {
+            (false, false) => Ok(Box::new(tokio::fs::File::open(&self.path).await?)),
+            (true, false) => Ok(Box::new(compression::decompressing_reader(tokio::fs::File::open(&self.path).await?))),
+            // Decrypting is done on a blocking thread, the chunks are sent over as they come
+            (_, true) => Ok(Box::new(StreamReader::new(blocking_reader_stream(self.plaintext_reader()?))))
+        }
+    }
+
+    /// Part of the content of the blob, to be streamed. Blobs stored as-is are read from the start of the
+    /// range, the others are read up to there.
+    pub async fn range_reader(&self, range: Range<u64>) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let length = range.end - range.start;
+        if !self.compressed && !self.encrypted {
+            let mut file = tokio::fs::File::open(&self.path).await?;
+            file.seek(io::SeekFrom::Start(range.start)).await?;
+            return Ok(Box::new(file.take(length)));
+        }
+
+        let mut reader = self.reader().await?;
+        tokio::io::copy(&mut (&mut reader).take(range.start), &mut tokio::io::sink()).await?;
+        Ok(Box::new(reader.take(length)))
+    }
+}
+
+/// Files that may hold a pushed blob, with whether they are compressed and encrypted. A blob is compressed,
+/// then encrypted: each file is listed before the ones it's turned into.
+pub fn stored_blob_paths(blob_path: &Path) -> [(PathBuf, bool, bool); 4] {
+    let compressed_path = compression::compressed_blob_path(blob_path);
+    [
+        (blob_path.to_path_buf(), false, false),
+        (compressed_path.clone(), true, false),
+        (encryption::encrypted_path(&compressed_path), true, true),
+        (encryption::encrypted_path(blob_path), false, true)
+    ]
+}
+
+fn blocking_reader_stream(mut reader: Box<dyn Read + Send>) -> impl Stream<Item = io::Result<Bytes>> + Send + Unpin {
+    let (mut sender, receiver) = futures::channel::mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        loop {
+            let mut chunk = vec![0; PLAINTEXT_CHUNK_SIZE];
+            let chunk = match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(size) => {
+                    chunk.truncate(size);
+                    Ok(Bytes::from(chunk))
+                },
+                Err(e) => Err(e)
+            };
+
+            let failed = chunk.is_err();
+            // The receiver is gone when the client went away
+            if futures::executor::block_on(sender.send(chunk)).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, sync::Arc, fmt::Write};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+/// Proxy cache counters of a repository, since the start of the registry.
+#[derive(Clone, Default, Serialize)]
+pub struct RepositoryCacheCounters {
+    pub manifest_hits: u64,
+    pub manifest_misses: u64,
+    pub blob_hits: u64,
+    pub blob_misses: u64,
+    /// Cached blobs hashed again before being served
+    pub blob_verifications: u64,
+    /// Cached blobs whose content no longer matched their digest
+    pub blob_verification_failures: u64,
+    /// Last time the upstream registry was asked about the manifests of the repository
+    pub last_refresh: Option<DateTime<Utc>>,
+}
+
+/// Proxy cache counters keyed by repository, such as `registry-1.docker.io/library/alpine`.
+#[derive(Clone, Default)]
+pub struct CacheStatistics {
+    repositories: Arc<RwLock<HashMap<String, RepositoryCacheCounters>>>
+}
+
+impl CacheStatistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn record_manifest(&self, container_ref: &str, hit: bool) {
+        let mut repositories = self.repositories.write().await;
+        let counters = repositories.entry(container_ref.to_string()).or_default();
+        if hit {
+            counters.manifest_hits += 1;
+        } else {
+            counters.manifest_misses += 1;
+        }
+    }
+
+    pub async fn record_blob(&self, container_ref: &str, hit: bool) {
+        let mut repositories = self.repositories.write().await;
+        let counters = repositories.entry(container_ref.to_string()).or_default();
+        if hit {
+            counters.blob_hits += 1;
+        } else {
+            counters.blob_misses += 1;
+        }
+    }
+
+    pub async fn record_verification(&self, container_ref: &str, intact: bool) {
+        let mut repositories = self.repositories.write().await;
+        let counters = repositories.entry(container_ref.to_string()).or_default();
+        counters.blob_verifications += 1;
+        if !intact {
+            counters.blob_verification_failures += 1;
+        }
+    }
+
+    pub async fn record_refresh(&self, container_ref: &str) {
+        let mut repositories = self.repositories.write().await;
+        repositories.entry(container_ref.to_string()).or_default().last_refresh = Some(Utc::now());
+    }
+
+    pub async fn get(&self, container_ref: &str) -> RepositoryCacheCounters {
+        self.repositories.read().await
+            .get(container_ref)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Writes the verification counters in the Prometheus text format
+    pub async fn render(&self, output: &mut String) {
+        let repositories = self.repositories.read().await;
+        let mut repository_names = repositories
+            .iter()
+            .filter(|(_, counters)| counters.blob_verifications > 0)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        repository_names.sort();
+
+        writeln!(output, "# HELP proxy_cache_blob_verifications_total Cached blobs hashed again before being served").unwrap();
+        writeln!(output, "# TYPE proxy_cache_blob_verifications_total counter").unwrap();
+        for repository in &repository_names {
+            writeln!(output, "proxy_cache_blob_verifications_total{{repository=\"{}\"}} {}", repository, repositories[*repository].blob_verifications).unwrap();
+        }
+
+        writeln!(output, "# HELP proxy_cache_blob_verification_failures_total Cached blobs not matching their digest anymore").unwrap();
+        writeln!(output, "# TYPE proxy_cache_blob_verification_failures_total counter").unwrap();
+        for repository in &repository_names {
+            writeln!(output, "proxy_cache_blob_verification_failures_total{{repository=\"{}\"}} {}", repository, repositories[*repository].blob_verification_failures).unwrap();
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::{collections::HashSet, fmt::Write, io, path::{Path, PathBuf}, sync::Mutex, time::SystemTime};
+
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+use crate::configuration::FreeSpaceWatermark;
+
+use super::{helpers::{self, RegistryPathsHelper}, proxy_cache};
+
+/// Eviction goes on until the free space is this much over the watermark, so the next fills don't put
+/// the cache right back under it
+const EVICTION_HEADROOM: f64 = 1.1;
+
+/// Proxy storage roots under their watermark, whose blobs are passed through instead of cached
+static SUSPENDED_CACHES: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+static WATERMARK_METRICS: Lazy<Mutex<WatermarkMetrics>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct WatermarkMetrics {
+    checks: u64,
+    evicted_blobs: u64,
+    evicted_bytes: u64
+}
+
+#[derive(Default, Debug)]
+pub struct WatermarkReport {
+    pub evicted_blobs: u64,
+    pub evicted_bytes: u64,
+    /// The cache is still under the watermark once everything that could go was evicted
+    pub fills_suspended: bool
+}
+
+/// What is left on the filesystem for unprivileged users
+#[derive(Debug, Clone, Copy)]
+pub struct FreeSpace {
+    pub bytes: u64,
+    pub inodes: u64
+}
+
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<Option<FreeSpace>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only fills the structure it is given, the path is a valid C string
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Some(FreeSpace {
+        // The field types change from one platform to the other
+        bytes: stat.f_bavail as u64 * stat.f_frsize as u64,
+        inodes: stat.f_favail as u64
+    }))
+}
+
+/// The free space isn't looked up on other platforms, the watermark never triggers
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<Option<FreeSpace>> {
+    Ok(None)
+}
+
+fn is_below(watermark: &FreeSpaceWatermark, free_space: &FreeSpace, headroom: f64) -> bool {
+    let below = |free: u64, minimum: Option<u64>| minimum
+        .map(|minimum| (free as f64) < minimum as f64 * headroom)
+        .unwrap_or(false);
+
+    below(free_space.bytes, watermark.min_free_bytes) || below(free_space.inodes, watermark.min_free_inodes)
+}
+
+/// Whether blobs are to be passed through rather than cached in this proxy storage, because its
+/// filesystem is under the watermark
+pub fn fills_suspended(proxy_root: &Path) -> bool {
+    SUSPENDED_CACHES.lock().unwrap().contains(proxy_root)
+}
+
+fn set_fills_suspended(proxy_root: &Path, suspended: bool) {
+    let mut suspended_caches = SUSPENDED_CACHES.lock().unwrap();
+    if suspended && suspended_caches.insert(proxy_root.to_path_buf()) {
+        warn!("Free space of {:?} is under the watermark, blobs are passed through until it is back", proxy_root);
+    } else if !suspended && suspended_caches.remove(proxy_root) {
+        info!("Free space of {:?} is back over the watermark, blobs are cached again", proxy_root);
+    }
+}
+
+/// Checks the free space of the filesystem holding the proxy cache. Under the watermark, the least
+/// recently used blobs are evicted until there is some room again, and the blobs are passed through
+/// instead of cached as long as there isn't.
+pub fn enforce_watermark(proxy_root: &Path, watermark: &FreeSpaceWatermark) -> io::Result<WatermarkReport> {
+    let mut report = WatermarkReport::default();
+    let mut free = match free_space(proxy_root)? {
+        Some(free) => free,
+        None => return Ok(report)
+    };
+    WATERMARK_METRICS.lock().unwrap().checks += 1;
+
+    if !is_below(watermark, &free, 1.0) {
+        set_fills_suspended(proxy_root, false);
+        return Ok(report);
+    }
+
+    set_fills_suspended(proxy_root, true);
+    warn!("Free space of {:?} under the watermark ({} bytes, {} inodes), evicting cached blobs", proxy_root, free.bytes, free.inodes);
+
+    let mut blobs = cached_blobs(proxy_root)?;
+    blobs.sort_by_key(|blob| blob.last_used);
+    for blob in blobs {
+        if !is_below(watermark, &free, EVICTION_HEADROOM) {
+            break;
+        }
+
+        match std::fs::remove_file(&blob.path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        }
+        std::fs::remove_file(RegistryPathsHelper::blob_meta(proxy_root, &blob.repository, &blob.digest)).ok();
+        info!("Evicted blob {} of {} from the cache", blob.digest, blob.repository);
+
+        report.evicted_blobs += 1;
+        report.evicted_bytes += blob.size;
+        // Blobs sharing their content with a pushed copy don't free anything, the filesystem tells
+        free = free_space(proxy_root)?.unwrap_or(free);
+    }
+
+    report.fills_suspended = is_below(watermark, &free, 1.0);
+    set_fills_suspended(proxy_root, report.fills_suspended);
+
+    let mut metrics = WATERMARK_METRICS.lock().unwrap();
+    metrics.evicted_blobs += report.evicted_blobs;
+    metrics.evicted_bytes += report.evicted_bytes;
+
+    Ok(report)
+}
+
+pub fn enforce_watermark_async(proxy_root: PathBuf, watermark: FreeSpaceWatermark) -> tokio::task::JoinHandle<io::Result<WatermarkReport>> {
+    tokio::task::spawn_blocking(move || enforce_watermark(&proxy_root, &watermark))
+}
+
+struct CachedBlob {
+    repository: String,
+    digest: String,
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime
+}
+
+/// Every blob of the proxy cache, with the last time it was read. Filesystems mounted without access
+/// times give the time it was cached instead.
+fn cached_blobs(proxy_root: &Path) -> io::Result<Vec<CachedBlob>> {
+    let mut blobs = Vec::new();
+
+    for repository in proxy_cache::list_repositories(proxy_root)? {
+        let blobs_directory = RegistryPathsHelper::blob_path(proxy_root, &repository, "");
+        if !blobs_directory.is_dir() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&blobs_directory)? {
+            let entry = entry?;
+            let digest = entry.file_name().to_string_lossy().to_string();
+            let metadata = entry.metadata()?;
+            if !helpers::is_sha256_digest(&digest) || !metadata.is_file() {
+                continue;
+            }
+
+            let modified = metadata.modified()?;
+            let last_used = metadata.accessed().map(|accessed| accessed.max(modified)).unwrap_or(modified);
+            blobs.push(CachedBlob {
+                repository: repository.clone(),
+                digest,
+                path: entry.path(),
+                size: metadata.len(),
+                last_used
+            });
+        }
+    }
+
+    Ok(blobs)
+}
+
+/// Writes the watermark metrics in the Prometheus text format
+pub fn render_watermark_metrics(output: &mut String) {
+    let metrics = WATERMARK_METRICS.lock().unwrap();
+
+    writeln!(output, "# HELP cache_watermark_checks_total Checks of the free space of the proxy storage against the watermark").unwrap();
+    writeln!(output, "# TYPE cache_watermark_checks_total counter").unwrap();
+    writeln!(output, "cache_watermark_checks_total {}", metrics.checks).unwrap();
+
+    writeln!(output, "# HELP cache_watermark_evicted_blobs_total Cached blobs evicted because the proxy storage was under the watermark").unwrap();
+    writeln!(output, "# TYPE cache_watermark_evicted_blobs_total counter").unwrap();
+    writeln!(output, "cache_watermark_evicted_blobs_total {}", metrics.evicted_blobs).unwrap();
+
+    writeln!(output, "# HELP cache_watermark_evicted_bytes_total Bytes of the cached blobs evicted because the proxy storage was under the watermark").unwrap();
+    writeln!(output, "# TYPE cache_watermark_evicted_bytes_total counter").unwrap();
+    writeln!(output, "cache_watermark_evicted_bytes_total {}", metrics.evicted_bytes).unwrap();
+
+    writeln!(output, "# HELP cache_fills_suspended Proxy storages under the watermark, whose blobs are passed through").unwrap();
+    writeln!(output, "# TYPE cache_fills_suspended gauge").unwrap();
+    writeln!(output, "cache_fills_suspended {}", SUSPENDED_CACHES.lock().unwrap().len()).unwrap();
+}
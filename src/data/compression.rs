@@ -0,0 +1,82 @@
+use std::{path::{Path, PathBuf}, io::{Read, Write}};
+
+use tokio::io::{AsyncRead, BufReader};
+use tracing::info;
+
+/// Magic numbers of the formats that won't get any smaller: gzip, zstd, xz and bzip2
+const COMPRESSED_MAGIC_NUMBERS: &[&[u8]] = &[
+    &[0x1f, 0x8b],
+    &[0x28, 0xb5, 0x2f, 0xfd],
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+    b"BZh",
+];
+
+/// Size of the sample compressed to decide whether a blob is worth compressing
+const SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Blobs whose sample doesn't shrink below this ratio are stored as-is
+const MINIMUM_COMPRESSION_RATIO: f64 = 0.9;
+
+/// Largest zstd frame header, where the decompressed size is found
+pub const FRAME_HEADER_MAX_SIZE: usize = 18;
+
+/// Where the compressed version of a stored blob lives
+pub fn compressed_blob_path(blob_path: &Path) -> PathBuf {
+    let mut file_name = blob_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".zst");
+    blob_path.with_file_name(file_name)
+}
+
+/// Replaces a stored blob with its zstd compressed version, unless it doesn't compress well.
+/// Returns whether the blob got compressed.
+pub fn compress_blob(blob_path: &Path, level: i32) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(blob_path)?;
+    let size = file.metadata()?.len();
+
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    (&mut file).take(SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    if sample.is_empty() || COMPRESSED_MAGIC_NUMBERS.iter().any(|magic| sample.starts_with(magic)) {
+        return Ok(false);
+    }
+
+    let compressed_sample = zstd::bulk::compress(&sample, level)?;
+    if compressed_sample.len() as f64 > sample.len() as f64 * MINIMUM_COMPRESSION_RATIO {
+        return Ok(false);
+    }
+
+    // The decompressed size is kept in the frame header, so HEAD requests don't need to decompress anything
+    let compressed_path = compressed_blob_path(blob_path);
+    let temporary_path = compressed_path.with_extension("zst.partial");
+    let mut encoder = zstd::Encoder::new(std::fs::File::create(&temporary_path)?, level)?;
+    encoder.include_contentsize(true)?;
+    encoder.set_pledged_src_size(Some(size))?;
+    encoder.write_all(&sample)?;
+    std::io::copy(&mut file, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+
+    // Readers look for the uncompressed blob first, it only goes away once the compressed one is in place
+    std::fs::rename(&temporary_path, &compressed_path)?;
+    std::fs::remove_file(blob_path)?;
+    info!("Compressed blob {:?} from {} bytes to {} bytes", blob_path, size, std::fs::metadata(&compressed_path)?.len());
+
+    Ok(true)
+}
+
+pub fn compress_blob_async(blob_path: PathBuf, level: i32) -> tokio::task::JoinHandle<std::io::Result<bool>> {
+    tokio::task::spawn_blocking(move || {
+        compress_blob(blob_path.as_path(), level)
+    })
+}
+
+/// Size of a compressed blob once decompressed, taken from the header of its zstd frame.
+pub fn frame_content_size(header: &[u8]) -> std::io::Result<u64> {
+    match zstd::zstd_safe::get_frame_content_size(header) {
+        Ok(Some(size)) => Ok(size),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Compressed blob without its decompressed size"))
+    }
+}
+
+/// Decompresses a compressed blob while it is read
+pub fn decompressing_reader(compressed_file: tokio::fs::File) -> impl AsyncRead {
+    async_compression::tokio::bufread::ZstdDecoder::new(BufReader::new(compressed_file))
+}
@@ -0,0 +1,185 @@
+use std::{collections::HashMap, fmt::Write, io, path::{Path, PathBuf}, sync::Mutex, time::{Duration, SystemTime}};
+
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{helpers::{self, RegistryPathsHelper}, proxy_cache};
+
+/// Blobs written this recently are left for the next run, in case something is still working on them
+const MINIMUM_BLOB_AGE: Duration = Duration::from_secs(600);
+
+static DEDUPLICATION_METRICS: Lazy<Mutex<DeduplicationMetrics>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct DeduplicationMetrics {
+    runs: u64,
+    linked_blobs: u64,
+    saved_bytes: u64,
+    /// Blobs found in both storages whose pushed copy doesn't match its digest
+    mismatched_blobs: u64
+}
+
+#[derive(Default, Debug)]
+pub struct DeduplicationReport {
+    pub linked_blobs: u64,
+    pub saved_bytes: u64,
+    pub mismatched_blobs: u64
+}
+
+/// Replaces the blobs of the proxy cache also pushed to the registry with hard links to the pushed copy.
+/// The pushed copy is only linked once it has been checked against its digest. Blobs stored compressed or
+/// encrypted are left alone, their files don't hold the content the cache has.
+///
+/// Removing the blob on either side, or replacing it as the storage does, doesn't touch the other one:
+/// files are never written in place, so the shared content stays as long as one of the names does.
+pub fn deduplicate_blobs(registry_root: &Path, proxy_root: &Path) -> io::Result<DeduplicationReport> {
+    let mut report = DeduplicationReport::default();
+    let cached_blobs = proxy_blobs(proxy_root)?;
+    if cached_blobs.is_empty() {
+        return Ok(report);
+    }
+
+    for repository in proxy_cache::list_repositories(registry_root)? {
+        let blobs_directory = RegistryPathsHelper::blob_path(registry_root, &repository, "");
+        if !blobs_directory.is_dir() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&blobs_directory)? {
+            let entry = entry?;
+            let hash = entry.file_name().to_string_lossy().to_string();
+            let cached_copies = match cached_blobs.get(&hash) {
+                Some(cached_copies) => cached_copies,
+                None => continue
+            };
+
+            deduplicate_blob(&entry.path(), &hash, cached_copies, &mut report)?;
+        }
+    }
+
+    let mut metrics = DEDUPLICATION_METRICS.lock().unwrap();
+    metrics.runs += 1;
+    metrics.linked_blobs += report.linked_blobs;
+    metrics.saved_bytes += report.saved_bytes;
+    metrics.mismatched_blobs += report.mismatched_blobs;
+
+    Ok(report)
+}
+
+pub fn deduplicate_blobs_async(registry_root: PathBuf, proxy_root: PathBuf) -> tokio::task::JoinHandle<io::Result<DeduplicationReport>> {
+    tokio::task::spawn_blocking(move || deduplicate_blobs(&registry_root, &proxy_root))
+}
+
+/// Cached blobs by hash, wherever they are in the proxy cache
+fn proxy_blobs(proxy_root: &Path) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    let mut blobs: HashMap<String, Vec<PathBuf>> = HashMap::new();
+
+    for repository in proxy_cache::list_repositories(proxy_root)? {
+        let blobs_directory = RegistryPathsHelper::blob_path(proxy_root, &repository, "");
+        if !blobs_directory.is_dir() {
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&blobs_directory)? {
+            let entry = entry?;
+            let digest = entry.file_name().to_string_lossy().to_string();
+            if helpers::is_sha256_digest(&digest) && entry.file_type()?.is_file() {
+                blobs.entry(digest["sha256:".len()..].to_string()).or_default().push(entry.path());
+            }
+        }
+    }
+
+    Ok(blobs)
+}
+
+fn deduplicate_blob(pushed_path: &Path, hash: &str, cached_copies: &[PathBuf], report: &mut DeduplicationReport) -> io::Result<()> {
+    let pushed_metadata = std::fs::metadata(pushed_path)?;
+    if !pushed_metadata.is_file() || is_recent(&pushed_metadata) {
+        return Ok(());
+    }
+
+    let mut pushed_blob_verified = false;
+    for cached_path in cached_copies {
+        let cached_metadata = match std::fs::metadata(cached_path) {
+            Ok(cached_metadata) => cached_metadata,
+            // Removed from the cache in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        if cached_metadata.len() != pushed_metadata.len() || is_recent(&cached_metadata) || is_same_file(&pushed_metadata, &cached_metadata) {
+            continue;
+        }
+
+        if !pushed_blob_verified {
+            if helpers::file256sum(pushed_path)? != hash {
+                warn!("Pushed blob {:?} doesn't match its digest, leaving it apart from the cache", pushed_path);
+                report.mismatched_blobs += 1;
+                return Ok(());
+            }
+            pushed_blob_verified = true;
+        }
+
+        // The cached copy is replaced at once, readers get either file
+        let mut link_file_name = cached_path.file_name().unwrap_or_default().to_os_string();
+        link_file_name.push(format!(".{}.link", Uuid::new_v4()));
+        let link_path = cached_path.with_file_name(link_file_name);
+        if let Err(e) = std::fs::hard_link(pushed_path, &link_path) {
+            // Storage roots on different file systems can't share their blobs
+            warn!("Unable to link {:?} to {:?}: {}", pushed_path, cached_path, e);
+            return Ok(());
+        }
+        if let Err(e) = std::fs::rename(&link_path, cached_path) {
+            std::fs::remove_file(&link_path).ok();
+            return Err(e);
+        }
+
+        info!("Cached blob {:?} now shares the pushed copy {:?}", cached_path, pushed_path);
+        report.linked_blobs += 1;
+        report.saved_bytes += cached_metadata.len();
+    }
+
+    Ok(())
+}
+
+fn is_recent(metadata: &std::fs::Metadata) -> bool {
+    metadata.modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age < MINIMUM_BLOB_AGE)
+        .unwrap_or(true)
+}
+
+#[cfg(unix)]
+fn is_same_file(first: &std::fs::Metadata, second: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    first.dev() == second.dev() && first.ino() == second.ino()
+}
+
+/// Links are not told apart from copies, they are linked again on every run
+#[cfg(not(unix))]
+fn is_same_file(_first: &std::fs::Metadata, _second: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Writes the deduplication metrics in the Prometheus text format
+pub fn render_deduplication_metrics(output: &mut String) {
+    let metrics = DEDUPLICATION_METRICS.lock().unwrap();
+
+    writeln!(output, "# HELP blob_deduplication_runs_total Runs of the deduplication of the proxy cache against the pushed blobs").unwrap();
+    writeln!(output, "# TYPE blob_deduplication_runs_total counter").unwrap();
+    writeln!(output, "blob_deduplication_runs_total {}", metrics.runs).unwrap();
+
+    writeln!(output, "# HELP blob_deduplication_linked_total Cached blobs replaced with a link to their pushed copy").unwrap();
+    writeln!(output, "# TYPE blob_deduplication_linked_total counter").unwrap();
+    writeln!(output, "blob_deduplication_linked_total {}", metrics.linked_blobs).unwrap();
+
+    writeln!(output, "# HELP blob_deduplication_saved_bytes_total Bytes freed by linking cached blobs to their pushed copy").unwrap();
+    writeln!(output, "# TYPE blob_deduplication_saved_bytes_total counter").unwrap();
+    writeln!(output, "blob_deduplication_saved_bytes_total {}", metrics.saved_bytes).unwrap();
+
+    writeln!(output, "# HELP blob_deduplication_mismatched_total Pushed blobs not matching their digest, left apart from the cache").unwrap();
+    writeln!(output, "# TYPE blob_deduplication_mismatched_total counter").unwrap();
+    writeln!(output, "blob_deduplication_mismatched_total {}", metrics.mismatched_blobs).unwrap();
+}
@@ -0,0 +1,294 @@
+use std::{io::{self, Read, Write, BufRead, BufReader, BufWriter}, path::{Path, PathBuf}, sync::Arc};
+
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, generic_array::GenericArray}};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::configuration::EncryptionConfiguration;
+
+/// Starts the encrypted files, followed by the random prefix of their nonces
+const MAGIC: &[u8] = b"DSPRAES1";
+const NONCE_PREFIX_SIZE: usize = 7;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_PREFIX_SIZE;
+
+/// Files are encrypted by segments, so they can be decrypted on the fly without holding them in memory
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Encrypts the files of the registry storage with AES-256-GCM.
+///
+/// Each segment is sealed with a nonce made of the random prefix of the file, the index of the segment
+/// and a flag set on the last one, so segments can't be reordered, swapped between files or cut off.
+pub struct StorageCipher {
+    cipher: Aes256Gcm
+}
+
+impl StorageCipher {
+    pub fn load(configuration: &EncryptionConfiguration) -> eyre::Result<Self> {
+        let encoded_key = match (&configuration.key, &configuration.key_file) {
+            (Some(key), None) => key.clone(),
+            (None, Some(key_file)) => std::fs::read_to_string(key_file)?,
+            _ => eyre::bail!("Either key or key_file is needed to encrypt the storage, not both")
+        };
+
+        let key = base64::decode(encoded_key.trim())?;
+        if key.len() != 32 {
+            eyre::bail!("The storage encryption key must be 32 bytes long, base64 encoded");
+        }
+
+        Ok(Self { cipher: Aes256Gcm::new(GenericArray::from_slice(&key)) })
+    }
+
+    fn nonce(prefix: &[u8], index: u32, last: bool) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    /// Replaces a file with its encrypted version, named after it with the `.enc` extension.
+    /// Returns the path of the encrypted file.
+    pub fn encrypt_file(&self, path: &Path) -> io::Result<PathBuf> {
+        let encrypted_path = encrypted_path(path);
+        let mut temporary_file_name = encrypted_path.file_name().unwrap_or_default().to_os_string();
+        temporary_file_name.push(".partial");
+        let temporary_path = path.with_file_name(temporary_file_name);
+
+        let mut source = std::fs::File::open(path)?;
+        let mut destination = BufWriter::new(std::fs::File::create(&temporary_path)?);
+
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        destination.write_all(MAGIC)?;
+        destination.write_all(&prefix)?;
+
+        let mut segment = vec![0; SEGMENT_SIZE];
+        let mut segment_size = read_full(&mut source, &mut segment)?;
+        let mut next_segment = vec![0; SEGMENT_SIZE];
+        for index in 0.. {
+            // A full segment may well be the last one, only the next read tells
+            let next_segment_size = if segment_size == SEGMENT_SIZE {
+                read_full(&mut source, &mut next_segment)?
+            } else {
+                0
+            };
+            let last = next_segment_size == 0;
+
+            let sealed_segment = self.cipher
+                .encrypt(GenericArray::from_slice(&Self::nonce(&prefix, index, last)), &segment[..segment_size])
+                .map_err(|_| io::Error::other("Unable to encrypt the file"))?;
+            destination.write_all(&sealed_segment)?;
+
+            if last {
+                break;
+            }
+            std::mem::swap(&mut segment, &mut next_segment);
+            segment_size = next_segment_size;
+        }
+
+        destination.into_inner()?.sync_all()?;
+        // Readers look for the file in the clear first, it only goes away once the encrypted one is in place
+        std::fs::rename(&temporary_path, &encrypted_path)?;
+        std::fs::remove_file(path)?;
+
+        Ok(encrypted_path)
+    }
+
+    pub fn encrypt_file_async(self: &Arc<Self>, path: PathBuf) -> tokio::task::JoinHandle<io::Result<PathBuf>> {
+        let cipher = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            cipher.encrypt_file(path.as_path())
+        })
+    }
+
+    /// Reader giving the decrypted content of an encrypted file.
+    pub fn decrypting_reader<R: Read>(&self, mut reader: R) -> io::Result<DecryptingReader<R>> {
+        let mut header = [0; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The file is not encrypted"));
+        }
+
+        Ok(DecryptingReader {
+            inner: BufReader::new(reader),
+            cipher: self.cipher.clone(),
+            prefix: header[MAGIC.len()..].to_vec(),
+            index: 0,
+            plaintext: Vec::new(),
+            position: 0,
+            finished: false
+        })
+    }
+
+    /// Decrypted content of a small encrypted file, such as a manifest.
+    pub fn decrypt(&self, content: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        self.decrypting_reader(content)?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+}
+
+pub struct DecryptingReader<R> {
+    inner: BufReader<R>,
+    cipher: Aes256Gcm,
+    prefix: Vec<u8>,
+    index: u32,
+    plaintext: Vec<u8>,
+    position: usize,
+    finished: bool
+}
+
+impl<R: Read> DecryptingReader<R> {
+    fn read_segment(&mut self) -> io::Result<()> {
+        let mut sealed_segment = vec![0; SEGMENT_SIZE + TAG_SIZE];
+        let sealed_segment_size = read_full(&mut self.inner, &mut sealed_segment)?;
+        if sealed_segment_size < TAG_SIZE {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The encrypted file is truncated"));
+        }
+        let last = self.inner.fill_buf()?.is_empty();
+
+        let nonce = StorageCipher::nonce(&self.prefix, self.index, last);
+        self.plaintext = self.cipher
+            .decrypt(GenericArray::from_slice(&nonce), &sealed_segment[..sealed_segment_size])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The encrypted file is corrupted or the key is wrong"))?;
+        self.position = 0;
+        self.index += 1;
+        self.finished = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_segment()?;
+        }
+
+        let size = buf.len().min(self.plaintext.len() - self.position);
+        buf[..size].copy_from_slice(&self.plaintext[self.position..self.position + size]);
+        self.position += size;
+
+        Ok(size)
+    }
+}
+
+/// Where the encrypted version of a file of the storage lives
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".enc");
+    path.with_file_name(file_name)
+}
+
+/// Content of a stored manifest, decrypted if it was encrypted. Manifests are stored under the digest of
+/// their content, so one that doesn't match it is the encrypted version. Manifests written before the
+/// encryption was enabled are still readable, and the decrypted ones are checked against their digest too.
+pub fn decrypt_manifest(cipher: Option<&StorageCipher>, digest: &str, content: Vec<u8>) -> io::Result<Vec<u8>> {
+    let matches_digest = |content: &[u8]| digest.strip_prefix("sha256:") == Some(base16ct::lower::encode_string(&Sha256::digest(content)).as_str());
+    if matches_digest(&content) {
+        return Ok(content);
+    }
+
+    let plaintext = cipher
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Manifest {} doesn't match its digest and no encryption key is configured", digest)))?
+        .decrypt(&content)?;
+    if !matches_digest(&plaintext) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Manifest {} doesn't match its digest once decrypted", digest)));
+    }
+
+    Ok(plaintext)
+}
+
+/// Size of the content of an encrypted file, from the size of the file
+pub fn plaintext_size(encrypted_size: u64) -> u64 {
+    let sealed_segment_size = (SEGMENT_SIZE + TAG_SIZE) as u64;
+    let sealed_size = encrypted_size.saturating_sub(HEADER_SIZE as u64);
+    let last_segment_size = sealed_size % sealed_segment_size;
+
+    (sealed_size / sealed_segment_size) * SEGMENT_SIZE as u64 + last_segment_size.saturating_sub(TAG_SIZE as u64)
+}
+
+/// Reads until the buffer is full or the reader is exhausted
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() {
+        match reader.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use super::{MAGIC, StorageCipher, decrypt_manifest, encrypted_path};
+    use crate::{configuration::EncryptionConfiguration, data::blob_storage::StoredBlob};
+
+    fn cipher() -> StorageCipher {
+        StorageCipher::load(&EncryptionConfiguration { key: Some(base64::encode([7; 32])), key_file: None }).unwrap()
+    }
+
+    fn digest(content: &[u8]) -> String {
+        format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(content)))
+    }
+
+    #[tokio::test]
+    async fn encrypted_blobs_are_told_by_their_name() {
+        let directory = std::env::temp_dir().join(format!("encryption-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let cipher = Arc::new(cipher());
+
+        // A blob in the clear that happens to start like an encrypted file
+        let plain_content = [MAGIC, b"but stored in the clear"].concat();
+        let plain_path = directory.join("plain");
+        std::fs::write(&plain_path, &plain_content).unwrap();
+        let blob = StoredBlob::open(&plain_path, Some(cipher.clone())).await.unwrap().unwrap();
+        assert_eq!(blob.size, plain_content.len() as u64);
+
+        let content = b"layer content".repeat(10_000);
+        let blob_path = directory.join("layer");
+        std::fs::write(&blob_path, &content).unwrap();
+        assert_eq!(cipher.encrypt_file_async(blob_path.clone()).await.unwrap().unwrap(), encrypted_path(&blob_path));
+        assert!(!blob_path.exists());
+
+        let blob = StoredBlob::open(&blob_path, Some(cipher.clone())).await.unwrap().unwrap();
+        assert_eq!(blob.size, content.len() as u64);
+        assert_eq!(blob.sha256().await.unwrap(), digest(&content).trim_start_matches("sha256:"));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn manifests_are_told_by_their_digest() {
+        let cipher = cipher();
+        let manifest = [MAGIC, b"{\"schemaVersion\":2}"].concat();
+        assert_eq!(decrypt_manifest(Some(&cipher), &digest(&manifest), manifest.clone()).unwrap(), manifest);
+        assert_eq!(decrypt_manifest(None, &digest(&manifest), manifest.clone()).unwrap(), manifest);
+
+        let directory = std::env::temp_dir().join(format!("encryption-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let manifest_path = directory.join("manifest");
+        std::fs::write(&manifest_path, &manifest).unwrap();
+        let encrypted_manifest = std::fs::read(cipher.encrypt_file(&manifest_path).unwrap()).unwrap();
+
+        assert_eq!(decrypt_manifest(Some(&cipher), &digest(&manifest), encrypted_manifest.clone()).unwrap(), manifest);
+        assert!(decrypt_manifest(None, &digest(&manifest), encrypted_manifest.clone()).is_err());
+        // An encrypted manifest moved under the digest of another one is refused
+        assert!(decrypt_manifest(Some(&cipher), &digest(b"{}"), encrypted_manifest).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashSet, io, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
+
+use serde_json::Value;
+use tracing::{info, warn};
+
+use super::{encryption::{self, StorageCipher}, helpers::RegistryPathsHelper, manifests, proxy_cache};
+
+/// Blobs are pushed before the manifests referencing them, those written this recently are left alone
+const MINIMUM_BLOB_AGE: Duration = Duration::from_secs(3600);
+
+#[derive(Default, Debug)]
+pub struct GarbageCollectionReport {
+    pub removed_blobs: u64,
+    pub freed_bytes: u64,
+    /// Repositories left alone because one of their manifests couldn't be read
+    pub skipped_repositories: u64
+}
+
+/// Removes the pushed blobs no manifest of their repository references anymore. Manifests and tags are
+/// kept, only the blobs left behind by deleted or overwritten images, or by pushes that never completed,
+/// are removed. With `dry_run`, the report tells what would be removed without touching anything.
+pub fn collect_garbage(registry_root: &Path, cipher: Option<&StorageCipher>, dry_run: bool) -> io::Result<GarbageCollectionReport> {
+    let mut report = GarbageCollectionReport::default();
+
+    for repository in proxy_cache::list_repositories(registry_root)? {
+        let blobs_directory = RegistryPathsHelper::blob_path(registry_root, &repository, "");
+        if !blobs_directory.is_dir() {
+            continue;
+        }
+
+        let referenced_hashes = match referenced_hashes(registry_root, &repository, cipher) {
+            Ok(referenced_hashes) => referenced_hashes,
+            Err(e) => {
+                warn!("Unable to read the manifests of {}, leaving its blobs alone: {}", repository, e);
+                report.skipped_repositories += 1;
+                continue;
+            }
+        };
+
+        for entry in std::fs::read_dir(&blobs_directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            // Compressed and encrypted blobs are named after their hash too, with extensions
+            let hash = file_name.split('.').next().unwrap_or_default();
+            if !metadata.is_file() || is_recent(&metadata) || referenced_hashes.contains(hash) {
+                continue;
+            }
+
+            info!("Blob {} of {} isn't referenced anymore{}", file_name, repository, if dry_run { "" } else { ", removing it" });
+            if !dry_run {
+                std::fs::remove_file(entry.path())?;
+                std::fs::remove_file(RegistryPathsHelper::blob_meta(registry_root, &repository, &format!("sha256:{}", hash))).ok();
+            }
+            report.removed_blobs += 1;
+            report.freed_bytes += metadata.len();
+        }
+    }
+
+    Ok(report)
+}
+
+pub fn collect_garbage_async(registry_root: PathBuf, cipher: Option<Arc<StorageCipher>>, dry_run: bool) -> tokio::task::JoinHandle<io::Result<GarbageCollectionReport>> {
+    tokio::task::spawn_blocking(move || collect_garbage(&registry_root, cipher.as_deref(), dry_run))
+}
+
+/// Hashes of the blobs referenced by the manifests of the repository, without the `sha256:` prefix
+fn referenced_hashes(registry_root: &Path, repository: &str, cipher: Option<&StorageCipher>) -> io::Result<HashSet<String>> {
+    let mut hashes = HashSet::new();
+
+    for digest in manifests::list_manifest_digests(registry_root, repository)? {
+        let manifest_path = RegistryPathsHelper::manifest_path(registry_root, repository, &digest);
+        let manifest = encryption::decrypt_manifest(cipher, &digest, std::fs::read(&manifest_path)?)?;
+        let manifest = serde_json::from_slice::<Value>(&manifest)?;
+
+        // Images and artifacts list their blobs under `config` and `layers`, OCI artifact manifests under `blobs`
+        // and the schema 1 manifests under `fsLayers`. Indexes only reference other manifests.
+        let descriptors = std::iter::once(&manifest["config"])
+            .chain(manifest["layers"].as_array().into_iter().flatten())
+            .chain(manifest["blobs"].as_array().into_iter().flatten());
+        let digests = descriptors
+            .filter_map(|descriptor| descriptor["digest"].as_str())
+            .chain(manifest["fsLayers"].as_array().into_iter().flatten().filter_map(|layer| layer["blobSum"].as_str()));
+
+        hashes.extend(digests.filter_map(|digest| digest.strip_prefix("sha256:")).map(str::to_string));
+    }
+
+    Ok(hashes)
+}
+
+fn is_recent(metadata: &std::fs::Metadata) -> bool {
+    metadata.modified()
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age < MINIMUM_BLOB_AGE)
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use super::collect_garbage;
+    use crate::data::helpers::RegistryPathsHelper;
+
+    fn write_blob(registry_root: &std::path::Path, repository: &str, file_name: &str, aged: bool) {
+        let blob_path = RegistryPathsHelper::blob_path(registry_root, repository, file_name);
+        std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+        std::fs::write(&blob_path, file_name).unwrap();
+        if aged {
+            let file = std::fs::File::options().write(true).open(&blob_path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(2 * 3600)).unwrap();
+        }
+    }
+
+    #[test]
+    fn unreferenced_blobs_are_removed() {
+        let registry_root = std::env::temp_dir().join(format!("garbage-collection-{}", Uuid::new_v4()));
+        let (config, layer, compressed_layer, orphan, recent_orphan) = ("a".repeat(64), "b".repeat(64), "c".repeat(64), "d".repeat(64), "e".repeat(64));
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "config": { "digest": format!("sha256:{}", config) },
+            "layers": [{ "digest": format!("sha256:{}", layer) }, { "digest": format!("sha256:{}", compressed_layer) }]
+        });
+        let manifests_directory = RegistryPathsHelper::manifests_directory(&registry_root, "team/app");
+        std::fs::create_dir_all(&manifests_directory).unwrap();
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let manifest_digest = format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(&manifest)));
+        std::fs::write(manifests_directory.join(manifest_digest), manifest).unwrap();
+
+        write_blob(&registry_root, "team/app", &config, true);
+        write_blob(&registry_root, "team/app", &layer, true);
+        write_blob(&registry_root, "team/app", &format!("{}.zst.enc", compressed_layer), true);
+        write_blob(&registry_root, "team/app", &format!("{}.enc", orphan), true);
+        write_blob(&registry_root, "team/app", &recent_orphan, false);
+
+        let report = collect_garbage(&registry_root, None, true).unwrap();
+        assert_eq!((report.removed_blobs, report.freed_bytes), (1, 68));
+        assert!(RegistryPathsHelper::blob_path(&registry_root, "team/app", &format!("{}.enc", orphan)).is_file());
+
+        let report = collect_garbage(&registry_root, None, false).unwrap();
+        assert_eq!((report.removed_blobs, report.freed_bytes), (1, 68));
+        assert!(!RegistryPathsHelper::blob_path(&registry_root, "team/app", &format!("{}.enc", orphan)).exists());
+        for kept in [config, layer, format!("{}.zst.enc", compressed_layer), recent_orphan] {
+            assert!(RegistryPathsHelper::blob_path(&registry_root, "team/app", &kept).is_file(), "{} was removed", kept);
+        }
+
+        std::fs::remove_dir_all(&registry_root).unwrap();
+    }
+
+    #[test]
+    fn repositories_with_unreadable_manifests_are_left_alone() {
+        let registry_root = std::env::temp_dir().join(format!("garbage-collection-{}", Uuid::new_v4()));
+        let manifests_directory = RegistryPathsHelper::manifests_directory(&registry_root, "team/app");
+        std::fs::create_dir_all(&manifests_directory).unwrap();
+        std::fs::write(manifests_directory.join(format!("sha256:{}", "f".repeat(64))), b"{\"layers\":").unwrap();
+        write_blob(&registry_root, "team/app", &"a".repeat(64), true);
+
+        let report = collect_garbage(&registry_root, None, false).unwrap();
+        assert_eq!((report.removed_blobs, report.skipped_repositories), (0, 1));
+
+        std::fs::remove_dir_all(&registry_root).unwrap();
+    }
+}
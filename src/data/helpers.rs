@@ -1,18 +1,66 @@
+use std::fmt::Write;
+use std::io::Read;
 use std::path::{PathBuf, Path};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use once_cell::sync::Lazy;
+use futures::{Stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
 use regex::Regex;
 use sha2::{Sha256, Digest};
+use tokio::sync::Semaphore;
+use tracing::warn;
 use uuid::Uuid;
 
-use crate::controllers::RegistryHttpError;
+use crate::{controllers::RegistryHttpError, docker_client::metrics::LatencyHistogram};
 
-static REGISTRY_CONTAINER_SEPARATION_REGEX: Lazy<Regex> = Lazy::new(|| {
-    // The registry part is mandatory, I don't want to deal with "rust:latest"
-    // aliasing to "registry.docker.io/library/rust:latest"
-    Regex::new("(?P<registry>[a-zA-Z0-9-.]+(?::[0-9]{1,6})?)/(?P<container>[a-zA-Z0-9-./]+)$").unwrap()
+static REPOSITORY_COMPONENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    // Path components of the OCI distribution specification
+    Regex::new("^[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*$").unwrap()
 });
 
+/// Host serving the Docker Hub registry API
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+/// Size of the reads when hashing files. Large reads keep the number of system calls down on multi-gigabyte layers.
+const HASHING_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Files at least this large are memory-mapped to be hashed, instead of being copied through a buffer
+const HASHING_MEMORY_MAP_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+static HASHING_METRICS: Lazy<Mutex<HashingMetrics>> = Lazy::new(Default::default);
+
+/// Slots of the files sent to the blocking threads to be hashed, when limited
+static HASHING_SLOTS: OnceCell<Arc<Semaphore>> = OnceCell::new();
+
+/// Files hashed since the start of the registry. Digests are computed before answering some requests,
+/// so the time spent there is worth keeping an eye on.
+#[derive(Default)]
+struct HashingMetrics {
+    bytes: u64,
+    duration: LatencyHistogram
+}
+
+impl HashingMetrics {
+    fn record(&mut self, bytes: u64, duration: Duration) {
+        self.bytes += bytes;
+        self.duration.observe(duration);
+    }
+}
+
+/// Writes the hashing metrics in the Prometheus text format
+pub fn render_hashing_metrics(output: &mut String) {
+    let metrics = HASHING_METRICS.lock().unwrap();
+
+    writeln!(output, "# HELP file_hashing_bytes_total Bytes of the files hashed to compute their digest").unwrap();
+    writeln!(output, "# TYPE file_hashing_bytes_total counter").unwrap();
+    writeln!(output, "file_hashing_bytes_total {}", metrics.bytes).unwrap();
+
+    writeln!(output, "# HELP file_hashing_duration_seconds Time spent hashing each file").unwrap();
+    writeln!(output, "# TYPE file_hashing_duration_seconds histogram").unwrap();
+    metrics.duration.render(output, "file_hashing_duration_seconds", "");
+}
+
 pub struct RegistryPathsHelper;
 
 impl RegistryPathsHelper {
@@ -24,18 +72,35 @@ impl RegistryPathsHelper {
             .join(hash)
     }
 
+    pub fn blob_meta(registry_path: &Path, container_ref: &str, digest: &str) -> PathBuf {
+        registry_path
+            .join(container_ref)
+            .join("_repository")
+            .join("blob_meta")
+            .join(digest)
+    }
+
     pub fn temporary_blob_path(temp_path: &Path, upload_id: Uuid) -> PathBuf {
         temp_path
             .join("blobs")
             .join(upload_id.to_string())
     }
 
-    pub fn manifest_path(registry_path: &Path, container_ref: &str, manifest_ref: &str) -> PathBuf {
+    pub fn temporary_upload_directory(temp_path: &Path, upload_id: Uuid) -> PathBuf {
+        temp_path
+            .join("uploads")
+            .join(upload_id.to_string())
+    }
+
+    pub fn manifests_directory(registry_path: &Path, container_ref: &str) -> PathBuf {
         registry_path
             .join(container_ref)
             .join("_repository")
             .join("manifests")
-            .join(manifest_ref)
+    }
+
+    pub fn manifest_path(registry_path: &Path, container_ref: &str, manifest_ref: &str) -> PathBuf {
+        Self::manifests_directory(registry_path, container_ref).join(manifest_ref)
     }
 
     pub fn manifest_meta(registry_path: &Path, container_ref: &str, manifest_ref: &str) -> PathBuf {
@@ -45,6 +110,33 @@ impl RegistryPathsHelper {
             .join("meta")
             .join(manifest_ref)
     }
+
+    pub fn referrers_directory(registry_path: &Path, container_ref: &str, subject_digest: &str) -> PathBuf {
+        registry_path
+            .join(container_ref)
+            .join("_repository")
+            .join("referrers")
+            .join(subject_digest)
+    }
+
+    pub fn scan_report(registry_path: &Path, container_ref: &str, digest: &str) -> PathBuf {
+        registry_path
+            .join(container_ref)
+            .join("_repository")
+            .join("scans")
+            .join(digest)
+    }
+
+    pub fn tags_directory(registry_path: &Path, container_ref: &str) -> PathBuf {
+        registry_path
+            .join(container_ref)
+            .join("_repository")
+            .join("tags")
+    }
+
+    pub fn tag_link(registry_path: &Path, container_ref: &str, tag: &str) -> PathBuf {
+        Self::tags_directory(registry_path, container_ref).join(tag)
+    }
 }
 
 pub fn reject_invalid_container_refs(container_ref: &str) -> Result<(), RegistryHttpError> {
@@ -64,26 +156,212 @@ pub fn reject_invalid_tags_refs(tag: &str) -> Result<(), RegistryHttpError> {
 }
 
 pub fn file256sum(path: &Path) -> std::io::Result<String> {
+    let started_at = Instant::now();
     let mut file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
     let mut hasher = Sha256::new();
-    std::io::copy(&mut file, &mut hasher)?;
+
+    if size >= HASHING_MEMORY_MAP_THRESHOLD {
+        // SAFETY: stored files are never modified in place, they are only replaced by renames
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        // Read-ahead hints are only given where madvise exists
+        #[cfg(unix)]
+        map.advise(memmap2::Advice::Sequential)?;
+        hasher.update(&map[..]);
+    } else {
+        let mut buffer = vec![0; HASHING_BUFFER_SIZE];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+    }
+
     let hash = hasher.finalize();
+    HASHING_METRICS.lock().unwrap().record(size, started_at.elapsed());
     Ok(base16ct::lower::encode_string(&hash))
 }
 
+/// Caps the files hashed or waiting to be hashed at the same time, for the whole process
+pub fn limit_hashing_jobs(max_jobs: usize) {
+    if HASHING_SLOTS.set(Arc::new(Semaphore::new(max_jobs))).is_err() {
+        warn!("The hashing jobs are already limited, ignoring the new limit");
+    }
+}
+
 pub fn file256sum_async(path: PathBuf) -> tokio::task::JoinHandle<std::io::Result<String>> {
+    let slots = match HASHING_SLOTS.get() {
+        Some(slots) => Arc::clone(slots),
+        None => return tokio::task::spawn_blocking(move || file256sum(path.as_path()))
+    };
+
+    tokio::spawn(async move {
+        let _slot = slots.acquire_owned().await.map_err(std::io::Error::other)?;
+        tokio::task::spawn_blocking(move || file256sum(path.as_path())).await?
+    })
+}
+
+/// Total size in bytes of the files under a directory, recursively. Missing directories count as empty.
+pub fn directory_size(path: &Path) -> std::io::Result<u64> {
+    if !path.is_dir() {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+pub fn directory_size_async(path: PathBuf) -> tokio::task::JoinHandle<std::io::Result<u64>> {
     tokio::task::spawn_blocking(move || {
-        file256sum(path.as_path())
+        directory_size(path.as_path())
     })
 }
 
-pub fn split_registry_and_container(registry_container: &str) -> (&str, &str) {
-    let components = REGISTRY_CONTAINER_SEPARATION_REGEX.captures(registry_container).unwrap();
+/// Puts a stored file at another place of the storage, as a hard link when the file system allows it
+/// and as a copy otherwise. An existing destination is left as is, files are only ever stored once complete.
+pub async fn link_or_copy(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    match tokio::fs::hard_link(source, destination).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => warn!("Unable to link {:?} to {:?}, copying it: {}", source, destination, e)
+    }
+
+    let mut copy_file_name = destination.file_name().unwrap_or_default().to_os_string();
+    copy_file_name.push(format!(".{}.tmp", Uuid::new_v4()));
+    let copy_path = destination.with_file_name(copy_file_name);
+
+    if let Err(e) = tokio::fs::copy(source, &copy_path).await {
+        tokio::fs::remove_file(&copy_path).await.ok();
+        return Err(e);
+    }
+    tokio::fs::rename(&copy_path, destination).await
+}
+
+/// Waits for the next item of a stream, for at most `timeout` if there is one.
+pub async fn next_chunk<S: Stream + Unpin>(stream: &mut S, timeout: Option<Duration>) -> eyre::Result<Option<S::Item>> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, stream.next())
+            .await
+            .map_err(|_| eyre::eyre!("Timed out after {:?} waiting for the next chunk", timeout)),
+        None => Ok(stream.next().await)
+    }
+}
 
-    let registry = components.name("registry").unwrap().as_str();
-    let container = components.name("container").unwrap().as_str();
+#[derive(thiserror::Error, Debug)]
+pub enum ContainerRefError {
+    #[error("{0} doesn't start with a registry")]
+    MissingRegistry(String),
+    #[error("{0} has an invalid registry host or port")]
+    InvalidRegistry(String),
+    #[error("{0} has an invalid repository name")]
+    InvalidRepository(String)
+}
+
+impl ContainerRefError {
+    pub fn container_ref(&self) -> &str {
+        match self {
+            ContainerRefError::MissingRegistry(container_ref)
+            | ContainerRefError::InvalidRegistry(container_ref)
+            | ContainerRefError::InvalidRepository(container_ref) => container_ref
+        }
+    }
+}
+
+/// Splits a proxied repository like `localhost:5000/library/alpine` into its registry and its repository name.
+/// The registry part is mandatory, I don't want to deal with "rust:latest" aliasing to
+/// "registry.docker.io/library/rust:latest".
+pub fn split_registry_and_container(registry_container: &str) -> Result<(&str, &str), ContainerRefError> {
+    let (registry, container) = registry_container
+        .split_once('/')
+        .ok_or_else(|| ContainerRefError::MissingRegistry(registry_container.to_string()))?;
+
+    if !registry_is_valid(registry) {
+        return Err(ContainerRefError::InvalidRegistry(registry_container.to_string()));
+    }
+    if !container.split('/').all(|component| REPOSITORY_COMPONENT_REGEX.is_match(component)) {
+        return Err(ContainerRefError::InvalidRepository(registry_container.to_string()));
+    }
+
+    Ok((registry, container))
+}
+
+/// Host of a registry, with its port if any: a domain name, an IPv4 address or a bracketed IPv6 address
+fn registry_is_valid(registry: &str) -> bool {
+    let (host_is_valid, port) = match registry.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((address, port)) => (address.parse::<std::net::Ipv6Addr>().is_ok(), port),
+            None => return false
+        },
+        None => {
+            let (host, port) = registry.find(':').map_or((registry, ""), |colon| registry.split_at(colon));
+            // IPv4 addresses fit the domain name grammar
+            let host_is_valid = host.split('.').all(|label| {
+                !label.is_empty()
+                    && label.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+            });
+            (host_is_valid, port)
+        }
+    };
+
+    let port_is_valid = match port.strip_prefix(':') {
+        Some(port) => port.bytes().all(|c| c.is_ascii_digit()) && port.parse::<u16>().is_ok(),
+        None => port.is_empty()
+    };
+
+    host_is_valid && port_is_valid
+}
+
+/// Host name the requests to an upstream registry are really sent to. Host names are case-insensitive,
+/// HTTPS is used anyway and Docker Hub goes by several names.
+pub fn resolve_upstream_registry(registry: &str) -> String {
+    let registry = registry.to_lowercase();
+    let registry = registry.strip_suffix(":443").unwrap_or(&registry);
+
+    match registry {
+        "docker.io" | "index.docker.io" => DOCKER_HUB_REGISTRY.to_string(),
+        registry => registry.to_string()
+    }
+}
+
+/// Proxied repository with its upstream registry resolved, such as `registry-1.docker.io/library/alpine`
+/// for `docker.io/library/alpine`. Cached content is stored under this name, so aliases of a registry
+/// share their cache and repositories of different registries never mix.
+pub fn resolve_upstream_container_ref(container_ref: &str) -> String {
+    match container_ref.split_once('/') {
+        Some((registry, container)) => format!("{}/{}", resolve_upstream_registry(registry), container),
+        None => container_ref.to_string()
+    }
+}
+
+/// Repository as found in the URLs, with the upstream registry of proxied repositories resolved
+pub fn resolve_repository(repository: &str) -> String {
+    match repository.strip_prefix("proxy/") {
+        Some(proxied_container_ref) => format!("proxy/{}", resolve_upstream_container_ref(proxied_container_ref)),
+        None => repository.to_string()
+    }
+}
 
-    (registry, container)
+/// Digests coming from documents are used in paths, only well-formed ones are accepted
+pub fn is_sha256_digest(digest: &str) -> bool {
+    matches!(digest.strip_prefix("sha256:"), Some(hash) if hash.len() == 64 && hash.bytes().all(|c| c.is_ascii_hexdigit()))
 }
 
 fn ref_is_valid(rref: &str) -> bool {
@@ -19,6 +19,17 @@ impl RegistryJsonErrorReprWrapper {
         }
     }
 
+    /// Error whose detail is a structured object rather than a message
+    pub fn single_with_detail<C, M>(code: C, message: M, detail: serde_json::Value) -> Self
+    where
+        C: ToString,
+        M: ToString,
+    {
+        Self {
+            errors: vec![RegistryJsonErrorRepr { code: code.to_string(), message: message.to_string(), detail }],
+        }
+    }
+
     #[allow(dead_code)]
     pub fn multiple(errors: &[RegistryJsonErrorRepr]) -> Self {
         Self {
@@ -31,7 +42,7 @@ impl RegistryJsonErrorReprWrapper {
 pub struct RegistryJsonErrorRepr {
     code: String,
     message: String,
-    detail: String,
+    detail: serde_json::Value,
 }
 
 impl RegistryJsonErrorRepr {
@@ -44,7 +55,7 @@ impl RegistryJsonErrorRepr {
         Self {
             code: code.to_string(),
             message: message.to_string(),
-            detail: detail.to_string(),
+            detail: serde_json::Value::String(detail.to_string()),
         }
     }
 }
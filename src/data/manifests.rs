@@ -1,18 +1,223 @@
-use std::path::{PathBuf, Path};
+use std::{collections::HashMap, path::{PathBuf, Path}, sync::{Arc, Mutex}, time::SystemTime};
 
 use axum::extract::BodyStream;
+use chrono::{DateTime, Utc};
 use eyre::ContextCompat;
 use futures_util::StreamExt;
+use once_cell::sync::Lazy;
 use serde::{Serialize, Deserialize};
-use tokio::io::AsyncWriteExt;
+use sha2::{Digest, Sha256};
+use tokio::{io::AsyncWriteExt, sync::OwnedMutexGuard};
+use tracing::warn;
 use uuid::Uuid;
 
-use super::helpers::{RegistryPathsHelper, file256sum_async};
+use super::{helpers::{RegistryPathsHelper, file256sum_async, is_sha256_digest}, encryption::StorageCipher};
+
+/// Locks of the tags being updated, by their link path. Entries are dropped along with the last guard.
+static TAG_LOCKS: Lazy<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> = Lazy::new(Default::default);
+
+/// Held while a manifest is saved and its tag linked, so concurrent pushes of the same tag don't interleave
+/// and leave the tag pointing to a manifest whose metadata is missing or half written
+pub struct TagLock {
+    tag_link_path: PathBuf,
+    _guard: OwnedMutexGuard<()>
+}
+
+impl Drop for TagLock {
+    fn drop(&mut self) {
+        let mut tag_locks = TAG_LOCKS.lock().unwrap();
+        // Held by the map and this guard only, nobody else is waiting for the tag
+        if tag_locks.get(&self.tag_link_path).map(|lock| Arc::strong_count(lock) <= 2).unwrap_or(false) {
+            tag_locks.remove(&self.tag_link_path);
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct ManifestMetadata<'a> {
     pub hash: &'a str,
     pub content_type: &'a str,
+    /// Missing from the metadata written by older versions
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Client which pushed the manifest, None for proxied manifests
+    #[serde(default)]
+    pub pushed_by: Option<String>,
+}
+
+/// Entry of the referrers index: a manifest referring to another one through its `subject` field.
+/// Shaped like the descriptors of the OCI referrers API.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReferrerDescriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The fields of a manifest needed to index it as a referrer
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestReferenceFields {
+    subject: Option<DescriptorDigest>,
+    artifact_type: Option<String>,
+    config: Option<DescriptorMediaType>,
+    annotations: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct DescriptorDigest {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DescriptorMediaType {
+    media_type: String,
+}
+
+/// Media types of the layers of cosign signatures and attestations
+const COSIGN_LAYER_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.dev.cosign.simplesigning.v1+json",
+    "application/vnd.dsse.envelope.v1+json",
+];
+
+/// Artifact types and layer media types of the signatures, SBOMs and attestations
+const DESCRIPTIVE_MEDIA_TYPES: [&str; 14] = [
+    "application/vnd.dev.cosign.artifact.sig.v1+json",
+    "application/vnd.dev.cosign.simplesigning.v1+json",
+    "application/vnd.cncf.notary.signature",
+    "application/jose+json",
+    "application/cose",
+    "application/vnd.dev.sigstore.bundle.v0.3+json",
+    "application/vnd.dev.sigstore.bundle+json;version=0.3",
+    "application/vnd.dsse.envelope.v1+json",
+    "application/vnd.in-toto+json",
+    "application/spdx+json",
+    "text/spdx",
+    "application/vnd.cyclonedx+json",
+    "application/vnd.cyclonedx+xml",
+    "application/vnd.syft+json",
+];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArtifactFields {
+    subject: Option<serde_json::Value>,
+    artifact_type: Option<String>,
+    config: Option<DescriptorMediaType>,
+    #[serde(default)]
+    layers: Vec<DescriptorMediaType>,
+}
+
+impl ArtifactFields {
+    /// Whether the manifest is a signature, SBOM or attestation rather than something to run. Its layers
+    /// have to be made of them, a runnable image can't get past the policies by naming another one as subject.
+    fn is_descriptive(&self) -> bool {
+        // Artifacts following the OCI 1.0 guidance tell their type by the media type of their configuration
+        let artifact_type = self.artifact_type
+            .as_deref()
+            .or_else(|| self.config.as_ref().map(|config| config.media_type.as_str()));
+        let descriptive_artifact_type = artifact_type.is_some_and(|artifact_type| DESCRIPTIVE_MEDIA_TYPES.contains(&artifact_type));
+
+        self.layers.iter().all(|layer| DESCRIPTIVE_MEDIA_TYPES.contains(&layer.media_type.as_str()))
+            && (descriptive_artifact_type || !self.layers.is_empty())
+    }
+}
+
+/// The fields of a manifest telling its media type, for manifests stored without their metadata
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaTypeFields {
+    media_type: Option<String>,
+    schema_version: Option<u64>,
+    manifests: Option<serde_json::Value>,
+}
+
+/// Media type of a manifest, from its `mediaType` field. OCI manifests may go without it, the shape
+/// of the document tells an index from an image manifest then.
+pub fn guess_manifest_media_type(manifest: &[u8]) -> String {
+    let fields = match serde_json::from_slice::<MediaTypeFields>(manifest) {
+        Ok(fields) => fields,
+        Err(_) => return "application/vnd.oci.image.manifest.v1+json".to_string()
+    };
+
+    match fields {
+        MediaTypeFields { media_type: Some(media_type), .. } => media_type,
+        MediaTypeFields { schema_version: Some(1), .. } => "application/vnd.docker.distribution.manifest.v1+prettyjws".to_string(),
+        MediaTypeFields { manifests: Some(_), .. } => "application/vnd.oci.image.index.v1+json".to_string(),
+        _ => "application/vnd.oci.image.manifest.v1+json".to_string()
+    }
+}
+
+#[derive(Deserialize)]
+struct IndexFields {
+    #[serde(default)]
+    manifests: Vec<DescriptorDigest>,
+}
+
+/// The image a signature, SBOM or attestation describes: the manifest named by its `subject`, or for the
+/// cosign signatures and attestations without one, the manifest named by the `sha256-<hex>.sig` style tag
+/// they are pushed under. Other manifests with a subject describe nothing, they stand on their own.
+pub fn described_manifest(manifest: &[u8], manifest_ref: &str) -> Option<String> {
+    let fields = serde_json::from_slice::<ArtifactFields>(manifest).ok()?;
+    if let Some(subject) = &fields.subject {
+        if !fields.is_descriptive() {
+            return None;
+        }
+        return subject.get("digest")
+            .and_then(|digest| digest.as_str())
+            .filter(|digest| is_sha256_digest(digest))
+            .map(|digest| digest.to_string());
+    }
+
+    let is_cosign_artifact = !fields.layers.is_empty()
+        && fields.layers.iter().all(|layer| COSIGN_LAYER_MEDIA_TYPES.contains(&layer.media_type.as_str()));
+    if !is_cosign_artifact {
+        return None;
+    }
+    let hash = manifest_ref
+        .strip_suffix(".sig")
+        .or_else(|| manifest_ref.strip_suffix(".att"))?
+        .strip_prefix("sha256-")?;
+    Some(format!("sha256:{}", hash)).filter(|digest| is_sha256_digest(digest))
+}
+
+/// Whether one of the indexes of the repository lists the manifest
+pub async fn listed_by_index(registry_root: &Path, container_ref: &str, digest: &str) -> eyre::Result<bool> {
+    let mut manifests = match tokio::fs::read_dir(RegistryPathsHelper::manifests_directory(registry_root, container_ref)).await {
+        Ok(manifests) => manifests,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into())
+    };
+
+    while let Some(entry) = manifests.next_entry().await? {
+        if !entry.file_name().to_str().is_some_and(is_sha256_digest) {
+            continue;
+        }
+        let manifest = tokio::fs::read(entry.path()).await?;
+        let listed = serde_json::from_slice::<IndexFields>(&manifest)
+            .is_ok_and(|index| index.manifests.iter().any(|child| child.digest == digest));
+        if listed {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// A tag of a repository and the manifest it points to
+pub struct TagLink {
+    pub name: String,
+    pub digest: Option<String>,
+    /// When the tag was last moved
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 pub struct Manifest {
@@ -21,11 +226,18 @@ pub struct Manifest {
     container_ref: String,
     registry_root: PathBuf,
     registry_temp_root: PathBuf,
+    storage_cipher: Option<Arc<StorageCipher>>,
+    pushed_by: Option<String>,
+    /// Set once the manifest is saved if it has a subject
+    reference_fields: Option<ManifestReferenceFields>,
+    size: u64,
 }
 
 pub enum ManifestContentSources<'a> {
     ServerRequest(&'a mut BodyStream),
-    ProxyResponse(&'a mut reqwest::Response)
+    ProxyResponse(&'a mut reqwest::Response),
+    /// Manifests made by the registry itself
+    Content(&'a [u8])
 }
 
 impl<'a> From<&'a mut BodyStream> for ManifestContentSources<'a> {
@@ -40,6 +252,12 @@ impl<'a> From<&'a mut reqwest::Response> for ManifestContentSources<'a> {
     }
 }
 
+impl<'a> From<&'a [u8]> for ManifestContentSources<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        ManifestContentSources::Content(value)
+    }
+}
+
 impl Manifest {
     pub fn new(registry_root: &Path, registry_temp_root: &Path, container_ref: &str, manifest_reference: &str) -> Self {
         let docker_hash = if manifest_reference.starts_with("sha256:") {
@@ -53,10 +271,32 @@ impl Manifest {
             manifest_reference: manifest_reference.to_string(),
             container_ref: container_ref.to_string(),
             registry_root: registry_root.to_path_buf(),
-            registry_temp_root: registry_temp_root.to_path_buf()
+            registry_temp_root: registry_temp_root.to_path_buf(),
+            storage_cipher: None,
+            pushed_by: None,
+            reference_fields: None,
+            size: 0
         }
     }
 
+    /// Encrypts the manifest before it is stored
+    pub fn with_encryption(mut self, storage_cipher: Option<Arc<StorageCipher>>) -> Self {
+        self.storage_cipher = storage_cipher;
+        self
+    }
+
+    /// Digest of a manifest already stored, to link a tag to it without saving it again
+    pub fn with_docker_hash(mut self, docker_hash: String) -> Self {
+        self.docker_hash = Some(docker_hash);
+        self
+    }
+
+    /// Records who pushed the manifest in its metadata
+    pub fn with_pusher(mut self, pushed_by: String) -> Self {
+        self.pushed_by = Some(pushed_by);
+        self
+    }
+
     pub async fn save_manifest(&mut self, manifest_content_source: ManifestContentSources<'_>) -> eyre::Result<()> {
         // Chicken and egg problem if the manifest reference is not a hash.
         // To make a hash, we need the file content to be saved on disk. To save on disk, we need a path.
@@ -65,7 +305,6 @@ impl Manifest {
         // To solve this problem, make a temporary file regardless of the manifest reference being a Docker
         // hash or not.
         let manifest_temporary_file_path = self.registry_temp_root.join(Uuid::new_v4().to_string());
-        let manifest_is_a_docker_hash = self.docker_hash.is_some();
 
         let mut manifest_temporary_file = tokio::fs::File::create(&manifest_temporary_file_path).await?;
         match manifest_content_source {
@@ -80,6 +319,9 @@ impl Manifest {
                     manifest_temporary_file.write_all(&chunk).await?;
                 }
             },
+            ManifestContentSources::Content(content) => {
+                manifest_temporary_file.write_all(content).await?;
+            },
         }
 
         let docker_hash = match &self.docker_hash {
@@ -92,26 +334,29 @@ impl Manifest {
             }
         };
 
-        // Paths for the manifest hash file and its named tag.
+        // Manifests are small, and only the ones with a subject are kept
+        let manifest_content = tokio::fs::read(&manifest_temporary_file_path).await?;
+        self.size = manifest_content.len() as u64;
+        self.reference_fields = serde_json::from_slice::<ManifestReferenceFields>(&manifest_content)
+            .ok()
+            .filter(|reference_fields| matches!(&reference_fields.subject, Some(subject) if is_sha256_digest(&subject.digest)));
+
+        // Manifests are only stored under their hash, tags are links to it.
         let manifest_hash_path = RegistryPathsHelper::manifest_path(&self.registry_root, &self.container_ref, docker_hash);
         let manifest_hash_parent = manifest_hash_path.parent().unwrap();
         if !manifest_hash_parent.is_dir() {
             tokio::fs::create_dir_all(&manifest_hash_parent).await?;
         }
 
+        // The hash is the one of the manifest itself, not of its encrypted version
+        let manifest_temporary_file_path = match &self.storage_cipher {
+            Some(storage_cipher) => storage_cipher.encrypt_file_async(manifest_temporary_file_path).await??,
+            None => manifest_temporary_file_path
+        };
+
         // Move the manifest to its destination file
         tokio::fs::rename(&manifest_temporary_file_path, &manifest_hash_path).await?;
 
-        // If the tag originally supplied by the caller was not a hash (see the first few lines of this function),
-        // then we copy the hash file as the current tag.
-
-        // This verification prevents overwriting the manifest file if it's a docker hash, 
-        // because the hash path and the tag one would be the same.
-        if !manifest_is_a_docker_hash {
-            let manifest_tag_path = RegistryPathsHelper::manifest_path(&self.registry_root, &self.container_ref, &self.manifest_reference);
-            tokio::fs::copy(&manifest_hash_path, &manifest_tag_path).await?;
-        }
-
         Ok(())
     }
 
@@ -124,18 +369,94 @@ impl Manifest {
             tokio::fs::create_dir_all(&manifest_metadata_parent).await?;
         }
 
+        // The same manifest can be pushed again, under another tag for instance
+        let now = Utc::now();
+        let created_at = match tokio::fs::read_to_string(&manifest_metadata_hash_path).await {
+            Ok(previous_metadata) => serde_json::from_str::<ManifestMetadata>(&previous_metadata)
+                .ok()
+                .and_then(|previous_metadata| previous_metadata.created_at),
+            Err(_) => None
+        };
+
         let manifest_metadata = ManifestMetadata {
             hash: &docker_hash.replace("sha256:", ""),
             content_type,
+            created_at: Some(created_at.unwrap_or(now)),
+            updated_at: Some(now),
+            pushed_by: self.pushed_by.clone(),
+        };
+
+        // Replaced in one go, readers never see a half written file
+        let manifest_metadata_temporary_path = self.registry_temp_root.join(Uuid::new_v4().to_string());
+        tokio::fs::write(&manifest_metadata_temporary_path, serde_json::to_string(&manifest_metadata)?).await?;
+        tokio::fs::rename(&manifest_metadata_temporary_path, &manifest_metadata_hash_path).await?;
+
+        self.index_subject(docker_hash, content_type).await?;
+
+        Ok(())
+    }
+
+    /// Records the manifest among the referrers of its subject, so they can be listed without reading every manifest.
+    async fn index_subject(&self, docker_hash: &str, content_type: &str) -> eyre::Result<()> {
+        let (reference_fields, subject) = match &self.reference_fields {
+            Some(reference_fields @ ManifestReferenceFields { subject: Some(subject), .. }) => (reference_fields, subject),
+            _ => return Ok(())
+        };
+
+        let referrer = ReferrerDescriptor {
+            media_type: content_type.to_string(),
+            digest: docker_hash.to_string(),
+            size: self.size,
+            // Per the OCI distribution spec, the media type of the config stands in for a missing artifact type
+            artifact_type: reference_fields.artifact_type
+                .clone()
+                .or_else(|| reference_fields.config.as_ref().map(|config| config.media_type.clone())),
+            annotations: reference_fields.annotations.clone(),
         };
 
-        let manifest_metadata_content = serde_json::to_string(&manifest_metadata)?;
-        let mut manifest_metadata_file = tokio::fs::File::create(&manifest_metadata_hash_path).await?;
-        manifest_metadata_file.write_all(manifest_metadata_content.as_bytes()).await?;
+        let referrers_path = RegistryPathsHelper::referrers_directory(&self.registry_root, &self.container_ref, &subject.digest);
+        tokio::fs::create_dir_all(&referrers_path).await?;
+        tokio::fs::write(referrers_path.join(docker_hash), serde_json::to_vec(&referrer)?).await?;
 
-        if !self.manifest_reference.starts_with("sha256:") {
-            let manifest_metadata_tag_path = RegistryPathsHelper::manifest_meta(&self.registry_root, &self.container_ref, &self.manifest_reference);
-            tokio::fs::copy(&manifest_metadata_hash_path, &manifest_metadata_tag_path).await?;
+        Ok(())
+    }
+
+    /// Waits for the other updates of the reference to be done. To be held from the manifest being saved to the
+    /// tag being linked.
+    pub async fn lock_tag(&self) -> TagLock {
+        let tag_link_path = RegistryPathsHelper::tag_link(&self.registry_root, &self.container_ref, &self.manifest_reference);
+        let lock = Arc::clone(TAG_LOCKS.lock().unwrap().entry(tag_link_path.clone()).or_default());
+
+        TagLock {
+            tag_link_path,
+            _guard: lock.lock_owned().await
+        }
+    }
+
+    /// Points the tag supplied by the caller to the saved manifest. Does nothing if the manifest was referenced
+    /// by its hash. The link file is replaced in one go, so readers see either the old or the new manifest.
+    pub async fn link_tag(&self) -> eyre::Result<()> {
+        if self.manifest_reference.starts_with("sha256:") {
+            return Ok(());
+        }
+
+        let docker_hash = self.docker_hash()?;
+        let tag_link_path = RegistryPathsHelper::tag_link(&self.registry_root, &self.container_ref, &self.manifest_reference);
+        tokio::fs::create_dir_all(tag_link_path.parent().unwrap()).await?;
+
+        let tag_link_temporary_path = self.registry_temp_root.join(Uuid::new_v4().to_string());
+        tokio::fs::write(&tag_link_temporary_path, docker_hash.as_bytes()).await?;
+        tokio::fs::rename(&tag_link_temporary_path, &tag_link_path).await?;
+
+        // Tags used to be full copies of the manifest and its metadata
+        for legacy_path in [
+            RegistryPathsHelper::manifest_path(&self.registry_root, &self.container_ref, &self.manifest_reference),
+            RegistryPathsHelper::manifest_meta(&self.registry_root, &self.container_ref, &self.manifest_reference)
+        ] {
+            match tokio::fs::remove_file(legacy_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => ()
+            }
         }
 
         Ok(())
@@ -146,4 +467,212 @@ impl Manifest {
             .as_ref()
             .context("Hash for the manifest has not been calculated yet")
     }
-}
\ No newline at end of file
+
+    /// Size of the saved manifest, in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+fn read_legacy_tag_digest(registry_root: &Path, container_ref: &str, tag: &str) -> Option<String> {
+    let manifest_meta = std::fs::read_to_string(RegistryPathsHelper::manifest_meta(registry_root, container_ref, tag)).ok()?;
+    serde_json::from_str::<ManifestMetadata>(&manifest_meta)
+        .ok()
+        .map(|manifest_meta| format!("sha256:{}", manifest_meta.hash))
+}
+
+/// Hash of the manifest a tag points to, None if the tag is unknown.
+/// Digest and media type of a stored manifest, from its metadata. Manifests missing their metadata, copied
+/// by hand or left behind by an interrupted push, get it worked out from their content and written back.
+pub async fn read_manifest_metadata(registry_root: &Path, container_ref: &str, manifest_digest: &str, manifest_content: &[u8]) -> eyre::Result<(String, String)> {
+    let manifest_meta_path = RegistryPathsHelper::manifest_meta(registry_root, container_ref, manifest_digest);
+    match tokio::fs::read_to_string(&manifest_meta_path).await {
+        Ok(manifest_meta) => {
+            if let Ok(manifest_meta) = serde_json::from_str::<ManifestMetadata>(&manifest_meta) {
+                return Ok((format!("sha256:{}", manifest_meta.hash), manifest_meta.content_type.to_string()));
+            }
+            warn!("Unreadable metadata for manifest {} of {}, rebuilding it", manifest_digest, container_ref);
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("Missing metadata for manifest {} of {}, rebuilding it", manifest_digest, container_ref);
+        },
+        Err(e) => return Err(e.into())
+    }
+
+    let hash = format!("{:x}", Sha256::digest(manifest_content));
+    let content_type = guess_manifest_media_type(manifest_content);
+    if format!("sha256:{}", hash) != manifest_digest {
+        warn!("Manifest {} of {} has the digest sha256:{}, leaving its metadata alone", manifest_digest, container_ref, hash);
+        return Ok((format!("sha256:{}", hash), content_type));
+    }
+
+    let created_at = tokio::fs::metadata(RegistryPathsHelper::manifest_path(registry_root, container_ref, manifest_digest))
+        .await
+        .and_then(|metadata| metadata.modified())
+        .map(DateTime::<Utc>::from)
+        .ok();
+    let manifest_metadata = ManifestMetadata {
+        hash: &hash,
+        content_type: &content_type,
+        created_at,
+        updated_at: created_at,
+        pushed_by: None,
+    };
+    tokio::fs::create_dir_all(manifest_meta_path.parent().unwrap()).await?;
+    tokio::fs::write(&manifest_meta_path, serde_json::to_string(&manifest_metadata)?).await?;
+
+    Ok((format!("sha256:{}", hash), content_type))
+}
+
+/// When the tag was last linked, which is also when a proxied tag was last confirmed against upstream
+pub async fn tag_linked_at(registry_root: &Path, container_ref: &str, tag: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(RegistryPathsHelper::tag_link(registry_root, container_ref, tag))
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub fn resolve_tag(registry_root: &Path, container_ref: &str, tag: &str) -> std::io::Result<Option<String>> {
+    match std::fs::read_to_string(RegistryPathsHelper::tag_link(registry_root, container_ref, tag)) {
+        Ok(docker_hash) => Ok(Some(docker_hash.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(read_legacy_tag_digest(registry_root, container_ref, tag)),
+        Err(e) => Err(e)
+    }
+}
+
+/// Tags of a repository sorted by name, including the ones stored as manifest copies by older versions.
+pub fn list_tags(registry_root: &Path, container_ref: &str) -> std::io::Result<Vec<TagLink>> {
+    let mut tags = Vec::new();
+
+    let tags_path = RegistryPathsHelper::tags_directory(registry_root, container_ref);
+    if tags_path.is_dir() {
+        for entry in std::fs::read_dir(&tags_path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            tags.push(TagLink {
+                name: entry.file_name().to_string_lossy().to_string(),
+                digest: std::fs::read_to_string(entry.path()).ok().map(|digest| digest.trim().to_string()),
+                updated_at: entry.metadata()?.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+    }
+
+    let manifests_path = RegistryPathsHelper::manifests_directory(registry_root, container_ref);
+    if manifests_path.is_dir() {
+        for entry in std::fs::read_dir(&manifests_path)? {
+            let entry = entry?;
+            let tag = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type()?.is_file() || tag.starts_with("sha256:") || tags.iter().any(|tag_link| tag_link.name == tag) {
+                continue;
+            }
+
+            tags.push(TagLink {
+                digest: read_legacy_tag_digest(registry_root, container_ref, &tag),
+                name: tag,
+                updated_at: entry.metadata()?.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+    }
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(tags)
+}
+
+/// Hashes of every manifest stored in a repository, tagged or not, sorted.
+pub fn list_manifest_digests(registry_root: &Path, container_ref: &str) -> std::io::Result<Vec<String>> {
+    let manifests_path = RegistryPathsHelper::manifests_directory(registry_root, container_ref);
+    if !manifests_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut digests = Vec::new();
+    for entry in std::fs::read_dir(&manifests_path)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_file() && file_name.starts_with("sha256:") {
+            digests.push(file_name);
+        }
+    }
+    digests.sort();
+
+    Ok(digests)
+}
+
+/// Hash of the manifest a reference points to, whether it is a tag or a hash already.
+pub fn resolve_manifest_reference_async(registry_root: PathBuf, container_ref: String, manifest_ref: String) -> tokio::task::JoinHandle<std::io::Result<Option<String>>> {
+    tokio::task::spawn_blocking(move || {
+        if manifest_ref.starts_with("sha256:") {
+            return Ok(Some(manifest_ref));
+        }
+
+        resolve_tag(&registry_root, &container_ref, &manifest_ref)
+    })
+}
+
+/// Manifests of a repository referring to the given one through their `subject` field.
+pub fn list_referrers(registry_root: &Path, container_ref: &str, subject_digest: &str) -> std::io::Result<Vec<ReferrerDescriptor>> {
+    let referrers_path = RegistryPathsHelper::referrers_directory(registry_root, container_ref, subject_digest);
+    if !referrers_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut referrers = Vec::new();
+    for entry in std::fs::read_dir(&referrers_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let referrer = serde_json::from_slice::<ReferrerDescriptor>(&std::fs::read(entry.path())?)?;
+        referrers.push(referrer);
+    }
+    referrers.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+    Ok(referrers)
+}
+
+pub fn list_referrers_async(registry_root: PathBuf, container_ref: String, subject_digest: String) -> tokio::task::JoinHandle<std::io::Result<Vec<ReferrerDescriptor>>> {
+    tokio::task::spawn_blocking(move || list_referrers(&registry_root, &container_ref, &subject_digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::described_manifest;
+
+    const SUBJECT: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn manifest_with_subject(artifact_type: Option<&str>, layer_media_types: &[&str]) -> Vec<u8> {
+        let mut manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": { "mediaType": "application/vnd.oci.empty.v1+json", "digest": SUBJECT, "size": 2 },
+            "layers": layer_media_types.iter().map(|media_type| serde_json::json!({ "mediaType": media_type, "digest": SUBJECT, "size": 1 })).collect::<Vec<_>>(),
+            "subject": { "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": SUBJECT, "size": 1 }
+        });
+        if let Some(artifact_type) = artifact_type {
+            manifest["artifactType"] = artifact_type.into();
+        }
+        serde_json::to_vec(&manifest).unwrap()
+    }
+
+    #[test]
+    fn only_descriptive_manifests_describe_their_subject() {
+        let signature = manifest_with_subject(Some("application/vnd.cncf.notary.signature"), &["application/jose+json"]);
+        assert_eq!(described_manifest(&signature, "latest").as_deref(), Some(SUBJECT));
+        let sbom = manifest_with_subject(None, &["application/spdx+json"]);
+        assert_eq!(described_manifest(&sbom, "latest").as_deref(), Some(SUBJECT));
+        let attestation = manifest_with_subject(Some("application/vnd.in-toto+json"), &[]);
+        assert_eq!(described_manifest(&attestation, "latest").as_deref(), Some(SUBJECT));
+
+        // Runnable images are judged on their own, whatever subject or artifact type they claim
+        let image = manifest_with_subject(None, &["application/vnd.oci.image.layer.v1.tar+gzip"]);
+        assert_eq!(described_manifest(&image, "latest"), None);
+        let disguised_image = manifest_with_subject(Some("application/spdx+json"), &["application/spdx+json", "application/vnd.oci.image.layer.v1.tar+gzip"]);
+        assert_eq!(described_manifest(&disguised_image, "latest"), None);
+        let untyped_artifact = manifest_with_subject(None, &[]);
+        assert_eq!(described_manifest(&untyped_artifact, "latest"), None);
+    }
+}
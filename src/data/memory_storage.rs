@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{configuration::{Configuration, MemoryStorageConfiguration}, controllers::RegistryHttpError};
+
+use super::helpers::directory_size_async;
+
+/// Shared memory filesystem, whose files never reach a disk
+const SHARED_MEMORY_ROOT: &str = "/dev/shm";
+
+/// Storage roots of the registry kept in a directory of the shared memory filesystem, private to this
+/// instance and deleted along with it. The rest of the registry sees regular storage roots.
+pub struct MemoryStorage {
+    root: PathBuf,
+    max_bytes: u64
+}
+
+impl MemoryStorage {
+    /// Fails on systems without a shared memory filesystem, rather than writing the storage to a disk
+    pub fn create(configuration: &MemoryStorageConfiguration) -> eyre::Result<Self> {
+        if !Path::new(SHARED_MEMORY_ROOT).is_dir() {
+            return Err(eyre::eyre!("The storage can't be kept in memory without a shared memory filesystem at {}", SHARED_MEMORY_ROOT));
+        }
+
+        let root = Path::new(SHARED_MEMORY_ROOT).join(format!("docker-registry-{}", Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&root)?;
+        info!("Keeping the storage in memory, in {} (up to {} bytes)", root.display(), configuration.max_bytes);
+
+        Ok(Self {
+            root,
+            max_bytes: configuration.max_bytes
+        })
+    }
+
+    /// Points the storage roots of the configuration and of its tenants to the memory storage
+    pub fn relocate(&self, configuration: &mut Configuration) {
+        configuration.registry_storage = self.root.join("registry");
+        configuration.temporary_registry_storage = self.root.join("_tmp");
+        configuration.proxy_storage = self.root.join("proxy");
+
+        for tenant in &mut configuration.tenants {
+            let tenant_root = self.root.join("tenants").join(&tenant.name);
+            tenant.registry_storage = tenant_root.join("registry");
+            tenant.temporary_registry_storage = tenant_root.join("_tmp");
+            tenant.proxy_storage = tenant_root.join("proxy");
+        }
+    }
+
+    /// Refuses pushes once the memory storage is full, whoever the content belongs to
+    pub async fn check_capacity(&self) -> Result<(), RegistryHttpError> {
+        self.check_capacity_for(0).await
+    }
+
+    /// Refuses to store `incoming_bytes` more once the memory storage would go past its capacity
+    pub async fn check_capacity_for(&self, incoming_bytes: u64) -> Result<(), RegistryHttpError> {
+        let used_bytes = directory_size_async(self.root.clone()).await??;
+        if used_bytes.saturating_add(incoming_bytes) > self.max_bytes || used_bytes >= self.max_bytes {
+            info!("The memory storage is full: {} of {} bytes used, {} more bytes refused", used_bytes, self.max_bytes, incoming_bytes);
+            return Err(RegistryHttpError::StorageFull { used_bytes, max_bytes: self.max_bytes });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStorage {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.root) {
+            warn!("Unable to delete the memory storage {}: {}", self.root.display(), e);
+        }
+    }
+}
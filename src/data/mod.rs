@@ -1,4 +1,18 @@
 pub mod uploads;
+pub mod blob_storage;
 pub mod json_registry_error;
+pub mod cache_stats;
+pub mod cache_watermark;
+pub mod compression;
+pub mod deduplication;
+pub mod encryption;
+pub mod garbage_collection;
 pub mod helpers;
-pub mod manifests;
\ No newline at end of file
+pub mod manifests;
+pub mod memory_storage;
+pub mod open_blobs;
+pub mod operation_metrics;
+pub mod proxy_cache;
+pub mod rate_limits;
+pub mod scans;
+pub mod throttling;
\ No newline at end of file
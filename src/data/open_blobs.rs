@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fmt::Write, fs::{File, Metadata}, io, ops::Range, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Instant};
+
+use axum::body::Bytes;
+use futures::{stream, Stream};
+
+/// Size of the pieces a range is read in
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Cached blobs kept open for their ranged reads. Lazy-pulling snapshotters, like the stargz one, read
+/// layers in many small ranges at once, and opening the file for each of them costs more than the read
+/// itself. Reads are positional, so every reader of a blob shares its descriptor without seeking it.
+#[derive(Clone)]
+pub struct OpenBlobs {
+    capacity: usize,
+    blobs: Arc<Mutex<HashMap<PathBuf, OpenBlob>>>,
+    metrics: Arc<Mutex<OpenBlobsMetrics>>
+}
+
+struct OpenBlob {
+    file: Arc<File>,
+    metadata: Metadata,
+    last_used: Instant
+}
+
+#[derive(Default)]
+struct OpenBlobsMetrics {
+    hits: u64,
+    misses: u64
+}
+
+impl OpenBlobs {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blobs: Default::default(),
+            metrics: Default::default()
+        }
+    }
+
+    /// The open file of a blob, along with its metadata. The least recently used blob is closed when more
+    /// than the capacity are open.
+    pub async fn open(&self, path: &Path) -> io::Result<(Arc<File>, Metadata)> {
+        if let Some(open_blob) = self.blobs.lock().unwrap().get_mut(path) {
+            open_blob.last_used = Instant::now();
+            self.metrics.lock().unwrap().hits += 1;
+            return Ok((Arc::clone(&open_blob.file), open_blob.metadata.clone()));
+        }
+
+        let blob_path = path.to_path_buf();
+        let (file, metadata) = tokio::task::spawn_blocking(move || {
+            let file = File::open(blob_path)?;
+            let metadata = file.metadata()?;
+            Ok::<_, io::Error>((Arc::new(file), metadata))
+        }).await??;
+        self.metrics.lock().unwrap().misses += 1;
+
+        let mut blobs = self.blobs.lock().unwrap();
+        if blobs.len() >= self.capacity {
+            let least_recently_used = blobs.iter()
+                .min_by_key(|(_, open_blob)| open_blob.last_used)
+                .map(|(path, _)| path.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                blobs.remove(&least_recently_used);
+            }
+        }
+        // Another reader may have opened it meanwhile, either descriptor will do
+        blobs.insert(path.to_path_buf(), OpenBlob { file: Arc::clone(&file), metadata: metadata.clone(), last_used: Instant::now() });
+
+        Ok((file, metadata))
+    }
+
+    /// Closes the blob, for instance once it's removed from the cache
+    pub fn forget(&self, path: &Path) {
+        self.blobs.lock().unwrap().remove(path);
+    }
+
+    /// Writes the metrics in the Prometheus text format
+    pub fn render(&self, output: &mut String) {
+        let metrics = self.metrics.lock().unwrap();
+
+        writeln!(output, "# HELP open_blob_hits_total Ranged reads of cached blobs that found them already open").unwrap();
+        writeln!(output, "# TYPE open_blob_hits_total counter").unwrap();
+        writeln!(output, "open_blob_hits_total {}", metrics.hits).unwrap();
+
+        writeln!(output, "# HELP open_blob_misses_total Ranged reads of cached blobs that had to open them").unwrap();
+        writeln!(output, "# TYPE open_blob_misses_total counter").unwrap();
+        writeln!(output, "open_blob_misses_total {}", metrics.misses).unwrap();
+
+        writeln!(output, "# HELP open_blobs Cached blobs kept open for their ranged reads").unwrap();
+        writeln!(output, "# TYPE open_blobs gauge").unwrap();
+        writeln!(output, "open_blobs {}", self.blobs.lock().unwrap().len()).unwrap();
+    }
+}
+
+/// Streams a range of an open blob, read in pieces on blocking threads
+pub fn read_range(file: Arc<File>, range: Range<u64>) -> impl Stream<Item = io::Result<Bytes>> + Send {
+    stream::try_unfold(range, move |range| {
+        let file = Arc::clone(&file);
+        async move {
+            if range.is_empty() {
+                return Ok(None);
+            }
+
+            let length = READ_CHUNK_SIZE.min(range.end - range.start);
+            let chunk = tokio::task::spawn_blocking(move || {
+                let mut chunk = vec![0; length as usize];
+                read_exact_at(&file, &mut chunk, range.start)?;
+                Ok::<_, io::Error>(chunk)
+            }).await??;
+
+            Ok(Some((Bytes::from(chunk), range.start + length..range.end)))
+        }
+    })
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buffer.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buffer, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            },
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io, ops::Range, path::{Path, PathBuf}, sync::Arc};
+
+    use futures::TryStreamExt;
+    use uuid::Uuid;
+
+    use super::{OpenBlobs, read_range, READ_CHUNK_SIZE};
+
+    /// Removed along with its blobs once the test is done
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("open-blobs-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// A blob whose every byte tells its offset, so misplaced reads show
+        fn blob(&self, name: &str, size: u64) -> (PathBuf, Vec<u8>) {
+            let content = (0..size).map(|offset| (offset % 251) as u8).collect::<Vec<_>>();
+            let path = self.0.join(name);
+            std::fs::write(&path, &content).unwrap();
+            (path, content)
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    async fn read(file: Arc<File>, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let chunks = read_range(file, range).try_collect::<Vec<_>>().await?;
+        Ok(chunks.concat())
+    }
+
+    async fn open_and_read(open_blobs: &OpenBlobs, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let (file, _) = open_blobs.open(path).await?;
+        read(file, range).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_ranged_reads() {
+        let directory = TestDirectory::new();
+        let (path, content) = directory.blob("layer", 3 * READ_CHUNK_SIZE + 123);
+        let open_blobs = OpenBlobs::new(4);
+
+        // Many small ranges of the same layer at once, as lazy-pulling snapshotters do, some across chunks
+        let reads = (0..256u64).map(|read| {
+            let open_blobs = open_blobs.clone();
+            let path = path.clone();
+            let start = (read * 7919) % (content.len() as u64 - 1);
+            let end = (start + 1 + read * 613).min(content.len() as u64);
+            tokio::spawn(async move { (start..end, open_and_read(&open_blobs, &path, start..end).await) })
+        });
+
+        for read in futures::future::join_all(reads).await {
+            let (range, bytes) = read.unwrap();
+            assert_eq!(bytes.unwrap(), &content[range.start as usize..range.end as usize], "range {:?}", range);
+        }
+
+        // Readers opening the blob at the same time may each open it, but the blob stays open once
+        assert_eq!(open_blobs.blobs.lock().unwrap().len(), 1);
+        let hits = {
+            let metrics = open_blobs.metrics.lock().unwrap();
+            assert_eq!(metrics.hits + metrics.misses, 256);
+            metrics.hits
+        };
+        open_and_read(&open_blobs, &path, 0..1).await.unwrap();
+        assert_eq!(open_blobs.metrics.lock().unwrap().hits, hits + 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_reads_of_more_blobs_than_the_capacity() {
+        let directory = TestDirectory::new();
+        let blobs = (0..8).map(|blob| directory.blob(&format!("layer-{}", blob), READ_CHUNK_SIZE + blob * 1000)).collect::<Vec<_>>();
+        let open_blobs = OpenBlobs::new(2);
+
+        let reads = (0..128usize).map(|read| {
+            let open_blobs = open_blobs.clone();
+            let (path, content) = blobs[read % blobs.len()].clone();
+            tokio::spawn(async move {
+                let range = (read as u64 * 37) % 1000..content.len() as u64;
+                let bytes = open_and_read(&open_blobs, &path, range.clone()).await.unwrap();
+                assert_eq!(bytes, &content[range.start as usize..]);
+            })
+        });
+
+        for read in futures::future::join_all(reads).await {
+            read.unwrap();
+        }
+        assert!(open_blobs.blobs.lock().unwrap().len() <= 2);
+    }
+
+    #[tokio::test]
+    async fn eviction_while_a_read_is_open() {
+        let directory = TestDirectory::new();
+        let (first_path, first_content) = directory.blob("first", 4 * READ_CHUNK_SIZE);
+        let (second_path, second_content) = directory.blob("second", READ_CHUNK_SIZE);
+        let open_blobs = OpenBlobs::new(1);
+
+        let (file, _) = open_blobs.open(&first_path).await.unwrap();
+        let mut first_read = Box::pin(read_range(file, 0..first_content.len() as u64));
+        let first_chunk = first_read.try_next().await.unwrap().unwrap();
+
+        // Opening the second blob closes the first one, whose reader keeps its descriptor
+        assert_eq!(open_and_read(&open_blobs, &second_path, 0..second_content.len() as u64).await.unwrap(), second_content);
+        assert!(!open_blobs.blobs.lock().unwrap().contains_key(&first_path));
+
+        let mut first_bytes = first_chunk.to_vec();
+        while let Some(chunk) = first_read.try_next().await.unwrap() {
+            first_bytes.extend_from_slice(&chunk);
+        }
+        assert_eq!(first_bytes, first_content);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn blob_removed_while_a_read_is_open() {
+        let directory = TestDirectory::new();
+        let (path, content) = directory.blob("corrupted", 2 * READ_CHUNK_SIZE);
+        let open_blobs = OpenBlobs::new(4);
+
+        let (file, _) = open_blobs.open(&path).await.unwrap();
+        // Removed from the cache, as done with the blobs failing their verification
+        std::fs::remove_file(&path).unwrap();
+        open_blobs.forget(&path);
+
+        assert_eq!(read(file, 0..content.len() as u64).await.unwrap(), content);
+        assert_eq!(open_blobs.open(&path).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn range_past_the_end_of_the_blob() {
+        let directory = TestDirectory::new();
+        let (path, content) = directory.blob("short", 100);
+        let open_blobs = OpenBlobs::new(1);
+
+        let error = open_and_read(&open_blobs, &path, 50..content.len() as u64 + 1).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
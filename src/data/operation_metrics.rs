@@ -0,0 +1,102 @@
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use axum::http::Method;
+use once_cell::sync::Lazy;
+
+use crate::docker_client::metrics::LatencyHistogram;
+
+static OPERATION_METRICS: Lazy<Mutex<BTreeMap<&'static str, OperationMetrics>>> = Lazy::new(Default::default);
+
+/// Requests handled by each operation of the registry, so SLOs can be defined per operation
+#[derive(Default)]
+struct OperationMetrics {
+    in_flight: u64,
+    /// Responses by status class, such as `2xx`
+    responses: BTreeMap<&'static str, u64>,
+    duration: LatencyHistogram
+}
+
+/// Name of the operation served by a route, from the route path as declared in the router
+pub fn operation_name(method: &Method, route: &str) -> &'static str {
+    let is_head = *method == Method::HEAD;
+    match (method.as_str(), route) {
+        (_, "/v2/") => "base",
+        (_, "/token") => "token_issue",
+        ("GET" | "HEAD", "/v2/:container_ref/manifests/:reference") => if is_head { "manifest_head" } else { "manifest_get" },
+        ("PUT", "/v2/:container_ref/manifests/:reference") => "manifest_put",
+        ("GET" | "HEAD", "/v2/:container_ref/blobs/:digest") => if is_head { "blob_head" } else { "blob_get" },
+        ("POST", "/v2/:container_ref/blobs/uploads/") => "blob_upload_start",
+        ("PATCH", "/v2/:container_ref/blobs/uploads/:uuid") => "blob_upload_chunk",
+        ("PUT", "/v2/:container_ref/blobs/uploads/:uuid") => "blob_upload_finish",
+        ("DELETE", "/v2/:container_ref/blobs/uploads/:uuid") => "blob_upload_cancel",
+        ("GET" | "HEAD", "/v2/proxy/:container_ref/manifests/:reference") => if is_head { "proxy_manifest_head" } else { "proxy_manifest_get" },
+        ("GET" | "HEAD", "/v2/proxy/:container_ref/blobs/:digest") => "proxy_blob",
+        ("GET", "/api/images/*path") => "image_resource_get",
+        ("PUT", "/api/images/*path") => "sbom_put",
+        ("GET", "/metrics") => "metrics",
+        ("GET", "/openapi.json") => "openapi",
+        (_, route) if route.starts_with("/admin/") => "admin",
+        _ => "other"
+    }
+}
+
+/// Request being handled, counted in flight until dropped. Requests abandoned by their client are dropped
+/// without a response, and only leave the in-flight gauge.
+pub struct OperationInFlight {
+    operation: &'static str
+}
+
+impl OperationInFlight {
+    pub fn start(operation: &'static str) -> Self {
+        OPERATION_METRICS.lock().unwrap().entry(operation).or_default().in_flight += 1;
+        Self { operation }
+    }
+
+    pub fn finish(self, status: u16, duration: Duration) {
+        let status_class = match status {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx"
+        };
+
+        let mut metrics = OPERATION_METRICS.lock().unwrap();
+        let operation_metrics = metrics.entry(self.operation).or_default();
+        *operation_metrics.responses.entry(status_class).or_insert(0) += 1;
+        operation_metrics.duration.observe(duration);
+    }
+}
+
+impl Drop for OperationInFlight {
+    fn drop(&mut self) {
+        if let Some(operation_metrics) = OPERATION_METRICS.lock().unwrap().get_mut(self.operation) {
+            operation_metrics.in_flight = operation_metrics.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// Writes the operation metrics in the Prometheus text format
+pub fn render_operation_metrics(output: &mut String) {
+    let metrics = OPERATION_METRICS.lock().unwrap();
+
+    writeln!(output, "# HELP http_operation_in_flight Requests being handled by operation").unwrap();
+    writeln!(output, "# TYPE http_operation_in_flight gauge").unwrap();
+    for (operation, operation_metrics) in metrics.iter() {
+        writeln!(output, "http_operation_in_flight{{operation=\"{}\"}} {}", operation, operation_metrics.in_flight).unwrap();
+    }
+
+    writeln!(output, "# HELP http_operation_responses_total Responses sent by operation and status class").unwrap();
+    writeln!(output, "# TYPE http_operation_responses_total counter").unwrap();
+    for (operation, operation_metrics) in metrics.iter() {
+        for (status_class, count) in &operation_metrics.responses {
+            writeln!(output, "http_operation_responses_total{{operation=\"{}\",status=\"{}\"}} {}", operation, status_class, count).unwrap();
+        }
+    }
+
+    writeln!(output, "# HELP http_operation_duration_seconds Time until the response headers are sent, by operation").unwrap();
+    writeln!(output, "# TYPE http_operation_duration_seconds histogram").unwrap();
+    for (operation, operation_metrics) in metrics.iter() {
+        operation_metrics.duration.render(output, "http_operation_duration_seconds", &format!("operation=\"{}\"", operation));
+    }
+}
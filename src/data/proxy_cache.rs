@@ -0,0 +1,353 @@
+use std::{io::Write, path::{Component, Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::{helpers::{directory_size, is_sha256_digest, RegistryPathsHelper, resolve_upstream_registry, resolve_upstream_container_ref}, manifests};
+
+/// Directory holding the manifests, metadata and blobs of a repository
+const REPOSITORY_DIRECTORY: &str = "_repository";
+
+#[derive(Serialize)]
+pub struct RepositorySummary {
+    pub name: String,
+    pub tags: usize,
+    pub manifests: usize,
+    pub blobs: usize,
+    pub size_bytes: u64,
+}
+
+/// Written next to a cached blob once its content has been checked against its digest
+#[derive(Serialize, Deserialize)]
+pub struct BlobMetadata {
+    pub digest: String,
+    pub size: u64,
+    pub verified_at: DateTime<Utc>,
+    /// Missing from the metadata written by older versions
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl BlobMetadata {
+    /// Metadata of a blob whose content was just checked against its digest
+    pub fn verified(digest: &str, size: u64) -> Self {
+        let now = Utc::now();
+        Self {
+            digest: digest.to_string(),
+            size,
+            verified_at: now,
+            created_at: Some(now),
+            updated_at: Some(now),
+        }
+    }
+
+    pub async fn load(root: &Path, name: &str, digest: &str) -> Option<Self> {
+        let metadata = tokio::fs::read(RegistryPathsHelper::blob_meta(root, name, digest)).await.ok()?;
+        serde_json::from_slice(&metadata).ok()
+    }
+
+    /// Keeps the creation time of the blob if it was cached before
+    pub async fn save(mut self, root: &Path, name: &str) -> std::io::Result<()> {
+        if let Some(previous_metadata) = Self::load(root, name, &self.digest).await {
+            self.created_at = previous_metadata.created_at.or(self.created_at);
+        }
+
+        let metadata_path = RegistryPathsHelper::blob_meta(root, name, &self.digest);
+        tokio::fs::create_dir_all(metadata_path.parent().unwrap()).await?;
+        tokio::fs::write(metadata_path, serde_json::to_vec(&self)?).await
+    }
+}
+
+/// Names of the repositories stored under a storage root, such as `registry-1.docker.io/library/alpine`.
+pub fn list_repositories(root: &Path) -> std::io::Result<Vec<String>> {
+    let mut repositories = Vec::new();
+    find_repositories(root, root, &mut repositories)?;
+    repositories.sort();
+
+    Ok(repositories)
+}
+
+fn find_repositories(root: &Path, directory: &Path, repositories: &mut Vec<String>) -> std::io::Result<()> {
+    if !directory.is_dir() {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_name() == REPOSITORY_DIRECTORY {
+            if let Ok(name) = directory.strip_prefix(root) {
+                repositories.push(name.to_string_lossy().to_string());
+            }
+        } else {
+            // Repository names can be nested in one another, like `library/alpine` and `library/alpine/edge`
+            find_repositories(root, &path, repositories)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the repositories cached under an alias of their upstream registry, such as `docker.io`, to the
+/// directory of the resolved registry. Files already present in both are kept from the resolved registry.
+/// Returns the names of the migrated registry directories.
+pub fn migrate_registry_aliases(root: &Path) -> std::io::Result<Vec<String>> {
+    let mut migrated = Vec::new();
+
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let registry = entry.file_name().to_string_lossy().to_string();
+        let resolved_registry = resolve_upstream_registry(&registry);
+        if resolved_registry != registry {
+            merge_directories(&entry.path(), &root.join(&resolved_registry))?;
+            migrated.push(registry);
+        }
+    }
+
+    Ok(migrated)
+}
+
+fn merge_directories(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if !destination.exists() {
+        return std::fs::rename(source, destination);
+    }
+
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let destination_path = destination.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            merge_directories(&entry.path(), &destination_path)?;
+        } else if !destination_path.exists() {
+            std::fs::rename(entry.path(), destination_path)?;
+        }
+    }
+
+    // Only duplicates are left behind
+    std::fs::remove_dir_all(source)
+}
+
+fn count_files(directory: &Path, filter: impl Fn(&str) -> bool) -> std::io::Result<usize> {
+    if !directory.is_dir() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && filter(&entry.file_name().to_string_lossy()) {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+pub fn summarize_repository(root: &Path, name: &str) -> std::io::Result<RepositorySummary> {
+    let repository_path = root.join(name).join(REPOSITORY_DIRECTORY);
+
+    Ok(RepositorySummary {
+        name: name.to_string(),
+        tags: manifests::list_tags(root, name)?.len(),
+        manifests: manifests::list_manifest_digests(root, name)?.len(),
+        blobs: count_files(&repository_path.join("blobs"), |_| true)?,
+        size_bytes: directory_size(&repository_path)?,
+    })
+}
+
+#[derive(Serialize)]
+pub struct CachedTag {
+    pub name: String,
+    pub digest: Option<String>,
+    /// When the tag was last fetched from the upstream registry
+    pub cached_at: Option<DateTime<Utc>>,
+}
+
+/// Tags of a repository, with the manifest they currently point to in the cache.
+pub fn list_tags(root: &Path, name: &str) -> std::io::Result<Vec<CachedTag>> {
+    Ok(manifests::list_tags(root, name)?
+        .into_iter()
+        .map(|tag| CachedTag {
+            name: tag.name,
+            digest: tag.digest,
+            cached_at: tag.updated_at,
+        })
+        .collect())
+}
+
+#[derive(Serialize)]
+pub struct RepositoryDetails {
+    #[serde(flatten)]
+    pub summary: RepositorySummary,
+    pub tag_list: Vec<CachedTag>,
+}
+
+pub fn repository_details_async(root: PathBuf, name: String) -> tokio::task::JoinHandle<std::io::Result<Option<RepositoryDetails>>> {
+    tokio::task::spawn_blocking(move || {
+        if !root.join(&name).join(REPOSITORY_DIRECTORY).is_dir() {
+            return Ok(None);
+        }
+
+        Ok(Some(RepositoryDetails {
+            summary: summarize_repository(&root, &name)?,
+            tag_list: list_tags(&root, &name)?
+        }))
+    })
+}
+
+pub fn summarize_repositories_async(root: PathBuf) -> tokio::task::JoinHandle<std::io::Result<Vec<RepositorySummary>>> {
+    tokio::task::spawn_blocking(move || {
+        list_repositories(&root)?
+            .iter()
+            .map(|name| summarize_repository(&root, name))
+            .collect()
+    })
+}
+
+/// Writes the given repositories, or all of them, to a tar archive. Paths in the archive are the ones in
+/// the storage, so the digests and metadata of the manifests are kept as is.
+pub fn export_repositories<W: std::io::Write>(root: &Path, names: &[String], writer: W) -> std::io::Result<Vec<String>> {
+    let names = if names.is_empty() {
+        list_repositories(root)?
+    } else {
+        names.to_vec()
+    };
+
+    let mut archive = tar::Builder::new(writer);
+    for name in &names {
+        let repository_path = root.join(name).join(REPOSITORY_DIRECTORY);
+        if !repository_path.is_dir() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Repository {} is not in the cache", name)));
+        }
+
+        archive.append_dir_all(Path::new(name).join(REPOSITORY_DIRECTORY), &repository_path)?;
+    }
+    archive.finish()?;
+
+    Ok(names)
+}
+
+/// Directories of a cached repository an archive may hold files in, with how deep the files are in them
+const REPOSITORY_CONTENT: [(&str, usize); 7] = [
+    ("blobs", 1),
+    ("blob_meta", 1),
+    ("manifests", 1),
+    ("meta", 1),
+    ("tags", 1),
+    ("scans", 1),
+    ("referrers", 2)
+];
+
+/// Where an archive entry goes in the storage: the repository it belongs to, and the hash its content
+/// must have for the blobs and the manifests stored by digest
+struct ImportedFile {
+    repository: String,
+    expected_hash: Option<String>
+}
+
+impl ImportedFile {
+    /// Only files of the repository content are accepted, under plain relative paths
+    fn from_archive_path(path: &Path) -> Option<Self> {
+        let components = path.components()
+            .map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let repository_directory = components.iter().position(|name| *name == REPOSITORY_DIRECTORY)?;
+        let (repository, content) = (&components[..repository_directory], &components[repository_directory + 1..]);
+        let (kind, names) = content.split_first()?;
+        let depth = REPOSITORY_CONTENT.iter().find(|(directory, _)| directory == kind)?.1;
+        if repository.is_empty() || names.len() != depth {
+            return None;
+        }
+
+        let name = names[0];
+        let expected_hash = match *kind {
+            "blobs" if is_sha256_digest(&format!("sha256:{}", name)) => Some(name.to_string()),
+            "blobs" => return None,
+            "manifests" if is_sha256_digest(name) => Some(name.trim_start_matches("sha256:").to_string()),
+            "blob_meta" | "scans" | "referrers" if !is_sha256_digest(name) => return None,
+            _ => None
+        };
+
+        Some(Self { repository: repository.join("/"), expected_hash })
+    }
+}
+
+/// Extracts an archive made by `export_repositories` into the storage. Returns the imported repositories.
+/// Each file is written aside first, blobs and manifests are checked against their digest before being
+/// moved into place.
+pub fn import_repositories<R: std::io::Read>(root: &Path, reader: R) -> std::io::Result<Vec<String>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut names = Vec::new();
+    let invalid_data = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => continue,
+            tar::EntryType::Regular | tar::EntryType::Continuous => (),
+            entry_type => return Err(invalid_data(format!("Unexpected {:?} entry {:?} in the archive", entry_type, path)))
+        }
+        let imported_file = ImportedFile::from_archive_path(&path)
+            .ok_or_else(|| invalid_data(format!("Unexpected path {:?} in the archive", path)))?;
+
+        let staging_path = root.join(format!(".import-{}", Uuid::new_v4()));
+        let staged = stage_archive_file(&mut entry, &staging_path).and_then(|actual_hash| {
+            if let Some(expected_hash) = imported_file.expected_hash.as_deref().filter(|expected_hash| *expected_hash != actual_hash) {
+                return Err(invalid_data(format!("{:?} doesn't match its digest sha256:{}, got sha256:{}", path, expected_hash, actual_hash)));
+            }
+
+            let destination = root.join(&path);
+            std::fs::create_dir_all(destination.parent().unwrap())?;
+            std::fs::rename(&staging_path, destination)
+        });
+        if let Err(e) = staged {
+            let _ = std::fs::remove_file(&staging_path);
+            return Err(e);
+        }
+
+        let repository = resolve_upstream_container_ref(&imported_file.repository);
+        if !names.contains(&repository) {
+            names.push(repository);
+        }
+    }
+
+    // Archives of older versions can hold registry aliases
+    migrate_registry_aliases(root)?;
+
+    Ok(names)
+}
+
+/// Writes the content of an archive entry to the staging file, returning its hash
+fn stage_archive_file<R: std::io::Read>(entry: &mut R, staging_path: &Path) -> std::io::Result<String> {
+    let mut staging_file = std::fs::File::create(staging_path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = entry.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        staging_file.write_all(&buffer[..read])?;
+    }
+    staging_file.sync_all()?;
+
+    Ok(base16ct::lower::encode_string(&hasher.finalize()))
+}
@@ -0,0 +1,53 @@
+use std::{collections::HashMap, sync::Arc, time::{Instant, Duration}};
+
+use tokio::sync::Mutex;
+
+use crate::configuration::RateLimit;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant
+}
+
+/// Token buckets keyed by client identity (IP address or account name).
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes one request out of the bucket of the key. If the bucket is empty, returns
+    /// how long the client should wait before trying again.
+    pub async fn check(&self, key: &str, limit: &RateLimit) -> Result<(), Duration> {
+        let capacity = limit.requests as f64;
+        let refill_per_second = capacity / limit.per_seconds.max(1) as f64;
+
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.entry(key.to_string()).or_insert_with(|| TokenBucket {
+            tokens: capacity,
+            last_refill: Instant::now()
+        });
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill_per_second))
+        }
+    }
+
+    /// Forgets about the clients that haven't sent anything for a while, their bucket would be full anyway.
+    pub async fn prune(&self, max_idle: Duration) {
+        let mut buckets = self.buckets.lock().await;
+        buckets.retain(|_, bucket| bucket.last_refill.elapsed() < max_idle);
+    }
+}
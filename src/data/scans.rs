@@ -0,0 +1,36 @@
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use super::helpers::RegistryPathsHelper;
+
+/// Result of the vulnerability scan of an image, stored next to its manifest
+#[derive(Serialize, Deserialize)]
+pub struct ScanReport {
+    pub digest: String,
+    pub scanner: String,
+    pub scanned_at: DateTime<Utc>,
+    /// Number of vulnerabilities by lowercase severity, such as `critical` or `high`
+    pub severities: BTreeMap<String, u64>,
+    /// Set if the scan failed, in which case there is no report
+    pub error: Option<String>,
+    /// Report as sent by the scanner
+    pub report: Option<serde_json::Value>,
+}
+
+impl ScanReport {
+    pub async fn load(root: &Path, container_ref: &str, digest: &str) -> std::io::Result<Option<Self>> {
+        match tokio::fs::read(RegistryPathsHelper::scan_report(root, container_ref, digest)).await {
+            Ok(report) => Ok(Some(serde_json::from_slice(&report)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn save(&self, root: &Path, container_ref: &str) -> std::io::Result<()> {
+        let report_path = RegistryPathsHelper::scan_report(root, container_ref, &self.digest);
+        tokio::fs::create_dir_all(report_path.parent().unwrap()).await?;
+        tokio::fs::write(report_path, serde_json::to_vec(self)?).await
+    }
+}
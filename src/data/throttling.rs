@@ -0,0 +1,135 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}, pin::Pin};
+
+use axum::body::Bytes;
+use futures::{Stream, stream, StreamExt};
+use tokio::sync::Mutex;
+
+use crate::configuration::{BandwidthConfiguration, RateLimitsConfiguration};
+
+use super::helpers::resolve_repository;
+
+/// Byte budget refilled continuously at a fixed rate. Taking more than what's available
+/// puts the bucket in debt, which tells how long to wait before sending more.
+struct ByteBucket {
+    bytes_per_second: f64,
+    available: f64,
+    last_refill: Instant
+}
+
+impl ByteBucket {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            available: bytes_per_second as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.bytes_per_second).min(self.bytes_per_second);
+        self.last_refill = now;
+        self.available -= bytes as f64;
+
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / self.bytes_per_second)
+        }
+    }
+}
+
+struct ThrottledStreamState<S> {
+    inner_stream: Pin<Box<S>>,
+    connection_bucket: Option<ByteBucket>,
+    client_bucket: Option<Arc<Mutex<ByteBucket>>>,
+    repository_bucket: Option<Arc<Mutex<ByteBucket>>>
+}
+
+/// Caps the egress bandwidth of the blob responses, per response, per client and per repository.
+#[derive(Clone)]
+pub struct BandwidthLimiter {
+    per_connection: Option<u64>,
+    per_client: Option<u64>,
+    rate_limits: Arc<RateLimitsConfiguration>,
+    clients: Arc<Mutex<HashMap<String, Arc<Mutex<ByteBucket>>>>>,
+    repositories: Arc<Mutex<HashMap<String, Arc<Mutex<ByteBucket>>>>>
+}
+
+async fn shared_bucket(buckets: &Mutex<HashMap<String, Arc<Mutex<ByteBucket>>>>, key: &str, bytes_per_second: u64) -> Arc<Mutex<ByteBucket>> {
+    let mut buckets = buckets.lock().await;
+    let bucket = buckets
+        .entry(key.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(ByteBucket::new(bytes_per_second))));
+    Arc::clone(bucket)
+}
+
+impl BandwidthLimiter {
+    pub fn new(configuration: &BandwidthConfiguration, rate_limits: Arc<RateLimitsConfiguration>) -> Self {
+        Self {
+            per_connection: configuration.per_connection_bytes_per_second,
+            per_client: configuration.per_client_bytes_per_second,
+            rate_limits,
+            clients: Default::default(),
+            repositories: Default::default()
+        }
+    }
+
+    /// Repository as found in the URLs, starting with `proxy/` for the proxy cache. Proxied repositories are
+    /// limited under their upstream registry, whatever alias the client used.
+    pub async fn throttle<S, E>(&self, client_key: &str, repository: &str, inner_stream: S) -> impl Stream<Item = Result<Bytes, E>>
+    where
+        S: Stream<Item = Result<Bytes, E>>
+    {
+        let repository = resolve_repository(repository);
+        let client_bucket = match self.per_client {
+            Some(bytes_per_second) => Some(shared_bucket(&self.clients, client_key, bytes_per_second).await),
+            None => None
+        };
+
+        let repository_bucket = match self.rate_limits.repository_rule(&repository).and_then(|rule| rule.bytes_per_second) {
+            Some(bytes_per_second) => Some(shared_bucket(&self.repositories, &repository, bytes_per_second).await),
+            None => None
+        };
+
+        let state = ThrottledStreamState {
+            inner_stream: Box::pin(inner_stream),
+            connection_bucket: self.per_connection.map(ByteBucket::new),
+            client_bucket,
+            repository_bucket
+        };
+
+        stream::unfold(state, |mut state| async move {
+            let chunk = state.inner_stream.next().await?;
+
+            if let Ok(bytes) = &chunk {
+                let connection_wait = state.connection_bucket
+                    .as_mut()
+                    .map(|bucket| bucket.take(bytes.len()))
+                    .unwrap_or_default();
+                let client_wait = match &state.client_bucket {
+                    Some(bucket) => bucket.lock().await.take(bytes.len()),
+                    None => Duration::ZERO
+                };
+                let repository_wait = match &state.repository_bucket {
+                    Some(bucket) => bucket.lock().await.take(bytes.len()),
+                    None => Duration::ZERO
+                };
+
+                let wait = connection_wait.max(client_wait).max(repository_wait);
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+
+            Some((chunk, state))
+        })
+    }
+
+    /// Forgets about the clients and repositories that are not downloading anything anymore.
+    pub async fn prune(&self) {
+        self.clients.lock().await.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+        self.repositories.lock().await.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+    }
+}
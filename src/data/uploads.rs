@@ -1,131 +1,515 @@
-use std::{collections::HashMap, path::{PathBuf, Path}, time::Instant, sync::Arc};
+use std::{collections::HashMap, ops::Range, path::{PathBuf, Path}, time::Instant, sync::Arc};
+use std::io::{Read, Write};
 use std::time::Duration;
 
 use axum::extract::BodyStream;
-use futures_util::StreamExt;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::{sync::RwLock, io::AsyncWriteExt};
-use tokio::io::AsyncSeekExt;
 use tracing::{info, warn};
 use uuid::Uuid;
 use crate::UPLOAD_PRUNE_AGE;
+use crate::upload_affinity::{AFFINITY_PARAMETER, UploadAffinity};
 
-use super::helpers::RegistryPathsHelper;
+use super::helpers::{RegistryPathsHelper, file256sum, next_chunk};
 
-type UploadStoreItem = Arc<RwLock<Upload>>;
+/// Name of the assembled blob in the temporary directory of the upload
+const ASSEMBLED_BLOB_FILE_NAME: &str = "blob";
 
+pub type UploadStoreItem = Arc<RwLock<Upload>>;
+
+/// An upload along with what can be told about it without waiting for a write in progress
+struct UploadStoreEntry {
+    repository: String,
+    temporary_directory: PathBuf,
+    created_at: DateTime<Utc>,
+    upload: UploadStoreItem
+}
+
+/// State of an upload session, as shown on the admin endpoints
+#[derive(Serialize)]
+pub struct UploadSummary {
+    pub id: String,
+    pub repository: String,
+    pub bytes_received: u64,
+    /// Directory holding the chunks received so far
+    pub temporary_file_path: PathBuf,
+    pub created_at: DateTime<Utc>,
+    pub age_seconds: i64,
+    pub last_activity: DateTime<Utc>,
+    /// A chunk is being received right now
+    pub writing: bool,
+}
+
+/// Kept with the chunks, so another instance sharing the temporary storage can take the upload over
+#[derive(Serialize, Deserialize)]
+struct UploadSession {
+    repository: String,
+    #[serde(default)]
+    announced_digest: Option<String>,
+    #[serde(default)]
+    announced_size: Option<u64>
+}
+
+/// A chunk received in full, named after where it starts in the blob and its SHA-256 hash
+#[derive(Debug)]
+struct StagedChunk {
+    offset: u64,
+    size: u64,
+    hash: String,
+    path: PathBuf
+}
+
+impl StagedChunk {
+    fn file_name(offset: u64, hash: &str) -> String {
+        // Zero-padded, the chunks are listed in order
+        format!("{:020}-{}.chunk", offset, hash)
+    }
+
+    fn parse(path: PathBuf, size: u64) -> Option<Self> {
+        let (offset, hash) = path.file_name()?.to_str()?.strip_suffix(".chunk")?.split_once('-')?;
+
+        Some(Self {
+            offset: offset.parse().ok()?,
+            size,
+            hash: hash.to_string(),
+            path
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+
+    /// Chunks of an upload directory, by offset. Chunks still being written are left out.
+    fn list(directory: &Path) -> std::io::Result<Vec<Self>> {
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+
+        let mut chunks = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if let Some(chunk) = Self::parse(entry.path(), entry.metadata()?.len()) {
+                chunks.push(chunk);
+            }
+        }
+        chunks.sort_by_key(|chunk| chunk.offset);
+
+        Ok(chunks)
+    }
+
+    async fn list_async(directory: &Path) -> std::io::Result<Vec<Self>> {
+        let directory = directory.to_path_buf();
+        tokio::task::spawn_blocking(move || Self::list(&directory)).await?
+    }
+
+    /// Chunks following each other from the start of the blob. Chunks received ahead of a missing one wait for it.
+    fn contiguous(chunks: &[Self]) -> impl Iterator<Item = &Self> {
+        let mut end = 0;
+        chunks.iter().take_while(move |chunk| {
+            let follows = chunk.offset == end;
+            end = chunk.end();
+            follows
+        })
+    }
+
+    /// Size of the blob received so far, without the gaps
+    fn committed_size(chunks: &[Self]) -> u64 {
+        Self::contiguous(chunks).last().map(|chunk| chunk.end()).unwrap_or(0)
+    }
+}
+
+/// Blob being uploaded, staged as chunk files in a directory of its own. Retried chunks replace the ones
+/// they overlap, and chunks are only committed once received in full, so an interrupted chunk leaves the
+/// upload where it was before it.
 #[derive(Debug)]
 pub struct Upload {
     pub id: Uuid,
-    pub temporary_file_path: PathBuf,
-    pub last_interacted_with: Instant,
+    pub temporary_directory: PathBuf,
+    last_interacted_with: std::sync::Mutex<Instant>,
+    /// Ranges of the chunks being received side by side
+    chunks_in_flight: std::sync::Mutex<Vec<Range<u64>>>,
+    /// Digest the client said the blob would have when starting the upload
+    announced_digest: Option<String>,
+    /// Size of the announced blob, when the registry already knows it
+    announced_size: Option<u64>,
+    /// Names the replica holding the session in the upload URL, behind a load balancer
+    affinity_token: Option<String>,
     container_reference: String,
     registry_root: PathBuf
 }
 
+/// What became of a chunk sent to the upload
+pub enum ChunkOutcome {
+    /// The chunk is staged, with the size of the blob received so far
+    Staged(u64),
+    /// The chunk doesn't have the size of the range it was reserved
+    WrongSize,
+    /// The chunk goes past the size of the blob announced when the upload started
+    PastAnnouncedSize
+}
+
+/// Range of a chunk being received, released when dropped
+pub struct ChunkReservation<'a> {
+    upload: &'a Upload,
+    range: Range<u64>
+}
+
+impl ChunkReservation<'_> {
+    pub fn size(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+}
+
+impl Drop for ChunkReservation<'_> {
+    fn drop(&mut self) {
+        let mut chunks_in_flight = self.upload.chunks_in_flight.lock().unwrap();
+        if let Some(position) = chunks_in_flight.iter().position(|range| *range == self.range) {
+            chunks_in_flight.swap_remove(position);
+        }
+    }
+}
+
 impl Upload {
     pub fn new(container_reference: &str, temporary_root: &Path, registry_root: &Path) -> Self {
-        let id = Uuid::new_v4();
+        Self::with_id(Uuid::new_v4(), container_reference, temporary_root, registry_root)
+    }
 
+    fn with_id(id: Uuid, container_reference: &str, temporary_root: &Path, registry_root: &Path) -> Self {
         Self {
             id,
-            temporary_file_path: RegistryPathsHelper::temporary_blob_path(temporary_root, id),
+            temporary_directory: RegistryPathsHelper::temporary_upload_directory(temporary_root, id),
             container_reference: container_reference.to_string(),
-            last_interacted_with: Instant::now(),
+            last_interacted_with: std::sync::Mutex::new(Instant::now()),
+            chunks_in_flight: Default::default(),
+            announced_digest: None,
+            announced_size: None,
+            affinity_token: None,
             registry_root: registry_root.to_path_buf()
         }
     }
 
-    pub async fn create_parent_directory(&self) -> Result<(), std::io::Error> {
-        let parent = self.temporary_file_path
-            .parent()
-            .expect("Expected parent of the file");
-
-        if parent.is_dir() {
+    pub async fn create_directory(&self) -> Result<(), std::io::Error> {
+        if self.temporary_directory.is_dir() {
             return Ok(())
         }
 
-        tokio::fs::create_dir_all(parent).await
+        tokio::fs::create_dir_all(&self.temporary_directory).await
+    }
+
+    fn session_path(temporary_directory: &Path) -> PathBuf {
+        temporary_directory.join("session")
+    }
+
+    /// The chunks put together, once the upload is complete
+    pub fn assembled_blob_path(&self) -> PathBuf {
+        self.temporary_directory.join(ASSEMBLED_BLOB_FILE_NAME)
+    }
+
+    /// Records the upload in the temporary storage, for the instance replacing this one
+    pub async fn save_session(&self) -> std::io::Result<()> {
+        let session = serde_json::to_vec(&UploadSession {
+            repository: self.container_reference.clone(),
+            announced_digest: self.announced_digest.clone(),
+            announced_size: self.announced_size
+        })?;
+        tokio::fs::write(Self::session_path(&self.temporary_directory), session).await
+    }
+
+    /// Records the digest the blob is expected to have, and its size when known, so chunks going past
+    /// it are refused as they come and the upload can't be completed under another digest
+    pub fn announce(&mut self, digest: &str, size: Option<u64>) {
+        self.announced_digest = Some(digest.to_string());
+        self.announced_size = size;
     }
 
-    pub async fn write_blob(&mut self, layer: &mut BodyStream) -> eyre::Result<u64> {
-        let mut file = if self.temporary_file_path.is_file() {
-            tokio::fs::File::open(&self.temporary_file_path).await?
-        } else {
-            tokio::fs::File::create(&self.temporary_file_path).await?
+    pub fn announced_digest(&self) -> Option<&str> {
+        self.announced_digest.as_deref()
+    }
+
+    /// Whether a chunk may start there: where the blob received so far ends, ahead of it, or in place
+    /// of a chunk being sent again
+    pub async fn accepts_chunk_at(&self, offset: u64) -> std::io::Result<bool> {
+        let chunks = StagedChunk::list_async(&self.temporary_directory).await?;
+
+        Ok(offset >= StagedChunk::committed_size(&chunks) || chunks.iter().any(|chunk| chunk.offset == offset))
+    }
+
+    /// Reserves the range of a chunk received alongside others. Returns None when the range overlaps a
+    /// chunk still being received, the two would replace each other.
+    pub fn reserve_chunk(&self, range: Range<u64>) -> Option<ChunkReservation<'_>> {
+        let mut chunks_in_flight = self.chunks_in_flight.lock().unwrap();
+        if chunks_in_flight.iter().any(|in_flight| in_flight.start < range.end && range.start < in_flight.end) {
+            return None;
+        }
+        chunks_in_flight.push(range.clone());
+
+        Some(ChunkReservation { upload: self, range })
+    }
+
+    /// Stages the body as a chunk starting at `offset`, or after the blob received so far. If the client
+    /// doesn't send the next part of the body within `chunk_timeout`, the write is aborted so a stalled
+    /// client doesn't hold the upload forever. Chunks received side by side come with their reservation,
+    /// and are left out if the body doesn't have the size of the reserved range.
+    ///
+    /// Chunks going past the announced size of the blob are refused as soon as they do.
+    pub async fn write_blob(&self, layer: &mut BodyStream, chunk_timeout: Option<Duration>, offset: Option<u64>, reservation: Option<&ChunkReservation<'_>>) -> eyre::Result<ChunkOutcome> {
+        self.create_directory().await?;
+        let chunks = StagedChunk::list_async(&self.temporary_directory).await?;
+        let offset = offset.unwrap_or_else(|| StagedChunk::committed_size(&chunks));
+
+        let max_size = match self.announced_size {
+            Some(announced_size) if offset > announced_size || reservation.map(|reservation| offset + reservation.size() > announced_size).unwrap_or(false) => {
+                warn!("Chunk at {} of upload {} goes past the announced size of the blob", offset, self.id);
+                return Ok(ChunkOutcome::PastAnnouncedSize);
+            },
+            Some(announced_size) => Some(announced_size - offset),
+            None => None
         };
 
-        file.seek(std::io::SeekFrom::End(0)).await?;
+        let partial_path = self.temporary_directory.join(format!("{}.partial", Uuid::new_v4()));
+        let written = self.write_chunk(&partial_path, layer, chunk_timeout, max_size).await;
+        let (size, hash) = match written {
+            Ok(Some(written)) => written,
+            Ok(None) => {
+                warn!("Chunk at {} of upload {} goes past the announced size of the blob", offset, self.id);
+                tokio::fs::remove_file(&partial_path).await?;
+                return Ok(ChunkOutcome::PastAnnouncedSize);
+            },
+            Err(e) => {
+                tokio::fs::remove_file(&partial_path).await.ok();
+                return Err(e);
+            }
+        };
+
+        if reservation.map(|reservation| reservation.size() != size).unwrap_or(false) {
+            warn!("Chunk at {} of upload {} is {} bytes long, not the size of its range", offset, self.id, size);
+            tokio::fs::remove_file(&partial_path).await?;
+            return Ok(ChunkOutcome::WrongSize);
+        }
 
-        while let Some(chunk) = layer.next().await {
+        if size == 0 {
+            tokio::fs::remove_file(&partial_path).await?;
+            return Ok(ChunkOutcome::Staged(StagedChunk::committed_size(&chunks)));
+        }
+
+        // The latest chunk wins over the ones it overlaps, which a retried request may well have
+        // sent with other boundaries. Chunks received side by side never overlap, but may have
+        // replaced the same older chunk.
+        for chunk in chunks.iter().filter(|chunk| chunk.offset < offset + size && chunk.end() > offset) {
+            match tokio::fs::remove_file(&chunk.path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        tokio::fs::rename(&partial_path, self.temporary_directory.join(StagedChunk::file_name(offset, &hash))).await?;
+
+        Ok(ChunkOutcome::Staged(self.size().await?))
+    }
+
+    /// Writes the chunk with its size and hash, or None as soon as it goes over `max_size`
+    async fn write_chunk(&self, partial_path: &Path, layer: &mut BodyStream, chunk_timeout: Option<Duration>, max_size: Option<u64>) -> eyre::Result<Option<(u64, String)>> {
+        let mut file = tokio::fs::File::create(partial_path).await?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+
+        while let Some(chunk) = next_chunk(layer, chunk_timeout).await? {
             let chunk = chunk?;
+            if max_size.map(|max_size| size + chunk.len() as u64 > max_size).unwrap_or(false) {
+                return Ok(None);
+            }
             file.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
             // Make sure we update the last interaction so this upload won't get cleaned up by
             // the uploads pruning of the store.
             self.update_last_interacted();
         }
+        file.flush().await?;
 
-        let position = file.seek(std::io::SeekFrom::End(0)).await?;
+        Ok(Some((size, base16ct::lower::encode_string(&hasher.finalize()))))
+    }
 
-        Ok(position)
+    /// Number of bytes of the blob received so far, from its start and without gaps.
+    pub async fn size(&self) -> std::io::Result<u64> {
+        let chunks = StagedChunk::list_async(&self.temporary_directory).await?;
+        Ok(StagedChunk::committed_size(&chunks))
     }
 
-    pub async fn cleanup_upload(&self) -> std::io::Result<()> {
-        if self.temporary_file_path.is_file() {
-            tokio::fs::remove_file(&self.temporary_file_path).await?;
+    /// Puts the chunks received so far together, checking each one against its hash on the way.
+    /// Returns the SHA-256 hash of the blob. The chunks are left in place until the upload is finalized,
+    /// so it can be completed again if anything fails before that. Chunks that changed since they were
+    /// received fail with `InvalidData`.
+    pub async fn assemble(&self) -> std::io::Result<String> {
+        let directory = self.temporary_directory.clone();
+        let assembled_blob_path = self.assembled_blob_path();
+
+        tokio::task::spawn_blocking(move || {
+            let chunks = StagedChunk::list(&directory)?;
+            let chunks = StagedChunk::contiguous(&chunks).collect::<Vec<_>>();
+
+            // A single chunk is the blob itself
+            if let [chunk] = chunks.as_slice() {
+                Self::verify_chunk(chunk, file256sum(&chunk.path)?)?;
+                match std::fs::remove_file(&assembled_blob_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                if std::fs::hard_link(&chunk.path, &assembled_blob_path).is_err() {
+                    std::fs::copy(&chunk.path, &assembled_blob_path)?;
+                }
+                return Ok(chunk.hash.clone());
+            }
+
+            let mut assembled_blob = std::io::BufWriter::new(std::fs::File::create(&assembled_blob_path)?);
+            let mut blob_hasher = Sha256::new();
+            let mut buffer = vec![0; 1024 * 1024];
+            for chunk in chunks {
+                let mut chunk_file = std::fs::File::open(&chunk.path)?;
+                let mut chunk_hasher = Sha256::new();
+                loop {
+                    let read = chunk_file.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    chunk_hasher.update(&buffer[..read]);
+                    blob_hasher.update(&buffer[..read]);
+                    assembled_blob.write_all(&buffer[..read])?;
+                }
+                Self::verify_chunk(chunk, base16ct::lower::encode_string(&chunk_hasher.finalize()))?;
+            }
+            assembled_blob.into_inner()?.sync_all()?;
+
+            Ok(base16ct::lower::encode_string(&blob_hasher.finalize()))
+        }).await?
+    }
+
+    fn verify_chunk(chunk: &StagedChunk, actual_hash: String) -> std::io::Result<()> {
+        if actual_hash != chunk.hash {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk at offset {} changed since it was received (sha256:{} instead of sha256:{})", chunk.offset, actual_hash, chunk.hash)
+            ));
         }
 
         Ok(())
     }
 
-    pub async fn finalize_upload(&self, hash: &str) -> std::io::Result<()> {
-        // Move this blob to its final resting place.
-        let final_blob_path = RegistryPathsHelper::blob_path(&self.registry_root, &self.container_reference, hash);
+    async fn remove_directory(temporary_directory: &Path) -> std::io::Result<()> {
+        match tokio::fs::remove_dir_all(temporary_directory).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(())
+        }
+    }
+
+    pub async fn cleanup_upload(&self) -> std::io::Result<()> {
+        Self::remove_directory(&self.temporary_directory).await
+    }
+
+    /// Moves the assembled blob, once compressed or encrypted as it's going to be stored, to its final resting place.
+    /// It keeps the extensions it got along the way, `.zst` and `.enc`.
+    pub async fn finalize_upload(&self, hash: &str, prepared_blob_path: &Path) -> std::io::Result<()> {
+        let prepared_file_name = prepared_blob_path.file_name().unwrap_or_default().to_string_lossy();
+        let extensions = prepared_file_name.strip_prefix(ASSEMBLED_BLOB_FILE_NAME).unwrap_or_default();
+        let final_blob_path = RegistryPathsHelper::blob_path(&self.registry_root, &self.container_reference, &format!("{}{}", hash, extensions));
         let blob_parent = final_blob_path.parent().unwrap();
         if !blob_parent.is_dir() {
             tokio::fs::create_dir_all(blob_parent).await?;
         }
 
-        tokio::fs::rename(&self.temporary_file_path, &final_blob_path).await?;
+        tokio::fs::rename(prepared_blob_path, &final_blob_path).await?;
 
-        Ok(())
+        self.cleanup_upload().await
+    }
+
+    /// Value of the Range header telling the client which bytes we have. The end is inclusive.
+    pub fn committed_range(size: u64) -> String {
+        format!("0-{}", size.saturating_sub(1))
     }
 
     pub fn http_upload_uri(&self) -> String {
-        format!("/v2/{}/blobs/uploads/{}", self.container_reference, self.id)
+        match &self.affinity_token {
+            Some(token) => format!("/v2/{}/blobs/uploads/{}?{}={}", self.container_reference, self.id, AFFINITY_PARAMETER, token),
+            None => format!("/v2/{}/blobs/uploads/{}", self.container_reference, self.id)
+        }
     }
 
-    pub fn update_last_interacted(&mut self) {
-        self.last_interacted_with = Instant::now();
+    pub fn update_last_interacted(&self) {
+        *self.last_interacted_with.lock().unwrap() = Instant::now();
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_interacted_with.lock().unwrap().elapsed()
     }
 }
 
 #[derive(Clone)]
 pub struct UploadsStore {
-    inner: Arc<RwLock<HashMap<Uuid, UploadStoreItem>>>
+    inner: Arc<RwLock<HashMap<Uuid, UploadStoreEntry>>>,
+    affinity: Option<Arc<UploadAffinity>>
 }
 
 impl UploadsStore {
-    pub fn new() -> Self {
+    pub fn new(affinity: Option<Arc<UploadAffinity>>) -> Self {
         Self {
-            inner: Default::default()
+            inner: Default::default(),
+            affinity
         }
     }
 
     pub async fn create_upload(&self, container_ref: &str, temporary_files_root: &Path, registry_root: &Path) -> UploadStoreItem {
-        let upload = Upload::new(container_ref, temporary_files_root, registry_root);
+        self.insert_upload(Upload::new(container_ref, temporary_files_root, registry_root), Utc::now()).await
+    }
+
+    async fn insert_upload(&self, mut upload: Upload, created_at: DateTime<Utc>) -> UploadStoreItem {
         let id = upload.id;
+        upload.affinity_token = self.affinity.as_ref().map(|affinity| affinity.token(id));
+        let repository = upload.container_reference.clone();
+        let temporary_directory = upload.temporary_directory.clone();
 
         let upload = Arc::new(RwLock::new(upload));
         let mut lock = self.inner.write().await;
-        lock.insert(id, Arc::clone(&upload));
+        // Two requests may resume the same upload at once, the first one wins
+        let entry = lock.entry(id).or_insert(UploadStoreEntry {
+            repository,
+            temporary_directory,
+            created_at,
+            upload
+        });
+
+        Arc::clone(&entry.upload)
+    }
+
+    /// Takes over an upload started by another instance sharing the temporary storage, like the one
+    /// this instance replaced. The upload has to be resumed in the repository it was started in.
+    pub async fn resume_upload(&self, upload_id: Uuid, container_ref: &str, temporary_files_root: &Path, registry_root: &Path) -> std::io::Result<Option<UploadStoreItem>> {
+        let mut upload = Upload::with_id(upload_id, container_ref, temporary_files_root, registry_root);
+        let session_path = Upload::session_path(&upload.temporary_directory);
+
+        let session = match tokio::fs::read(&session_path).await {
+            Ok(session) => serde_json::from_slice::<UploadSession>(&session)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e)
+        };
+        if session.repository != container_ref {
+            return Ok(None);
+        }
+        upload.announced_digest = session.announced_digest;
+        upload.announced_size = session.announced_size;
 
-        upload
+        let created_at = tokio::fs::metadata(&session_path).await?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+        info!("Resuming upload {} of {}, started by another instance", upload_id, container_ref);
+        Ok(Some(self.insert_upload(upload, created_at).await))
     }
 
     pub async fn fetch_upload(&self, upload: Uuid) -> Option<UploadStoreItem> {
         let lock = self.inner.read().await;
 
-        lock.get(&upload).cloned()
+        lock.get(&upload).map(|entry| Arc::clone(&entry.upload))
     }
 
     pub async fn fetch_upload_string_uuid(&self, upload: &str) -> Result<Option<UploadStoreItem>, uuid::Error> {
@@ -144,12 +528,84 @@ impl UploadsStore {
         Ok(())
     }
 
-    pub async fn prune(&self) {
+    /// Removes the upload and its chunks.
+    pub async fn discard_upload(&self, upload_id: Uuid) {
+        let upload_lock = match self.fetch_upload(upload_id).await {
+            Some(upload_lock) => upload_lock,
+            None => return
+        };
+
+        let upload = upload_lock.write().await;
+        info!("Discarding upload {}", upload_id);
+        if let Err(delete_error) = upload.cleanup_upload().await {
+            warn!("Error while deleting upload file for {}: {:?}", upload_id, delete_error);
+        }
+
+        self.delete_upload(upload_id).await;
+    }
+
+    /// Discards the upload in the background, once whoever is holding it lets it go.
+    pub fn schedule_discard(&self, upload_id: Uuid) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            store.discard_upload(upload_id).await;
+        });
+    }
+
+    /// Forgets the upload right away, even if a chunk is being received, and removes its chunks.
+    /// Returns false if there is no such upload.
+    pub async fn abort_upload(&self, upload_id: Uuid) -> std::io::Result<bool> {
+        let entry = match self.inner.write().await.remove(&upload_id) {
+            Some(entry) => entry,
+            None => return Ok(false)
+        };
+
+        info!("Aborting upload {}", upload_id);
+        Upload::remove_directory(&entry.temporary_directory).await.map(|_| true)
+    }
+
+    /// Every upload session in progress, oldest first.
+    pub async fn list(&self) -> std::io::Result<Vec<UploadSummary>> {
+        let lock = self.inner.read().await;
+        let now = Utc::now();
+
+        let mut uploads = Vec::with_capacity(lock.len());
+        for (id, entry) in lock.iter() {
+            // The staged chunks tell how far the upload went, even while a chunk is being written
+            let bytes_received = StagedChunk::committed_size(&StagedChunk::list_async(&entry.temporary_directory).await?);
+            let last_activity = match tokio::fs::metadata(&entry.temporary_directory).await {
+                Ok(metadata) => DateTime::<Utc>::from(metadata.modified()?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => entry.created_at,
+                Err(e) => return Err(e)
+            };
+
+            uploads.push(UploadSummary {
+                id: id.to_string(),
+                repository: entry.repository.clone(),
+                bytes_received,
+                temporary_file_path: entry.temporary_directory.clone(),
+                created_at: entry.created_at,
+                age_seconds: (now - entry.created_at).num_seconds(),
+                last_activity,
+                writing: entry.upload.try_write().is_err()
+            });
+        }
+        uploads.sort_by_key(|upload| upload.created_at);
+
+        Ok(uploads)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.inner.read().await.len()
+    }
+
+    /// Deletes the abandoned uploads, returning how many there were
+    pub async fn prune(&self) -> usize {
         let mut lock = self.inner.write().await;
         let mut prune_uuids = Vec::new();
-        for (key, upload) in lock.iter() {
-            let upload = upload.write().await;
-            if upload.last_interacted_with.elapsed() > Duration::from_secs(UPLOAD_PRUNE_AGE) {
+        for (key, entry) in lock.iter() {
+            let upload = entry.upload.write().await;
+            if upload.idle_time() > Duration::from_secs(UPLOAD_PRUNE_AGE) {
                 info!("Deleting upload {}", key);
                 if let Err(delete_error) = upload.cleanup_upload().await {
                     warn!("Error while deleting upload file for {}: {:?}", key, delete_error);
@@ -159,14 +615,82 @@ impl UploadsStore {
             }
         }
 
-        for uuid_to_prune in prune_uuids {
-            lock.remove(&uuid_to_prune);
+        for uuid_to_prune in &prune_uuids {
+            lock.remove(uuid_to_prune);
         }
+
+        prune_uuids.len()
     }
 }
 
 impl Default for UploadsStore {
     fn default() -> Self {
-        Self::new()
+        Self::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use uuid::Uuid;
+
+    use super::{RegistryPathsHelper, StagedChunk, Upload};
+
+    fn stage_chunk(upload: &Upload, offset: u64, content: &[u8]) -> std::path::PathBuf {
+        let hash = base16ct::lower::encode_string(&Sha256::digest(content));
+        let chunk_path = upload.temporary_directory.join(StagedChunk::file_name(offset, &hash));
+        std::fs::write(&chunk_path, content).unwrap();
+        chunk_path
+    }
+
+    #[tokio::test]
+    async fn uploads_can_be_assembled_again() {
+        let storage = std::env::temp_dir().join(format!("uploads-{}", Uuid::new_v4()));
+        let upload = Upload::new("team/app", &storage.join("tmp"), &storage.join("registry"));
+        upload.create_directory().await.unwrap();
+
+        // A failed completion leaves the chunks where they were, for the client to complete the upload again
+        for chunks in [vec![b"whole blob".to_vec()], vec![b"first ".to_vec(), b"second".to_vec()]] {
+            let mut offset = 0;
+            for chunk in &chunks {
+                stage_chunk(&upload, offset, chunk);
+                offset += chunk.len() as u64;
+            }
+            let blob_hash = base16ct::lower::encode_string(&Sha256::digest(chunks.concat()));
+
+            assert_eq!(upload.assemble().await.unwrap(), blob_hash);
+            assert_eq!(upload.assemble().await.unwrap(), blob_hash);
+            assert_eq!(upload.size().await.unwrap(), offset);
+
+            upload.cleanup_upload().await.unwrap();
+            upload.create_directory().await.unwrap();
+        }
+
+        let chunk_path = stage_chunk(&upload, 0, b"received");
+        std::fs::write(chunk_path, b"changed!").unwrap();
+        assert_eq!(upload.assemble().await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(&storage).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prepared_blobs_keep_their_extensions() {
+        let storage = std::env::temp_dir().join(format!("uploads-{}", Uuid::new_v4()));
+        let upload = Upload::new("team/app", &storage.join("tmp"), &storage.join("registry"));
+        upload.create_directory().await.unwrap();
+        stage_chunk(&upload, 0, b"compressed and encrypted");
+        let hash = upload.assemble().await.unwrap();
+
+        // Stands for the compression and the encryption, done in the temporary storage
+        let prepared_blob_path = upload.temporary_directory.join("blob.zst.enc");
+        std::fs::rename(upload.assembled_blob_path(), &prepared_blob_path).unwrap();
+        upload.finalize_upload(&hash, &prepared_blob_path).await.unwrap();
+
+        let blob_path = RegistryPathsHelper::blob_path(&storage.join("registry"), "team/app", &hash);
+        assert!(!blob_path.exists());
+        assert!(blob_path.with_file_name(format!("{}.zst.enc", hash)).is_file());
+        assert!(!upload.temporary_directory.exists());
+
+        std::fs::remove_dir_all(&storage).unwrap();
     }
 }
@@ -3,7 +3,7 @@ use std::{time::Duration, collections::HashMap};
 use async_trait::async_trait;
 use chrono::Utc;
 use serde::Deserialize;
-use tracing::{info, error, debug};
+use tracing::{info, error, debug, warn};
 
 use super::client::DockerClientError;
 
@@ -11,19 +11,35 @@ use super::client::DockerClientError;
 pub trait AuthenticationStrategy: Send + Sync {
     fn inject_authentication(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder;
     fn needs_reauthenticating(&self) -> bool;
+    /// Name of the strategy, as in the admin listing of the clients and the metrics
+    fn kind(&self) -> &'static str;
+    /// Expiration of the token or password in use, when it has one
+    fn expires_at(&self) -> Option<chrono::DateTime<Utc>> {
+        None
+    }
+    /// Token to renew the authentication with, carried over to the next strategy of the client
+    fn refresh_token(&self) -> Option<String> {
+        None
+    }
     async fn execute_authentication(&mut self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<(), DockerClientError>; 
 }
 
 pub struct HttpBasicAuthStrategy {
     username: String,
     password: Option<String>,
+    /// Renewal time of short-lived passwords, such as the ECR authorization tokens
+    refresh_at: Option<chrono::DateTime<Utc>>,
+    expires_at: Option<chrono::DateTime<Utc>>,
 }
 
 impl<> HttpBasicAuthStrategy<> {
-    pub fn new(username: &str, password: Option<&str>) -> Self {
+    /// Short-lived passwords are renewed when their provider renews them, so both get a new one at the same time
+    pub fn new(username: &str, password: Option<&str>, refresh_at: Option<chrono::DateTime<Utc>>, expires_at: Option<chrono::DateTime<Utc>>) -> Self {
         Self {
             username: username.to_string(),
-            password: password.map(|s| s.to_string())
+            password: password.map(|s| s.to_string()),
+            refresh_at,
+            expires_at
         }
     }
 }
@@ -35,7 +51,15 @@ impl<> AuthenticationStrategy for HttpBasicAuthStrategy<> {
     }
 
     fn needs_reauthenticating(&self) -> bool {
-        false
+        self.refresh_at.map(|refresh_at| Utc::now() >= refresh_at).unwrap_or(false)
+    }
+
+    fn kind(&self) -> &'static str {
+        "basic"
+    }
+
+    fn expires_at(&self) -> Option<chrono::DateTime<Utc>> {
+        self.expires_at
     }
 
     async fn execute_authentication(&mut self, _client: &reqwest::Client, _authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<(), DockerClientError> { 
@@ -48,44 +72,53 @@ impl<> AuthenticationStrategy for HttpBasicAuthStrategy<> {
 
 pub struct BearerTokenAuthStrategy {
     token: Option<String>,
-    created_at: chrono::DateTime<Utc>,
-    expires_in: Duration, 
+    /// Shortly before the expiration, so requests never go out with an expired token
+    refresh_at: chrono::DateTime<Utc>,
+    expires_at: Option<chrono::DateTime<Utc>>,
+    /// Long-lived token handed out by OAuth2 token services, exchanged for new access tokens
+    refresh_token: Option<String>,
     scope: String,
 }
 
 #[derive(Deserialize)]
 struct BearerToken {
-    token: String,
+    // Token services send either or both, with the same value
+    token: Option<String>,
+    access_token: Option<String>,
+    refresh_token: Option<String>,
     issued_at: Option<String>,
     expires_in: Option<u64>
 }
 
+impl BearerToken {
+    fn value(&self) -> &str {
+        self.token.as_deref().or(self.access_token.as_deref()).unwrap_or_default()
+    }
+}
+
 impl BearerTokenAuthStrategy {
-    pub fn new(container_repository: &str) -> Self {
-        let scope = format!("repository:{}:pull", container_repository);
+    /// The token is asked for the actions on the repository, as in `pull` or `pull,push`. Without a repository,
+    /// it is asked without a scope and only proves the credentials, as `docker login` does.
+    pub fn new(container_repository: &str, actions: &str, refresh_token: Option<String>) -> Self {
+        let scope = match container_repository {
+            "" => String::new(),
+            container_repository => format!("repository:{}:{}", container_repository, actions)
+        };
         Self {
             token: None,
-            created_at: Utc::now(),
-            expires_in: Duration::from_secs(0),
+            refresh_at: Utc::now(),
+            expires_at: None,
+            refresh_token,
             scope,
         }
     }
-}
-
-#[async_trait]
-impl AuthenticationStrategy for BearerTokenAuthStrategy {
-    fn inject_authentication(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
-        request.bearer_auth(self.token.as_ref().expect("The authentication flow has not been executed"))
-    }
 
-    fn needs_reauthenticating(&self) -> bool {
-        let now = Utc::now();
-        now.timestamp() - self.created_at.timestamp() >= self.expires_in.as_secs() as i64
-    }
-
-    async fn execute_authentication(&mut self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<(), DockerClientError> {
+    /// Classic token request: a GET on the token service, with the credentials in a Basic authorization header.
+    async fn request_token(&self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<BearerToken, DockerClientError> {
         let mut authentication_parameters = authentication_parameters.clone();
-        authentication_parameters.insert("scope", &self.scope);
+        if !self.scope.is_empty() {
+            authentication_parameters.insert("scope", &self.scope);
+        }
 
         let authentication_service = authentication_parameters.get("realm").expect("Who am I supposed to authenticate to ?");
         debug!("Querying token auth service {} with parameters {:#?}", authentication_service, authentication_parameters);
@@ -95,40 +128,156 @@ impl AuthenticationStrategy for BearerTokenAuthStrategy {
             .collect::<Vec<_>>()
             .join("&");
 
-            info!("Attempting to authenticate to {}", authentication_service);
-            let mut token_request = client.get(format!("{}?{}", authentication_service, authentication_query_string));
-            if let Some(username) = username {
-                token_request = token_request.basic_auth(username, password);
-            }
+        info!("Attempting to authenticate to {}", authentication_service);
+        let mut token_request = client.get(format!("{}?{}", authentication_service, authentication_query_string));
+        if let Some(username) = username {
+            token_request = token_request.basic_auth(username, password);
+        }
 
-            let response = token_request.send().await?;
-            if response.status() == 401 {
-                info!("Response is 401, credentials are propably rejected");
-                return Err(DockerClientError::BadAuthenticationCredentials);
-            } else if response.status() != 200 {
-                info!("Response is {}, not the expected 200", response.status());
-                return Err(DockerClientError::UnexpectedStatusCode(response.status().as_u16()));
+        let response = token_request.send().await?;
+        if response.status() == 401 {
+            info!("Response is 401, credentials are propably rejected");
+            return Err(DockerClientError::BadAuthenticationCredentials);
+        } else if response.status() != 200 {
+            info!("Response is {}, not the expected 200", response.status());
+            return Err(DockerClientError::UnexpectedStatusCode(response.status().as_u16()));
+        }
+
+        info!("Deserializing 200 response from {}", authentication_service);
+        Ok(response.json::<BearerToken>().await?)
+    }
+
+    /// Token for the configured credentials: through the refresh token or the password with OAuth2 if
+    /// the token service supports it, with the classic token request otherwise.
+    async fn request_credentialed_token(&mut self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<BearerToken, DockerClientError> {
+        let mut token = None;
+
+        if let Some(refresh_token) = self.refresh_token.clone() {
+            let grant = [("grant_type", "refresh_token"), ("refresh_token", refresh_token.as_str())];
+            match self.request_oauth2_token(client, authentication_parameters, &grant).await {
+                Ok(refresh_token_grant) => token = refresh_token_grant,
+                Err(DockerClientError::BadAuthenticationCredentials) if password.is_some() => {
+                    warn!("Refresh token has been rejected, falling back to the password");
+                    self.refresh_token = None;
+                },
+                Err(e) => return Err(e)
             }
+        }
+
+        if let (None, Some(username), Some(password)) = (&token, username, password) {
+            let grant = [("grant_type", "password"), ("username", username), ("password", password)];
+            token = self.request_oauth2_token(client, authentication_parameters, &grant).await?;
+        }
+
+        match token {
+            Some(token) => Ok(token),
+            None => self.request_token(client, authentication_parameters, username, password).await
+        }
+    }
 
-            info!("Deserializing 200 response from {}", authentication_service);
-            let token = response.json::<BearerToken>().await?;
-            // Inspiration from https://github.com/camallo/dkregistry-rs/blob/37acecb4b8139dd1b1cc83795442f94f90e1ffc5/src/v2/auth.rs#L67.
-            // Apparently, token servers can return a 200 and "unauthenticated" as a token. Why ?
-            if token.token.is_empty() || token.token == "unauthenticated" {
-                error!("Registry token server did return a 200 response but NO TOKEN. Bailing out.");
-                return Err(DockerClientError::BadAuthenticationCredentials);
+    /// OAuth2 token request: a POST on the token service with the grant in the form. Returns None when
+    /// the token service doesn't support it, in which case the classic token request must be used.
+    async fn request_oauth2_token(&self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, grant: &[(&str, &str)]) -> Result<Option<BearerToken>, DockerClientError> {
+        let authentication_service = authentication_parameters.get("realm").expect("Who am I supposed to authenticate to ?");
+        let mut form = vec![
+            ("client_id", env!("CARGO_PKG_NAME")),
+            // Asks for a refresh token along with the access token
+            ("access_type", "offline"),
+        ];
+        if !self.scope.is_empty() {
+            form.push(("scope", self.scope.as_str()));
+        }
+        if let Some(service) = authentication_parameters.get("service") {
+            form.push(("service", service));
+        }
+        form.extend_from_slice(grant);
+
+        info!("Attempting to authenticate to {} with OAuth2", authentication_service);
+        let response = client.post(*authentication_service).form(&form).send().await?;
+        match response.status().as_u16() {
+            200 => Ok(Some(response.json::<BearerToken>().await?)),
+            404 | 405 => {
+                info!("Token service doesn't support OAuth2 (got {})", response.status());
+                Ok(None)
+            },
+            // OAuth2 token services answer 400 invalid_grant to rejected credentials
+            400 | 401 => {
+                info!("Response is {}, credentials are propably rejected", response.status());
+                Err(DockerClientError::BadAuthenticationCredentials)
+            },
+            status => {
+                info!("Response is {}, not the expected 200", status);
+                Err(DockerClientError::UnexpectedStatusCode(status))
             }
+        }
+    }
+}
+
+#[async_trait]
+impl AuthenticationStrategy for BearerTokenAuthStrategy {
+    fn inject_authentication(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        request.bearer_auth(self.token.as_ref().expect("The authentication flow has not been executed"))
+    }
+
+    fn needs_reauthenticating(&self) -> bool {
+        Utc::now() >= self.refresh_at
+    }
+
+    fn kind(&self) -> &'static str {
+        "bearer"
+    }
+
+    fn expires_at(&self) -> Option<chrono::DateTime<Utc>> {
+        self.expires_at
+    }
+
+    fn refresh_token(&self) -> Option<String> {
+        self.refresh_token.clone()
+    }
+
+    async fn execute_authentication(&mut self, client: &reqwest::Client, authentication_parameters: &HashMap<&str, &str>, username: Option<&str>, password: Option<&str>) -> Result<(), DockerClientError> {
+        let has_credentials = username.is_some() || self.refresh_token.is_some();
+        let token = match self.request_credentialed_token(client, authentication_parameters, username, password).await {
+            // The credentials may be for other repositories of the registry, while this one is public.
+            // Like the Docker client, try without them before giving up.
+            Err(DockerClientError::BadAuthenticationCredentials) if has_credentials => {
+                warn!("Credentials rejected for the scope {}, asking for an anonymous token", self.scope);
+                self.request_token(client, authentication_parameters, None, None).await?
+            },
+            token => token?
+        };
+
+        // Inspiration from https://github.com/camallo/dkregistry-rs/blob/37acecb4b8139dd1b1cc83795442f94f90e1ffc5/src/v2/auth.rs#L67.
+        // Apparently, token servers can return a 200 and "unauthenticated" as a token. Why ?
+        if token.value().is_empty() || token.value() == "unauthenticated" {
+            error!("Registry token server did return a 200 response but NO TOKEN. Bailing out.");
+            return Err(DockerClientError::BadAuthenticationCredentials);
+        }
+
+        let created_at: chrono::DateTime<Utc> = token.issued_at
+            .as_ref()
+            .map(|issued| chrono::DateTime::parse_from_rfc3339(issued).unwrap())
+            .unwrap_or_else(|| Utc::now().into())
+            .into();
+        let expires_in = token.expires_in.map(Duration::from_secs).unwrap_or_else(|| Duration::from_secs(60));
+        self.refresh_at = created_at + chrono::Duration::from_std(expires_in - refresh_margin(expires_in)).unwrap();
+        self.expires_at = Some(created_at + chrono::Duration::from_std(expires_in).unwrap());
+        self.token = Some(token.value().to_string());
+        if token.refresh_token.is_some() {
+            self.refresh_token = token.refresh_token;
+        }
 
-            self.created_at = token.issued_at
-                .map(|issued| chrono::DateTime::parse_from_rfc3339(&issued).unwrap())
-                .unwrap_or_else(|| Utc::now().into())
-                .into();
-            self.expires_in = token.expires_in.map(Duration::from_secs).unwrap_or_else(|| Duration::from_secs(60));
-            self.token = Some(token.token);
         Ok(())
     }
 }
 
+/// Renew tokens and passwords when 10 to 20 % of their lifetime is left. The jitter spreads the renewals
+/// of clients that got their token at the same time.
+pub(super) fn refresh_margin(expires_in: Duration) -> Duration {
+    let margin = expires_in / 10;
+    margin + margin.mul_f64(rand::random::<f64>())
+}
+
 pub struct AnonymousAuthStrategy;
 
 #[async_trait]
@@ -141,6 +290,10 @@ impl AuthenticationStrategy for AnonymousAuthStrategy {
         false
     }
 
+    fn kind(&self) -> &'static str {
+        "anonymous"
+    }
+
     async fn execute_authentication(&mut self, _client: &reqwest::Client, _authentication_parameters: &HashMap<&str, &str>, _username: Option<&str>, _password: Option<&str>) -> Result<(), DockerClientError> {
         Ok(())
     }
@@ -0,0 +1,39 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+/// Deadlines set by upstream registries answering 429 Too Many Requests. No request is sent to
+/// a registry until its deadline passed.
+#[derive(Clone, Default)]
+pub struct RegistryBackoff {
+    deadlines: Arc<Mutex<HashMap<String, Instant>>>
+}
+
+impl RegistryBackoff {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time left before the registry can be queried again, if it is rate limiting us.
+    pub fn remaining(&self, registry: &str) -> Option<Duration> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let remaining = deadlines
+            .get(registry)
+            .and_then(|deadline| deadline.checked_duration_since(Instant::now()));
+
+        if remaining.is_none() {
+            deadlines.remove(registry);
+        }
+
+        remaining
+    }
+
+    pub fn back_off(&self, registry: &str, retry_after: Duration) {
+        let deadline = Instant::now() + retry_after;
+        let mut deadlines = self.deadlines.lock().unwrap();
+        let current_deadline = deadlines.entry(registry.to_string()).or_insert(deadline);
+
+        // Concurrent 429s may come with different values, keep the farthest deadline
+        if deadline > *current_deadline {
+            *current_deadline = deadline;
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use super::www_authenticate::AuthenticationChallenge;
+
+/// How upstream registries asked to be authenticated to, shared by all their clients so the base
+/// endpoint isn't queried again for every repository
+#[derive(Clone)]
+pub struct ChallengeCache {
+    ttl: Duration,
+    challenges: Arc<Mutex<HashMap<String, CachedChallenge>>>
+}
+
+struct CachedChallenge {
+    /// None when the registry can be accessed without any credentials
+    challenge: Option<AuthenticationChallenge>,
+    discovered_at: Instant
+}
+
+impl ChallengeCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            challenges: Default::default()
+        }
+    }
+
+    /// Challenge the registry answered with, if it was discovered recently enough. The inner option
+    /// is None when the registry needs no authentication.
+    pub fn get(&self, registry: &str) -> Option<Option<AuthenticationChallenge>> {
+        let mut challenges = self.challenges.lock().unwrap();
+        let cached_challenge = challenges.get(registry)?;
+
+        if cached_challenge.discovered_at.elapsed() >= self.ttl {
+            challenges.remove(registry);
+            return None;
+        }
+
+        Some(cached_challenge.challenge.clone())
+    }
+
+    pub fn insert(&self, registry: &str, challenge: Option<AuthenticationChallenge>) {
+        self.challenges.lock().unwrap().insert(registry.to_string(), CachedChallenge {
+            challenge,
+            discovered_at: Instant::now()
+        });
+    }
+
+    /// Forgets the challenge of a registry, so the next authentication discovers it again
+    pub fn forget(&self, registry: &str) {
+        self.challenges.lock().unwrap().remove(registry);
+    }
+}
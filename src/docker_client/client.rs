@@ -1,13 +1,19 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::{Instant, Duration, SystemTime}, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}};
 
-use reqwest::{RequestBuilder, IntoUrl, Method};
+use reqwest::{RequestBuilder, IntoUrl, Method, StatusCode, header::{HeaderMap, HeaderValue}};
+use tokio::sync::{Semaphore, OwnedSemaphorePermit, RwLock};
 use tracing::{info, warn, debug};
 
+use crate::requests::TraceContext;
+use crate::data::helpers::ContainerRefError;
 use crate::docker_client::{www_authenticate::AuthenticationChallenge, authentication_strategies::{AnonymousAuthStrategy, HttpBasicAuthStrategy, BearerTokenAuthStrategy}, client_responses::ProxyManifestResponse};
 
-use super::{www_authenticate::WwwAuthenticateError, authentication_strategies::AuthenticationStrategy, client_responses::ProxyBlobResponse};
+use super::{backoff::RegistryBackoff, challenges::ChallengeCache, credentials::CredentialsProvider, digest_mismatches::DigestMismatches, metrics::UpstreamMetrics, www_authenticate::WwwAuthenticateError, authentication_strategies::AuthenticationStrategy, client_responses::{ProxyBlobResponse, ProxyBlobHeadResponse, UploadStart}};
 
-const SUPPORTED_MIMETYPES: &[&'static str] = &[
+/// Backoff when a registry answers 429 without telling when to come back
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+const SUPPORTED_MIMETYPES: &[&str] = &[
     "application/vnd.docker.distribution.manifest.v2+json",
     "application/vnd.docker.distribution.manifest.list.v2+json",
     "application/vnd.docker.image.rootfs.diff.tar.gzip",
@@ -22,6 +28,9 @@ pub enum DockerClientError {
     #[error("Missing header {0} from the proxied registry")]
     MissingProxyHeader(String),
 
+    #[error("Header {0} from the proxied registry isn't valid UTF-8")]
+    InvalidProxyHeader(String),
+
     #[error("Provided credentials are errorneous or unable to be provided when requested")]
     BadAuthenticationCredentials,
 
@@ -31,43 +40,146 @@ pub enum DockerClientError {
     #[error("Authentication has not been initialized yet")]
     UninitiatedAuthentication,
 
+    #[error("Rate limited by the registry, retry in {} seconds", .retry_after.as_secs())]
+    RateLimited { retry_after: Duration },
+
+    #[error("Credential helper error: {0}")]
+    CredentialHelper(String),
+
+    #[error("Too many redirects, gave up after {0} hops")]
+    TooManyRedirects(usize),
+
+    #[error(transparent)]
+    InvalidUrl(#[from] url::ParseError),
+
+    #[error(transparent)]
+    InvalidContainerRef(#[from] ContainerRefError),
+
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error)
 }
 
+/// What the clients of the upstream registries share, each registry having its own state in there
+#[derive(Clone)]
+pub struct SharedRegistryState {
+    pub metrics: UpstreamMetrics,
+    /// Registries rate limiting us
+    pub backoff: RegistryBackoff,
+    /// Authentication challenges of the registries
+    pub challenges: ChallengeCache,
+    pub digest_mismatches: DigestMismatches
+}
+
 pub struct DockerClient {
-    auth_strat: Option<Box<dyn AuthenticationStrategy>>,
+    auth_strat: RwLock<Option<Box<dyn AuthenticationStrategy>>>,
+    /// Bumped each time the authentication is renewed, to tell whether a rejected request used stale credentials
+    authentication_generation: AtomicU64,
+    /// Set once the registry keeps rejecting our credentials, even renewed, so the store builds a new client
+    rejected: AtomicBool,
+    credentials: CredentialsProvider,
     registry: String,
     container: String,
-    http_client: reqwest::Client
+    /// Asks the registry for the right to push to the repository as well
+    push_access: bool,
+    http_client: reqwest::Client,
+    max_redirects: usize,
+    metrics: UpstreamMetrics,
+    connection_limit: Option<Arc<Semaphore>>,
+    backoff: RegistryBackoff,
+    challenges: ChallengeCache,
+    digest_mismatches: DigestMismatches
 }
 
 impl DockerClient {
-    pub fn new(registry: &str, container: &str, client: reqwest::Client) -> Self {
+    pub fn new(
+        registry: &str,
+        container: &str,
+        client: reqwest::Client,
+        max_redirects: usize,
+        connection_limit: Option<Arc<Semaphore>>,
+        shared_state: SharedRegistryState
+    ) -> Self {
         Self {
-            auth_strat: None,
+            auth_strat: RwLock::new(None),
+            authentication_generation: AtomicU64::new(0),
+            rejected: AtomicBool::new(false),
+            credentials: CredentialsProvider::Anonymous,
             registry: registry.to_string(),
             container: container.to_string(),
+            push_access: false,
             http_client: client,
+            max_redirects,
+            metrics: shared_state.metrics,
+            connection_limit,
+            backoff: shared_state.backoff,
+            challenges: shared_state.challenges,
+            digest_mismatches: shared_state.digest_mismatches
         }
     }
 
-    pub async fn authenticate(&mut self, registry_username: Option<&str>, registry_password: Option<&str>) -> Result<(), DockerClientError> {
-        if self.auth_strat.is_some() {
+    /// Client of a push-through repository, allowed to push to it
+    pub fn with_push_access(mut self) -> Self {
+        self.push_access = true;
+        self
+    }
+
+    pub async fn authenticate(&mut self, credentials: CredentialsProvider) -> Result<(), DockerClientError> {
+        if self.auth_strat.get_mut().is_some() {
             return Ok(());
         }
 
-        // Fetch the base and see what the authorization header has to say
+        self.credentials = credentials;
+        *self.auth_strat.get_mut() = Some(self.negotiate_authentication(None).await?);
+
+        if let Err(auth_error) = self.check_authentication().await {
+            *self.auth_strat.get_mut() = None;
+            self.challenges.forget(&self.registry);
+            return Err(auth_error);
+        }
+
+        Ok(())
+    }
+
+    /// Asks the registry how to authenticate, unless another client did recently, and runs the authentication flow.
+    async fn negotiate_authentication(&self, refresh_token: Option<String>) -> Result<Box<dyn AuthenticationStrategy>, DockerClientError> {
+        let auth_challenge = match self.challenges.get(&self.registry) {
+            Some(auth_challenge) => {
+                debug!("Reusing the authentication challenge of the registry {}", self.registry);
+                auth_challenge
+            },
+            None => {
+                let auth_challenge = self.discover_authentication().await?;
+                self.challenges.insert(&self.registry, auth_challenge.clone());
+                auth_challenge
+            }
+        };
+
+        let auth_challenge = match auth_challenge {
+            Some(auth_challenge) => auth_challenge,
+            None => return Ok(Box::new(AnonymousAuthStrategy))
+        };
+
+        // The registry may have moved its token endpoint since, it is asked again next time
+        let auth_strategy = self.execute_authentication(auth_challenge, refresh_token).await;
+        if auth_strategy.is_err() {
+            self.challenges.forget(&self.registry);
+        }
+
+        auth_strategy
+    }
+
+    /// Fetches the base and sees what the authorization header has to say. Returns None when the
+    /// registry can be accessed without any credentials.
+    async fn discover_authentication(&self) -> Result<Option<AuthenticationChallenge>, DockerClientError> {
         info!("Discovering authentication strategies for the registry {}", self.registry);
 
         let url = url::Url::from_str(&format!("https://{}/v2/", self.registry)).unwrap();
-        let base_response = self.http_client.get(url).send().await.unwrap();
+        let base_response = Self::add_trace_context(self.http_client.get(url)).send().await?;
 
         // If the server responds 200 immediately, we'll consider we don't need authentication.
         if base_response.status() == 200 {
             info!("Got 200, assuming repository can be accessed without any credentials");
-            self.auth_strat = Some(Box::new(AnonymousAuthStrategy));
-            return Ok(());
+            return Ok(None);
         }
 
         // The next thing we probably will have a 401 Unauthorized code with a WWW-Authenticate header.
@@ -77,21 +189,26 @@ impl DockerClient {
             return Err(DockerClientError::UnexpectedStatusCode(base_response.status().as_u16()));
         }
 
-        // This will be a crude parser. It DOES NOT support registries with multiple challenges and WILL be thrown off
-        // if a registry sends multiple challenges.
+        // Registries like Artifactory offer several challenges, in one header or several
         let www_authenticate = base_response.headers()
-            .get("WWW-Authenticate")
-            .expect("If we received a 401, we should have a WWW-Authenticate header. What's the point otherwise ?")
-            .to_str()
-            .expect("The header should contain only UTF8 characters");
-        info!("Got authentication challenge header [{}]", www_authenticate);
+            .get_all("WWW-Authenticate")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        info!("Got authentication challenge headers {:?}", www_authenticate);
 
-        let auth_challenge = AuthenticationChallenge::from_www_authenticate(www_authenticate)?;
+        Ok(Some(AuthenticationChallenge::from_www_authenticate(www_authenticate)?))
+    }
+
+    async fn execute_authentication(&self, auth_challenge: AuthenticationChallenge, refresh_token: Option<String>) -> Result<Box<dyn AuthenticationStrategy>, DockerClientError> {
+        let credentials = self.credentials.credentials(&self.http_client, &self.registry).await?;
+        let registry_username = credentials.username.as_deref();
+        let registry_password = credentials.password.as_deref();
 
         let mut auth_strategy: Box<dyn AuthenticationStrategy> = match auth_challenge {
             AuthenticationChallenge::Basic(_) if registry_username.is_some() => {
                 info!("Applying HTTP Basic for registry {}", self.registry);
-                Box::new(HttpBasicAuthStrategy::new(registry_username.unwrap(), registry_password))
+                Box::new(HttpBasicAuthStrategy::new(registry_username.unwrap(), registry_password, credentials.renew_at, credentials.expires_at))
             },
 
             AuthenticationChallenge::Basic(_) => {
@@ -101,29 +218,58 @@ impl DockerClient {
 
             AuthenticationChallenge::Bearer(_) => {
                 info!("Applying Bearer token authentication for registry {}", self.registry);
-                Box::new(BearerTokenAuthStrategy::new(&self.container))
+                let actions = if self.push_access { "pull,push" } else { "pull" };
+                Box::new(BearerTokenAuthStrategy::new(&self.container, actions, refresh_token.or(credentials.identity_token.clone())))
             }
         };
 
         auth_strategy.execute_authentication(
-            &self.http_client, auth_challenge.authentication_parameters(),
+            &self.http_client, &auth_challenge.authentication_parameters(),
             registry_username,
             registry_password
         ).await?;
 
-        self.auth_strat = Some(auth_strategy);
+        Ok(auth_strategy)
+    }
 
-        if let Err(auth_error) = self.check_authentication().await {
-            self.auth_strat = None;
-            return Err(auth_error);
+    /// Renews the authentication when it is about to expire or, given the generation a request was sent
+    /// with, when the registry rejected it. Requests waiting on the lock don't renew it a second time.
+    async fn refresh_authentication(&self, rejected_generation: Option<u64>) -> Result<(), DockerClientError> {
+        let mut auth_strat = self.auth_strat.write().await;
+
+        let is_up_to_date = match rejected_generation {
+            Some(generation) => self.authentication_generation.load(Ordering::Acquire) != generation,
+            None => auth_strat.as_ref().map(|strat| !strat.needs_reauthenticating()).unwrap_or(false)
+        };
+        if is_up_to_date {
+            return Ok(());
         }
 
+        info!("Renewing the authentication to the registry {}", self.registry);
+        if rejected_generation.is_some() {
+            self.credentials.invalidate().await;
+            // The registry may no longer take the authentication it asked for, such as anonymous pulls
+            self.challenges.forget(&self.registry);
+        }
+        let refresh_token = auth_strat.as_ref().and_then(|strat| strat.refresh_token());
+        let auth_strategy = match self.negotiate_authentication(refresh_token.clone()).await {
+            // The refresh token has expired, start over from the configured credentials
+            Err(DockerClientError::BadAuthenticationCredentials) if refresh_token.is_some() => {
+                warn!("Refresh token for the registry {} has been rejected", self.registry);
+                self.negotiate_authentication(None).await?
+            },
+            auth_strategy => auth_strategy?
+        };
+        *auth_strat = Some(auth_strategy);
+        self.authentication_generation.fetch_add(1, Ordering::AcqRel);
+
         Ok(())
     }
 
     pub async fn query_base(&self) -> Result<(), DockerClientError> {
         let query = self.http_client.get(format!("https://{}/v2/", self.registry));
-        let query = self.add_authentication(query);
+        let query = self.add_authentication(query).await?;
+        let query = Self::add_trace_context(query);
         let response = query.send().await?;
 
         if response.status() != 200 {
@@ -135,6 +281,21 @@ impl DockerClient {
 
     #[tracing::instrument(skip_all, fields(manifest_ref = manifest_ref, head = query_head))]
     pub async fn query_manifest(&self, manifest_ref: &str, query_head: bool) -> Result<ProxyManifestResponse, DockerClientError> {
+        self.send_manifest_query(manifest_ref, query_head, HeaderMap::new()).await
+    }
+
+    /// HEAD on a manifest, answered with a 304 by the registries supporting it when the manifest is still
+    /// the cached one. It ends up as `UnexpectedStatusCode(304)`, like the other statuses.
+    pub async fn revalidate_manifest(&self, manifest_ref: &str, cached_digest: &str) -> Result<ProxyManifestResponse, DockerClientError> {
+        let mut headers = HeaderMap::new();
+        if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", cached_digest)) {
+            headers.insert("If-None-Match", etag);
+        }
+
+        self.send_manifest_query(manifest_ref, true, headers).await
+    }
+
+    async fn send_manifest_query(&self, manifest_ref: &str, query_head: bool, headers: HeaderMap) -> Result<ProxyManifestResponse, DockerClientError> {
         let url = format!("https://{}/v2/{}/manifests/{}",
             self.registry,
             self.container,
@@ -143,7 +304,9 @@ impl DockerClient {
 
         let method = if query_head { Method::HEAD } else { Method::GET };
         debug!("Sending {} to {}", method, url);
-        let response = self.create_request(method, url)?.send().await?;
+        let (response, connection_permit) = self.send_request_with_headers(method, &url, headers).await?;
+        // HEAD responses have no body to wait for
+        let connection_permit = connection_permit.filter(|_| !query_head);
         debug!("Got response {}", response.status());
         debug!("Got headers: {:#?}", response.headers());
 
@@ -152,34 +315,21 @@ impl DockerClient {
         }
 
         Ok(ProxyManifestResponse {
-            hash: response.headers()
-                .get("Docker-Content-Digest")
-                .ok_or(DockerClientError::MissingProxyHeader("Docker-Content-Digest".to_string()))?
-                .to_str()
-                .expect("Invalid UTF-8 in header content")
-                .to_string(),
-            content_type: response.headers()
-                .get("Content-Type")
-                .ok_or(DockerClientError::MissingProxyHeader("Content-Type".to_string()))?
-                .to_str()
-                .expect("Invalid UTF-8 in header content")
-                .to_string(),
-            content_length: response.headers()
-                .get("Content-Length")
-                .ok_or(DockerClientError::MissingProxyHeader("Content-Length".to_string()))?
-                .to_str()
-                .expect("Invalid UTF-8 in header content")
-                .parse()
-                .expect("Content length is not a number"),
+            hash: Self::header(&response, "Docker-Content-Digest")?
+                .ok_or(DockerClientError::MissingProxyHeader("Docker-Content-Digest".to_string()))?,
+            content_type: Self::header(&response, "Content-Type")?
+                .ok_or(DockerClientError::MissingProxyHeader("Content-Type".to_string()))?,
+            content_length: Self::content_length(&response),
             raw_response: response,
+            connection_permit,
         })
     }
 
     pub async fn query_blob(&self, blob_hash: &str) -> Result<ProxyBlobResponse, DockerClientError> {
-        let response = self.create_request(
-            Method::GET, 
-            format!("https://{}/v2/{}/blobs/{}", self.registry, self.container, blob_hash)
-        )?.send().await?;
+        let (response, connection_permit) = self.send_request(
+            Method::GET,
+            &format!("https://{}/v2/{}/blobs/{}", self.registry, self.container, blob_hash)
+        ).await?;
 
         if response.status() != 200 {
             return Err(DockerClientError::UnexpectedStatusCode(response.status().as_u16()));
@@ -189,48 +339,346 @@ impl DockerClient {
         debug!("Returned headers: {:#?}", response.headers());
 
         Ok(ProxyBlobResponse {
-            hash: response.headers()
-                .get("Docker-Content-Digest")
-                .map(|value| value
-                    .to_str()
-                    .expect("Invalid UTF-8 in header content").to_string()
-                ),
-            content_length: response.headers()
-                .get("Content-Length")
-                .ok_or(DockerClientError::MissingProxyHeader("Content-Length".to_string()))?
-                .to_str()
-                .expect("Invalid UTF-8 in header content")
-                .parse()
-                .expect("Content length is not a number"),
+            hash: Self::header(&response, "Docker-Content-Digest")?,
+            content_length: Self::content_length(&response),
             raw_response: response,
+            connection_permit,
+        })
+    }
+
+    pub async fn query_blob_head(&self, blob_hash: &str) -> Result<ProxyBlobHeadResponse, DockerClientError> {
+        let (response, _connection_permit) = self.send_request(
+            Method::HEAD,
+            &format!("https://{}/v2/{}/blobs/{}", self.registry, self.container, blob_hash)
+        ).await?;
+
+        if response.status() != 200 {
+            return Err(DockerClientError::UnexpectedStatusCode(response.status().as_u16()));
+        }
+
+        debug!("Got response: {}", response.status());
+        debug!("Returned headers: {:#?}", response.headers());
+
+        Ok(ProxyBlobHeadResponse {
+            hash: Self::header(&response, "Docker-Content-Digest")?,
+            content_length: Self::content_length(&response),
         })
     }
 
-    pub fn authentication_needs_revalidation(&self) -> bool {
-        match &self.auth_strat {
+    /// Whether the repository holds the blob
+    pub async fn has_blob(&self, digest: &str) -> Result<bool, DockerClientError> {
+        match self.query_blob_head(digest).await {
+            Ok(_) => Ok(true),
+            Err(DockerClientError::UnexpectedStatusCode(404)) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Starts an upload to the repository, mounting the blob from another repository of the registry when given
+    /// its digest and the repository. Registries unable to mount it start a regular upload session instead.
+    pub async fn start_upload(&self, mount: Option<(&str, &str)>) -> Result<UploadStart, DockerClientError> {
+        let mut url = url::Url::parse(&format!("https://{}/v2/{}/blobs/uploads/", self.registry, self.container))?;
+        if let Some((digest, from)) = mount {
+            url.query_pairs_mut().append_pair("mount", digest).append_pair("from", from);
+        }
+
+        let (response, _connection_permit) = self.send_request(Method::POST, url.as_str()).await?;
+        match response.status().as_u16() {
+            201 => Ok(UploadStart::Mounted),
+            202 => {
+                let location = response.headers()
+                    .get("Location")
+                    .ok_or(DockerClientError::MissingProxyHeader("Location".to_string()))?
+                    .to_str()
+                    .map_err(|_| DockerClientError::MissingProxyHeader("Location".to_string()))?;
+                Ok(UploadStart::Session(url.join(location)?))
+            },
+            status => Err(DockerClientError::UnexpectedStatusCode(status))
+        }
+    }
+
+    /// Sends the whole blob to an upload session and completes it
+    pub async fn upload_blob(&self, mut session: url::Url, digest: &str, size: u64, body: reqwest::Body) -> Result<(), DockerClientError> {
+        session.query_pairs_mut().append_pair("digest", digest);
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", HeaderValue::from_static("application/octet-stream"));
+        headers.insert("Content-Length", HeaderValue::from(size));
+
+        let response = self.send_body(Method::PUT, session, headers, body).await?;
+        match response.status().as_u16() {
+            201 => Ok(()),
+            status => Err(DockerClientError::UnexpectedStatusCode(status))
+        }
+    }
+
+    /// Drops an upload session left unused, rather than waiting for the registry to expire it
+    pub async fn cancel_upload(&self, session: url::Url) -> Result<(), DockerClientError> {
+        let (response, _connection_permit) = self.send_request(Method::DELETE, session.as_str()).await?;
+        match response.status().as_u16() {
+            204 | 404 => Ok(()),
+            status => Err(DockerClientError::UnexpectedStatusCode(status))
+        }
+    }
+
+    pub async fn push_manifest(&self, manifest_ref: &str, content_type: &str, manifest: Vec<u8>) -> Result<(), DockerClientError> {
+        let url = url::Url::parse(&format!("https://{}/v2/{}/manifests/{}", self.registry, self.container, manifest_ref))?;
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", HeaderValue::from_str(content_type).map_err(|_| DockerClientError::MissingProxyHeader("Content-Type".to_string()))?);
+
+        let response = self.send_body(Method::PUT, url, headers, manifest.into()).await?;
+        match response.status().as_u16() {
+            201 => Ok(()),
+            status => Err(DockerClientError::UnexpectedStatusCode(status))
+        }
+    }
+
+    /// Value of a header of the registry response, if any
+    fn header(response: &reqwest::Response, name: &str) -> Result<Option<String>, DockerClientError> {
+        response.headers()
+            .get(name)
+            .map(|value| value.to_str().map(str::to_string).map_err(|_| DockerClientError::InvalidProxyHeader(name.to_string())))
+            .transpose()
+    }
+
+    /// Size of the body announced by the registry, if any and readable
+    fn content_length(response: &reqwest::Response) -> Option<u64> {
+        response.headers()
+            .get("Content-Length")?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    pub fn registry(&self) -> &str {
+        &self.registry
+    }
+
+    pub fn container(&self) -> &str {
+        &self.container
+    }
+
+    /// Kind of the authentication strategy in use, and the expiration of its token or password
+    pub async fn authentication(&self) -> (Option<&'static str>, Option<chrono::DateTime<chrono::Utc>>) {
+        match &*self.auth_strat.read().await {
+            Some(strat) => (Some(strat.kind()), strat.expires_at()),
+            None => (None, None)
+        }
+    }
+
+    /// Whether the registry rejected our credentials even after renewing them
+    pub fn is_rejected(&self) -> bool {
+        self.rejected.load(Ordering::Acquire)
+    }
+
+    /// Accounts for body bytes received from the registry, as they are streamed by the callers.
+    pub fn record_downloaded_bytes(&self, bytes: u64) {
+        self.metrics.record_downloaded_bytes(&self.registry, bytes);
+    }
+
+    /// Records content from the registry that doesn't match the digest it was expected to have, and was
+    /// therefore not cached. Returns whether the registry went over its budget of mismatches.
+    pub fn record_digest_mismatch(&self, expected_digest: &str, actual_digest: &str) -> bool {
+        self.digest_mismatches.record(&self.registry, &self.container, expected_digest, actual_digest)
+    }
+
+    async fn authentication_needs_revalidation(&self) -> bool {
+        match &*self.auth_strat.read().await {
             Some(strat) => strat.needs_reauthenticating(),
             None => false
         }
     }
 
-    fn create_request(&self, method: reqwest::Method, url: impl IntoUrl) -> Result<reqwest::RequestBuilder, DockerClientError> {
-        let builder = self.http_client.request(method, url);
-        let builder = self.auth_strat.as_ref().ok_or(DockerClientError::UninitiatedAuthentication)?.inject_authentication(builder);
+    async fn create_request(&self, method: reqwest::Method, url: impl IntoUrl, headers: HeaderMap) -> Result<reqwest::RequestBuilder, DockerClientError> {
+        let builder = self.http_client.request(method, url).headers(headers);
+        let builder = self.add_authentication(builder).await?;
+        let builder = Self::add_trace_context(builder);
         Ok(
             builder.
                 header("Accept", SUPPORTED_MIMETYPES.join(","))
         )
     }
 
-    fn add_authentication(&self, request: RequestBuilder) -> RequestBuilder {
-        self.auth_strat.as_ref().unwrap().inject_authentication(request)
+    /// Sends a request to the registry, following the redirects ourselves. Registries like the
+    /// DockerHub redirect blob downloads to a CDN, which must not see our registry credentials.
+    ///
+    /// The returned permit, if any, holds one of the connection slots of the registry and must be kept
+    /// until the response body has been read.
+    async fn send_request(&self, method: reqwest::Method, url: &str) -> Result<(reqwest::Response, Option<OwnedSemaphorePermit>), DockerClientError> {
+        self.send_request_with_headers(method, url, HeaderMap::new()).await
+    }
+
+    /// The headers only go to the registry, not to the hosts it redirects to
+    #[tracing::instrument(name = "upstream_request", skip_all, fields(registry = %self.registry, repository = %self.container, method = %method, url = url, status = tracing::field::Empty))]
+    async fn send_request_with_headers(&self, method: reqwest::Method, url: &str, headers: HeaderMap) -> Result<(reqwest::Response, Option<OwnedSemaphorePermit>), DockerClientError> {
+        // Don't make things worse while the registry is rate limiting us
+        if let Some(retry_after) = self.backoff.remaining(&self.registry) {
+            debug!("Registry {} is rate limiting us for {:?}", self.registry, retry_after);
+            return Err(DockerClientError::RateLimited { retry_after });
+        }
+
+        let permit = self.connection_permit().await;
+
+        // Renew tokens shortly before they expire rather than having a request rejected
+        if self.authentication_needs_revalidation().await {
+            self.refresh_authentication(None).await?;
+        }
+
+        let generation = self.authentication_generation.load(Ordering::Acquire);
+        let response = match self.send_attempt(method.clone(), url, headers.clone()).await? {
+            // The token may have been revoked or have expired earlier than announced: authenticate again and retry once
+            response if response.status() == 401 => {
+                warn!("Registry {} rejected our credentials, authenticating again", self.registry);
+                if let Err(e) = self.refresh_authentication(Some(generation)).await {
+                    if matches!(e, DockerClientError::BadAuthenticationCredentials) {
+                        self.rejected.store(true, Ordering::Release);
+                    }
+                    return Err(e);
+                }
+
+                let response = self.send_attempt(method, url, headers).await?;
+                if response.status() == 401 {
+                    warn!("Registry {} rejected our renewed credentials, the client will be built again", self.registry);
+                    self.rejected.store(true, Ordering::Release);
+                }
+                response
+            },
+            response => response
+        };
+
+        tracing::Span::current().record("status", response.status().as_u16());
+        if response.status() == 429 {
+            let retry_after = Self::retry_after(&response).unwrap_or(DEFAULT_RETRY_AFTER);
+            warn!("Registry {} is rate limiting us, backing off for {:?}", self.registry, retry_after);
+            self.backoff.back_off(&self.registry, retry_after);
+            return Err(DockerClientError::RateLimited { retry_after });
+        }
+
+        Ok((response, permit))
+    }
+
+    /// Sends a request with a body to the registry. The body is only streamed once: no redirect is followed,
+    /// and a request rejected with a stale token fails once the authentication is renewed.
+    #[tracing::instrument(name = "upstream_request", skip_all, fields(registry = %self.registry, repository = %self.container, method = %method, url = %url, status = tracing::field::Empty))]
+    async fn send_body(&self, method: reqwest::Method, url: url::Url, headers: HeaderMap, body: reqwest::Body) -> Result<reqwest::Response, DockerClientError> {
+        if let Some(retry_after) = self.backoff.remaining(&self.registry) {
+            debug!("Registry {} is rate limiting us for {:?}", self.registry, retry_after);
+            return Err(DockerClientError::RateLimited { retry_after });
+        }
+
+        let _permit = self.connection_permit().await;
+        if self.authentication_needs_revalidation().await {
+            self.refresh_authentication(None).await?;
+        }
+
+        let generation = self.authentication_generation.load(Ordering::Acquire);
+        let started_at = Instant::now();
+        let response = self.create_request(method, url, headers).await?.body(body).send().await;
+        match &response {
+            Ok(response) => self.metrics.record_response(&self.registry, response.status().as_u16(), started_at.elapsed()),
+            Err(_) => self.metrics.record_error(&self.registry, started_at.elapsed())
+        }
+        let response = response?;
+
+        tracing::Span::current().record("status", response.status().as_u16());
+        match response.status().as_u16() {
+            401 => {
+                warn!("Registry {} rejected our credentials, authenticating again for the next requests", self.registry);
+                self.refresh_authentication(Some(generation)).await?;
+                Err(DockerClientError::UnexpectedStatusCode(401))
+            },
+            429 => {
+                let retry_after = Self::retry_after(&response).unwrap_or(DEFAULT_RETRY_AFTER);
+                warn!("Registry {} is rate limiting us, backing off for {:?}", self.registry, retry_after);
+                self.backoff.back_off(&self.registry, retry_after);
+                Err(DockerClientError::RateLimited { retry_after })
+            },
+            _ => Ok(response)
+        }
+    }
+
+    /// One of the connection slots of the registry, when their number is limited
+    async fn connection_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let connection_limit = self.connection_limit.as_ref()?;
+        if connection_limit.available_permits() == 0 {
+            debug!("Waiting for a free connection slot to {}", self.registry);
+        }
+
+        Some(Arc::clone(connection_limit).acquire_owned().await.expect("Connection limit semaphore is never closed"))
+    }
+
+    /// Retry-After holds either a number of seconds or an HTTP date.
+    fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+        let retry_after = response.headers().get("Retry-After")?.to_str().ok()?.trim();
+
+        match retry_after.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => {
+                let date = chrono::DateTime::parse_from_rfc2822(retry_after).ok()?;
+                SystemTime::from(date).duration_since(SystemTime::now()).ok()
+            }
+        }
+    }
+
+    async fn send_attempt(&self, method: reqwest::Method, url: &str, headers: HeaderMap) -> Result<reqwest::Response, DockerClientError> {
+        let started_at = Instant::now();
+        let response = self.follow_redirects(method, url, headers).await;
+
+        match &response {
+            Ok(response) => self.metrics.record_response(&self.registry, response.status().as_u16(), started_at.elapsed()),
+            Err(_) => self.metrics.record_error(&self.registry, started_at.elapsed())
+        }
+
+        response
+    }
+
+    async fn follow_redirects(&self, method: reqwest::Method, url: &str, headers: HeaderMap) -> Result<reqwest::Response, DockerClientError> {
+        let mut current_url = url::Url::parse(url)?;
+        let registry_origin = current_url.origin();
+
+        for _ in 0..=self.max_redirects {
+            let request = if current_url.origin() == registry_origin {
+                self.create_request(method.clone(), current_url.clone(), headers.clone()).await?
+            } else {
+                Self::add_trace_context(self.http_client.request(method.clone(), current_url.clone()))
+            };
+
+            let response = request.send().await?;
+            // 304 Not Modified is no redirect
+            if !response.status().is_redirection() || response.status() == StatusCode::NOT_MODIFIED {
+                return Ok(response);
+            }
+
+            let location = Self::header(&response, "Location")?
+                .ok_or(DockerClientError::MissingProxyHeader("Location".to_string()))?;
+            let next_url = current_url.join(&location)?;
+            debug!("Following {} redirect to {}", response.status(), next_url);
+
+            current_url = next_url;
+        }
+
+        warn!("Too many redirects while querying {}", url);
+        Err(DockerClientError::TooManyRedirects(self.max_redirects))
+    }
+
+    async fn add_authentication(&self, request: RequestBuilder) -> Result<RequestBuilder, DockerClientError> {
+        let auth_strat = self.auth_strat.read().await;
+        Ok(auth_strat.as_ref().ok_or(DockerClientError::UninitiatedAuthentication)?.inject_authentication(request))
+    }
+
+    fn add_trace_context(request: RequestBuilder) -> RequestBuilder {
+        // Connect the downstream request trace to the upstream call, if we are handling one.
+        match TraceContext::current() {
+            Some(trace_context) => trace_context.inject(request),
+            None => request
+        }
     }
 
     async fn check_authentication(&self) -> Result<(), DockerClientError>{
         let response = self.query_base().await;
 
         match response {
-            Err(DockerClientError::UnexpectedStatusCode(code)) if code == 401 => {
+            Err(DockerClientError::UnexpectedStatusCode(401)) => {
                 warn!("Invalid credentials");
                 Err(DockerClientError::BadAuthenticationCredentials)
             },
@@ -246,4 +694,57 @@ impl DockerClient {
             },
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use axum::http;
+
+    use super::{DockerClient, DockerClientError};
+
+    fn response_with_content_length(content_length: Option<&str>) -> reqwest::Response {
+        let mut response = http::Response::builder().status(200);
+        if let Some(content_length) = content_length {
+            response = response.header("Content-Length", content_length);
+        }
+
+        reqwest::Response::from(response.body(Vec::<u8>::new()).unwrap())
+    }
+
+    #[test]
+    fn content_length_past_u32() {
+        let response = response_with_content_length(Some("5368709120"));
+        assert_eq!(DockerClient::content_length(&response), Some(5 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn content_length_up_to_u64() {
+        let response = response_with_content_length(Some(&u64::MAX.to_string()));
+        assert_eq!(DockerClient::content_length(&response), Some(u64::MAX));
+    }
+
+    #[test]
+    fn missing_content_length() {
+        let response = response_with_content_length(None);
+        assert_eq!(DockerClient::content_length(&response), None);
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        for content_length in ["", "-1", "4GiB", "18446744073709551616"] {
+            let response = response_with_content_length(Some(content_length));
+            assert_eq!(DockerClient::content_length(&response), None, "Content-Length {:?}", content_length);
+        }
+    }
+
+    #[test]
+    fn headers_not_in_utf8() {
+        let response = reqwest::Response::from(http::Response::builder()
+            .status(200)
+            .header("Docker-Content-Digest", http::HeaderValue::from_bytes(b"sha256:\xff").unwrap())
+            .body(Vec::<u8>::new())
+            .unwrap());
+
+        assert!(matches!(DockerClient::header(&response, "Docker-Content-Digest"), Err(DockerClientError::InvalidProxyHeader(_))));
+        assert_eq!(DockerClient::header(&response, "Content-Type").unwrap(), None);
+    }
+}
@@ -1,15 +1,35 @@
+use tokio::sync::OwnedSemaphorePermit;
 
 pub struct ProxyManifestResponse {
     // pub container: String,
     // pub manifest_ref: String,
     pub hash: String,
     pub content_type: String,
-    pub content_length: u32,
-    pub raw_response: reqwest::Response
+    /// Missing from chunked responses
+    pub content_length: Option<u64>,
+    pub raw_response: reqwest::Response,
+    /// Keeps the upstream connection slot of the registry until the body has been read
+    pub connection_permit: Option<OwnedSemaphorePermit>
 }
 
 pub struct ProxyBlobResponse {
     pub hash: Option<String>,
-    pub content_length: u32,
-    pub raw_response: reqwest::Response
-}
\ No newline at end of file
+    /// Missing from chunked responses
+    pub content_length: Option<u64>,
+    pub raw_response: reqwest::Response,
+    /// Keeps the upstream connection slot of the registry until the body has been read
+    pub connection_permit: Option<OwnedSemaphorePermit>
+}
+
+pub struct ProxyBlobHeadResponse {
+    pub hash: Option<String>,
+    pub content_length: Option<u64>
+}
+
+/// How the registry answered the start of an upload
+pub enum UploadStart {
+    /// The blob was mounted from another repository, there's nothing to send
+    Mounted,
+    /// The blob has to be sent to this upload session
+    Session(url::Url)
+}
@@ -1,60 +1,390 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, sync::Arc, net::SocketAddr, time::{Duration, Instant}};
 
-use tokio::sync::RwLock;
-use tracing::debug;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock, Semaphore};
+use tracing::{debug, info, warn};
 
-use crate::data::helpers::split_registry_and_container;
+use crate::{data::helpers::{split_registry_and_container, resolve_upstream_registry}, configuration::UpstreamConfiguration};
 
-use super::client::{DockerClient, DockerClientError};
+use super::{backoff::RegistryBackoff, challenges::ChallengeCache, client::{DockerClient, DockerClientError, SharedRegistryState}, credentials::CredentialsProvider, digest_mismatches::DigestMismatches, docker_config::DockerConfig, metrics::UpstreamMetrics, peers::Peers, signatures::SignatureVerifier};
+
+/// Appended to the keys of the clients allowed to push, which can't be found as the key of a repository
+const PUSH_CLIENT_KEY_SUFFIX: &str = "#push";
 
 #[derive(Clone)]
 pub struct DockerClientsStore {
     http_client: reqwest::Client,
-    docker_clients_store: Arc<RwLock<HashMap<String, Arc<DockerClient>>>>
+    /// Clients of the registries reached through their own proxy
+    proxied_http_clients: HashMap<String, reqwest::Client>,
+    configuration: UpstreamConfiguration,
+    peers: Peers,
+    signature_verifier: SignatureVerifier,
+    /// Metrics, rate limiting, authentication challenges and digest mismatches of the registries, shared by all their clients
+    shared_state: SharedRegistryState,
+    /// Bounds the simultaneous requests to each registry, shared by all the clients of the registry
+    connection_limits: Arc<RwLock<HashMap<String, Arc<Semaphore>>>>,
+    /// Shared by the clients of each registry, so short-lived credentials are fetched once per registry
+    credentials_providers: Arc<RwLock<HashMap<String, CredentialsProvider>>>,
+    docker_clients_store: Arc<RwLock<HashMap<String, CachedClient>>>,
+    /// Held while the client of a key is built, so its authentication runs once
+    client_initializations: Arc<RwLock<HashMap<String, Arc<Mutex<()>>>>>
+}
+
+struct CachedClient {
+    client: Arc<DockerClient>,
+    created_at: Instant,
+    last_used: std::sync::Mutex<Instant>
+}
+
+/// Client of an upstream repository cached in the store, with the credentials it uses
+#[derive(Serialize)]
+pub struct UpstreamClientSummary {
+    pub client_key: String,
+    pub registry: String,
+    pub repository: String,
+    /// "anonymous", "basic" or "bearer"
+    pub auth_strategy: Option<&'static str>,
+    pub token_expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub idle_seconds: u64,
+    /// The registry kept rejecting its credentials, it's built again on its next use
+    pub rejected: bool,
 }
 
 impl DockerClientsStore {
-    pub fn new() -> Self {
+    pub fn new(configuration: &UpstreamConfiguration) -> Self {
+        let http_client = Self::http_client_builder(configuration)
+            .build()
+            .expect("Unable to create the HTTP client");
+
+        let proxied_http_clients = configuration.registries
+            .iter()
+            .filter_map(|(registry, registry_configuration)| {
+                let proxy_url = registry_configuration.proxy.as_ref()?;
+                let proxy = reqwest::Proxy::all(proxy_url)
+                    .unwrap_or_else(|e| panic!("Invalid proxy {} for the registry {}: {}", proxy_url, registry, e));
+                let http_client = Self::http_client_builder(configuration)
+                    .proxy(proxy)
+                    .build()
+                    .expect("Unable to create the HTTP client");
+
+                Some((resolve_upstream_registry(registry), http_client))
+            })
+            .collect();
+
         Self {
-            http_client: reqwest::Client::new(),
-            docker_clients_store: Default::default()
+            http_client,
+            proxied_http_clients,
+            configuration: configuration.clone(),
+            peers: Peers::new(&configuration.peers, &configuration.user_agent()),
+            signature_verifier: SignatureVerifier::new(&configuration.signature_verification),
+            shared_state: SharedRegistryState {
+                metrics: UpstreamMetrics::new(),
+                backoff: RegistryBackoff::new(),
+                challenges: ChallengeCache::new(Duration::from_secs(configuration.challenge_ttl_seconds)),
+                digest_mismatches: DigestMismatches::new(&configuration.digest_mismatch_budget)
+            },
+            connection_limits: Default::default(),
+            credentials_providers: Default::default(),
+            docker_clients_store: Default::default(),
+            client_initializations: Default::default()
         }
     }
 
+    fn http_client_builder(configuration: &UpstreamConfiguration) -> reqwest::ClientBuilder {
+        // Redirects are followed by the Docker clients themselves
+        let builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .user_agent(configuration.user_agent());
+
+        configuration.dns_overrides
+            .iter()
+            .fold(builder, |builder, (host, addresses)| {
+                // The port is taken from the URL, whatever is set here
+                let addresses = addresses.iter()
+                    .map(|address| SocketAddr::new(*address, 0))
+                    .collect::<Vec<_>>();
+                builder.resolve_to_addrs(host, &addresses)
+            })
+    }
+
     #[tracing::instrument(skip_all, fields(registry_key = registry_container_key))]
     pub async fn get_client(&self, registry_container_key: &str) -> Result<Arc<DockerClient>, DockerClientError> {
-        let map_lock = self.docker_clients_store.read().await;
+        self.get_client_with_access(registry_container_key, false).await
+    }
+
+    /// Client allowed to push to the upstream repository, for the push-through repositories of the registry
+    pub async fn get_push_client(&self, registry_container_key: &str) -> Result<Arc<DockerClient>, DockerClientError> {
+        self.get_client_with_access(registry_container_key, true).await
+    }
+
+    async fn get_client_with_access(&self, registry_container_key: &str, push_access: bool) -> Result<Arc<DockerClient>, DockerClientError> {
+        let (registry, container) = split_registry_and_container(registry_container_key)?;
+        // Tokens allowing to push are only asked for when needed, the clients are kept apart
+        let registry_container_key = &match push_access {
+            true => format!("{}{}", registry_container_key, PUSH_CLIENT_KEY_SUFFIX),
+            false => registry_container_key.to_string()
+        };
 
         debug!("Checking if key exists");
-        if map_lock.contains_key(registry_container_key) {
+        if let Some(client) = self.cached_client(registry_container_key).await {
             debug!("Key exists");
-            let client = map_lock
-                .get(registry_container_key)
-                .expect("Registry key for the client must exist");
-
-            // Check if the authentication needs revalidation or not.
-            // If yes, we'll replace the client further in this function body. Otherwise, we can return
-            // it to the caller.
-            debug!("Check if key needs revalidation");
-            if !client.authentication_needs_revalidation() {
-                debug!("Doesn't need revalidation, returning");
-                return Ok(Arc::clone(client));
-            }
+            return Ok(client);
+        }
 
-            debug!("Key needs revalidation, continuing");
+        // A single task builds the client of a key, the others wait for it rather than authenticating
+        // to the registry at the same time
+        let initialization = self.client_initialization(registry_container_key).await;
+        let _initialization_guard = initialization.lock().await;
+        if let Some(client) = self.cached_client(registry_container_key).await {
+            debug!("Client built while waiting");
+            return Ok(client);
         }
 
-        drop(map_lock);
-        // Client doesn't exist or needs revalidation. We drop the existing read and will non-atomically upgrade to a write
-        // lock on the map.
-        let mut map_lock = self.docker_clients_store.write().await;
-        let (registry, container) = split_registry_and_container(registry_container_key);
-        let mut client = DockerClient::new(registry, container, self.http_client.clone());
-        client.authenticate(None, None).await?;
+        let mut client = DockerClient::new(
+            registry,
+            container,
+            self.proxied_http_clients.get(registry).unwrap_or(&self.http_client).clone(),
+            self.configuration.max_redirects,
+            self.connection_limit(registry).await,
+            self.shared_state.clone()
+        );
+        if push_access {
+            client = client.with_push_access();
+        }
+        client.authenticate(self.credentials_provider(registry).await).await?;
         let client = Arc::new(client);
 
-        map_lock.insert(registry_container_key.to_string(), Arc::clone(&client));
+        let mut map_lock = self.docker_clients_store.write().await;
+        map_lock.insert(registry_container_key.to_string(), CachedClient {
+            client: Arc::clone(&client),
+            created_at: Instant::now(),
+            last_used: std::sync::Mutex::new(Instant::now())
+        });
 
         Ok(client)
     }
-}
\ No newline at end of file
+
+    /// Client of the registry itself rather than one of its repositories, authenticated as `docker login` would.
+    /// It isn't cached, it's meant to check that the registry is reachable and takes the credentials.
+    pub async fn registry_client(&self, registry: &str) -> Result<DockerClient, DockerClientError> {
+        let registry = resolve_upstream_registry(registry);
+        let mut client = DockerClient::new(
+            &registry,
+            "",
+            self.proxied_http_clients.get(&registry).unwrap_or(&self.http_client).clone(),
+            self.configuration.max_redirects,
+            self.connection_limit(&registry).await,
+            self.shared_state.clone()
+        );
+        client.authenticate(self.credentials_provider(&registry).await).await?;
+
+        Ok(client)
+    }
+
+    /// Upstream registries given settings in the configuration, by their resolved names
+    pub fn configured_registries(&self) -> Vec<String> {
+        let mut registries = self.configuration.registries
+            .keys()
+            .map(|registry| resolve_upstream_registry(registry))
+            .collect::<Vec<_>>();
+        registries.sort();
+        registries.dedup();
+
+        registries
+    }
+
+    async fn cached_client(&self, registry_container_key: &str) -> Option<Arc<DockerClient>> {
+        let map_lock = self.docker_clients_store.read().await;
+        let cached_client = map_lock.get(registry_container_key)?;
+
+        // Clients renew their authentication by themselves, they are only built again once expired
+        // or when the registry keeps rejecting them
+        if !self.is_usable(cached_client) {
+            info!("Client of {} expired or rejected, building a new one", registry_container_key);
+            return None;
+        }
+
+        *cached_client.last_used.lock().unwrap() = Instant::now();
+        Some(Arc::clone(&cached_client.client))
+    }
+
+    async fn client_initialization(&self, registry_container_key: &str) -> Arc<Mutex<()>> {
+        let mut client_initializations = self.client_initializations.write().await;
+
+        let initialization = client_initializations
+            .entry(registry_container_key.to_string())
+            .or_default();
+
+        Arc::clone(initialization)
+    }
+
+    fn is_usable(&self, cached_client: &CachedClient) -> bool {
+        cached_client.created_at.elapsed() < Duration::from_secs(self.configuration.client_ttl_seconds)
+            && !cached_client.client.is_rejected()
+    }
+
+    /// Drops the expired and rejected clients, so the store doesn't keep the ones of repositories no longer pulled
+    pub async fn prune(&self) {
+        let mut map_lock = self.docker_clients_store.write().await;
+        map_lock.retain(|_, cached_client| self.is_usable(cached_client));
+        drop(map_lock);
+
+        // Initializations no task is holding are done with
+        let mut client_initializations = self.client_initializations.write().await;
+        client_initializations.retain(|_, initialization| Arc::strong_count(initialization) > 1);
+    }
+
+    /// Drops the clients of a registry, its cached credentials and challenge, to pick up rotated credentials.
+    /// Returns the number of dropped clients.
+    pub async fn flush_registry(&self, registry: &str) -> usize {
+        let registry = resolve_upstream_registry(registry);
+
+        let mut map_lock = self.docker_clients_store.write().await;
+        let clients_count = map_lock.len();
+        map_lock.retain(|_, cached_client| cached_client.client.registry() != registry);
+        let flushed = clients_count - map_lock.len();
+        drop(map_lock);
+
+        self.credentials_providers.write().await.remove(&registry);
+        self.shared_state.challenges.forget(&registry);
+        info!("Flushed {} clients of the registry {}", flushed, registry);
+
+        flushed
+    }
+
+    async fn connection_limit(&self, registry: &str) -> Option<Arc<Semaphore>> {
+        let max_concurrent_requests = self.configuration.max_concurrent_requests_per_registry?;
+        let mut connection_limits = self.connection_limits.write().await;
+
+        let semaphore = connection_limits
+            .entry(registry.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(max_concurrent_requests)));
+
+        Some(Arc::clone(semaphore))
+    }
+
+    async fn credentials_provider(&self, registry: &str) -> CredentialsProvider {
+        let mut credentials_providers = self.credentials_providers.write().await;
+
+        credentials_providers
+            .entry(registry.to_string())
+            .or_insert_with(|| match self.configuration.registry(registry) {
+                Some(registry_configuration) => CredentialsProvider::from_configuration(Some(registry_configuration)),
+                None => self.docker_config_credentials(registry)
+            })
+            .clone()
+    }
+
+    /// The config.json is read again for each registry, to pick up the logins made since the start.
+    fn docker_config_credentials(&self, registry: &str) -> CredentialsProvider {
+        let docker_config_path = match &self.configuration.docker_config {
+            Some(docker_config_path) => docker_config_path,
+            None => return CredentialsProvider::Anonymous
+        };
+
+        match DockerConfig::load(docker_config_path) {
+            Ok(docker_config) => docker_config
+                .credentials(registry)
+                .map(CredentialsProvider::Static)
+                .unwrap_or(CredentialsProvider::Anonymous),
+            Err(e) => {
+                warn!("Unable to read the Docker config {:?}: {}", docker_config_path, e);
+                CredentialsProvider::Anonymous
+            }
+        }
+    }
+
+    pub async fn len(&self) -> usize {
+        self.docker_clients_store.read().await.len()
+    }
+
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+
+    pub fn signature_verifier(&self) -> &SignatureVerifier {
+        &self.signature_verifier
+    }
+
+    pub fn metrics(&self) -> &UpstreamMetrics {
+        &self.shared_state.metrics
+    }
+
+    pub fn digest_mismatches(&self) -> &DigestMismatches {
+        &self.shared_state.digest_mismatches
+    }
+
+    /// Snapshot of the clients currently cached in the store, with their keys.
+    pub async fn clients(&self) -> Vec<(String, Arc<DockerClient>)> {
+        let map_lock = self.docker_clients_store.read().await;
+
+        map_lock.iter()
+            .map(|(key, cached_client)| (key.clone(), Arc::clone(&cached_client.client)))
+            .collect()
+    }
+
+    /// Clients currently cached in the store, with their authentication and use, by key.
+    pub async fn summaries(&self) -> Vec<UpstreamClientSummary> {
+        let cached_clients = self.docker_clients_store.read().await
+            .iter()
+            .map(|(key, cached_client)| (
+                key.clone(),
+                Arc::clone(&cached_client.client),
+                cached_client.created_at,
+                *cached_client.last_used.lock().unwrap()
+            ))
+            .collect::<Vec<_>>();
+
+        let now = Utc::now();
+        let time_of = |instant: Instant| now - chrono::Duration::from_std(instant.elapsed()).unwrap_or_else(|_| chrono::Duration::zero());
+        let mut summaries = Vec::with_capacity(cached_clients.len());
+        for (client_key, client, created_at, last_used) in cached_clients {
+            let (auth_strategy, token_expires_at) = client.authentication().await;
+            summaries.push(UpstreamClientSummary {
+                client_key,
+                registry: client.registry().to_string(),
+                repository: client.container().to_string(),
+                auth_strategy,
+                token_expires_at,
+                created_at: time_of(created_at),
+                last_used: time_of(last_used),
+                idle_seconds: last_used.elapsed().as_secs(),
+                rejected: client.is_rejected()
+            });
+        }
+        summaries.sort_by(|a, b| a.client_key.cmp(&b.client_key));
+
+        summaries
+    }
+}
+
+/// Writes the cached clients by registry and authentication strategy, and the time left before the
+/// soonest expiring token of each registry, in the Prometheus text format
+pub fn render_client_metrics(summaries: &[UpstreamClientSummary], output: &mut String) {
+    let mut clients = BTreeMap::<(&str, &str), usize>::new();
+    let mut soonest_expirations = BTreeMap::<&str, DateTime<Utc>>::new();
+    for summary in summaries {
+        *clients.entry((&summary.registry, summary.auth_strategy.unwrap_or("none"))).or_default() += 1;
+        if let Some(token_expires_at) = summary.token_expires_at {
+            soonest_expirations.entry(&summary.registry)
+                .and_modify(|soonest| *soonest = (*soonest).min(token_expires_at))
+                .or_insert(token_expires_at);
+        }
+    }
+
+    writeln!(output, "# HELP upstream_clients Clients of the upstream repositories cached with their credentials").unwrap();
+    writeln!(output, "# TYPE upstream_clients gauge").unwrap();
+    for ((registry, auth_strategy), count) in &clients {
+        writeln!(output, "upstream_clients{{registry=\"{}\",auth_strategy=\"{}\"}} {}", registry, auth_strategy, count).unwrap();
+    }
+
+    let now = Utc::now();
+    writeln!(output, "# HELP upstream_client_token_expiry_seconds Time left before the soonest expiring token or password of the registry clients").unwrap();
+    writeln!(output, "# TYPE upstream_client_token_expiry_seconds gauge").unwrap();
+    for (registry, expires_at) in &soonest_expirations {
+        writeln!(output, "upstream_client_token_expiry_seconds{{registry=\"{}\"}} {}", registry, (*expires_at - now).num_seconds()).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+use std::{sync::Arc, process::Stdio, time::Duration};
+
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::{sync::Mutex, io::AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::configuration::UpstreamRegistryConfiguration;
+
+use super::{authentication_strategies::refresh_margin, client::DockerClientError};
+
+/// Username ACR expects along with an identity token in place of the password
+const ACR_IDENTITY_TOKEN_USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Azure instance metadata service, giving out tokens for the managed identities of the machine
+const AZURE_IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+#[derive(Default, Clone, Debug)]
+pub struct RegistryCredentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Refresh token exchanged for access tokens with the OAuth2 flow
+    pub identity_token: Option<String>,
+    /// When short-lived credentials stop being accepted
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+    /// When short-lived credentials are renewed, some time before they expire
+    pub renew_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl RegistryCredentials {
+    pub fn new(username: Option<&str>, password: Option<&str>, identity_token: Option<&str>) -> Self {
+        // ACR hands out identity tokens as the password of a null user
+        if username == Some(ACR_IDENTITY_TOKEN_USERNAME) && identity_token.is_none() {
+            return Self {
+                identity_token: password.map(|password| password.to_string()),
+                ..Default::default()
+            };
+        }
+
+        Self {
+            username: username.map(|username| username.to_string()),
+            password: password.map(|password| password.to_string()),
+            identity_token: identity_token.map(|identity_token| identity_token.to_string()),
+            expires_at: None,
+            renew_at: None
+        }
+    }
+
+    /// The credentials, renewed with a margin before they expire
+    fn expiring_at(self, expires_at: chrono::DateTime<Utc>) -> Self {
+        let expires_in = (expires_at - Utc::now()).to_std().unwrap_or_default();
+        Self {
+            expires_at: Some(expires_at),
+            renew_at: Some(expires_at - chrono::Duration::from_std(refresh_margin(expires_in)).unwrap()),
+            ..self
+        }
+    }
+
+    fn needs_renewal(&self) -> bool {
+        self.renew_at.map(|renew_at| Utc::now() >= renew_at).unwrap_or(false)
+    }
+}
+
+/// Where the credentials of an upstream registry come from. They are resolved each time a client
+/// authenticates, so short-lived credentials are fetched again when needed.
+#[derive(Clone, Debug, Default)]
+pub enum CredentialsProvider {
+    #[default]
+    Anonymous,
+    Static(RegistryCredentials),
+    /// Exchanges a token of the Azure managed identity of the machine for an ACR identity token
+    AzureManagedIdentity { client_id: Option<String> },
+    /// Gets ECR authorization tokens with the AWS credentials of the environment. The tokens are valid
+    /// for 12 hours and shared by all the clients of the registry.
+    AwsEcr { region: Option<String>, cached_credentials: Arc<Mutex<Option<RegistryCredentials>>> },
+    /// Runs a Docker credential helper (`docker-credential-<helper> get`). Its answer is kept for
+    /// `cache_duration`, or until the registry rejects it.
+    CredentialHelper { helper: String, cache_duration: Duration, cached_credentials: Arc<Mutex<Option<RegistryCredentials>>> },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialHelperResponse {
+    username: String,
+    secret: String
+}
+
+#[derive(Deserialize)]
+struct AzureAccessToken {
+    access_token: String
+}
+
+#[derive(Deserialize)]
+struct AcrRefreshToken {
+    refresh_token: String
+}
+
+impl CredentialsProvider {
+    pub fn from_configuration(configuration: Option<&UpstreamRegistryConfiguration>) -> Self {
+        let configuration = match configuration {
+            Some(configuration) => configuration,
+            None => return Self::Anonymous
+        };
+
+        if configuration.aws_ecr {
+            return Self::AwsEcr {
+                region: configuration.aws_region.clone(),
+                cached_credentials: Default::default()
+            };
+        }
+
+        if let Some(helper) = &configuration.credential_helper {
+            return Self::CredentialHelper {
+                helper: helper.clone(),
+                cache_duration: Duration::from_secs(configuration.credential_helper_cache_seconds),
+                cached_credentials: Default::default()
+            };
+        }
+
+        if configuration.azure_managed_identity {
+            return Self::AzureManagedIdentity { client_id: configuration.azure_client_id.clone() };
+        }
+
+        Self::Static(RegistryCredentials::new(
+            configuration.username.as_deref(),
+            configuration.password.as_deref(),
+            configuration.identity_token.as_deref()
+        ))
+    }
+
+    pub async fn credentials(&self, client: &reqwest::Client, registry: &str) -> Result<RegistryCredentials, DockerClientError> {
+        match self {
+            Self::Anonymous => Ok(RegistryCredentials::default()),
+            Self::Static(credentials) => Ok(credentials.clone()),
+            Self::AzureManagedIdentity { client_id } => {
+                let identity_token = Self::exchange_azure_managed_identity(client, registry, client_id.as_deref()).await?;
+                Ok(RegistryCredentials::new(None, None, Some(&identity_token)))
+            },
+            Self::AwsEcr { region, cached_credentials } => {
+                let mut cached_credentials = cached_credentials.lock().await;
+                match &*cached_credentials {
+                    Some(credentials) if !credentials.needs_renewal() => Ok(credentials.clone()),
+                    _ => {
+                        let credentials = Self::get_ecr_authorization_token(registry, region.as_deref()).await?;
+                        *cached_credentials = Some(credentials.clone());
+                        Ok(credentials)
+                    }
+                }
+            },
+            Self::CredentialHelper { helper, cache_duration, cached_credentials } => {
+                let mut cached_credentials = cached_credentials.lock().await;
+                match &*cached_credentials {
+                    Some(credentials) if !credentials.needs_renewal() => Ok(credentials.clone()),
+                    _ => {
+                        let credentials = Self::run_credential_helper(helper, registry)
+                            .await?
+                            .expiring_at(Utc::now() + chrono::Duration::from_std(*cache_duration).unwrap());
+                        *cached_credentials = Some(credentials.clone());
+                        Ok(credentials)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forgets cached credentials, after the registry rejected them.
+    pub async fn invalidate(&self) {
+        match self {
+            Self::AwsEcr { cached_credentials, .. } | Self::CredentialHelper { cached_credentials, .. } => {
+                *cached_credentials.lock().await = None;
+            },
+            _ => ()
+        }
+    }
+
+    async fn run_credential_helper(helper: &str, registry: &str) -> Result<RegistryCredentials, DockerClientError> {
+        let program = format!("docker-credential-{}", helper);
+        info!("Getting the credentials of {} from {}", registry, program);
+
+        let mut process = tokio::process::Command::new(&program)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| DockerClientError::CredentialHelper(format!("unable to run {}: {}", program, e)))?;
+
+        // The helper reads the server URL on its standard input
+        let mut stdin = process.stdin.take().expect("Standard input of the helper is piped");
+        stdin.write_all(registry.as_bytes()).await
+            .map_err(|e| DockerClientError::CredentialHelper(format!("unable to write to {}: {}", program, e)))?;
+        drop(stdin);
+
+        let output = process.wait_with_output().await
+            .map_err(|e| DockerClientError::CredentialHelper(format!("unable to read from {}: {}", program, e)))?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stdout);
+            // Helpers have no credentials for registries the user never logged in to
+            if message.contains("credentials not found") {
+                info!("{} has no credentials for {}", program, registry);
+                return Ok(RegistryCredentials::default());
+            }
+
+            return Err(DockerClientError::CredentialHelper(format!("{} failed: {}", program, message.trim())));
+        }
+
+        let response = serde_json::from_slice::<CredentialHelperResponse>(&output.stdout)
+            .map_err(|e| DockerClientError::CredentialHelper(format!("invalid answer from {}: {}", program, e)))?;
+
+        // Identity tokens come with the "<token>" username
+        if response.username == "<token>" {
+            return Ok(RegistryCredentials::new(None, None, Some(&response.secret)));
+        }
+
+        Ok(RegistryCredentials::new(Some(&response.username), Some(&response.secret), None))
+    }
+
+    async fn get_ecr_authorization_token(registry: &str, region: Option<&str>) -> Result<RegistryCredentials, DockerClientError> {
+        // ECR registries are named <account>.dkr.ecr.<region>.amazonaws.com
+        let region = region
+            .map(|region| region.to_string())
+            .or_else(|| registry.split('.').nth(3).map(|region| region.to_string()));
+
+        info!("Requesting an ECR authorization token for {}", registry);
+        let mut aws_configuration = aws_config::defaults(aws_config::BehaviorVersion::latest());
+        if let Some(region) = region {
+            aws_configuration = aws_configuration.region(aws_config::Region::new(region));
+        }
+        let ecr_client = aws_sdk_ecr::Client::new(&aws_configuration.load().await);
+
+        let response = ecr_client.get_authorization_token().send().await.map_err(|e| {
+            warn!("Unable to get an ECR authorization token: {}", e);
+            DockerClientError::BadAuthenticationCredentials
+        })?;
+        let authorization_data = response
+            .authorization_data()
+            .first()
+            .ok_or(DockerClientError::BadAuthenticationCredentials)?;
+
+        // The token is the base64 of "AWS:<password>"
+        let token = authorization_data
+            .authorization_token()
+            .and_then(|token| base64::decode(token).ok())
+            .and_then(|token| String::from_utf8(token).ok())
+            .ok_or(DockerClientError::BadAuthenticationCredentials)?;
+        let (username, password) = token
+            .split_once(':')
+            .ok_or(DockerClientError::BadAuthenticationCredentials)?;
+
+        let credentials = RegistryCredentials::new(Some(username), Some(password), None);
+        let expires_at = authorization_data
+            .expires_at()
+            .and_then(|expires_at| chrono::NaiveDateTime::from_timestamp_opt(expires_at.secs(), 0))
+            .map(|expires_at| chrono::DateTime::from_utc(expires_at, Utc));
+
+        Ok(match expires_at {
+            Some(expires_at) => credentials.expiring_at(expires_at),
+            None => credentials
+        })
+    }
+
+    async fn exchange_azure_managed_identity(client: &reqwest::Client, registry: &str, client_id: Option<&str>) -> Result<String, DockerClientError> {
+        info!("Requesting an Azure managed identity token for {}", registry);
+        let mut token_request = client
+            .get(AZURE_IMDS_TOKEN_URL)
+            .header("Metadata", "true")
+            .query(&[("api-version", "2018-02-01"), ("resource", "https://containerregistry.azure.net")]);
+        if let Some(client_id) = client_id {
+            token_request = token_request.query(&[("client_id", client_id)]);
+        }
+
+        let response = token_request.send().await?;
+        if response.status() != 200 {
+            return Err(DockerClientError::UnexpectedStatusCode(response.status().as_u16()));
+        }
+        let azure_token = response.json::<AzureAccessToken>().await?;
+
+        info!("Exchanging the managed identity token for an ACR identity token");
+        let response = client
+            .post(format!("https://{}/oauth2/exchange", registry))
+            .form(&[
+                ("grant_type", "access_token"),
+                ("service", registry),
+                ("access_token", &azure_token.access_token)
+            ])
+            .send()
+            .await?;
+
+        match response.status().as_u16() {
+            200 => Ok(response.json::<AcrRefreshToken>().await?.refresh_token),
+            401 | 403 => Err(DockerClientError::BadAuthenticationCredentials),
+            status => Err(DockerClientError::UnexpectedStatusCode(status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::RegistryCredentials;
+
+    #[test]
+    fn short_lived_credentials_are_renewed_before_they_expire() {
+        let expires_at = Utc::now() + chrono::Duration::hours(12);
+        let credentials = RegistryCredentials::new(Some("AWS"), Some("token"), None).expiring_at(expires_at);
+
+        let renew_at = credentials.renew_at.unwrap();
+        assert_eq!(credentials.expires_at, Some(expires_at));
+        assert!(renew_at <= expires_at - chrono::Duration::minutes(71), "renewed at {}", renew_at);
+        assert!(renew_at >= expires_at - chrono::Duration::minutes(145), "renewed at {}", renew_at);
+        assert!(!credentials.needs_renewal());
+
+        let credentials = RegistryCredentials::new(Some("AWS"), Some("token"), None).expiring_at(Utc::now() + chrono::Duration::seconds(1));
+        assert!(credentials.renew_at.unwrap() < credentials.expires_at.unwrap());
+    }
+}
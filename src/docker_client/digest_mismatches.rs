@@ -0,0 +1,84 @@
+use std::{collections::{HashMap, VecDeque}, fmt::Write, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use tracing::{error, warn};
+
+use crate::configuration::DigestMismatchBudget;
+
+/// Content downloaded from the upstream registries that doesn't match its digest. A few are put down to
+/// broken downloads, more than the budget within its window point at a broken or compromised path to
+/// the registry.
+#[derive(Clone)]
+pub struct DigestMismatches {
+    max_mismatches: usize,
+    window: Duration,
+    registries: Arc<Mutex<HashMap<String, RegistryMismatches>>>
+}
+
+#[derive(Default)]
+struct RegistryMismatches {
+    total: u64,
+    /// Times of the mismatches within the window
+    recent: VecDeque<Instant>
+}
+
+impl RegistryMismatches {
+    fn forget_older_than(&mut self, window: Duration) {
+        while self.recent.front().map(|time| time.elapsed() > window).unwrap_or(false) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+impl DigestMismatches {
+    pub fn new(budget: &DigestMismatchBudget) -> Self {
+        Self {
+            max_mismatches: budget.max_mismatches,
+            window: Duration::from_secs(budget.window_seconds),
+            registries: Default::default()
+        }
+    }
+
+    /// Records content of the registry not matching the digest it was expected to have. Returns whether the
+    /// registry went over its budget.
+    pub fn record(&self, registry: &str, repository: &str, expected_digest: &str, actual_digest: &str) -> bool {
+        let mut registries = self.registries.lock().unwrap();
+        let mismatches = registries.entry(registry.to_string()).or_default();
+        mismatches.total += 1;
+        mismatches.recent.push_back(Instant::now());
+        mismatches.forget_older_than(self.window);
+
+        warn!("Content of {} from {} doesn't match the digest {}, got {}", repository, registry, expected_digest, actual_digest);
+        let exhausted = mismatches.recent.len() > self.max_mismatches;
+        if exhausted {
+            error!(
+                "{} downloads from {} didn't match their digest within {:?}, the path to the registry may be broken or compromised",
+                mismatches.recent.len(), registry, self.window
+            );
+        }
+
+        exhausted
+    }
+
+    /// Writes the mismatches in the Prometheus text format
+    pub fn render(&self, output: &mut String) {
+        let mut registries = self.registries.lock().unwrap();
+        for mismatches in registries.values_mut() {
+            mismatches.forget_older_than(self.window);
+        }
+        let mut registry_names = registries.keys().cloned().collect::<Vec<_>>();
+        registry_names.sort();
+
+        writeln!(output, "# HELP upstream_digest_mismatches_total Content downloaded from the upstream registries not matching its digest, never cached").unwrap();
+        writeln!(output, "# TYPE upstream_digest_mismatches_total counter").unwrap();
+        for registry in &registry_names {
+            writeln!(output, "upstream_digest_mismatches_total{{registry=\"{}\"}} {}", registry, registries[registry].total).unwrap();
+        }
+
+        writeln!(output, "# HELP upstream_digest_mismatch_budget_exhausted Whether the registry sent more mismatching content than the budget allows within its window").unwrap();
+        writeln!(output, "# TYPE upstream_digest_mismatch_budget_exhausted gauge").unwrap();
+        for registry in &registry_names {
+            let exhausted = registries[registry].recent.len() > self.max_mismatches;
+            writeln!(output, "upstream_digest_mismatch_budget_exhausted{{registry=\"{}\"}} {}", registry, u8::from(exhausted)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::Deserialize;
+
+use super::credentials::RegistryCredentials;
+
+/// Host the DockerHub credentials are stored under by `docker login`
+const DOCKER_HUB_AUTH_KEY: &str = "index.docker.io";
+
+#[derive(thiserror::Error, Debug)]
+pub enum DockerConfigError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Deserialize, Default)]
+struct DockerAuthEntry {
+    /// base64 of "username:password"
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// Credentials saved by `docker login` in a Docker-style config.json
+#[derive(Deserialize, Default)]
+pub struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuthEntry>,
+}
+
+impl DockerConfig {
+    pub fn load(path: &Path) -> Result<Self, DockerConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn credentials(&self, registry: &str) -> Option<RegistryCredentials> {
+        let registry = normalize_registry(registry);
+        let (_, entry) = self.auths
+            .iter()
+            .find(|(key, _)| normalize_registry(key) == registry)?;
+
+        let (username, password) = match entry.auth.as_ref().and_then(|auth| base64::decode(auth).ok()) {
+            Some(auth) => {
+                let auth = String::from_utf8(auth).ok()?;
+                let (username, password) = auth.split_once(':')?;
+                (Some(username.to_string()), Some(password.to_string()))
+            },
+            None => (entry.username.clone(), entry.password.clone())
+        };
+
+        Some(RegistryCredentials::new(
+            username.as_deref().filter(|username| !username.is_empty()),
+            password.as_deref().filter(|password| !password.is_empty()),
+            entry.identitytoken.as_deref().filter(|identity_token| !identity_token.is_empty())
+        ))
+    }
+}
+
+/// Keys of the auths section can be host names or URLs, and the DockerHub goes by several names.
+fn normalize_registry(registry: &str) -> &str {
+    let registry = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let registry = registry.split('/').next().unwrap_or(registry);
+
+    match registry {
+        "docker.io" | "registry-1.docker.io" => DOCKER_HUB_AUTH_KEY,
+        registry => registry
+    }
+}
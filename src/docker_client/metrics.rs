@@ -0,0 +1,124 @@
+use std::{collections::{HashMap, BTreeMap}, sync::{Arc, Mutex}, time::Duration, fmt::Write};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+pub const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default, Clone)]
+pub struct LatencyHistogram {
+    /// Number of observations less than or equal to each bucket of LATENCY_BUCKETS
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64
+}
+
+impl LatencyHistogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+
+        for (bucket, upper_bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= *upper_bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// Writes the histogram in the Prometheus text format
+    pub fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (index, upper_bound) in LATENCY_BUCKETS.iter().enumerate() {
+            let bucket = self.buckets.get(index).copied().unwrap_or(0);
+            writeln!(output, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, upper_bound, bucket).unwrap();
+        }
+        writeln!(output, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count).unwrap();
+        writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(output, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+#[derive(Default, Clone)]
+struct RegistryMetrics {
+    requests: u64,
+    errors: u64,
+    statuses: BTreeMap<u16, u64>,
+    latency: LatencyHistogram,
+    downloaded_bytes: u64
+}
+
+/// Requests sent to each upstream registry, shared by all the Docker clients.
+#[derive(Clone, Default)]
+pub struct UpstreamMetrics {
+    registries: Arc<Mutex<HashMap<String, RegistryMetrics>>>
+}
+
+impl UpstreamMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a request that got a response, whatever its status.
+    pub fn record_response(&self, registry: &str, status: u16, latency: Duration) {
+        let mut registries = self.registries.lock().unwrap();
+        let metrics = registries.entry(registry.to_string()).or_default();
+        metrics.requests += 1;
+        *metrics.statuses.entry(status).or_default() += 1;
+        metrics.latency.observe(latency);
+    }
+
+    /// Records a request that didn't get any response (connection failure, timeout...).
+    pub fn record_error(&self, registry: &str, latency: Duration) {
+        let mut registries = self.registries.lock().unwrap();
+        let metrics = registries.entry(registry.to_string()).or_default();
+        metrics.requests += 1;
+        metrics.errors += 1;
+        metrics.latency.observe(latency);
+    }
+
+    pub fn record_downloaded_bytes(&self, registry: &str, bytes: u64) {
+        let mut registries = self.registries.lock().unwrap();
+        registries.entry(registry.to_string()).or_default().downloaded_bytes += bytes;
+    }
+
+    /// Writes the metrics in the Prometheus text format
+    pub fn render(&self, output: &mut String) {
+        let registries = self.registries.lock().unwrap().clone();
+        let mut registry_names = registries.keys().collect::<Vec<_>>();
+        registry_names.sort();
+
+        writeln!(output, "# HELP upstream_requests_total Requests sent to the upstream registries").unwrap();
+        writeln!(output, "# TYPE upstream_requests_total counter").unwrap();
+        for registry in &registry_names {
+            writeln!(output, "upstream_requests_total{{registry=\"{}\"}} {}", registry, registries[*registry].requests).unwrap();
+        }
+
+        writeln!(output, "# HELP upstream_request_errors_total Upstream requests that failed without a response").unwrap();
+        writeln!(output, "# TYPE upstream_request_errors_total counter").unwrap();
+        for registry in &registry_names {
+            writeln!(output, "upstream_request_errors_total{{registry=\"{}\"}} {}", registry, registries[*registry].errors).unwrap();
+        }
+
+        writeln!(output, "# HELP upstream_responses_total Upstream responses by status code").unwrap();
+        writeln!(output, "# TYPE upstream_responses_total counter").unwrap();
+        for registry in &registry_names {
+            for (status, count) in &registries[*registry].statuses {
+                writeln!(output, "upstream_responses_total{{registry=\"{}\",status=\"{}\"}} {}", registry, status, count).unwrap();
+            }
+        }
+
+        writeln!(output, "# HELP upstream_request_duration_seconds Time until the upstream response headers are received").unwrap();
+        writeln!(output, "# TYPE upstream_request_duration_seconds histogram").unwrap();
+        for registry in &registry_names {
+            registries[*registry].latency.render(output, "upstream_request_duration_seconds", &format!("registry=\"{}\"", registry));
+        }
+
+        writeln!(output, "# HELP upstream_downloaded_bytes_total Bytes downloaded from the upstream registries").unwrap();
+        writeln!(output, "# TYPE upstream_downloaded_bytes_total counter").unwrap();
+        for registry in &registry_names {
+            writeln!(output, "upstream_downloaded_bytes_total{{registry=\"{}\"}} {}", registry, registries[*registry].downloaded_bytes).unwrap();
+        }
+    }
+}
@@ -1,5 +1,13 @@
 mod authentication_strategies;
+pub mod backoff;
+pub mod challenges;
 pub mod client;
 pub mod clients_store;
+pub mod credentials;
+pub mod digest_mismatches;
+pub mod docker_config;
 pub mod www_authenticate;
 pub mod client_responses;
+pub mod metrics;
+pub mod peers;
+pub mod signatures;
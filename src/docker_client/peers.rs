@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::requests::TraceContext;
+
+/// Marks requests coming from a sibling proxy. They are answered from the cache only, so proxies
+/// never bounce a cache miss between each other.
+pub const PEER_REQUEST_HEADER: &str = "X-Proxy-Peer-Request";
+
+/// Peers that don't answer this fast are skipped, the upstream registry is asked instead
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sibling proxy instances, asked for blobs before the upstream registries.
+#[derive(Clone)]
+pub struct Peers {
+    http_client: reqwest::Client,
+    peers: Vec<String>
+}
+
+impl Peers {
+    pub fn new(peers: &[String], user_agent: &str) -> Self {
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(PEER_CONNECT_TIMEOUT)
+            .user_agent(user_agent)
+            .build()
+            .expect("Unable to create the HTTP client");
+
+        Self {
+            http_client,
+            peers: peers.iter().map(|peer| peer.trim_end_matches('/').to_string()).collect()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Asks each peer in turn for a blob of its proxy cache. Returns the first successful response.
+    pub async fn query_blob(&self, container_ref: &str, digest: &str) -> Option<reqwest::Response> {
+        for peer in &self.peers {
+            let url = format!("{}/v2/proxy/{}/blobs/{}", peer, container_ref, digest);
+            debug!("Asking peer {} for the blob", peer);
+
+            let request = self.http_client.get(&url).header(PEER_REQUEST_HEADER, "1");
+            let request = match TraceContext::current() {
+                Some(trace_context) => trace_context.inject(request),
+                None => request
+            };
+
+            match request.send().await {
+                Ok(response) if response.status() == 200 => {
+                    info!("Peer {} has the blob", peer);
+                    return Some(response);
+                },
+                Ok(response) => debug!("Peer {} answered {}", peer, response.status()),
+                Err(e) => warn!("Unable to reach peer {}: {}", peer, e)
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashMap, sync::Arc};
+
+use openssl::{hash::MessageDigest, pkey::{PKey, Public}, sign::Verifier};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::configuration::{SignatureVerificationRule, repository_matches};
+
+use super::client::DockerClient;
+
+/// Annotation of the cosign signature layers holding the base64 signature of the layer
+const COSIGN_SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+#[derive(Deserialize)]
+struct SignatureManifest {
+    #[serde(default)]
+    layers: Vec<SignatureLayer>,
+}
+
+#[derive(Deserialize)]
+struct SignatureLayer {
+    digest: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// Simple signing payload, the document actually signed by cosign
+#[derive(Deserialize)]
+struct SimpleSigningPayload {
+    critical: SimpleSigningCritical,
+}
+
+#[derive(Deserialize)]
+struct SimpleSigningCritical {
+    image: SimpleSigningImage,
+}
+
+#[derive(Deserialize)]
+struct SimpleSigningImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+struct TrustedRepository {
+    repository: String,
+    public_keys: Vec<PKey<Public>>,
+}
+
+/// Checks the cosign signatures of proxied images against the public keys trusted for their repository
+#[derive(Clone)]
+pub struct SignatureVerifier {
+    repositories: Arc<Vec<TrustedRepository>>,
+}
+
+impl SignatureVerifier {
+    pub fn new(rules: &[SignatureVerificationRule]) -> Self {
+        let repositories = rules
+            .iter()
+            .map(|rule| TrustedRepository {
+                repository: rule.repository.clone(),
+                public_keys: rule.public_keys
+                    .iter()
+                    .map(|key_path| {
+                        let key = std::fs::read(key_path)
+                            .unwrap_or_else(|e| panic!("Unable to read the public key {:?}: {}", key_path, e));
+                        PKey::public_key_from_pem(&key)
+                            .unwrap_or_else(|e| panic!("Invalid public key {:?}: {}", key_path, e))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            repositories: Arc::new(repositories)
+        }
+    }
+
+    fn trusted_repository(&self, container_ref: &str) -> Option<&TrustedRepository> {
+        self.repositories.iter().find(|trusted_repository| repository_matches(&trusted_repository.repository, container_ref))
+    }
+
+    /// Whether the images of the repository need a signature to be cached and served
+    pub fn requires_signature(&self, container_ref: &str) -> bool {
+        self.trusted_repository(container_ref).is_some()
+    }
+
+    /// Looks for a cosign signature of the manifest made with one of the trusted keys. Returns why the manifest
+    /// is refused, None if it is properly signed or its repository doesn't need signatures.
+    pub async fn verify(&self, client: &DockerClient, container_ref: &str, digest: &str) -> eyre::Result<Option<String>> {
+        let trusted_repository = match self.trusted_repository(container_ref) {
+            Some(trusted_repository) => trusted_repository,
+            None => return Ok(None)
+        };
+
+        let signature_tag = format!("{}.sig", digest.replace(':', "-"));
+        let signature_manifest = match client.query_manifest(&signature_tag, false).await {
+            Ok(response) => response.raw_response.bytes().await?,
+            Err(e) => {
+                debug!("No signature for {}: {}", digest, e);
+                return Ok(Some(format!("No cosign signature found for {}", digest)));
+            }
+        };
+        let signature_manifest = serde_json::from_slice::<SignatureManifest>(&signature_manifest)?;
+
+        for layer in &signature_manifest.layers {
+            let signature = match layer.annotations.get(COSIGN_SIGNATURE_ANNOTATION) {
+                Some(signature) => base64::decode(signature)?,
+                None => continue
+            };
+
+            let payload = client.query_blob(&layer.digest).await?.raw_response.bytes().await?;
+            let payload_digest = format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(&payload)));
+            if payload_digest != layer.digest {
+                continue;
+            }
+
+            // The signature has to be about this very manifest, not another one of the repository
+            match serde_json::from_slice::<SimpleSigningPayload>(&payload) {
+                Ok(payload) if payload.critical.image.docker_manifest_digest == digest => (),
+                _ => continue
+            }
+
+            for public_key in &trusted_repository.public_keys {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), public_key)?;
+                verifier.update(&payload)?;
+                if verifier.verify(&signature).unwrap_or(false) {
+                    info!("Valid signature found for {}", digest);
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(format!("No cosign signature of {} made with a trusted key", digest)))
+    }
+}
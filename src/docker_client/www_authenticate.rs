@@ -1,50 +1,157 @@
 use std::collections::HashMap;
 
-use once_cell::sync::Lazy;
-use regex::Regex;
-
-static WWW_AUTHENTICATE_HEADER_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"((?P<method>[A-Za-z]+)\s)?(?P<key>[A-Za-z]+)\s*=\s*"(?P<value>[^"]+)""#).unwrap()
-});
-
 #[derive(thiserror::Error, Debug)]
 pub enum WwwAuthenticateError {
     #[error("Missing authentication method in header")]
     MissingMethod,
     #[error("Unsupported authentication method {0}")]
-    UnsupportedMethod(String)
+    UnsupportedMethod(String),
+    #[error("Malformed WWW-Authenticate header: {0}")]
+    Malformed(String)
 }
 
-pub enum AuthenticationChallenge<'auth> {
-    Basic(HashMap<&'auth str, &'auth str>),
-    Bearer(HashMap<&'auth str, &'auth str>)
+#[derive(Clone)]
+pub enum AuthenticationChallenge {
+    Basic(HashMap<String, String>),
+    Bearer(HashMap<String, String>)
 }
 
-impl<'auth> AuthenticationChallenge<'auth> {
-    pub fn from_www_authenticate(header_value: &'auth str) -> Result<Self, WwwAuthenticateError> {
-        let mut auth_strategy = None;
-        let mut auth_parameters = HashMap::new();
+impl AuthenticationChallenge {
+    /// Picks the strongest supported challenge among the ones of the WWW-Authenticate headers: Bearer, then Basic.
+    pub fn from_www_authenticate<'a>(header_values: impl IntoIterator<Item = &'a str>) -> Result<Self, WwwAuthenticateError> {
+        let mut challenges = Vec::new();
+        for header_value in header_values {
+            challenges.extend(parse_challenges(header_value)?);
+        }
 
-        for capture in WWW_AUTHENTICATE_HEADER_REGEX.captures_iter(header_value) {
-            if let Some(method) = capture.name("method") {
-                auth_strategy = Some(method.as_str().to_lowercase());
+        let mut basic = None;
+        let mut unsupported = None;
+        for challenge in challenges {
+            match challenge.scheme.as_str() {
+                "bearer" => return Ok(AuthenticationChallenge::Bearer(challenge.parameters)),
+                "basic" => basic = basic.or(Some(challenge.parameters)),
+                _ => unsupported = unsupported.or(Some(challenge.scheme))
             }
-
-            auth_parameters.insert(capture.name("key").unwrap().as_str(), capture.name("value").unwrap().as_str());
         }
 
-        match auth_strategy {
-            Some(method) if method == "basic" => Ok(AuthenticationChallenge::Basic(auth_parameters)),
-            Some(method) if method == "bearer" => Ok(AuthenticationChallenge::Bearer(auth_parameters)),
-            Some(method) => Err(WwwAuthenticateError::UnsupportedMethod(method)),
-            None => Err(WwwAuthenticateError::MissingMethod)
+        match (basic, unsupported) {
+            (Some(parameters), _) => Ok(AuthenticationChallenge::Basic(parameters)),
+            (None, Some(scheme)) => Err(WwwAuthenticateError::UnsupportedMethod(scheme)),
+            (None, None) => Err(WwwAuthenticateError::MissingMethod)
         }
     }
 
-    pub fn authentication_parameters(&self) -> &HashMap<&'auth str, &'auth str> {
-        match self {
+    pub fn authentication_parameters(&self) -> HashMap<&str, &str> {
+        let parameters = match self {
             AuthenticationChallenge::Basic(ref params) => params,
             AuthenticationChallenge::Bearer(ref params) => params,
+        };
+
+        parameters.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect()
+    }
+}
+
+/// A challenge of any scheme, the scheme and parameter names in lowercase
+struct Challenge {
+    scheme: String,
+    parameters: HashMap<String, String>
+}
+
+/// Challenges of a WWW-Authenticate header, as described in RFC 7235:
+/// `Basic realm="registry", Bearer realm="https://auth.example.com/token",service="registry"`.
+/// Challenges and parameters are both separated by commas, a parameter is told apart by the `=` following its name.
+fn parse_challenges(header_value: &str) -> Result<Vec<Challenge>, WwwAuthenticateError> {
+    let mut parser = Parser { rest: header_value };
+    let mut challenges: Vec<Challenge> = Vec::new();
+
+    loop {
+        parser.skip(|c| c == b',' || is_whitespace(c));
+        if parser.rest.is_empty() {
+            return Ok(challenges);
         }
+
+        let name = parser.token()?;
+        parser.skip(is_whitespace);
+
+        if parser.rest.starts_with('=') {
+            let challenge = challenges.last_mut()
+                .ok_or_else(|| WwwAuthenticateError::Malformed(format!("parameter {} before any scheme", name)))?;
+            parser.rest = &parser.rest[1..];
+            parser.skip(is_whitespace);
+            let value = match parser.rest.starts_with('"') {
+                true => parser.quoted_string()?,
+                false => parser.token()?.to_string()
+            };
+            challenge.parameters.insert(name.to_lowercase(), value);
+        } else {
+            challenges.push(Challenge { scheme: name.to_lowercase(), parameters: HashMap::new() });
+            // Schemes like Negotiate take a single base64-like value instead of parameters
+            parser.token68();
+        }
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str
+}
+
+impl<'a> Parser<'a> {
+    fn skip(&mut self, predicate: impl Fn(u8) -> bool) {
+        let skipped = self.rest.bytes().take_while(|c| predicate(*c)).count();
+        self.rest = &self.rest[skipped..];
     }
-}
\ No newline at end of file
+
+    fn token(&mut self) -> Result<&'a str, WwwAuthenticateError> {
+        let length = self.rest.bytes().take_while(|c| is_token_char(*c)).count();
+        if length == 0 {
+            return Err(WwwAuthenticateError::Malformed(format!("expected a token at [{}]", self.rest)));
+        }
+
+        let (token, rest) = self.rest.split_at(length);
+        self.rest = rest;
+        Ok(token)
+    }
+
+    fn quoted_string(&mut self) -> Result<String, WwwAuthenticateError> {
+        let mut value = String::new();
+        let mut chars = self.rest.char_indices().skip(1);
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[index + 1..];
+                    return Ok(value);
+                },
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break
+                },
+                c => value.push(c)
+            }
+        }
+
+        Err(WwwAuthenticateError::Malformed(format!("unterminated quoted string [{}]", self.rest)))
+    }
+
+    /// Skips a token68 if one comes next, ending the challenge
+    fn token68(&mut self) {
+        let length = self.rest.bytes().take_while(|c| c.is_ascii_alphanumeric() || b"-._~+/".contains(c)).count();
+        if length == 0 {
+            return;
+        }
+
+        let padding = self.rest[length..].bytes().take_while(|c| *c == b'=').count();
+        let after = self.rest[length + padding..].trim_start();
+        if after.is_empty() || after.starts_with(',') {
+            self.rest = after;
+        }
+    }
+}
+
+fn is_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
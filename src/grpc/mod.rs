@@ -0,0 +1,273 @@
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use hyper::server::accept::Accept;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tonic::{Request, Response, Status, codegen::InterceptedService, service::Interceptor, transport::Server};
+use tracing::{info, warn};
+
+use crate::{ApplicationState, configuration::GrpcConfiguration, controllers::{RegistryHttpError, prefetch::prefetch}, data::garbage_collection, listener, tenants::Tenant};
+
+use self::proto::{
+    AbortUploadRequest, AbortUploadResponse, CollectGarbageRequest, CollectGarbageResponse, GetUsageRequest, ListUploadsRequest,
+    ListUploadsResponse, PrewarmRequest, PrewarmResponse, PrewarmResult, PruneUploadsRequest, PruneUploadsResponse, Upload, Usage,
+    registry_admin_server::{RegistryAdmin, RegistryAdminServer}
+};
+
+/// The `registry.admin.v1` package, generated from `proto/registry_admin.proto`
+pub mod proto {
+    tonic::include_proto!("registry.admin.v1");
+}
+
+/// Serves the admin service over HTTP/2 on its own port until the shutdown future completes
+pub async fn serve<F: Future<Output = ()>>(configuration: GrpcConfiguration, app: ApplicationState, reuse_port: bool, shutdown: F) -> eyre::Result<()> {
+    let address = SocketAddr::from((configuration.address, configuration.port));
+    let mut incoming = listener::bind(&address, reuse_port)?;
+    let incoming = futures::stream::poll_fn(move |cx| Pin::new(&mut incoming).poll_accept(cx));
+
+    warn!("Serving the gRPC admin API on {}", address);
+    Server::builder()
+        .add_service(admin_service(&configuration.tokens, app))
+        .serve_with_incoming_shutdown(incoming, shutdown)
+        .await?;
+
+    Ok(())
+}
+
+/// The admin service, behind the check of the bearer tokens
+fn admin_service(tokens: &[String], app: ApplicationState) -> InterceptedService<RegistryAdminServer<AdminService>, BearerTokens> {
+    RegistryAdminServer::with_interceptor(AdminService { app }, BearerTokens::new(tokens))
+}
+
+/// Tokens accepted from the callers, kept as digests so they are compared in constant time whatever their length
+#[derive(Clone)]
+struct BearerTokens(Vec<[u8; 32]>);
+
+impl BearerTokens {
+    fn new(tokens: &[String]) -> Self {
+        Self(tokens.iter().map(|token| Sha256::digest(token.as_bytes()).into()).collect())
+    }
+}
+
+impl Interceptor for BearerTokens {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        let token = req.metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| Sha256::digest(token.as_bytes()));
+
+        // Every token is compared, the time taken doesn't tell which one nearly matched
+        let authorized = token.is_some_and(|token| {
+            self.0.iter().fold(0u8, |matched, allowed| matched | allowed.ct_eq(token.as_slice()).unwrap_u8()) == 1
+        });
+        if !authorized {
+            warn!("Refused a gRPC admin call without a valid token");
+            return Err(Status::unauthenticated("A valid bearer token is required"));
+        }
+
+        Ok(req)
+    }
+}
+
+fn internal<E: Into<RegistryHttpError>>(e: E) -> Status {
+    let e = e.into();
+    warn!("gRPC admin call failed: {}", e);
+    Status::internal(e.to_string())
+}
+
+struct AdminService {
+    app: ApplicationState
+}
+
+impl AdminService {
+    /// The storage roots at the top of the configuration without a tenant name
+    fn tenant(&self, tenant_name: &str) -> Option<std::sync::Arc<Tenant>> {
+        self.app.tenants.named(Some(tenant_name).filter(|tenant_name| !tenant_name.is_empty()))
+    }
+}
+
+fn unknown_tenant(tenant_name: &str) -> Status {
+    Status::not_found(format!("Unknown tenant {}", tenant_name))
+}
+
+#[tonic::async_trait]
+impl RegistryAdmin for AdminService {
+    async fn get_usage(&self, request: Request<GetUsageRequest>) -> Result<Response<Usage>, Status> {
+        info!("gRPC admin call GetUsage");
+        let tenant_name = request.into_inner().tenant;
+        let tenant = self.tenant(&tenant_name).ok_or_else(|| unknown_tenant(&tenant_name))?;
+
+        let storage_sizes = tenant.storage_sizes().await?;
+
+        Ok(Response::new(Usage {
+            registry_bytes: storage_sizes.registry_bytes,
+            temporary_bytes: storage_sizes.temporary_bytes,
+            proxy_bytes: storage_sizes.proxy_bytes,
+            active_uploads: self.app.uploads.len().await as u64,
+            uptime_seconds: self.app.started_at.elapsed().as_secs()
+        }))
+    }
+
+    async fn list_uploads(&self, _request: Request<ListUploadsRequest>) -> Result<Response<ListUploadsResponse>, Status> {
+        info!("gRPC admin call ListUploads");
+        let uploads = self.app.uploads.list().await.map_err(internal)?
+            .into_iter()
+            .map(|upload_summary| Upload {
+                id: upload_summary.id,
+                repository: upload_summary.repository,
+                bytes_received: upload_summary.bytes_received,
+                age_seconds: upload_summary.age_seconds,
+                writing: upload_summary.writing
+            })
+            .collect();
+
+        Ok(Response::new(ListUploadsResponse { uploads }))
+    }
+
+    async fn abort_upload(&self, request: Request<AbortUploadRequest>) -> Result<Response<AbortUploadResponse>, Status> {
+        info!("gRPC admin call AbortUpload");
+        let id = request.into_inner().id;
+        let upload_id = id.parse().map_err(|_| Status::invalid_argument(format!("Invalid upload ID {}", id)))?;
+        if !self.app.uploads.abort_upload(upload_id).await.map_err(internal)? {
+            return Err(Status::not_found(format!("Upload {} not found", id)));
+        }
+
+        Ok(Response::new(AbortUploadResponse {}))
+    }
+
+    async fn prune_uploads(&self, _request: Request<PruneUploadsRequest>) -> Result<Response<PruneUploadsResponse>, Status> {
+        info!("gRPC admin call PruneUploads");
+        Ok(Response::new(PruneUploadsResponse { pruned: self.app.uploads.prune().await as u64 }))
+    }
+
+    async fn prewarm(&self, request: Request<PrewarmRequest>) -> Result<Response<PrewarmResponse>, Status> {
+        info!("gRPC admin call Prewarm");
+        let request = request.into_inner();
+        let platform = Some(request.platform).filter(|platform| !platform.is_empty());
+        let parallelism = Some(request.parallelism as usize).filter(|parallelism| *parallelism > 0);
+
+        let results = prefetch(&self.app, request.images, platform, parallelism).await
+            .into_iter()
+            .map(|prefetch_result| PrewarmResult {
+                image: prefetch_result.image,
+                cached: prefetch_result.cached,
+                digest: prefetch_result.digest.unwrap_or_default(),
+                blobs: prefetch_result.blobs as u64,
+                error: prefetch_result.error.unwrap_or_default()
+            })
+            .collect();
+
+        Ok(Response::new(PrewarmResponse { results }))
+    }
+
+    async fn collect_garbage(&self, request: Request<CollectGarbageRequest>) -> Result<Response<CollectGarbageResponse>, Status> {
+        info!("gRPC admin call CollectGarbage");
+        let request = request.into_inner();
+        let tenant = self.tenant(&request.tenant).ok_or_else(|| unknown_tenant(&request.tenant))?;
+
+        let report = garbage_collection::collect_garbage_async(tenant.registry_storage.clone(), self.app.storage_cipher.clone(), request.dry_run)
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+        info!("Garbage collection removed {} blobs, {} bytes", report.removed_blobs, report.freed_bytes);
+        if !request.dry_run {
+            tenant.recount_usage().await?;
+        }
+
+        Ok(Response::new(CollectGarbageResponse {
+            removed_blobs: report.removed_blobs,
+            freed_bytes: report.freed_bytes,
+            skipped_repositories: report.skipped_repositories
+        }))
+    }
+}
+
+impl From<RegistryHttpError> for Status {
+    fn from(e: RegistryHttpError) -> Self {
+        internal(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+
+    use tonic::{Code, Request, transport::{Channel, Server}};
+    use uuid::Uuid;
+
+    use super::{admin_service, proto::{AbortUploadRequest, CollectGarbageRequest, GetUsageRequest, registry_admin_client::RegistryAdminClient}};
+    use crate::RegistryServer;
+
+    /// A client of the admin service of a registry served on a port of its own
+    async fn client(storage: &std::path::Path) -> RegistryAdminClient<Channel> {
+        let server = RegistryServer::builder()
+            .registry_storage(storage.join("registry"))
+            .temporary_registry_storage(storage.join("tmp"))
+            .proxy_storage(storage.join("proxy"))
+            .build()
+            .await
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = futures::stream::poll_fn(move |cx| {
+            Pin::new(&listener).poll_accept(cx).map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+        });
+        tokio::spawn(Server::builder().add_service(admin_service(&["secret".to_string()], server.state)).serve_with_incoming(incoming));
+
+        RegistryAdminClient::connect(format!("http://{}", address)).await.unwrap()
+    }
+
+    fn authorized<T>(message: T, token: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn calls_need_a_valid_token() {
+        let storage = std::env::temp_dir().join(format!("grpc-{}", Uuid::new_v4()));
+        let mut client = client(&storage).await;
+
+        let status = client.get_usage(GetUsageRequest::default()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = client.get_usage(authorized(GetUsageRequest::default(), "secreT")).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = client.get_usage(authorized(GetUsageRequest::default(), "secret-but-longer")).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let usage = client.get_usage(authorized(GetUsageRequest::default(), "secret")).await.unwrap().into_inner();
+        assert_eq!(usage.active_uploads, 0);
+
+        std::fs::remove_dir_all(&storage).unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_larger_than_a_frame() {
+        let storage = std::env::temp_dir().join(format!("grpc-{}", Uuid::new_v4()));
+        let mut client = client(&storage).await;
+
+        // Sent in several HTTP/2 DATA frames, the whole ID makes it to the handler
+        let id = "0".repeat(200_000);
+        let status = client.abort_upload(authorized(AbortUploadRequest { id: id.clone() }, "secret")).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), format!("Invalid upload ID {}", id));
+
+        std::fs::remove_dir_all(&storage).unwrap();
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_of_a_tenant() {
+        let storage = std::env::temp_dir().join(format!("grpc-{}", Uuid::new_v4()));
+        let mut client = client(&storage).await;
+
+        let report = client.collect_garbage(authorized(CollectGarbageRequest { tenant: String::new(), dry_run: true }, "secret")).await.unwrap().into_inner();
+        assert_eq!((report.removed_blobs, report.freed_bytes), (0, 0));
+
+        let status = client.collect_garbage(authorized(CollectGarbageRequest { tenant: "unknown".to_string(), dry_run: false }, "secret")).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        std::fs::remove_dir_all(&storage).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use tracing::warn;
+
+/// Asks the instance named in the PID file to drain, now that this one listens on the same port,
+/// and takes its place in the file
+pub fn take_over(pid_file: &Path) -> std::io::Result<()> {
+    let own_pid = std::process::id();
+
+    match std::fs::read_to_string(pid_file) {
+        Ok(previous_pid) => match previous_pid.trim().parse::<i32>() {
+            Ok(previous_pid) if previous_pid as u32 != own_pid => ask_to_drain(previous_pid),
+            Ok(_) => (),
+            Err(_) => warn!("Ignoring the malformed PID file {}", pid_file.display())
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e)
+    }
+
+    let temporary_pid_file = pid_file.with_extension("tmp");
+    std::fs::write(&temporary_pid_file, own_pid.to_string())?;
+    std::fs::rename(&temporary_pid_file, pid_file)
+}
+
+#[cfg(unix)]
+fn ask_to_drain(pid: i32) {
+    // A stale PID file may name a process that reused the PID since, which must not be stopped
+    let own_name = process_name(std::process::id() as i32);
+    match process_name(pid) {
+        Some(name) if Some(&name) == own_name.as_ref() => (),
+        Some(name) => {
+            warn!("PID {} of the PID file is now {}, not a previous instance, leaving it alone", pid, name);
+            return;
+        },
+        None => {
+            tracing::info!("The previous instance (PID {}) is not running anymore", pid);
+            return;
+        }
+    }
+
+    // SAFETY: kill only sends a signal, a process that is already gone makes it fail with ESRCH
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+        warn!("Asked the previous instance (PID {}) to drain", pid);
+    } else {
+        tracing::info!("The previous instance (PID {}) is not running anymore", pid);
+    }
+}
+
+/// Name of the executable a process runs, as the kernel records it
+#[cfg(target_os = "linux")]
+fn process_name(pid: i32) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|name| name.trim_end().to_string())
+}
+
+/// Name of the executable a process runs, from `ps` where there is no procfs
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_name(pid: i32) -> Option<String> {
+    let output = std::process::Command::new("ps").args(["-o", "comm=", "-p", &pid.to_string()]).output().ok()?;
+    let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let name = Path::new(&name).file_name()?.to_string_lossy().to_string();
+
+    Some(name).filter(|_| output.status.success())
+}
+
+#[cfg(not(unix))]
+fn ask_to_drain(pid: i32) {
+    warn!("The previous instance (PID {}) has to be stopped separately on this system", pid);
+}
+
+/// Removes the PID file, unless it already names the instance that took over
+pub fn release(pid_file: &Path) {
+    let owned = std::fs::read_to_string(pid_file)
+        .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+
+    if owned {
+        if let Err(e) = std::fs::remove_file(pid_file) {
+            warn!("Unable to remove the PID file {}: {}", pid_file.display(), e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use uuid::Uuid;
+
+    use super::take_over;
+
+    #[test]
+    fn processes_that_reused_the_pid_are_left_alone() {
+        let pid_file = std::env::temp_dir().join(format!("handoff-{}.pid", Uuid::new_v4()));
+        let mut other_process = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        std::fs::write(&pid_file, other_process.id().to_string()).unwrap();
+
+        take_over(&pid_file).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(other_process.try_wait().unwrap().is_none(), "the process was stopped");
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap(), std::process::id().to_string());
+
+        other_process.kill().unwrap();
+        other_process.wait().unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+}
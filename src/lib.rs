@@ -0,0 +1,578 @@
+mod authentication;
+pub mod cli;
+pub mod configuration;
+mod controllers;
+mod requests;
+mod repository_path;
+mod data;
+mod docker_client;
+mod grpc;
+mod handoff;
+mod listener;
+pub mod logging;
+mod notifications;
+mod policy;
+mod push_through;
+mod scanner;
+mod tenants;
+mod upload_affinity;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use axum::Router;
+use axum::extract::FromRef;
+use axum::routing::{get, post, patch, delete, MethodRouter};
+use axum::error_handling::HandleErrorLayer;
+use docker_client::clients_store::DockerClientsStore;
+use tokio::sync::RwLock;
+use tower::ServiceBuilder;
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+use crate::authentication::Authenticator;
+use crate::configuration::{AuthenticationConfiguration, AuthenticationMethod, Configuration, MemoryStorageConfiguration};
+use crate::listener::LimitedIncoming;
+use crate::repository_path::{RepositoryRouter, RepositoryRoute};
+use crate::notifications::Notifier;
+use crate::scanner::Scanner;
+use crate::tenants::Tenants;
+use crate::data::{cache_watermark, deduplication, proxy_cache};
+use crate::data::cache_stats::CacheStatistics;
+use crate::data::open_blobs::OpenBlobs;
+use crate::data::encryption::StorageCipher;
+use crate::data::memory_storage::MemoryStorage;
+use crate::data::rate_limits::RateLimiter;
+use crate::data::throttling::BandwidthLimiter;
+use crate::data::uploads::UploadsStore;
+use crate::upload_affinity::UploadAffinity;
+
+pub type UploadsInProgressState = Arc<RwLock<UploadsStore>>;
+
+static UPLOAD_PRUNE_INTERVAL: u64 = 60;
+static UPLOAD_PRUNE_AGE: u64 = 180;
+static RATE_LIMIT_PRUNE_AGE: u64 = 3600;
+static QUOTA_RECOUNT_INTERVAL: u64 = 3600;
+
+#[derive(FromRef, Clone)]
+pub struct ApplicationState {
+    conf: Arc<Configuration>,
+    docker_clients: DockerClientsStore,
+    uploads: UploadsStore,
+    authenticator: Option<Arc<Authenticator>>,
+    rate_limiter: RateLimiter,
+    bandwidth_limiter: BandwidthLimiter,
+    cache_stats: CacheStatistics,
+    #[from_ref(skip)]
+    open_blobs: Option<OpenBlobs>,
+    #[from_ref(skip)]
+    upload_affinity: Option<Arc<UploadAffinity>>,
+    #[from_ref(skip)]
+    storage_cipher: Option<Arc<StorageCipher>>,
+    #[from_ref(skip)]
+    scanner: Option<Arc<Scanner>>,
+    #[from_ref(skip)]
+    notifier: Option<Arc<Notifier>>,
+    #[from_ref(skip)]
+    tenants: Tenants,
+    #[from_ref(skip)]
+    started_at: Instant
+}
+
+/// Bounds the time the handlers of a route have to answer, the client gets a 503 past the deadline
+fn with_deadline(route: MethodRouter<ApplicationState>, deadline: Option<Duration>) -> MethodRouter<ApplicationState> {
+    match deadline {
+        Some(deadline) => route.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(controllers::base::handle_deadline))
+                .timeout(deadline)
+        ),
+        None => route
+    }
+}
+
+/// Address of the HTTP server unless told otherwise
+const DEFAULT_ADDRESS: ([u8; 4], u16) = ([0, 0, 0, 0], 8000);
+
+/// Settings of a registry server before it is started. Everything comes from the configuration file by default,
+/// the other settings override parts of it.
+pub struct RegistryServerBuilder {
+    configuration: Configuration,
+    address: SocketAddr,
+    proxy: bool
+}
+
+impl RegistryServerBuilder {
+    fn new() -> Self {
+        Self {
+            configuration: Configuration {
+                registry_storage: PathBuf::from("storage/registry"),
+                temporary_registry_storage: PathBuf::from("storage/_tmp"),
+                proxy_storage: PathBuf::from("storage/proxy"),
+                ..Default::default()
+            },
+            address: SocketAddr::from(DEFAULT_ADDRESS),
+            proxy: true
+        }
+    }
+
+    /// Replaces the whole configuration, as loaded from a configuration file
+    pub fn configuration(mut self, configuration: Configuration) -> Self {
+        self.configuration = configuration;
+        self
+    }
+
+    pub fn registry_storage<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.configuration.registry_storage = path.into();
+        self
+    }
+
+    pub fn temporary_registry_storage<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.configuration.temporary_registry_storage = path.into();
+        self
+    }
+
+    pub fn proxy_storage<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.configuration.proxy_storage = path.into();
+        self
+    }
+
+    /// Keeps the storage in memory, up to this many bytes, instead of the storage roots
+    pub fn in_memory(mut self, max_bytes: u64) -> Self {
+        self.configuration.storage.memory = Some(MemoryStorageConfiguration { max_bytes });
+        self
+    }
+
+    /// Downstream authentication, None to let everyone in
+    pub fn authentication(mut self, authentication: Option<AuthenticationConfiguration>) -> Self {
+        self.configuration.authentication = authentication;
+        self
+    }
+
+    /// Serves the pull-through cache of the upstream registries under `/v2/proxy/`, on by default
+    pub fn proxy(mut self, enabled: bool) -> Self {
+        self.proxy = enabled;
+        self
+    }
+
+    /// Address of the HTTP server, `0.0.0.0:8000` by default
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Prepares the storage and loads the keys and policies of the configuration, without serving anything yet
+    pub async fn build(self) -> eyre::Result<RegistryServer> {
+        let mut configuration = self.configuration;
+
+        let memory_storage = match &configuration.storage.memory {
+            Some(memory) => {
+                let memory_storage = MemoryStorage::create(memory)?;
+                memory_storage.relocate(&mut configuration);
+                Some(Arc::new(memory_storage))
+            },
+            None => None
+        };
+
+        info!("Creating registry directories");
+        tokio::fs::create_dir_all(&configuration.registry_storage).await?;
+        tokio::fs::create_dir_all(&configuration.temporary_registry_storage).await?;
+        tokio::fs::create_dir_all(&configuration.proxy_storage).await?;
+        for tenant in &configuration.tenants {
+            tokio::fs::create_dir_all(&tenant.registry_storage).await?;
+            tokio::fs::create_dir_all(&tenant.temporary_registry_storage).await?;
+            tokio::fs::create_dir_all(&tenant.proxy_storage).await?;
+        }
+
+        if let Some(max_blocking_queue) = configuration.runtime.max_blocking_queue {
+            data::helpers::limit_hashing_jobs(max_blocking_queue.get());
+        }
+
+        // The proxy cache used to be keyed by the registry name found in the request
+        for registry in proxy_cache::migrate_registry_aliases(&configuration.proxy_storage)? {
+            info!("Moved the proxy cache of {} to its upstream registry", registry);
+        }
+
+        let authenticator = match &configuration.authentication {
+            Some(authentication) => {
+                info!("Loading downstream authentication settings");
+                Some(Arc::new(Authenticator::load(authentication)?))
+            },
+            None => None
+        };
+
+        let storage_cipher = match &configuration.storage.encryption {
+            Some(encryption) => {
+                info!("Loading the storage encryption key");
+                Some(Arc::new(StorageCipher::load(encryption)?))
+            },
+            None => None
+        };
+
+        let notifier = configuration.notifications
+            .as_ref()
+            .map(|notifications| Notifier::new(notifications).map(Arc::new))
+            .transpose()?;
+
+        // The scanner is given tokens where the clients present some, other registries have to let it pull anonymously
+        let scanner_authenticator = authenticator.clone().filter(|_| configuration.authentication
+            .as_ref()
+            .is_some_and(|authentication| authentication.method == AuthenticationMethod::Bearer));
+        if configuration.scanner.is_some() && configuration.authentication.is_some() && scanner_authenticator.is_none() {
+            warn!("The scanner pulls the images anonymously, tokens are only minted for it with the token authentication");
+        }
+        let scanner = configuration.scanner
+            .as_ref()
+            .map(|scanner| Arc::new(Scanner::new(scanner, scanner_authenticator, storage_cipher.clone(), notifier.clone())));
+
+        let bandwidth_limiter = BandwidthLimiter::new(&configuration.bandwidth, Arc::new(configuration.rate_limits.clone()));
+
+        let upload_affinity = configuration.server.upload_affinity
+            .as_ref()
+            .map(|upload_affinity| Arc::new(UploadAffinity::new(upload_affinity)));
+
+        let docker_clients = DockerClientsStore::new(&configuration.upstream);
+        let tenants = Tenants::new(&configuration, docker_clients.clone(), memory_storage);
+
+        // Application state setup
+        let configuration = Arc::new(configuration);
+        let state = ApplicationState {
+            conf: Arc::clone(&configuration),
+            docker_clients,
+            uploads: UploadsStore::new(upload_affinity.clone()),
+            authenticator,
+            rate_limiter: RateLimiter::new(),
+            bandwidth_limiter,
+            cache_stats: CacheStatistics::new(),
+            open_blobs: configuration.cache.open_blobs.filter(|capacity| *capacity > 0).map(OpenBlobs::new),
+            upload_affinity,
+            storage_cipher,
+            scanner,
+            notifier,
+            tenants,
+            started_at: Instant::now()
+        };
+
+        Ok(RegistryServer {
+            state,
+            address: self.address,
+            proxy: self.proxy
+        })
+    }
+}
+
+/// A registry, ready to be served on its own or embedded in another application
+pub struct RegistryServer {
+    state: ApplicationState,
+    address: SocketAddr,
+    proxy: bool
+}
+
+impl RegistryServer {
+    pub fn builder() -> RegistryServerBuilder {
+        RegistryServerBuilder::new()
+    }
+
+    pub fn configuration(&self) -> &Configuration {
+        &self.state.conf
+    }
+
+    /// Every route of the registry with its middlewares, to be served by hyper or called in-process.
+    /// Repository routes are matched on the whole path, the router is better merged at the root of
+    /// another router than nested in it.
+    pub fn router(&self) -> Router {
+        let configuration = Arc::clone(&self.state.conf);
+        let application_state = self.state.clone();
+
+        let deadlines = &configuration.server.deadlines;
+        let (manifests, blobs, uploads, api) = (deadlines.manifests(), deadlines.blobs(), deadlines.uploads(), deadlines.api());
+
+        let mut app = Router::new()
+            .route("/", get(controllers::base::root))
+            .route("/v2/", with_deadline(get(controllers::base::registry_base), api))
+            .route("/token", with_deadline(get(controllers::token::issue_token), api))
+            .route("/admin/status", with_deadline(get(controllers::admin::status), api))
+            .route("/admin/upstreams/health", with_deadline(get(controllers::admin::upstreams_health), api))
+            .route("/admin/upstreams/clients", with_deadline(get(controllers::admin::upstream_clients), api))
+            .route("/admin/upstreams/:registry/clients", with_deadline(delete(controllers::admin::flush_upstream_clients), api))
+            .route("/admin/uploads", with_deadline(get(controllers::admin::uploads), api))
+            .route("/admin/uploads/:uuid", with_deadline(delete(controllers::admin::abort_upload), api))
+            .route("/admin/proxy-cache/repositories", with_deadline(get(controllers::admin::proxy_cache_repositories), api))
+            .route("/admin/proxy-cache/export", get(controllers::transfer::export_proxy_cache))
+            .route("/admin/proxy-cache/import", post(controllers::transfer::import_proxy_cache))
+            // Repository names contain slashes, the handler takes the /stats suffix off itself
+            .route("/admin/proxy-cache/*path", with_deadline(get(controllers::admin::proxy_cache_repository_stats), api))
+            .route("/admin/notifications/dead-letters", with_deadline(get(controllers::admin::dead_letters), api))
+            .route("/admin/notifications/dead-letters/replay", with_deadline(post(controllers::admin::replay_dead_letters), api))
+            .route("/admin/api-keys", with_deadline(get(controllers::admin::list_api_keys).post(controllers::admin::create_api_key), api))
+            .route("/admin/api-keys/:id", with_deadline(delete(controllers::admin::revoke_api_key), api))
+            .route("/admin/tokens/revoked", with_deadline(get(controllers::admin::revoked_tokens), api))
+            .route("/admin/tokens/revoke", with_deadline(post(controllers::admin::revoke_token), api))
+            .route("/metrics", with_deadline(get(controllers::metrics::metrics), api))
+            .route("/openapi.json", get(controllers::openapi::openapi))
+            .route("/api/images/*path", with_deadline(get(controllers::images::fetch_image_resource).put(controllers::sbom::upload_sbom), api));
+
+        let mut repositories = RepositoryRouter::new()
+            // The requests carrying blob data are never cut: a write dropped halfway would leave the session
+            // behind the client's offset. Stalled clients are let go by the body chunk timeout instead.
+            .route(RepositoryRoute::UploadStart, post(controllers::uploads::initiate_upload))
+            .route(
+                RepositoryRoute::Upload,
+                patch(controllers::uploads::process_blob_chunk_upload)
+                    .put(controllers::uploads::finalize_blob_upload)
+                    .merge(with_deadline(delete(controllers::uploads::delete_upload), uploads))
+            )
+            .route(
+                RepositoryRoute::Blob,
+                with_deadline(
+                    get(controllers::blobs::check_blob_exists)
+                        .head(controllers::blobs::check_blob_exists),
+                    blobs
+                )
+            )
+            .route(
+                RepositoryRoute::Manifest,
+                with_deadline(
+                    get(controllers::manifests::fetch_manifest)
+                        .put(controllers::manifests::upload_manifest),
+                    manifests
+                )
+            );
+
+        if self.proxy {
+            app = app.route("/admin/proxy-cache/prefetch", post(controllers::prefetch::prefetch_images));
+            repositories = repositories
+                .route(
+                    RepositoryRoute::ProxyManifest,
+                    with_deadline(get(controllers::manifests::proxy_fetch_manifest), manifests)
+                )
+                .route(
+                    RepositoryRoute::ProxyBlob,
+                    with_deadline(get(controllers::blobs::proxy_blob), blobs)
+                );
+        }
+
+        app
+            .route("/v2/*path", repositories.into_method_router())
+            // Runs after the authorization middleware, which tells who the client is
+            .route_layer(axum::middleware::from_fn_with_state(
+                application_state.clone(),
+                requests::limit_request_rate
+            ))
+            .route_layer(axum::middleware::from_fn_with_state(
+                application_state.clone(),
+                authentication::authorization::authorize_repository_access
+            ))
+            .route_layer(axum::middleware::from_fn_with_state(
+                application_state.clone(),
+                authentication::request_signing::verify_request_signature
+            ))
+            // Before anything reads the body, the chunks of another replica's uploads are sent along untouched
+            .route_layer(axum::middleware::from_fn_with_state(
+                application_state.clone(),
+                upload_affinity::route_to_owning_node
+            ))
+            // Outermost, so the time spent on authorization and rate limits is part of the operation
+            .route_layer(axum::middleware::from_fn(requests::measure_operation))
+            .with_state(application_state)
+            // Shed the requests above the in-flight cap instead of queuing them
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(controllers::base::handle_overload))
+                    .option_layer(configuration.server.max_in_flight_requests.map(|max_in_flight| {
+                        ServiceBuilder::new()
+                            .load_shed()
+                            .concurrency_limit(max_in_flight)
+                            .into_inner()
+                    }))
+            )
+            .layer(axum::middleware::from_fn(requests::handle_unsupported_methods))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(requests::request_span)
+                    .on_response(requests::record_response)
+            )
+            .layer(axum::middleware::from_fn(requests::propagate_trace_context))
+            .layer(axum::middleware::from_fn_with_state(Arc::clone(&configuration), requests::resolve_forwarded_info))
+            .layer(axum::middleware::from_fn(requests::restore_requested_repository))
+    }
+
+    /// Serves the registry, and the gRPC admin API when configured, along with the background tasks
+    /// until the shutdown future completes
+    pub async fn serve<F: Future<Output = ()> + Send + 'static>(self, shutdown: F) -> eyre::Result<()> {
+        let configuration = Arc::clone(&self.state.conf);
+
+        let notifications_task = self.state.notifier.as_ref().map(|notifier| {
+            info!("Starting the notifications delivery");
+            notifier.start()
+        });
+
+        let uploads_cleanup_task = {
+            let uploads_app_state = self.state.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(UPLOAD_PRUNE_INTERVAL)).await;
+                    uploads_app_state.uploads.prune().await;
+                    uploads_app_state.rate_limiter.prune(Duration::from_secs(RATE_LIMIT_PRUNE_AGE)).await;
+                    uploads_app_state.bandwidth_limiter.prune().await;
+                    for tenant in uploads_app_state.tenants.all() {
+                        tenant.docker_clients.prune().await;
+                    }
+                    if let Some(login_throttle) = uploads_app_state.authenticator.as_ref().and_then(|authenticator| authenticator.login_throttle.as_ref()) {
+                        login_throttle.prune();
+                    }
+                }
+            })
+        };
+
+        let deduplication_task = configuration.storage.deduplication_interval_seconds.map(|interval| {
+            let deduplication_app_state = self.state.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    for tenant in deduplication_app_state.tenants.all() {
+                        let deduplicated = deduplication::deduplicate_blobs_async(
+                            tenant.registry_storage.clone(),
+                            tenant.proxy_storage.clone()
+                        ).await;
+                        match deduplicated {
+                            Ok(Ok(report)) if report.linked_blobs > 0 => info!(
+                                "Linked {} cached blobs to their pushed copy, {} bytes saved",
+                                report.linked_blobs, report.saved_bytes
+                            ),
+                            Ok(Ok(_)) => (),
+                            Ok(Err(e)) => warn!("Unable to deduplicate the blobs of {:?}: {}", tenant.proxy_storage, e),
+                            Err(e) => error!("Blob deduplication task failed: {}", e)
+                        }
+                    }
+                }
+            })
+        });
+
+        let quota_task = configuration.tenants.iter().any(|tenant| tenant.quota_bytes.is_some()).then(|| {
+            let quota_app_state = self.state.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(QUOTA_RECOUNT_INTERVAL)).await;
+                    for tenant in quota_app_state.tenants.all() {
+                        if let Err(e) = tenant.recount_usage().await {
+                            warn!("Unable to count the storage used by {:?}: {}", tenant.registry_storage, e);
+                        }
+                    }
+                }
+            })
+        });
+
+        let watermark_task = configuration.cache.free_space_watermark.clone().map(|watermark| {
+            let watermark_app_state = self.state.clone();
+            tokio::spawn(async move {
+                loop {
+                    for tenant in watermark_app_state.tenants.all() {
+                        let enforced = cache_watermark::enforce_watermark_async(tenant.proxy_storage.clone(), watermark.clone()).await;
+                        match enforced {
+                            Ok(Ok(report)) if report.evicted_blobs > 0 => info!(
+                                "Evicted {} cached blobs ({} bytes) from {:?}",
+                                report.evicted_blobs, report.evicted_bytes, tenant.proxy_storage
+                            ),
+                            Ok(Ok(_)) => (),
+                            Ok(Err(e)) => warn!("Unable to enforce the free space watermark of {:?}: {}", tenant.proxy_storage, e),
+                            Err(e) => error!("Free space watermark task failed: {}", e)
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_secs(watermark.check_interval_seconds)).await;
+                }
+            })
+        });
+
+        // The shutdown is passed on to every server
+        let (termination_tx, _) = tokio::sync::broadcast::channel::<()>(1);
+
+        let reuse_port = configuration.server.reuse_port;
+        let grpc_server = configuration.grpc.clone().map(|grpc_configuration| {
+            let grpc_application_state = self.state.clone();
+            let mut grpc_termination_rx = termination_tx.subscribe();
+            tokio::spawn(async move {
+                let shutdown = async move {
+                    grpc_termination_rx.recv().await.ok();
+                    info!("gRPC server received termination");
+                };
+                if let Err(e) = grpc::serve(grpc_configuration, grpc_application_state, reuse_port, shutdown).await {
+                    error!("The gRPC admin API stopped: {}", e);
+                }
+            })
+        });
+
+        let incoming = LimitedIncoming::new(
+            listener::bind(&self.address, configuration.server.reuse_port)?,
+            configuration.server.max_connections,
+            configuration.server.idle_timeout()
+        );
+
+        let mut server = axum::Server::builder(incoming)
+            // Both protocols are served on the same port, hyper tells them apart from the connection preface
+            .http1_only(!configuration.server.http2)
+            .http2_max_concurrent_streams(configuration.server.max_concurrent_streams);
+        if let Some(header_read_timeout) = configuration.server.header_read_timeout() {
+            server = server.http1_header_read_timeout(header_read_timeout);
+        }
+
+        warn!("Listening on {}", self.address);
+        if let Some(pid_file) = &configuration.server.pid_file {
+            handoff::take_over(pid_file)?;
+        }
+
+        let (draining_tx, draining_rx) = tokio::sync::oneshot::channel::<()>();
+        let mut http_termination_rx = termination_tx.subscribe();
+        let served = server
+            .serve(self.router().into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                tokio::select! {
+                    _ = shutdown => (),
+                    _ = http_termination_rx.recv() => ()
+                }
+                info!("HTTP server received termination");
+                draining_tx.send(()).ok();
+            });
+
+        // The requests in progress are given some time to complete, new connections go to the other instances
+        let drain_timeout = configuration.server.drain_timeout();
+        let drained = async move {
+            draining_rx.await.ok();
+            match drain_timeout {
+                Some(drain_timeout) => tokio::time::sleep(drain_timeout).await,
+                None => std::future::pending().await
+            }
+        };
+        let served = tokio::select! {
+            served = served => served,
+            _ = drained => {
+                warn!("Stopping with requests still in progress after the drain timeout");
+                Ok(())
+            }
+        };
+
+        if let Some(pid_file) = &configuration.server.pid_file {
+            handoff::release(pid_file);
+        }
+        termination_tx.send(()).ok();
+        if let Some(grpc_server) = grpc_server {
+            grpc_server.await.ok();
+        }
+        uploads_cleanup_task.abort();
+        if let Some(deduplication_task) = deduplication_task {
+            deduplication_task.abort();
+        }
+        if let Some(watermark_task) = watermark_task {
+            watermark_task.abort();
+        }
+        if let Some(quota_task) = quota_task {
+            quota_task.abort();
+        }
+        if let Some(notifications_task) = notifications_task {
+            notifications_task.abort();
+        }
+
+        Ok(served?)
+    }
+}
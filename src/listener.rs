@@ -0,0 +1,146 @@
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc, task::{Context, Poll}, io, time::Duration};
+
+use axum::extract::connect_info::Connected;
+use hyper::server::{accept::Accept, conn::{AddrIncoming, AddrStream}};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, sync::{OwnedSemaphorePermit, Semaphore}, time::{Sleep, Instant}};
+use tokio_util::sync::PollSemaphore;
+
+/// Listens on the address. With `reuse_port`, other processes may listen on it too and the kernel
+/// spreads the new connections between them, which lets a new instance start before the old one stops.
+pub fn bind(address: &SocketAddr, reuse_port: bool) -> io::Result<AddrIncoming> {
+    let socket = match address {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?
+    };
+    // Unlike on Unix, SO_REUSEADDR lets another socket steal the port on Windows, which has no SO_REUSEPORT
+    #[cfg(unix)]
+    {
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(reuse_port)?;
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is only available on Unix"));
+    }
+    socket.bind(*address)?;
+
+    AddrIncoming::from_listener(socket.listen(1024)?).map_err(io::Error::other)
+}
+
+/// Incoming TCP connections, capped to a maximum number of simultaneous connections.
+/// Once the cap is reached, new connections wait in the kernel backlog until one is closed.
+pub struct LimitedIncoming {
+    inner: AddrIncoming,
+    permits: Option<PollSemaphore>,
+    idle_timeout: Option<Duration>
+}
+
+impl LimitedIncoming {
+    pub fn new(inner: AddrIncoming, max_connections: Option<usize>, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            permits: max_connections.map(|max| PollSemaphore::new(Arc::new(Semaphore::new(max)))),
+            idle_timeout
+        }
+    }
+}
+
+impl Accept for LimitedIncoming {
+    type Conn = LimitedStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        // Only accept a connection once we have a slot for it
+        let permit = match self.permits.as_mut() {
+            Some(permits) => match permits.poll_acquire(cx) {
+                Poll::Ready(Some(permit)) => Some(permit),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending
+            },
+            None => None
+        };
+
+        match Pin::new(&mut self.inner).poll_accept(cx) {
+            Poll::Ready(Some(Ok(stream))) => Poll::Ready(Some(Ok(LimitedStream {
+                inner: stream,
+                _permit: permit,
+                idle_timeout: self.idle_timeout,
+                idle_deadline: self.idle_timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout)))
+            }))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            // Dropping the permit here gives it back, we'll take another one on the next poll
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+/// A connection holding its slot until it's dropped. If there is no traffic on the connection
+/// for longer than the idle timeout, reads fail so the server closes it.
+pub struct LimitedStream {
+    inner: AddrStream,
+    _permit: Option<OwnedSemaphorePermit>,
+    idle_timeout: Option<Duration>,
+    idle_deadline: Option<Pin<Box<Sleep>>>
+}
+
+impl LimitedStream {
+    fn record_activity(&mut self) {
+        if let (Some(timeout), Some(deadline)) = (self.idle_timeout, self.idle_deadline.as_mut()) {
+            deadline.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                self.record_activity();
+                Poll::Ready(result)
+            },
+            Poll::Pending => match self.idle_deadline.as_mut().map(|deadline| deadline.as_mut().poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "Connection idle for too long"))),
+                _ => Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if result.is_ready() {
+            self.record_activity();
+        }
+
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if result.is_ready() {
+            self.record_activity();
+        }
+
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+impl Connected<&LimitedStream> for SocketAddr {
+    fn connect_info(target: &LimitedStream) -> Self {
+        target.inner.remote_addr()
+    }
+}
@@ -0,0 +1,240 @@
+use std::{fmt, fs::File, io::{self, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use tracing::{Event, Subscriber, field::{Field, Visit}};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    field::RecordFields,
+    fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, format::Writer, writer::{BoxMakeWriter, MakeWriter}},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt
+};
+
+use crate::configuration::{LogFileConfiguration, LogFormat, LogRotation, LoggingConfiguration};
+
+/// Installs the global subscriber, writing the logs in the configured format and place
+pub fn init(configuration: &LoggingConfiguration) -> eyre::Result<()> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(configuration.filter_directives()))?;
+
+    let (writer, ansi) = match &configuration.file {
+        Some(file) => (BoxMakeWriter::new(RotatingFile::open(file)?), false),
+        None => (BoxMakeWriter::new(io::stdout), true)
+    };
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let layer = match configuration.format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.fmt_fields(JsonFields).event_format(JsonFormat).boxed()
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Fields of an event or a span as JSON values
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+}
+
+/// Keeps the fields of the spans as a JSON object, for the events to copy them
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = JsonVisitor::default();
+        fields.record(&mut visitor);
+        write!(writer, "{}", Value::Object(visitor.0))
+    }
+
+    fn add_fields(&self, current: &'writer mut FormattedFields<Self>, fields: &tracing::span::Record<'_>) -> fmt::Result {
+        let mut visitor = JsonVisitor(serde_json::from_str(&current.fields).unwrap_or_default());
+        fields.record(&mut visitor);
+        current.fields = Value::Object(visitor.0).to_string();
+        Ok(())
+    }
+}
+
+/// One JSON object per line, with the time, level, target, message and fields of the event,
+/// and the spans it happened in from the outermost
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, JsonFields>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+        let mut fields = fields.0;
+
+        let message = fields.remove("message").unwrap_or(Value::Null);
+        let spans = ctx.event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let fields = span.extensions()
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|fields| serde_json::from_str(&fields.fields).ok())
+                    .unwrap_or_else(|| Value::Object(Map::new()));
+                serde_json::json!({ "name": span.name(), "fields": fields })
+            })
+            .collect::<Vec<_>>();
+
+        // Written by hand to keep the keys in this order, serde_json sorts them
+        writeln!(
+            writer,
+            r#"{{"timestamp":{},"level":{},"target":{},"message":{},"fields":{},"spans":{}}}"#,
+            Value::from(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true)),
+            Value::from(event.metadata().level().as_str()),
+            Value::from(event.metadata().target()),
+            message,
+            Value::Object(fields),
+            Value::from(spans)
+        )
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    /// Hour or day the file was started in, when rotated by time
+    period: Option<String>
+}
+
+/// Log file rotated by size or time. Rotated files get the time of the rotation appended to their name.
+pub struct RotatingFile {
+    configuration: LogFileConfiguration,
+    current: Mutex<LogFile>
+}
+
+impl RotatingFile {
+    pub fn open(configuration: &LogFileConfiguration) -> io::Result<Self> {
+        if let Some(parent) = configuration.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&configuration.path)?;
+        let metadata = file.metadata()?;
+        let period = configuration.rotation.map(|rotation| period(rotation, metadata.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now())));
+
+        Ok(Self {
+            configuration: configuration.clone(),
+            current: Mutex::new(LogFile { file, size: metadata.len(), period })
+        })
+    }
+
+    fn write_event(&self, event: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+
+        let now = Utc::now();
+        let period = self.configuration.rotation.map(|rotation| period(rotation, now));
+        let full = self.configuration.max_bytes.is_some_and(|max_bytes| current.size > 0 && current.size + event.len() as u64 > max_bytes);
+        if full || period != current.period {
+            current.file.flush()?;
+            let rotated = rotated_path(&self.configuration.path, now);
+            std::fs::rename(&self.configuration.path, rotated)?;
+            self.delete_old_files()?;
+
+            current.file = std::fs::OpenOptions::new().create(true).append(true).open(&self.configuration.path)?;
+            current.size = 0;
+            current.period = period;
+        }
+
+        current.file.write_all(event)?;
+        current.size += event.len() as u64;
+        Ok(())
+    }
+
+    /// The rotated files sort by name in the order they were rotated
+    fn delete_old_files(&self) -> io::Result<()> {
+        let directory = match self.configuration.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            Some(directory) => directory.to_path_buf(),
+            None => PathBuf::from(".")
+        };
+        let prefix = format!("{}.", self.configuration.path.file_name().unwrap_or_default().to_string_lossy());
+
+        let mut rotated = std::fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.configuration.keep);
+        for path in &rotated[..excess] {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn period(rotation: LogRotation, time: DateTime<Utc>) -> String {
+    match rotation {
+        LogRotation::Hourly => time.format("%Y%m%d%H").to_string(),
+        LogRotation::Daily => time.format("%Y%m%d").to_string()
+    }
+}
+
+fn rotated_path(path: &Path, time: DateTime<Utc>) -> PathBuf {
+    let mut rotated = path.as_os_str().to_os_string();
+    rotated.push(time.format(".%Y%m%dT%H%M%S%.3f").to_string());
+    PathBuf::from(rotated)
+}
+
+pub struct RotatingFileWriter<'a>(&'a RotatingFile);
+
+impl Write for RotatingFileWriter<'_> {
+    /// Events are written in a single call, so they never straddle two files
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_event(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.current.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = RotatingFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RotatingFileWriter(self)
+    }
+}
@@ -0,0 +1,465 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, sync::{Mutex, Notify}};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::configuration::{EndpointKind, EventFormat, NotificationEndpoint, NotificationsConfiguration};
+
+pub mod sinks;
+
+const EVENTS_MEDIA_TYPE: &str = "application/vnd.docker.distribution.events.v1+json";
+const CLOUD_EVENTS_MEDIA_TYPE: &str = "application/cloudevents+json";
+/// Longest sleep of the delivery task, in case an event was queued without waking it up
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    /// A manifest was pushed to the registry
+    Push,
+    /// The vulnerability scan of an image completed
+    Scan,
+}
+
+impl EventAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventAction::Push => "push",
+            EventAction::Scan => "scan"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EventTarget {
+    /// Repository as found in the URLs of the registry, starting with `proxy/` for the proxy cache
+    pub repository: String,
+    pub digest: String,
+    pub tag: Option<String>,
+    pub media_type: Option<String>,
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub action: EventAction,
+    pub target: EventTarget,
+    /// Account or IP address of the client behind the event
+    pub actor: Option<String>,
+    /// Vulnerabilities found by severity, for scan events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severities: Option<BTreeMap<String, u64>>,
+}
+
+impl Event {
+    pub fn new(action: EventAction, target: EventTarget) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            action,
+            target,
+            actor: None,
+            severities: None,
+        }
+    }
+
+    pub fn with_actor(mut self, actor: String) -> Self {
+        self.actor = Some(actor);
+        self
+    }
+
+    pub fn with_severities(mut self, severities: BTreeMap<String, u64>) -> Self {
+        self.severities = Some(severities);
+        self
+    }
+}
+
+/// An event on its way to one endpoint, saved to disk until it is delivered or given up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Delivery {
+    pub endpoint: String,
+    pub event: Event,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+/// Sends the registry events to webhooks, NATS subjects or Kafka topics, retrying failed deliveries with an exponential backoff.
+/// Deliveries still failing after the last attempt are written to the dead letters, to be replayed later on.
+pub struct Notifier {
+    http_client: reqwest::Client,
+    nats_connections: sinks::NatsConnections,
+    configuration: NotificationsConfiguration,
+    wake_up: Notify,
+    dead_letters: Mutex<()>,
+}
+
+impl Notifier {
+    pub fn new(configuration: &NotificationsConfiguration) -> eyre::Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .timeout(configuration.timeout())
+            .build()?;
+
+        if let Some(endpoint) = configuration.endpoints.iter().find(|endpoint| endpoint.kind != EndpointKind::Webhook && endpoint.topic.is_none()) {
+            return Err(eyre::eyre!("The notification endpoint {} needs a topic", endpoint.name));
+        }
+
+        if configuration.max_concurrent_deliveries == 0 {
+            return Err(eyre::eyre!("The notifications need at least one concurrent delivery"));
+        }
+
+        std::fs::create_dir_all(configuration.directory.join("pending"))?;
+        std::fs::create_dir_all(configuration.directory.join("quarantine"))?;
+
+        Ok(Self {
+            http_client,
+            nats_connections: sinks::NatsConnections::default(),
+            configuration: configuration.clone(),
+            wake_up: Notify::new(),
+            dead_letters: Mutex::new(()),
+        })
+    }
+
+    fn pending_directory(&self) -> PathBuf {
+        self.configuration.directory.join("pending")
+    }
+
+    fn quarantine_directory(&self) -> PathBuf {
+        self.configuration.directory.join("quarantine")
+    }
+
+    fn dead_letters_path(&self) -> PathBuf {
+        self.configuration.directory.join("dead-letters.jsonl")
+    }
+
+    fn endpoint(&self, name: &str) -> Option<&NotificationEndpoint> {
+        self.configuration.endpoints.iter().find(|endpoint| endpoint.name == name)
+    }
+
+    /// Queues the event for every endpoint interested in its repository. Events survive restarts once queued.
+    pub async fn notify(&self, event: Event) {
+        let endpoints = self.configuration.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.notifies(&event.target.repository));
+
+        for endpoint in endpoints {
+            let delivery = Delivery {
+                endpoint: endpoint.name.clone(),
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: Utc::now(),
+                last_error: None,
+            };
+
+            if let Err(e) = self.save_delivery(&delivery).await {
+                warn!("Unable to queue the event {} for {}: {}", event.id, endpoint.name, e);
+            }
+        }
+
+        self.wake_up.notify_one();
+    }
+
+    async fn save_delivery(&self, delivery: &Delivery) -> std::io::Result<()> {
+        let delivery_path = self.pending_directory().join(format!("{}-{}.json", delivery.event.id, delivery.endpoint));
+        let temporary_path = delivery_path.with_extension("tmp");
+
+        tokio::fs::write(&temporary_path, serde_json::to_vec(delivery)?).await?;
+        tokio::fs::rename(&temporary_path, &delivery_path).await
+    }
+
+    /// Delivers the queued events as they become due, until the server stops
+    pub fn start(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let notifier = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let idle = match notifier.deliver_due().await {
+                    Ok(Some(next_attempt_at)) => (next_attempt_at - Utc::now()).to_std().unwrap_or_default().min(MAX_IDLE),
+                    Ok(None) => MAX_IDLE,
+                    Err(e) => {
+                        warn!("Unable to go through the pending notifications: {}", e);
+                        MAX_IDLE
+                    }
+                };
+
+                tokio::select! {
+                    _ = notifier.wake_up.notified() => (),
+                    _ = tokio::time::sleep(idle) => ()
+                }
+            }
+        })
+    }
+
+    /// Attempts the deliveries that are due, a few at a time. Returns when the next one will be.
+    async fn deliver_due(&self) -> eyre::Result<Option<DateTime<Utc>>> {
+        let mut next_attempt_at: Option<DateTime<Utc>> = None;
+        let mut due = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.pending_directory()).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let delivery_path = entry.path();
+            if delivery_path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            let delivery = match tokio::fs::read(&delivery_path).await {
+                Ok(delivery) => match serde_json::from_slice::<Delivery>(&delivery) {
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        self.quarantine(&delivery_path, e).await;
+                        continue;
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("Unable to read the pending notification {}: {}", delivery_path.display(), e);
+                    continue;
+                }
+            };
+
+            if delivery.next_attempt_at > Utc::now() {
+                next_attempt_at = Some(next_attempt_at.map_or(delivery.next_attempt_at, |next| next.min(delivery.next_attempt_at)));
+            } else {
+                due.push((delivery_path, delivery));
+            }
+        }
+
+        let mut attempts = futures::stream::iter(due)
+            .map(|(delivery_path, delivery)| self.attempt(delivery_path, delivery))
+            .buffer_unordered(self.configuration.max_concurrent_deliveries);
+        while let Some(retry_at) = attempts.next().await {
+            if let Some(retry_at) = retry_at {
+                next_attempt_at = Some(next_attempt_at.map_or(retry_at, |next| next.min(retry_at)));
+            }
+        }
+
+        Ok(next_attempt_at)
+    }
+
+    /// Attempts one delivery, and records its outcome. Returns when it is retried, if it failed.
+    async fn attempt(&self, delivery_path: PathBuf, mut delivery: Delivery) -> Option<DateTime<Utc>> {
+        delivery.attempts += 1;
+        let outcome = match self.deliver(&delivery).await {
+            Ok(()) => {
+                debug!("Event {} delivered to {}", delivery.event.id, delivery.endpoint);
+                tokio::fs::remove_file(&delivery_path).await.map(|_| None)
+            },
+            Err(e) if delivery.attempts >= self.configuration.max_attempts => {
+                warn!("Giving up the delivery of the event {} to {} after {} attempts: {}", delivery.event.id, delivery.endpoint, delivery.attempts, e);
+                delivery.last_error = Some(e.to_string());
+                match self.write_dead_letter(&delivery).await {
+                    Ok(()) => tokio::fs::remove_file(&delivery_path).await.map(|_| None),
+                    Err(e) => Err(e)
+                }
+            },
+            Err(e) => {
+                info!("Delivery of the event {} to {} failed, attempt {}: {}", delivery.event.id, delivery.endpoint, delivery.attempts, e);
+                delivery.last_error = Some(e.to_string());
+                // Backoffs too large for a date are as good as the longest sleep of the delivery task
+                delivery.next_attempt_at = chrono::Duration::from_std(self.configuration.backoff(delivery.attempts))
+                    .ok()
+                    .and_then(|backoff| Utc::now().checked_add_signed(backoff))
+                    .unwrap_or_else(|| Utc::now() + chrono::Duration::seconds(MAX_IDLE.as_secs() as i64));
+                self.save_delivery(&delivery).await.map(|_| Some(delivery.next_attempt_at))
+            }
+        };
+
+        // The delivery stays pending as it was, and is attempted again on the next pass
+        outcome.unwrap_or_else(|e| {
+            warn!("Unable to record the delivery of the event {} to {}: {}", delivery.event.id, delivery.endpoint, e);
+            None
+        })
+    }
+
+    /// Moves a pending delivery that can't be read out of the way, for someone to look at it
+    async fn quarantine(&self, delivery_path: &Path, error: serde_json::Error) {
+        let file_name = delivery_path.file_name().unwrap_or_default();
+        let quarantine_path = self.quarantine_directory().join(file_name);
+        warn!("The pending notification {} is corrupted, moving it to {}: {}", delivery_path.display(), quarantine_path.display(), error);
+
+        if let Err(e) = tokio::fs::rename(delivery_path, &quarantine_path).await {
+            warn!("Unable to quarantine the pending notification {}: {}", delivery_path.display(), e);
+        }
+    }
+
+    async fn deliver(&self, delivery: &Delivery) -> eyre::Result<()> {
+        // The endpoint may have been removed from the configuration since the event was queued
+        let endpoint = self.endpoint(&delivery.endpoint)
+            .ok_or_else(|| eyre::eyre!("Endpoint {} is not configured anymore", delivery.endpoint))?;
+
+        let event = &delivery.event;
+        let topic = endpoint.topic.as_deref().unwrap_or_default();
+
+        match endpoint.kind {
+            EndpointKind::Webhook => {
+                let (content_type, body) = match endpoint.format {
+                    EventFormat::Json => (EVENTS_MEDIA_TYPE, json!({ "events": [event] })),
+                    EventFormat::CloudEvents => (CLOUD_EVENTS_MEDIA_TYPE, sinks::serialize_event(event, endpoint.format))
+                };
+
+                let mut request = self.http_client
+                    .post(&endpoint.url)
+                    .header("Content-Type", content_type)
+                    .body(serde_json::to_vec(&body)?);
+                for (name, value) in &endpoint.headers {
+                    request = request.header(name, value);
+                }
+
+                request.send().await?.error_for_status()?;
+                Ok(())
+            },
+            EndpointKind::Nats => {
+                let payload = serde_json::to_vec(&sinks::serialize_event(event, endpoint.format))?;
+                tokio::time::timeout(self.configuration.timeout(), self.nats_connections.publish(&endpoint.url, topic, &payload))
+                    .await
+                    .map_err(|_| eyre::eyre!("The NATS server didn't answer in time"))?
+            },
+            EndpointKind::Kafka => sinks::produce_kafka(
+                &self.http_client,
+                &endpoint.url,
+                topic,
+                &endpoint.headers,
+                &event.target.repository,
+                sinks::serialize_event(event, endpoint.format)
+            ).await
+        }
+    }
+
+    async fn write_dead_letter(&self, delivery: &Delivery) -> std::io::Result<()> {
+        let _dead_letters = self.dead_letters.lock().await;
+
+        let mut line = serde_json::to_vec(delivery)?;
+        line.push(b'\n');
+
+        let mut dead_letters = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dead_letters_path())
+            .await?;
+        dead_letters.write_all(&line).await?;
+        dead_letters.flush().await
+    }
+
+    pub async fn dead_letters(&self) -> std::io::Result<Vec<Delivery>> {
+        let _dead_letters = self.dead_letters.lock().await;
+        read_dead_letters(&self.dead_letters_path()).await
+    }
+
+    /// Queues the dead letters again, as if their events just happened. Returns how many were queued.
+    pub async fn replay_dead_letters(&self) -> std::io::Result<usize> {
+        let _dead_letters = self.dead_letters.lock().await;
+        let dead_letters = read_dead_letters(&self.dead_letters_path()).await?;
+
+        for dead_letter in &dead_letters {
+            self.save_delivery(&Delivery {
+                endpoint: dead_letter.endpoint.clone(),
+                event: dead_letter.event.clone(),
+                attempts: 0,
+                next_attempt_at: Utc::now(),
+                last_error: None,
+            }).await?;
+        }
+
+        match tokio::fs::remove_file(self.dead_letters_path()).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => ()
+        }
+
+        info!("Replaying {} dead letters", dead_letters.len());
+        self.wake_up.notify_one();
+        Ok(dead_letters.len())
+    }
+}
+
+async fn read_dead_letters(dead_letters_path: &Path) -> std::io::Result<Vec<Delivery>> {
+    let dead_letters = match tokio::fs::read_to_string(dead_letters_path).await {
+        Ok(dead_letters) => dead_letters,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+
+    dead_letters
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str::<Delivery>(line).map_err(std::io::Error::from))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::{Delivery, Event, EventAction, EventTarget, Notifier};
+    use crate::configuration::NotificationsConfiguration;
+
+    fn notifier(max_attempts: u32) -> Notifier {
+        Notifier::new(&NotificationsConfiguration {
+            directory: std::env::temp_dir().join(format!("notifications-{}", Uuid::new_v4())),
+            endpoints: Vec::new(),
+            max_attempts,
+            initial_backoff: 1,
+            max_backoff: 600,
+            timeout: 1,
+            max_concurrent_deliveries: 2
+        }).unwrap()
+    }
+
+    fn delivery(endpoint: &str) -> Delivery {
+        Delivery {
+            endpoint: endpoint.to_string(),
+            event: Event::new(EventAction::Push, EventTarget {
+                repository: "team/app".to_string(),
+                digest: format!("sha256:{}", "0".repeat(64)),
+                tag: Some("latest".to_string()),
+                media_type: None,
+                size: None
+            }),
+            attempts: 0,
+            next_attempt_at: Utc::now(),
+            last_error: None
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupted_deliveries_are_quarantined() {
+        let notifier = notifier(1);
+        std::fs::write(notifier.pending_directory().join("corrupted.json"), b"{\"endpoint\":").unwrap();
+        // Deliveries to endpoints removed from the configuration fail, and end up in the dead letters
+        for endpoint in ["removed", "also-removed", "removed-too"] {
+            notifier.save_delivery(&delivery(endpoint)).await.unwrap();
+        }
+
+        assert_eq!(notifier.deliver_due().await.unwrap(), None);
+
+        assert!(notifier.quarantine_directory().join("corrupted.json").exists());
+        assert_eq!(std::fs::read_dir(notifier.pending_directory()).unwrap().count(), 0);
+        let dead_letters = notifier.dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 3);
+        assert!(dead_letters.iter().all(|dead_letter| dead_letter.attempts == 1 && dead_letter.last_error.is_some()));
+
+        std::fs::remove_dir_all(&notifier.configuration.directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_later() {
+        let notifier = notifier(10);
+        notifier.save_delivery(&delivery("removed")).await.unwrap();
+
+        let next_attempt_at = notifier.deliver_due().await.unwrap().unwrap();
+        assert!(next_attempt_at > Utc::now());
+
+        // Not due yet
+        assert_eq!(notifier.deliver_due().await.unwrap(), Some(next_attempt_at));
+        assert!(notifier.dead_letters().await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&notifier.configuration.directory).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, net::TcpStream};
+use tracing::debug;
+
+use crate::configuration::EventFormat;
+
+use super::Event;
+
+const NATS_DEFAULT_PORT: u16 = 4222;
+const KAFKA_JSON_MEDIA_TYPE: &str = "application/vnd.kafka.json.v2+json";
+
+#[derive(Deserialize)]
+struct KafkaProduceResponse {
+    #[serde(default)]
+    offsets: Vec<KafkaRecordOffset>,
+}
+
+#[derive(Deserialize)]
+struct KafkaRecordOffset {
+    error: Option<String>,
+}
+
+/// Event as published on a topic, either as is or as a CloudEvent
+pub fn serialize_event(event: &Event, format: EventFormat) -> Value {
+    match format {
+        EventFormat::Json => json!(event),
+        EventFormat::CloudEvents => json!({
+            "specversion": "1.0",
+            "id": event.id,
+            "source": format!("/v2/{}", event.target.repository),
+            "type": format!("registry.{}", event.action.as_str()),
+            "subject": event.target.digest,
+            "time": event.timestamp.to_rfc3339(),
+            "datacontenttype": "application/json",
+            "data": event
+        })
+    }
+}
+
+/// Fields of the INFO greeting of a NATS server that matter here
+#[derive(Deserialize)]
+struct NatsServerInfo {
+    #[serde(default)]
+    tls_required: bool
+}
+
+trait NatsStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> NatsStream for T {}
+
+/// Connection to a NATS server the client was accepted on, upgraded to TLS when the server requires it
+struct NatsConnection {
+    stream: BufReader<Box<dyn NatsStream>>
+}
+
+impl NatsConnection {
+    /// Connects with the core NATS protocol. The server is asked to acknowledge every command, so failed
+    /// publications are retried.
+    async fn open(url: &str) -> eyre::Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        let host = url.host_str().ok_or_else(|| eyre::eyre!("No host in the NATS URL {}", url))?;
+        let mut reader = BufReader::new(TcpStream::connect((host, url.port().unwrap_or(NATS_DEFAULT_PORT))).await?);
+
+        let info = read_nats_line(&mut reader).await?;
+        let info = match info.strip_prefix("INFO ") {
+            Some(info) => serde_json::from_str::<NatsServerInfo>(info)?,
+            None => return Err(eyre::eyre!("Unexpected greeting from the NATS server: {}", info))
+        };
+
+        // The server waits for the TLS handshake after its greeting, nothing is left in the buffer
+        let tls = url.scheme() == "tls" || info.tls_required;
+        let stream: Box<dyn NatsStream> = if tls {
+            let connector = tokio_native_tls::TlsConnector::from(tokio_native_tls::native_tls::TlsConnector::new()?);
+            Box::new(connector.connect(host, reader.into_inner()).await?)
+        } else {
+            Box::new(reader.into_inner())
+        };
+        let mut connection = Self { stream: BufReader::new(stream) };
+
+        let mut connect = json!({ "verbose": true, "pedantic": false, "tls_required": tls, "name": "docker-registry" });
+        if !url.username().is_empty() {
+            connect["user"] = json!(url.username());
+            connect["pass"] = json!(url.password().unwrap_or_default());
+        }
+        connection.stream.write_all(format!("CONNECT {}\r\n", connect).as_bytes()).await?;
+        connection.expect_ok().await?;
+
+        Ok(connection)
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> eyre::Result<()> {
+        self.stream.write_all(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes()).await?;
+        self.stream.write_all(payload).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.expect_ok().await
+    }
+
+    /// Waits for the acknowledgement of the last command, answering the pings the server sent meanwhile,
+    /// or while the connection was idle
+    async fn expect_ok(&mut self) -> eyre::Result<()> {
+        loop {
+            match read_nats_line(&mut self.stream).await? {
+                line if line == "+OK" => return Ok(()),
+                line if line == "PING" => {
+                    self.stream.write_all(b"PONG\r\n").await?;
+                    self.stream.flush().await?;
+                },
+                line if line.starts_with("-ERR") => return Err(eyre::eyre!("NATS server error: {}", line)),
+                line if line.starts_with("INFO ") || line == "PONG" => (),
+                line => return Err(eyre::eyre!("Unexpected answer from the NATS server: {}", line))
+            }
+        }
+    }
+}
+
+async fn read_nats_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> eyre::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(eyre::eyre!("The NATS server closed the connection"));
+    }
+
+    Ok(line.trim_end().to_string())
+}
+
+/// Connections to the NATS servers kept open between the publications, by server URL. A connection is
+/// only handed back once its publication is acknowledged, those that failed or timed out are dropped.
+#[derive(Default)]
+pub struct NatsConnections {
+    idle: Mutex<HashMap<String, Vec<NatsConnection>>>
+}
+
+impl NatsConnections {
+    /// Publishes a message on a subject, on an idle connection to the server when there is one
+    pub async fn publish(&self, url: &str, subject: &str, payload: &[u8]) -> eyre::Result<()> {
+        // The server may have closed an idle connection since, the message is then sent on a new one
+        let idle_connection = self.idle.lock().unwrap().get_mut(url).and_then(Vec::pop);
+        if let Some(mut connection) = idle_connection {
+            match connection.publish(subject, payload).await {
+                Ok(()) => {
+                    self.put_back(url, connection);
+                    return Ok(());
+                },
+                Err(e) => debug!("Idle NATS connection to {} unusable, opening another: {}", url, e)
+            }
+        }
+
+        let mut connection = NatsConnection::open(url).await?;
+        connection.publish(subject, payload).await?;
+        self.put_back(url, connection);
+
+        Ok(())
+    }
+
+    fn put_back(&self, url: &str, connection: NatsConnection) {
+        self.idle.lock().unwrap().entry(url.to_string()).or_default().push(connection);
+    }
+}
+
+/// Produces a record to a Kafka topic through the REST Proxy v2 API, keyed by repository so the events
+/// of a repository stay ordered within their partition
+pub async fn produce_kafka(
+    http_client: &reqwest::Client,
+    url: &str,
+    topic: &str,
+    headers: &HashMap<String, String>,
+    key: &str,
+    value: Value
+) -> eyre::Result<()> {
+    let mut request = http_client
+        .post(format!("{}/topics/{}", url.trim_end_matches('/'), topic))
+        .header("Content-Type", KAFKA_JSON_MEDIA_TYPE)
+        .header("Accept", "application/vnd.kafka.v2+json")
+        .body(serde_json::to_vec(&json!({ "records": [{ "key": key, "value": value }] }))?);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+
+    let response = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<KafkaProduceResponse>()
+        .await?;
+
+    match response.offsets.into_iter().find_map(|offset| offset.error) {
+        Some(error) => Err(eyre::eyre!("Kafka refused the record: {}", error)),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpListener};
+
+    use super::NatsConnections;
+
+    #[tokio::test]
+    async fn nats_connections_are_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+
+        // A server accepting a single connection, acknowledging the commands and recording the subjects
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+
+            let mut subjects = Vec::new();
+            let mut line = String::new();
+            while subjects.len() < 2 {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                if let Some(publication) = line.strip_prefix("PUB ") {
+                    let (subject, size) = publication.trim_end().split_once(' ').unwrap();
+                    let mut payload = vec![0; size.parse::<usize>().unwrap() + 2];
+                    stream.read_exact(&mut payload).await.unwrap();
+                    subjects.push(subject.to_string());
+                    // Pings from the server are answered before the acknowledgement is awaited
+                    stream.write_all(b"PING\r\n").await.unwrap();
+                }
+                if line != "PONG\r\n" {
+                    stream.write_all(b"+OK\r\n").await.unwrap();
+                }
+            }
+            subjects
+        });
+
+        let connections = NatsConnections::default();
+        connections.publish(&url, "registry.first", b"{}").await.unwrap();
+        connections.publish(&url, "registry.second", b"{}").await.unwrap();
+
+        assert_eq!(server.await.unwrap(), ["registry.first", "registry.second"]);
+    }
+}
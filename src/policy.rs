@@ -0,0 +1,262 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::{ApplicationState, configuration::{PolicyRule, Severity}, controllers::RegistryHttpError, docker_client::client::{DockerClient, DockerClientError}, data::{blob_storage::StoredBlob, encryption, helpers::{is_sha256_digest, split_registry_and_container, RegistryPathsHelper}, manifests, scans::ScanReport}};
+
+/// Artifact types of the signatures stored as referrers of the manifest they sign
+const SIGNATURE_ARTIFACT_TYPES: [&str; 2] = [
+    "application/vnd.dev.cosign.artifact.sig.v1+json",
+    "application/vnd.cncf.notary.signature",
+];
+
+#[derive(Deserialize)]
+struct ManifestConfig {
+    config: Option<ConfigDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct ConfigDescriptor {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    created: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct PolicyViolation {
+    rule: &'static str,
+    message: String,
+}
+
+/// A manifest about to be served
+pub struct PolicyImage<'a> {
+    /// Storage holding the manifest
+    pub storage_root: PathBuf,
+    pub container_ref: &'a str,
+    /// Repository as found in the URLs of the registry, starting with `proxy/` for the proxy cache
+    pub repository: String,
+    pub digest: &'a str,
+    /// Tag or digest the manifest was asked by
+    pub reference: &'a str,
+    pub manifest: &'a [u8],
+    /// Client of the upstream registry of proxied images, to look for what isn't cached
+    pub upstream: Option<&'a DockerClient>,
+}
+
+/// Checks the image against the policy rule of its repository. Fails with a DENIED error listing the violations.
+pub async fn enforce(app: &ApplicationState, image: &PolicyImage<'_>) -> Result<(), RegistryHttpError> {
+    let rule = match app.conf.policy_rule(&image.repository) {
+        Some(rule) => rule,
+        None => return Ok(())
+    };
+
+    // Signatures, SBOMs and attestations are pulled along with the images they describe, and only served if those pass
+    let violations = match manifests::described_manifest(image.manifest, image.reference) {
+        Some(described_digest) => match described_manifest(app, image, &described_digest).await? {
+            Some(described_manifest) => {
+                let described_image = PolicyImage {
+                    storage_root: image.storage_root.clone(),
+                    container_ref: image.container_ref,
+                    repository: image.repository.clone(),
+                    digest: &described_digest,
+                    reference: &described_digest,
+                    manifest: &described_manifest,
+                    upstream: image.upstream,
+                };
+                evaluate(app, rule, &described_image, config_digest(&described_manifest)).await?
+            },
+            None => vec![PolicyViolation {
+                rule: "described_image",
+                message: format!("The image {} described by the manifest is not available", described_digest)
+            }]
+        },
+        None => evaluate(app, rule, image, config_digest(image.manifest)).await?
+    };
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    info!("Manifest {} of {} denied by the policy of {}", image.digest, image.repository, rule.repository);
+    Err(RegistryHttpError::PolicyViolation {
+        repository: image.repository.clone(),
+        digest: image.digest.to_string(),
+        detail: serde_json::json!({
+            "policy": rule.repository,
+            "violations": violations
+        })
+    })
+}
+
+fn config_digest(manifest: &[u8]) -> Option<String> {
+    serde_json::from_slice::<ManifestConfig>(manifest)
+        .ok()
+        .and_then(|manifest| manifest.config)
+        .map(|config| config.digest)
+}
+
+/// The manifest described by a signature, SBOM or attestation, from the storage or upstream for proxied images
+async fn described_manifest(app: &ApplicationState, image: &PolicyImage<'_>, described_digest: &str) -> eyre::Result<Option<Vec<u8>>> {
+    let manifest_path = RegistryPathsHelper::manifest_path(&image.storage_root, image.container_ref, described_digest);
+    match tokio::fs::read(&manifest_path).await {
+        Ok(manifest) => return Ok(Some(encryption::decrypt_manifest(app.storage_cipher.as_deref(), described_digest, manifest)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into())
+    }
+
+    match image.upstream {
+        Some(upstream) => match upstream.query_manifest(described_digest, false).await {
+            Ok(response) => {
+                let manifest = response.raw_response.bytes().await?.to_vec();
+                upstream.record_downloaded_bytes(manifest.len() as u64);
+                Ok(Some(manifest))
+            },
+            Err(DockerClientError::UnexpectedStatusCode(404)) => Ok(None),
+            Err(e) => Err(e.into())
+        },
+        None => Ok(None)
+    }
+}
+
+async fn evaluate(app: &ApplicationState, rule: &PolicyRule, image: &PolicyImage<'_>, config_digest: Option<String>) -> eyre::Result<Vec<PolicyViolation>> {
+    let mut violations = Vec::new();
+
+    if let Some(allowed_registries) = &rule.allowed_registries {
+        if let Some(proxied_container_ref) = image.repository.strip_prefix("proxy/") {
+            let (registry, _) = split_registry_and_container(proxied_container_ref)?;
+            if !allowed_registries.iter().any(|allowed_registry| allowed_registry == registry) {
+                violations.push(PolicyViolation {
+                    rule: "allowed_registries",
+                    message: format!("Images from {} are not allowed", registry)
+                });
+            }
+        }
+    }
+
+    if rule.require_signature && !is_signed(image).await? {
+        violations.push(PolicyViolation {
+            rule: "require_signature",
+            message: "No signature found for the manifest".to_string()
+        });
+    }
+
+    // Image indexes are neither scanned nor have a creation date, the manifests they list are checked on their own
+    let config_digest = match config_digest {
+        Some(config_digest) => config_digest,
+        None => return Ok(violations)
+    };
+
+    if let Some(max_severity) = rule.max_severity {
+        match ScanReport::load(&image.storage_root, image.container_ref, image.digest).await? {
+            Some(ScanReport { error: None, severities, .. }) => {
+                let exceeding = severities
+                    .iter()
+                    .filter(|(severity, _)| Severity::parse(severity) > max_severity)
+                    .map(|(severity, count)| format!("{} {}", count, severity))
+                    .collect::<Vec<_>>();
+                if !exceeding.is_empty() {
+                    violations.push(PolicyViolation {
+                        rule: "max_severity",
+                        message: format!("Vulnerabilities above {:?}: {}", max_severity, exceeding.join(", "))
+                    });
+                }
+            },
+            Some(ScanReport { error: Some(error), .. }) => violations.push(PolicyViolation {
+                rule: "max_severity",
+                message: format!("The scan of the image failed: {}", error)
+            }),
+            None => violations.push(PolicyViolation {
+                rule: "max_severity",
+                message: "The image has not been scanned yet".to_string()
+            })
+        }
+    }
+
+    if let Some(max_age_days) = rule.max_age_days {
+        match image_creation_date(app, image, &config_digest).await {
+            Ok(Some(created)) if Utc::now() - created > Duration::days(max_age_days) => violations.push(PolicyViolation {
+                rule: "max_age_days",
+                message: format!("The image was built on {}", created.to_rfc3339())
+            }),
+            Ok(Some(_)) => (),
+            Ok(None) => violations.push(PolicyViolation {
+                rule: "max_age_days",
+                message: "The image has no creation date".to_string()
+            }),
+            Err(e) => {
+                warn!("Unable to read the configuration {} of {}: {}", config_digest, image.repository, e);
+                violations.push(PolicyViolation {
+                    rule: "max_age_days",
+                    message: "The creation date of the image could not be read".to_string()
+                });
+            }
+        }
+    }
+
+    Ok(violations)
+}
+
+/// Looks for a cosign signature tag or a signature referrer, upstream as well for proxied images
+async fn is_signed(image: &PolicyImage<'_>) -> eyre::Result<bool> {
+    let cosign_tag = format!("{}.sig", image.digest.replace(':', "-"));
+
+    let storage_root = image.storage_root.clone();
+    let container_ref = image.container_ref.to_string();
+    let digest = image.digest.to_string();
+    let signed = tokio::task::spawn_blocking(move || -> std::io::Result<bool> {
+        if manifests::resolve_tag(&storage_root, &container_ref, &cosign_tag)?.is_some() {
+            return Ok(true);
+        }
+
+        Ok(manifests::list_referrers(&storage_root, &container_ref, &digest)?
+            .iter()
+            .any(|referrer| matches!(&referrer.artifact_type, Some(artifact_type) if SIGNATURE_ARTIFACT_TYPES.contains(&artifact_type.as_str()))))
+    }).await??;
+
+    if signed {
+        return Ok(true);
+    }
+
+    match image.upstream {
+        Some(upstream) => Ok(upstream.query_manifest(&format!("{}.sig", image.digest.replace(':', "-")), true).await.is_ok()),
+        None => Ok(false)
+    }
+}
+
+async fn image_creation_date(app: &ApplicationState, image: &PolicyImage<'_>, config_digest: &str) -> eyre::Result<Option<DateTime<Utc>>> {
+    if !is_sha256_digest(config_digest) {
+        return Err(eyre::eyre!("Invalid configuration digest {:?}", config_digest));
+    }
+
+    // Blobs pushed to the registry are stored by hash, the ones of the proxy cache by digest
+    let blob_path = if image.repository.starts_with("proxy/") {
+        RegistryPathsHelper::blob_path(&image.storage_root, image.container_ref, config_digest)
+    } else {
+        RegistryPathsHelper::blob_path(&image.storage_root, image.container_ref, config_digest.trim_start_matches("sha256:"))
+    };
+
+    let config = match StoredBlob::open(&blob_path, app.storage_cipher.clone()).await? {
+        Some(blob) => {
+            let mut config = Vec::new();
+            blob.reader().await?.read_to_end(&mut config).await?;
+            config
+        },
+        // Clients pull the configuration after the manifest, it is usually not cached yet
+        None => match image.upstream {
+            Some(upstream) => {
+                let response = upstream.query_blob(config_digest).await?;
+                let config = response.raw_response.bytes().await?.to_vec();
+                upstream.record_downloaded_bytes(config.len() as u64);
+                config
+            },
+            None => return Err(eyre::eyre!("Configuration blob {} is missing", config_digest))
+        }
+    };
+
+    Ok(serde_json::from_slice::<ImageConfig>(&config)?.created)
+}
@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use futures::{SinkExt, StreamExt};
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+use crate::{ApplicationState, controllers::RegistryHttpError, data::blob_storage::StoredBlob, docker_client::client_responses::UploadStart, tenants::Tenant};
+
+/// Sends a blob pushed to a push-through repository on to its upstream repository. Blobs the upstream repository
+/// already holds, or can mount from one of the repositories of the rule, aren't sent again.
+pub async fn forward_blob(app: &ApplicationState, tenant: &Tenant, container_ref: &str, digest: &str, blob_path: &Path) -> Result<(), RegistryHttpError> {
+    let rule = match app.conf.upstream.push_through_rule(container_ref) {
+        Some(rule) => rule,
+        None => return Ok(())
+    };
+    let client = tenant.docker_clients.get_push_client(&rule.upstream_repository(container_ref)).await?;
+
+    if client.has_blob(digest).await? {
+        info!("Blob {} is already in {}/{}, not sending it", digest, client.registry(), client.container());
+        return Ok(());
+    }
+
+    let mut session = None;
+    for mount_from in &rule.mount_from {
+        if let Some(unused_session) = session.take() {
+            client.cancel_upload(unused_session).await.ok();
+        }
+
+        match client.start_upload(Some((digest, mount_from))).await? {
+            UploadStart::Mounted => {
+                info!("Blob {} mounted from {} upstream", digest, mount_from);
+                return Ok(());
+            },
+            UploadStart::Session(upload_session) => session = Some(upload_session)
+        }
+    }
+    let session = match session {
+        Some(session) => session,
+        None => match client.start_upload(None).await? {
+            UploadStart::Session(session) => session,
+            UploadStart::Mounted => return Ok(())
+        }
+    };
+
+    let blob = StoredBlob::open(blob_path, app.storage_cipher.clone())
+        .await?
+        .ok_or_else(|| eyre::eyre!("Blob {} to send upstream is missing", digest))?;
+    info!("Sending blob {} ({} bytes) to {}/{}", digest, blob.size, client.registry(), client.container());
+    // The blob is read on a task of its own, its reader can't be shared by the request body
+    let mut blob_reader = ReaderStream::new(blob.reader().await?);
+    let (mut sender, receiver) = futures::channel::mpsc::channel(4);
+    tokio::spawn(async move {
+        while let Some(chunk) = blob_reader.next().await {
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    let body = reqwest::Body::wrap_stream(receiver);
+    if let Err(e) = client.upload_blob(session, digest, blob.size, body).await {
+        warn!("Unable to send blob {} upstream: {}", digest, e);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Pushes a manifest pushed to a push-through repository to its upstream repository, once its blobs are there
+pub async fn forward_manifest(app: &ApplicationState, tenant: &Tenant, container_ref: &str, manifest_ref: &str, content_type: &str, manifest: &[u8]) -> Result<(), RegistryHttpError> {
+    let rule = match app.conf.upstream.push_through_rule(container_ref) {
+        Some(rule) => rule,
+        None => return Ok(())
+    };
+    let client = tenant.docker_clients.get_push_client(&rule.upstream_repository(container_ref)).await?;
+
+    info!("Pushing manifest {} to {}/{}", manifest_ref, client.registry(), client.container());
+    if let Err(e) = client.push_manifest(manifest_ref, content_type, manifest.to_vec()).await {
+        warn!("Unable to push manifest {} upstream: {}", manifest_ref, e);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
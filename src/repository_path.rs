@@ -0,0 +1,179 @@
+use std::{collections::HashMap, convert::Infallible};
+
+use async_trait::async_trait;
+use axum::{body::Body, extract::{FromRequestParts, State}, http::{Request, StatusCode, request::Parts}, response::{IntoResponse, Response}, routing::{any, MethodRouter}};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tower::ServiceExt;
+
+use crate::{ApplicationState, controllers::RegistryHttpError};
+
+static REPOSITORY_PATH_REGEX: Lazy<Regex> = Lazy::new(|| {
+    // The repository name takes as many segments as it can, so a repository may be named like an object
+    // Upstream registries may come with a port or be IPv6 addresses
+    Regex::new(r"^/v2/(?P<isProxy>proxy/)?(?P<containerRef>[a-zA-Z0-9-_/.:\[\]]+)/(?P<object>blobs/uploads|blobs|manifests|tags)(?:/(?P<reference>[^/]*))?$").unwrap()
+});
+
+/// What a repository route is about, following the repository name in the URL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepositoryObject {
+    Manifests,
+    Blobs,
+    Uploads,
+    Tags
+}
+
+/// Repository routes, each served by the handlers of one method router
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RepositoryRoute {
+    Manifest,
+    Blob,
+    UploadStart,
+    Upload,
+    ProxyManifest,
+    ProxyBlob
+}
+
+impl RepositoryRoute {
+    /// Template of the route, naming the operations in the metrics
+    pub fn template(&self) -> &'static str {
+        match self {
+            RepositoryRoute::Manifest => "/v2/:container_ref/manifests/:reference",
+            RepositoryRoute::Blob => "/v2/:container_ref/blobs/:digest",
+            RepositoryRoute::UploadStart => "/v2/:container_ref/blobs/uploads/",
+            RepositoryRoute::Upload => "/v2/:container_ref/blobs/uploads/:uuid",
+            RepositoryRoute::ProxyManifest => "/v2/proxy/:container_ref/manifests/:reference",
+            RepositoryRoute::ProxyBlob => "/v2/proxy/:container_ref/blobs/:digest"
+        }
+    }
+}
+
+/// Repository route of the URL: `/v2/[proxy/]<repository>/<object>[/<reference>]`. Proxied repositories start
+/// with their upstream registry, like `proxy/docker.io/library/alpine/manifests/latest`.
+#[derive(Clone, Debug)]
+pub struct RepositoryPath {
+    pub proxy: bool,
+    /// Upstream registry of a proxied repository, as the client wrote it
+    pub registry: Option<String>,
+    /// Segments of the repository name before the last one, like `library`
+    pub namespace: Option<String>,
+    pub name: String,
+    pub object: RepositoryObject,
+    /// Tag, digest or upload ID following the object
+    pub reference: Option<String>
+}
+
+impl RepositoryPath {
+    pub fn parse(path: &str) -> Option<Self> {
+        let captures = REPOSITORY_PATH_REGEX.captures(path)?;
+        let proxy = captures.name("isProxy").is_some();
+        let container_ref = captures.name("containerRef").unwrap().as_str();
+        if container_ref.split('/').any(|segment| segment.is_empty() || segment == "." || segment == "..") {
+            return None;
+        }
+
+        let (registry, repository) = match container_ref.split_once('/') {
+            Some((registry, repository)) if proxy => (Some(registry.to_string()), repository),
+            _ => (None, container_ref)
+        };
+        if repository.contains([':', '[', ']']) {
+            return None;
+        }
+
+        let (namespace, name) = match repository.rsplit_once('/') {
+            Some((namespace, name)) => (Some(namespace.to_string()), name.to_string()),
+            None => (None, repository.to_string())
+        };
+
+        let object = match captures.name("object").unwrap().as_str() {
+            "blobs/uploads" => RepositoryObject::Uploads,
+            "blobs" => RepositoryObject::Blobs,
+            "manifests" => RepositoryObject::Manifests,
+            _ => RepositoryObject::Tags
+        };
+        let reference = captures.name("reference")
+            .map(|reference| reference.as_str().to_string())
+            .filter(|reference| !reference.is_empty());
+
+        Some(Self { proxy, registry, namespace, name, object, reference })
+    }
+
+    /// Repository name without the `proxy/` prefix, such as `docker.io/library/alpine`
+    pub fn container_ref(&self) -> String {
+        [self.registry.as_deref(), self.namespace.as_deref(), Some(self.name.as_str())]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Repository name as found in the token scopes, with the `proxy/` prefix of proxied repositories
+    pub fn repository(&self) -> String {
+        match self.proxy {
+            true => format!("proxy/{}", self.container_ref()),
+            false => self.container_ref()
+        }
+    }
+
+    pub fn reference(&self) -> String {
+        self.reference.clone().unwrap_or_default()
+    }
+
+    pub fn route(&self) -> Option<RepositoryRoute> {
+        match (self.proxy, self.object, self.reference.is_some()) {
+            (false, RepositoryObject::Manifests, true) => Some(RepositoryRoute::Manifest),
+            (false, RepositoryObject::Blobs, true) => Some(RepositoryRoute::Blob),
+            (false, RepositoryObject::Uploads, false) => Some(RepositoryRoute::UploadStart),
+            (false, RepositoryObject::Uploads, true) => Some(RepositoryRoute::Upload),
+            (true, RepositoryObject::Manifests, true) => Some(RepositoryRoute::ProxyManifest),
+            (true, RepositoryObject::Blobs, true) => Some(RepositoryRoute::ProxyBlob),
+            _ => None
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RepositoryPath {
+    type Rejection = RegistryHttpError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Self::parse(parts.uri.path()).ok_or_else(|| RegistryHttpError::invalid_repository_name(parts.uri.path()))
+    }
+}
+
+/// Routes of the repositories. Their names span several path segments, which the router can't match,
+/// so they are all behind `/v2/*path` and told apart by their `RepositoryPath`.
+#[derive(Clone, Default)]
+pub struct RepositoryRouter {
+    routes: HashMap<RepositoryRoute, MethodRouter<ApplicationState>>
+}
+
+impl RepositoryRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, route: RepositoryRoute, method_router: MethodRouter<ApplicationState>) -> Self {
+        self.routes.insert(route, method_router);
+        self
+    }
+
+    pub fn into_method_router(self) -> MethodRouter<ApplicationState> {
+        // Handlers are cloned for every request, routes included
+        any(move |State(app): State<ApplicationState>, req: Request<Body>| self.dispatch(app, req))
+    }
+
+    async fn dispatch(mut self, app: ApplicationState, req: Request<Body>) -> Response {
+        let method_router = RepositoryPath::parse(req.uri().path())
+            .and_then(|repository_path| repository_path.route())
+            .and_then(|route| self.routes.remove(&route));
+
+        match method_router {
+            Some(method_router) => {
+                let response: Result<Response, Infallible> = method_router.with_state(app).oneshot(req).await;
+                response.into_response()
+            },
+            None => StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
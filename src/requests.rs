@@ -1,24 +1,393 @@
-use axum::{http::Request, middleware::Next, response::Response};
+use std::{net::{SocketAddr, IpAddr}, convert::Infallible, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use axum::{http::{Request, HeaderValue, Extensions, request::Parts, StatusCode, Method, header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE, LOCATION}}, middleware::Next, response::{Response, IntoResponse}, extract::{State, ConnectInfo, FromRequestParts, MatchedPath}};
 use once_cell::sync::Lazy;
-use regex::{Regex, Captures};
+use regex::Regex;
+use tracing::{field, warn, Span};
+use uuid::Uuid;
+
+use crate::{ApplicationState, repository_path::RepositoryPath, authentication::{Identity, authorization::requested_repository}, controllers::RegistryHttpError, configuration::Configuration, data::{helpers::{resolve_repository, resolve_upstream_container_ref}, operation_metrics::{self, OperationInFlight}}};
 
-static REPLACE_REGEX: Lazy<Regex> = Lazy::new(|| {
-    regex::Regex::new("^/v2/(?P<isProxy>proxy/)?(?P<containerRef>[a-zA-Z0-9-/.]+)/(?P<object>blobs|manifests|tags)(?P<rest>/.*)?$")
-        .unwrap()
+static TRACEPARENT_REGEX: Lazy<Regex> = Lazy::new(|| {
+    // W3C Trace Context: version-trace_id-parent_id-flags
+    Regex::new("^(?P<version>[0-9a-f]{2})-(?P<traceId>[0-9a-f]{32})-(?P<parentId>[0-9a-f]{16})-(?P<flags>[0-9a-f]{2})$").unwrap()
 });
 
-pub async fn rewrite_container_part_url<B>(mut req: Request<B>, next: Next<B>) -> Response {
-    let uri = req.uri_mut();
+tokio::task_local! {
+    static CURRENT_TRACE_CONTEXT: TraceContext;
+}
+
+/// Trace information of the downstream request currently being handled. It is forwarded
+/// to the upstream registries so the proxy hop and the upstream call end up in the same trace.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    pub request_id: String,
+    pub trace_id: String,
+    pub span_id: String,
+    pub trace_flags: String,
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    pub fn from_headers<B>(req: &Request<B>) -> Self {
+        let header = |name: &str| req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+
+        let request_id = header("X-Request-Id").unwrap_or_else(|| Uuid::new_v4().to_string());
+        let traceparent = header("traceparent");
+        let parent = traceparent.as_deref().and_then(|traceparent| TRACEPARENT_REGEX.captures(traceparent));
+
+        // We are a new hop in the trace: keep the trace ID of the caller, but always generate
+        // our own span ID.
+        let (trace_id, trace_flags, trace_state) = match parent {
+            Some(captures) => (
+                captures.name("traceId").unwrap().as_str().to_string(),
+                captures.name("flags").unwrap().as_str().to_string(),
+                header("tracestate")
+            ),
+            None => (Uuid::new_v4().simple().to_string(), "01".to_string(), None)
+        };
+
+        Self {
+            request_id,
+            trace_id,
+            span_id: Uuid::new_v4().simple().to_string()[..16].to_string(),
+            trace_flags,
+            trace_state,
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.trace_flags)
+    }
+
+    /// Trace context of the request being handled by the current task, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT_TRACE_CONTEXT.try_with(|context| context.clone()).ok()
+    }
+
+    pub fn inject(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request
+            .header("traceparent", self.traceparent())
+            .header("X-Request-Id", &self.request_id);
+
+        match &self.trace_state {
+            Some(trace_state) => request.header("tracestate", trace_state),
+            None => request
+        }
+    }
+}
+
+/// Proxied repositories are resolved after their upstream registry. The names sent back to the client, in the
+/// Location headers and the error messages, are put back to the ones it used.
+pub async fn restore_requested_repository<B>(req: Request<B>, next: Next<B>) -> Response {
+    let requested_container_ref = RepositoryPath::parse(req.uri().path())
+        .filter(|repository_path| repository_path.proxy)
+        .map(|repository_path| repository_path.container_ref());
+
+    let response = next.run(req).await;
+    let container_ref = match requested_container_ref {
+        Some(container_ref) => container_ref,
+        None => return response
+    };
+    let resolved_container_ref = resolve_upstream_container_ref(&container_ref);
+    if resolved_container_ref == container_ref {
+        return response;
+    }
+    let restore = |text: &str| text.replace(&resolved_container_ref, &container_ref);
+
+    let (mut parts, body) = response.into_parts();
+    if let Some(location) = parts.headers.get(LOCATION).and_then(|location| location.to_str().ok()) {
+        if let Ok(location) = HeaderValue::from_str(&restore(location)) {
+            parts.headers.insert(LOCATION, location);
+        }
+    }
+
+    // Only the error messages mention the repository, and they are small enough to be read whole
+    let is_json_error = (parts.status.is_client_error() || parts.status.is_server_error())
+        && parts.headers.get(CONTENT_TYPE).is_some_and(|content_type| content_type == "application/json");
+    if !is_json_error {
+        return Response::from_parts(parts, body);
+    }
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => restore(&String::from_utf8_lossy(&body)),
+        Err(e) => {
+            warn!("Unable to read the error response: {}", e);
+            return RegistryHttpError::RegistryInternalError(eyre::eyre!("Unreadable error response")).into_response();
+        }
+    };
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Response::from_parts(parts, axum::body::boxed(axum::body::Full::from(body)))
+}
+
+pub async fn propagate_trace_context<B>(req: Request<B>, next: Next<B>) -> Response {
+    let trace_context = TraceContext::from_headers(&req);
+    let request_id = trace_context.request_id.clone();
+
+    let mut response = CURRENT_TRACE_CONTEXT.scope(trace_context, next.run(req)).await;
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("X-Request-Id", request_id);
+    }
+
+    response
+}
+
+/// Span of a downstream request, carrying what its logs are correlated with. The account is recorded once
+/// the client is authenticated, the status and the cache outcome once the response is ready.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    let client_ip = req.extensions()
+        .get::<ForwardedInfo>()
+        .and_then(|forwarded_info| forwarded_info.client_ip)
+        .map(|ip| ip.to_string())
+        .unwrap_or_default();
+    let trace_context = TraceContext::current();
+    let repository_path = RepositoryPath::parse(req.uri().path());
+
+    // At the info level, so the logs of the default level can be correlated
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        client_ip = %client_ip,
+        request_id = trace_context.as_ref().map(|trace_context| trace_context.request_id.as_str()),
+        trace_id = trace_context.as_ref().map(|trace_context| trace_context.trace_id.as_str()),
+        repository = repository_path.as_ref().map(|repository_path| repository_path.container_ref()).as_deref(),
+        reference = repository_path.as_ref().map(|repository_path| repository_path.reference()).as_deref(),
+        account = field::Empty,
+        status = field::Empty,
+        cache = field::Empty
+    )
+}
+
+pub fn record_response<B>(response: &axum::http::Response<B>, latency: Duration, span: &Span) {
+    span.record("status", response.status().as_u16());
+    if let Some(cache) = response.headers().get("Proxy-Docker-Cache").and_then(|cache| cache.to_str().ok()) {
+        span.record("cache", cache);
+    }
+
+    tracing::debug!(latency = %format!("{} ms", latency.as_millis()), status = response.status().as_u16(), "finished processing request");
+}
+
+/// Records the client on the span of the request, anonymous clients included
+pub fn record_identity(identity: &Identity) {
+    Span::current().record("account", identity.account.as_deref().unwrap_or("anonymous"));
+}
+
+/// Where the request really comes from, once the X-Forwarded-* headers of trusted proxies are taken into account.
+#[derive(Clone, Debug)]
+pub struct ForwardedInfo {
+    pub client_ip: Option<IpAddr>,
+    pub scheme: String,
+    pub host: Option<String>
+}
 
-    *uri = REPLACE_REGEX.replace(&uri.to_string(), |captures: &Captures| {
-        format!(
-            "/v2/{}{}/{}{}",
-            captures.name("isProxy").map(|m| m.as_str()).unwrap_or(""),
-            captures.name("containerRef").unwrap().as_str().replace('/', "%2F"),
-            captures.name("object").unwrap().as_str(),
-            captures.name("rest").unwrap().as_str()
-        )
-    }).parse().unwrap();
+impl ForwardedInfo {
+    pub fn from_request<B>(req: &Request<B>, trusted_proxies: &[IpNet]) -> Self {
+        let header = |name: &str| req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|network| network.contains(ip));
+
+        let peer_ip = req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+
+        if !peer_ip.map(|ip| is_trusted(&ip)).unwrap_or(false) {
+            return Self {
+                client_ip: peer_ip,
+                scheme: "http".to_string(),
+                host: header("Host")
+            };
+        }
+
+        // Each proxy appends the address it got the request from. Walking the chain backwards,
+        // the client is the first address that isn't one of our proxies.
+        let forwarded_for = header("X-Forwarded-For")
+            .map(|forwarded_for| forwarded_for
+                .split(',')
+                .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+                .collect::<Vec<_>>()
+            )
+            .unwrap_or_default();
+        let client_ip = forwarded_for.iter()
+            .rev()
+            .find(|ip| !is_trusted(ip))
+            .or_else(|| forwarded_for.first())
+            .copied()
+            .or(peer_ip);
+
+        Self {
+            client_ip,
+            scheme: header("X-Forwarded-Proto")
+                .and_then(|proto| proto.split(',').next().map(|proto| proto.trim().to_lowercase()))
+                .unwrap_or_else(|| "http".to_string()),
+            host: header("X-Forwarded-Host")
+                .and_then(|host| host.split(',').next().map(|host| host.trim().to_string()))
+                .or_else(|| header("Host"))
+        }
+    }
+
+    /// Scheme and host the client used to reach us, as in `https://registry.example.com`.
+    pub fn origin(&self) -> Option<String> {
+        self.host.as_ref().map(|host| format!("{}://{}", self.scheme, host))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ForwardedInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<ForwardedInfo>().cloned().unwrap_or(ForwardedInfo {
+            client_ip: None,
+            scheme: "http".to_string(),
+            host: None
+        }))
+    }
+}
+
+pub async fn resolve_forwarded_info<B>(
+    State(conf): State<Arc<Configuration>>,
+    mut req: Request<B>,
+    next: Next<B>
+) -> Response {
+    let forwarded_info = ForwardedInfo::from_request(&req, &conf.server.trusted_proxies);
+    req.extensions_mut().insert(forwarded_info);
+
+    next.run(req).await
+}
+
+/// Identifies the client behind a request: its account if the authorization middleware
+/// authenticated it, its IP address otherwise.
+pub struct ClientKey {
+    pub key: String,
+    pub authenticated: bool
+}
+
+impl ClientKey {
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        let account = extensions
+            .get::<Identity>()
+            .and_then(|identity| identity.account.clone());
+
+        match account {
+            Some(account) => Self { key: format!("account:{}", account), authenticated: true },
+            None => {
+                let address = extensions
+                    .get::<ForwardedInfo>()
+                    .and_then(|forwarded_info| forwarded_info.client_ip)
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default();
+                Self { key: format!("ip:{}", address), authenticated: false }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientKey {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_extensions(&parts.extensions))
+    }
+}
+
+/// Applies the anonymous or authenticated rate limit to the request, depending on the
+/// identity found by the authorization middleware.
+pub async fn limit_request_rate<B>(
+    State(app): State<ApplicationState>,
+    req: Request<B>,
+    next: Next<B>
+) -> Response {
+    let client = ClientKey::from_extensions(req.extensions());
+    let (limit_name, limit) = if client.authenticated {
+        ("authenticated", &app.conf.rate_limits.authenticated)
+    } else {
+        ("anonymous", &app.conf.rate_limits.anonymous)
+    };
+
+    if let Some(limit) = limit {
+        if let Err(retry_after) = app.rate_limiter.check(&client.key, limit).await {
+            warn!("Rate limit exceeded for {}", client.key);
+            return RegistryHttpError::too_many_requests(retry_after.as_secs() + 1, serde_json::json!({
+                "limit": limit_name,
+                "client": client.key,
+                "requests": limit.requests,
+                "per_seconds": limit.per_seconds
+            })).into_response();
+        }
+    }
+
+    // Proxied repositories are limited under their upstream registry, whatever alias the client used
+    let repository_limit = requested_repository(req.uri().path())
+        .map(|repository| resolve_repository(&repository))
+        .and_then(|repository| Some((app.conf.rate_limits.repository_rule(&repository)?.request_limit()?, repository)));
+    if let Some((limit, repository)) = repository_limit {
+        if let Err(retry_after) = app.rate_limiter.check(&format!("repository:{}", repository), &limit).await {
+            warn!("Rate limit exceeded on {}", repository);
+            return RegistryHttpError::too_many_requests(retry_after.as_secs() + 1, serde_json::json!({
+                "limit": "repository",
+                "repository": repository,
+                "requests": limit.requests,
+                "per_seconds": limit.per_seconds
+            })).into_response();
+        }
+    }
 
     next.run(req).await
 }
+
+/// Measures the requests by operation, named after the route they matched
+pub async fn measure_operation<B>(req: Request<B>, next: Next<B>) -> Response {
+    let operation = match req.extensions().get::<MatchedPath>().map(|route| route.as_str()) {
+        Some("/v2/*path") => match RepositoryPath::parse(req.uri().path()).and_then(|repository_path| repository_path.route()) {
+            Some(route) => operation_metrics::operation_name(req.method(), route.template()),
+            None => "other"
+        },
+        Some(route) => operation_metrics::operation_name(req.method(), route),
+        None => "other"
+    };
+
+    let started_at = std::time::Instant::now();
+    let in_flight = OperationInFlight::start(operation);
+    let response = next.run(req).await;
+    in_flight.finish(response.status().as_u16(), started_at.elapsed());
+
+    response
+}
+
+/// Answers OPTIONS requests with the methods allowed on the route, and turns axum's plain
+/// 405 responses into registry JSON errors. Both rely on the Allow header set by axum when
+/// a route doesn't handle a method.
+pub async fn handle_unsupported_methods<B>(req: Request<B>, next: Next<B>) -> Response {
+    let method = req.method().clone();
+    let response = next.run(req).await;
+
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+
+    let allow = response.headers().get(ALLOW).cloned();
+    let mut response = if method == Method::OPTIONS {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        RegistryHttpError::method_not_allowed(&method).into_response()
+    };
+
+    if let Some(allow) = allow {
+        response.headers_mut().insert(ALLOW, allow);
+    }
+
+    response
+}
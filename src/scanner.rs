@@ -0,0 +1,257 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf, sync::Arc};
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{authentication::{Authenticator, acl::ResourceAccess}, configuration::{ScannerConfiguration, ScannerKind}, data::{encryption::{self, StorageCipher}, helpers::RegistryPathsHelper, scans::ScanReport}, notifications::{Event, EventAction, EventTarget, Notifier}};
+
+/// Account named in the tokens the scanner pulls the images with
+const SCANNER_ACCOUNT: &str = "scanner";
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    #[serde(default)]
+    layers: Vec<ImageLayer>,
+}
+
+#[derive(Deserialize)]
+struct ImageLayer {
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct ClairVulnerabilityReport {
+    #[serde(default)]
+    vulnerabilities: HashMap<String, ClairVulnerability>,
+}
+
+#[derive(Deserialize)]
+struct ClairVulnerability {
+    normalized_severity: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyReport {
+    #[serde(default)]
+    results: Vec<TrivyResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyResult {
+    #[serde(default)]
+    vulnerabilities: Option<Vec<TrivyVulnerability>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyVulnerability {
+    severity: String,
+}
+
+/// An image to scan, as stored by the registry or the proxy cache
+pub struct ScannedImage {
+    /// Storage holding the manifest, where the report is saved as well
+    pub storage_root: PathBuf,
+    pub container_ref: String,
+    /// Repository as found in the URLs of the registry, starting with `proxy/` for the proxy cache
+    pub repository: String,
+    pub digest: String,
+}
+
+/// Submits the images pushed to the registry or entering the proxy cache to an external vulnerability scanner
+pub struct Scanner {
+    http_client: reqwest::Client,
+    configuration: ScannerConfiguration,
+    /// Issues the pull tokens of the scanner, when the registry authenticates its clients with tokens
+    authenticator: Option<Arc<Authenticator>>,
+    storage_cipher: Option<Arc<StorageCipher>>,
+    notifier: Option<Arc<Notifier>>,
+}
+
+impl Scanner {
+    pub fn new(
+        configuration: &ScannerConfiguration,
+        authenticator: Option<Arc<Authenticator>>,
+        storage_cipher: Option<Arc<StorageCipher>>,
+        notifier: Option<Arc<Notifier>>
+    ) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(configuration.timeout())
+            .build()
+            .expect("Unable to create the HTTP client");
+
+        Self {
+            http_client,
+            configuration: configuration.clone(),
+            authenticator,
+            storage_cipher,
+            notifier,
+        }
+    }
+
+    /// Scans the image in the background if its repository is configured to be scanned
+    pub fn schedule(self: &Arc<Self>, image: ScannedImage) {
+        if !self.configuration.scans(&image.repository) {
+            return;
+        }
+
+        let scanner = Arc::clone(self);
+        tokio::spawn(async move {
+            info!("Scanning {}@{}", image.repository, image.digest);
+            let report = match scanner.scan(&image).await {
+                Ok(Some((severities, report))) => ScanReport {
+                    digest: image.digest.clone(),
+                    scanner: scanner.name().to_string(),
+                    scanned_at: Utc::now(),
+                    severities,
+                    error: None,
+                    report: Some(report),
+                },
+                // Nothing to scan, in image indexes for instance
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Unable to scan {}@{}: {}", image.repository, image.digest, e);
+                    ScanReport {
+                        digest: image.digest.clone(),
+                        scanner: scanner.name().to_string(),
+                        scanned_at: Utc::now(),
+                        severities: BTreeMap::new(),
+                        error: Some(e.to_string()),
+                        report: None,
+                    }
+                }
+            };
+
+            if let Err(e) = report.save(&image.storage_root, &image.container_ref).await {
+                warn!("Unable to save the scan report of {}@{}: {}", image.repository, image.digest, e);
+                return;
+            }
+
+            // Failed scans are only found in the report
+            if let (Some(notifier), None) = (&scanner.notifier, &report.error) {
+                let target = EventTarget {
+                    repository: image.repository.clone(),
+                    digest: image.digest.clone(),
+                    tag: None,
+                    media_type: None,
+                    size: None,
+                };
+                notifier.notify(Event::new(EventAction::Scan, target).with_severities(report.severities.clone())).await;
+            }
+        });
+    }
+
+    fn name(&self) -> &'static str {
+        match self.configuration.kind {
+            ScannerKind::Clair => "clair",
+            ScannerKind::Http => "http"
+        }
+    }
+
+    /// Token letting the scanner pull the image and nothing else, expiring along with the time given to the scan
+    fn pull_token(&self, repository: &str) -> eyre::Result<Option<String>> {
+        let token_issuer = match self.authenticator.as_ref().and_then(|authenticator| authenticator.token_issuer.as_ref()) {
+            Some(token_issuer) => token_issuer,
+            None => return Ok(None)
+        };
+
+        let access = vec![ResourceAccess {
+            resource_type: "repository".to_string(),
+            name: repository.to_string(),
+            actions: vec!["pull".to_string()]
+        }];
+        Ok(Some(token_issuer.issue_for(Some(SCANNER_ACCOUNT), access, self.configuration.timeout())?.token))
+    }
+
+    async fn scan(&self, image: &ScannedImage) -> eyre::Result<Option<(BTreeMap<String, u64>, serde_json::Value)>> {
+        let manifest_path = RegistryPathsHelper::manifest_path(&image.storage_root, &image.container_ref, &image.digest);
+        let manifest = encryption::decrypt_manifest(self.storage_cipher.as_deref(), &image.digest, tokio::fs::read(manifest_path).await?)?;
+        let manifest = serde_json::from_slice::<ImageManifest>(&manifest)?;
+        if manifest.layers.is_empty() {
+            return Ok(None);
+        }
+
+        let registry_url = self.configuration.registry_url.trim_end_matches('/');
+        let scanner_url = self.configuration.url.trim_end_matches('/');
+        let pull_token = self.pull_token(&image.repository)?;
+
+        match self.configuration.kind {
+            ScannerKind::Clair => {
+                // The indexer downloads the layers from the registry itself
+                let headers = match &pull_token {
+                    Some(pull_token) => json!({ "Authorization": [format!("Bearer {}", pull_token)] }),
+                    None => json!({})
+                };
+                let layers = manifest.layers
+                    .iter()
+                    .map(|layer| json!({
+                        "hash": layer.digest,
+                        "uri": format!("{}/v2/{}/blobs/{}", registry_url, image.repository, layer.digest),
+                        "headers": headers
+                    }))
+                    .collect::<Vec<_>>();
+
+                self.http_client
+                    .post(format!("{}/indexer/api/v1/index_report", scanner_url))
+                    .json(&json!({ "hash": image.digest, "layers": layers }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+
+                let report = self.http_client
+                    .get(format!("{}/matcher/api/v1/vulnerability_report/{}", scanner_url, image.digest))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await?;
+
+                let vulnerabilities = serde_json::from_value::<ClairVulnerabilityReport>(report.clone())?.vulnerabilities;
+                let severities = count_severities(vulnerabilities.values().map(|vulnerability| vulnerability.normalized_severity.as_str()));
+                Ok(Some((severities, report)))
+            },
+            ScannerKind::Http => {
+                let registry_host = registry_url
+                    .split_once("://")
+                    .map(|(_, host)| host)
+                    .unwrap_or(registry_url);
+
+                let mut request = json!({
+                    "repository": image.repository,
+                    "digest": image.digest,
+                    "reference": format!("{}/{}@{}", registry_host, image.repository, image.digest)
+                });
+                if let Some(pull_token) = pull_token {
+                    request["registry_token"] = json!(pull_token);
+                }
+
+                let report = self.http_client
+                    .post(scanner_url)
+                    .json(&request)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await?;
+
+                let results = serde_json::from_value::<TrivyReport>(report.clone())?.results;
+                let severities = count_severities(results
+                    .iter()
+                    .flat_map(|result| result.vulnerabilities.iter().flatten())
+                    .map(|vulnerability| vulnerability.severity.as_str()));
+                Ok(Some((severities, report)))
+            }
+        }
+    }
+}
+
+fn count_severities<'a>(severities: impl Iterator<Item = &'a str>) -> BTreeMap<String, u64> {
+    severities.fold(BTreeMap::new(), |mut counts, severity| {
+        *counts.entry(severity.to_lowercase()).or_insert(0) += 1;
+        counts
+    })
+}
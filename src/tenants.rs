@@ -0,0 +1,263 @@
+use std::{path::PathBuf, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::{StatusCode, request::Parts}, response::{IntoResponse, Response}};
+use serde::Serialize;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{info, warn};
+
+use crate::{ApplicationState, authentication::{Identity, authorization::requested_repository}, configuration::{Configuration, TenantConfiguration}, controllers::RegistryHttpError, data::{helpers::{directory_size_async, resolve_repository}, memory_storage::MemoryStorage}, docker_client::clients_store::DockerClientsStore};
+
+/// The storage sizes given by the status endpoint and the `GetUsage` gRPC call are counted again at most this often
+const STORAGE_SIZES_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Bytes held by the storage roots of a tenant
+#[derive(Serialize, Clone, Copy)]
+pub struct StorageSizes {
+    pub registry_bytes: u64,
+    pub temporary_bytes: u64,
+    pub proxy_bytes: u64,
+}
+
+/// Storage and upstream clients of the team a request belongs to
+pub struct Tenant {
+    /// None for the storage roots of the configuration root, used by the requests belonging to no tenant
+    pub name: Option<String>,
+    pub registry_storage: PathBuf,
+    pub temporary_registry_storage: PathBuf,
+    pub proxy_storage: PathBuf,
+    pub quota_bytes: Option<u64>,
+    /// Bytes held by the registry storage, counted from the disk on the first push then kept up to date as
+    /// blobs and manifests are stored. Recounted by [`Tenant::recount_usage`] to catch up with the rest.
+    used_bytes: OnceCell<AtomicU64>,
+    /// Last count of the storage roots, along with when it was made
+    storage_sizes: Mutex<Option<(Instant, StorageSizes)>>,
+    pub docker_clients: DockerClientsStore,
+    /// Shared by every tenant when the storage is kept in memory
+    pub memory_storage: Option<Arc<MemoryStorage>>,
+}
+
+impl Tenant {
+    /// Refuses pushes once the registry storage of the tenant, or the memory storage, is full
+    pub async fn check_quota(&self) -> Result<(), RegistryHttpError> {
+        self.check_quota_for(0).await
+    }
+
+    /// Refuses to cache `incoming_bytes` more in the proxy storage of the tenant once the memory storage would be full.
+    /// Proxy caches have no quota, only the capacity of the memory storage bounds them.
+    pub async fn check_proxy_fill(&self, incoming_bytes: u64) -> Result<(), RegistryHttpError> {
+        match &self.memory_storage {
+            Some(memory_storage) => memory_storage.check_capacity_for(incoming_bytes).await,
+            None => Ok(())
+        }
+    }
+
+    /// Refuses to store `incoming_bytes` more in the registry storage of the tenant once it would go past its quota
+    pub async fn check_quota_for(&self, incoming_bytes: u64) -> Result<(), RegistryHttpError> {
+        if let Some(memory_storage) = &self.memory_storage {
+            memory_storage.check_capacity().await?;
+        }
+
+        let quota_bytes = match self.quota_bytes {
+            Some(quota_bytes) => quota_bytes,
+            None => return Ok(())
+        };
+
+        let used_bytes = self.used_bytes().await?.load(Ordering::Relaxed);
+        if used_bytes.saturating_add(incoming_bytes) > quota_bytes || used_bytes >= quota_bytes {
+            let tenant = self.name.clone().unwrap_or_default();
+            info!("Tenant {} is over its quota: {} of {} bytes used, {} more bytes refused", tenant, used_bytes, quota_bytes, incoming_bytes);
+            return Err(RegistryHttpError::QuotaExceeded { tenant, used_bytes, quota_bytes });
+        }
+
+        Ok(())
+    }
+
+    /// Counts bytes just stored in the registry storage of the tenant
+    pub fn record_stored(&self, bytes: u64) {
+        // Not counted yet, the first count will see them on the disk
+        if let Some(used_bytes) = self.used_bytes.get() {
+            used_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts the bytes of the registry storage from the disk again, for what isn't tracked as it happens:
+    /// compression, mounts and files removed by hand
+    pub async fn recount_usage(&self) -> Result<(), RegistryHttpError> {
+        if self.quota_bytes.is_none() {
+            return Ok(());
+        }
+
+        let counted_bytes = directory_size_async(self.registry_storage.clone()).await??;
+        match self.used_bytes.get() {
+            Some(used_bytes) => used_bytes.store(counted_bytes, Ordering::Relaxed),
+            None => { self.used_bytes.set(AtomicU64::new(counted_bytes)).ok(); }
+        }
+
+        Ok(())
+    }
+
+    /// Bytes held by the storage roots, counted from the disk again once the last count is a minute old.
+    /// Callers arriving during a count wait for it rather than going through the storage as well.
+    pub async fn storage_sizes(&self) -> Result<StorageSizes, RegistryHttpError> {
+        let mut storage_sizes = self.storage_sizes.lock().await;
+        if let Some((counted_at, sizes)) = *storage_sizes {
+            if counted_at.elapsed() < STORAGE_SIZES_LIFETIME {
+                return Ok(sizes);
+            }
+        }
+
+        let sizes = StorageSizes {
+            registry_bytes: directory_size_async(self.registry_storage.clone()).await??,
+            temporary_bytes: directory_size_async(self.temporary_registry_storage.clone()).await??,
+            proxy_bytes: directory_size_async(self.proxy_storage.clone()).await??,
+        };
+        *storage_sizes = Some((Instant::now(), sizes));
+
+        Ok(sizes)
+    }
+
+    async fn used_bytes(&self) -> Result<&AtomicU64, RegistryHttpError> {
+        self.used_bytes
+            .get_or_try_init(|| async {
+                Ok::<_, RegistryHttpError>(AtomicU64::new(directory_size_async(self.registry_storage.clone()).await??))
+            })
+            .await
+    }
+}
+
+#[derive(Clone)]
+pub struct Tenants {
+    default: Arc<Tenant>,
+    tenants: Arc<Vec<(TenantConfiguration, Arc<Tenant>)>>,
+}
+
+impl Tenants {
+    pub fn new(configuration: &Configuration, default_docker_clients: DockerClientsStore, memory_storage: Option<Arc<MemoryStorage>>) -> Self {
+        let default = Tenant {
+            name: None,
+            registry_storage: configuration.registry_storage.clone(),
+            temporary_registry_storage: configuration.temporary_registry_storage.clone(),
+            proxy_storage: configuration.proxy_storage.clone(),
+            quota_bytes: None,
+            used_bytes: OnceCell::new(),
+            storage_sizes: Mutex::new(None),
+            docker_clients: default_docker_clients,
+            memory_storage: memory_storage.clone(),
+        };
+
+        let tenants = configuration.tenants
+            .iter()
+            .map(|tenant_configuration| {
+                let mut upstream = configuration.upstream.clone();
+                upstream.registries.extend(tenant_configuration.upstream_registries.clone());
+
+                let tenant = Tenant {
+                    name: Some(tenant_configuration.name.clone()),
+                    registry_storage: tenant_configuration.registry_storage.clone(),
+                    temporary_registry_storage: tenant_configuration.temporary_registry_storage.clone(),
+                    proxy_storage: tenant_configuration.proxy_storage.clone(),
+                    quota_bytes: tenant_configuration.quota_bytes,
+                    used_bytes: OnceCell::new(),
+                    storage_sizes: Mutex::new(None),
+                    docker_clients: DockerClientsStore::new(&upstream),
+                    memory_storage: memory_storage.clone(),
+                };
+                (tenant_configuration.clone(), Arc::new(tenant))
+            })
+            .collect();
+
+        Self {
+            default: Arc::new(default),
+            tenants: Arc::new(tenants),
+        }
+    }
+
+    /// Every tenant, the default one first
+    pub fn all(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        std::iter::once(&self.default).chain(self.tenants.iter().map(|(_, tenant)| tenant))
+    }
+
+    /// Tenant named in the configuration, the default one for None
+    pub fn named(&self, name: Option<&str>) -> Option<Arc<Tenant>> {
+        match name {
+            Some(name) => self.tenants
+                .iter()
+                .find(|(tenant_configuration, _)| tenant_configuration.name == name)
+                .map(|(_, tenant)| Arc::clone(tenant)),
+            None => Some(Arc::clone(&self.default))
+        }
+    }
+
+    /// Tenant owning the repository, for the requests made by the registry itself
+    pub fn for_repository(&self, repository: &str) -> Arc<Tenant> {
+        match self.owner(repository) {
+            Some((_, tenant)) => Arc::clone(tenant),
+            None => Arc::clone(&self.default)
+        }
+    }
+
+    /// Tenant owning the repository, or else the tenant of the account. The repositories of a tenant listing
+    /// accounts are only open to these accounts.
+    pub fn select(&self, repository: Option<&str>, account: Option<&str>) -> Result<Arc<Tenant>, RegistryHttpError> {
+        if let Some((tenant_configuration, tenant)) = repository.and_then(|repository| self.owner(repository)) {
+            let is_member = tenant_configuration.accounts.is_empty()
+                || account.is_some_and(|account| tenant_configuration.accounts.iter().any(|tenant_account| tenant_account == account));
+            if !is_member {
+                warn!("Account {:?} doesn't belong to the tenant {}", account, tenant_configuration.name);
+                return Err(RegistryHttpError::denied(format!("The repository belongs to the tenant {}", tenant_configuration.name)));
+            }
+            return Ok(Arc::clone(tenant));
+        }
+
+        let by_account = account.and_then(|account| {
+            self.tenants.iter().find(|(tenant_configuration, _)| tenant_configuration.accounts.iter().any(|tenant_account| tenant_account == account))
+        });
+        match by_account {
+            Some((_, tenant)) => Ok(Arc::clone(tenant)),
+            None => Ok(Arc::clone(&self.default))
+        }
+    }
+
+    fn owner(&self, repository: &str) -> Option<&(TenantConfiguration, Arc<Tenant>)> {
+        let repository = resolve_repository(repository);
+        self.tenants.iter().find(|(tenant_configuration, _)| tenant_configuration.owns_repository(&repository))
+    }
+}
+
+/// Tenant of the current request, found from the repository in its path and the identity of the client
+pub struct CurrentTenant(pub Arc<Tenant>);
+
+#[async_trait]
+impl FromRequestParts<ApplicationState> for CurrentTenant {
+    type Rejection = RegistryHttpError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApplicationState) -> Result<Self, Self::Rejection> {
+        let repository = requested_repository(parts.uri.path());
+        let account = parts.extensions
+            .get::<Identity>()
+            .and_then(|identity| identity.account.as_deref());
+
+        Ok(Self(state.tenants.select(repository.as_deref(), account)?))
+    }
+}
+
+/// Tenant an admin request is about, named by its `tenant` query parameter. Without it, the storage roots at the
+/// top of the configuration.
+pub struct AdminTenant(pub Arc<Tenant>);
+
+#[async_trait]
+impl FromRequestParts<ApplicationState> for AdminTenant {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApplicationState) -> Result<Self, Self::Rejection> {
+        let name = url::form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "tenant")
+            .map(|(_, name)| name.to_string());
+
+        match state.tenants.named(name.as_deref()) {
+            Some(tenant) => Ok(Self(tenant)),
+            None => Err((StatusCode::NOT_FOUND, format!("Unknown tenant {}", name.unwrap_or_default())).into_response())
+        }
+    }
+}
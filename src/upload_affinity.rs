@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use axum::{body::{Body, HttpBody, StreamBody}, extract::State, http::{HeaderValue, Request, StatusCode, Uri}, middleware::Next, response::{IntoResponse, Response}};
+use futures::stream;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{
+    ApplicationState,
+    configuration::{UploadAffinityConfiguration, UploadAffinityMode},
+    controllers::RegistryHttpError,
+    repository_path::{RepositoryPath, RepositoryRoute},
+    requests::ForwardedInfo
+};
+
+/// Query parameter of the upload URLs naming the replica holding the session
+pub const AFFINITY_PARAMETER: &str = "affinity";
+
+/// Set on the chunks forwarded by another replica, which are handled where they land
+pub const FORWARDED_HEADER: &str = "X-Registry-Upload-Forwarded";
+
+/// Headers describing a single connection, not passed along by the forwarding replica
+const HOP_BY_HOP_HEADERS: [&str; 6] = ["Connection", "Keep-Alive", "Transfer-Encoding", "Upgrade", "Proxy-Connection", "TE"];
+
+/// Where the sessions of the uploads are held among the replicas. Upload URLs carry the name of the
+/// replica holding the session, signed so clients can't send chunks to a replica of their choosing.
+pub struct UploadAffinity {
+    node_id: String,
+    secret: Vec<u8>,
+    nodes: HashMap<String, String>,
+    mode: UploadAffinityMode,
+    http_client: reqwest::Client
+}
+
+impl UploadAffinity {
+    pub fn new(configuration: &UploadAffinityConfiguration) -> Self {
+        Self {
+            node_id: configuration.node_id.clone(),
+            secret: configuration.secret.as_bytes().to_vec(),
+            nodes: configuration.nodes.clone(),
+            mode: configuration.mode,
+            http_client: reqwest::Client::new()
+        }
+    }
+
+    /// Token of an upload held by this replica, as in `registry-0.<hex signature>`
+    pub fn token(&self, upload_id: Uuid) -> String {
+        let signature = self.mac(&self.node_id, upload_id).finalize().into_bytes();
+        format!("{}.{}", self.node_id, base16ct::lower::encode_string(&signature))
+    }
+
+    /// The signed string is made of the node name and the upload ID, separated by a new line
+    fn mac(&self, node_id: &str, upload_id: Uuid) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}", node_id, upload_id).as_bytes());
+        mac
+    }
+
+    /// Base URL of the replica holding the upload, unless it's this one or the URL doesn't tell
+    fn owning_node(&self, uri: &Uri) -> Option<&str> {
+        let repository_path = RepositoryPath::parse(uri.path())?;
+        if repository_path.route() != Some(RepositoryRoute::Upload) {
+            return None;
+        }
+        let upload_id = repository_path.reference().parse::<Uuid>().ok()?;
+
+        let token = uri.query()?
+            .split('&')
+            .filter_map(|parameter| parameter.split_once('='))
+            .find(|(name, _)| *name == AFFINITY_PARAMETER)
+            .map(|(_, value)| value)?;
+        let (node_id, signature) = token.rsplit_once('.')?;
+
+        let signature_matches = base16ct::mixed::decode_vec(signature)
+            .is_ok_and(|signature| self.mac(node_id, upload_id).verify_slice(&signature).is_ok());
+        if !signature_matches {
+            warn!("Upload {} has an invalid affinity token, handling it here", upload_id);
+            return None;
+        }
+        if node_id == self.node_id {
+            return None;
+        }
+
+        match self.nodes.get(node_id) {
+            Some(base_url) => Some(base_url.trim_end_matches('/')),
+            None => {
+                warn!("Upload {} is held by the unknown replica {}, handling it here", upload_id, node_id);
+                None
+            }
+        }
+    }
+
+    /// Sends the request to the replica holding the upload and relays its response
+    async fn forward(&self, base_url: &str, req: Request<Body>) -> Result<Response, reqwest::Error> {
+        let (parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or_default();
+
+        let mut headers = parts.headers.clone();
+        headers.remove("Host");
+        for header in HOP_BY_HOP_HEADERS {
+            headers.remove(header);
+        }
+        headers.insert(FORWARDED_HEADER, HeaderValue::from_static("1"));
+        // The replica holding the upload sees the client, as long as it trusts this one as a proxy
+        if let Some(client_ip) = parts.extensions.get::<ForwardedInfo>().and_then(|forwarded_info| forwarded_info.client_ip) {
+            if let Ok(client_ip) = HeaderValue::from_str(&client_ip.to_string()) {
+                headers.insert("X-Forwarded-For", client_ip);
+            }
+        }
+
+        let body = stream::unfold(body, |mut body| async move {
+            body.data().await.map(|chunk| (chunk, body))
+        });
+
+        let response = self.http_client
+            .request(parts.method, format!("{}{}", base_url, path))
+            .headers(headers)
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+
+        let mut headers = response.headers().clone();
+        for header in HOP_BY_HOP_HEADERS {
+            headers.remove(header);
+        }
+
+        Ok((response.status(), headers, StreamBody::new(response.bytes_stream())).into_response())
+    }
+}
+
+/// Sends the upload requests landing on a replica that doesn't hold their session to the one that does,
+/// or redirects the client to it
+pub async fn route_to_owning_node(
+    State(app): State<ApplicationState>,
+    req: Request<Body>,
+    next: Next<Body>
+) -> Response {
+    let upload_affinity = match app.upload_affinity.as_deref() {
+        Some(upload_affinity) if !req.headers().contains_key(FORWARDED_HEADER) => upload_affinity,
+        _ => return next.run(req).await
+    };
+
+    let base_url = match upload_affinity.owning_node(req.uri()) {
+        Some(base_url) => base_url.to_string(),
+        None => return next.run(req).await
+    };
+
+    match upload_affinity.mode {
+        UploadAffinityMode::Redirect => {
+            let path = req.uri().path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or_default();
+            debug!("Redirecting {} {} to {}", req.method(), path, base_url);
+            (StatusCode::TEMPORARY_REDIRECT, [("Location", format!("{}{}", base_url, path))]).into_response()
+        },
+        UploadAffinityMode::Proxy => {
+            debug!("Forwarding {} {} to {}", req.method(), req.uri(), base_url);
+            match upload_affinity.forward(&base_url, req).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Couldn't forward the upload request to {}: {}", base_url, e);
+                    RegistryHttpError::ServiceUnavailable.into_response()
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! The registry embedded in-process, called through its router as an application embedding it would
+
+use std::path::PathBuf;
+
+use axum::{Router, body::Body, http::{Method, Request, Response, StatusCode}};
+use docker_storage_proxy_registry::{RegistryServer, configuration::{AuthenticationConfiguration, AuthenticationMethod, CacheConfiguration, CacheVerificationMode, CacheVerificationRule, Configuration}};
+use sha2::{Digest, Sha256};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+const MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Storage roots of a registry, removed once the test is done
+struct TestStorage(PathBuf);
+
+impl TestStorage {
+    fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("registry-server-{}", Uuid::new_v4())))
+    }
+
+    async fn server(&self, authentication: Option<AuthenticationConfiguration>) -> RegistryServer {
+        RegistryServer::builder()
+            .registry_storage(self.0.join("registry"))
+            .temporary_registry_storage(self.0.join("tmp"))
+            .proxy_storage(self.0.join("proxy"))
+            .authentication(authentication)
+            .proxy(false)
+            .build()
+            .await
+            .unwrap()
+    }
+}
+
+impl Drop for TestStorage {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+fn digest(content: &[u8]) -> String {
+    format!("sha256:{}", base16ct::lower::encode_string(&Sha256::digest(content)))
+}
+
+async fn call(router: &Router, method: Method, uri: &str, content_type: Option<&str>, body: Vec<u8>) -> Response<axum::body::BoxBody> {
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(content_type) = content_type {
+        request = request.header("Content-Type", content_type);
+    }
+    router.clone().oneshot(request.body(Body::from(body)).unwrap()).await.unwrap()
+}
+
+async fn body(response: Response<axum::body::BoxBody>) -> Vec<u8> {
+    hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()
+}
+
+fn header<'a>(response: &'a Response<axum::body::BoxBody>, name: &str) -> &'a str {
+    response.headers().get(name).unwrap().to_str().unwrap()
+}
+
+/// A manifest of a single layer, along with its configuration
+fn image_manifest(config: &[u8], layer: &[u8]) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": MANIFEST_TYPE,
+        "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": digest(config), "size": config.len() },
+        "layers": [{ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": digest(layer), "size": layer.len() }]
+    })).unwrap()
+}
+
+#[tokio::test]
+async fn base_endpoint() {
+    let storage = TestStorage::new();
+    let router = storage.server(None).await.router();
+
+    let response = call(&router, Method::GET, "/v2/", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, "Docker-Distribution-Api-Version"), "registry/2.0");
+}
+
+#[tokio::test]
+async fn push_and_pull_an_image() {
+    let storage = TestStorage::new();
+    let router = storage.server(None).await.router();
+
+    let config = br#"{"architecture":"amd64","os":"linux"}"#.to_vec();
+    let layer = b"layer content".repeat(1000);
+
+    // The configuration in a single request
+    let response = call(&router, Method::POST, &format!("/v2/team/app/blobs/uploads/?digest={}", digest(&config)), None, config.clone()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // The layer in two chunks
+    let response = call(&router, Method::POST, "/v2/team/app/blobs/uploads/", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let location = header(&response, "Location").to_string();
+    let (first_chunk, second_chunk) = layer.split_at(5000);
+    let response = call(&router, Method::PATCH, &location, Some("application/octet-stream"), first_chunk.to_vec()).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(header(&response, "Range"), "0-4999");
+    let location = header(&response, "Location").to_string();
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let response = call(&router, Method::PUT, &format!("{}{}digest={}", location, separator, digest(&layer)), None, second_chunk.to_vec()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let manifest = image_manifest(&config, &layer);
+    let response = call(&router, Method::PUT, "/v2/team/app/manifests/1.0", Some(MANIFEST_TYPE), manifest.clone()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(header(&response, "Docker-Content-Digest"), digest(&manifest));
+
+    for reference in ["1.0".to_string(), digest(&manifest)] {
+        let response = call(&router, Method::GET, &format!("/v2/team/app/manifests/{}", reference), None, Vec::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "Docker-Content-Digest"), digest(&manifest));
+        assert_eq!(body(response).await, manifest);
+    }
+
+    let response = call(&router, Method::GET, &format!("/v2/team/app/blobs/{}", digest(&layer)), None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body(response).await, layer);
+
+    let response = call(&router, Method::HEAD, &format!("/v2/team/app/blobs/{}", digest(&config)), None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, "Content-Length"), config.len().to_string());
+}
+
+#[tokio::test]
+async fn blob_not_matching_its_digest() {
+    let storage = TestStorage::new();
+    let router = storage.server(None).await.router();
+
+    let response = call(&router, Method::POST, &format!("/v2/team/app/blobs/uploads/?digest={}", digest(b"expected")), None, b"received".to_vec()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8(body(response).await).unwrap().contains("DIGEST_INVALID"));
+
+    let response = call(&router, Method::HEAD, &format!("/v2/team/app/blobs/{}", digest(b"received")), None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn missing_manifest() {
+    let storage = TestStorage::new();
+    let router = storage.server(None).await.router();
+
+    let response = call(&router, Method::GET, "/v2/team/app/manifests/latest", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(String::from_utf8(body(response).await).unwrap().contains("NAME_UNKNOWN"));
+}
+
+#[tokio::test]
+async fn proxy_routes_left_out() {
+    let storage = TestStorage::new();
+    let router = storage.server(None).await.router();
+
+    let response = call(&router, Method::GET, "/v2/proxy/registry-1.docker.io/library/alpine/manifests/latest", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Basic authentication of the htpasswd user alice, whose password is `secret`
+fn basic_authentication_configuration(storage: &TestStorage) -> AuthenticationConfiguration {
+    std::fs::create_dir_all(&storage.0).unwrap();
+    let htpasswd = storage.0.join("htpasswd");
+    std::fs::write(&htpasswd, format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap())).unwrap();
+
+    AuthenticationConfiguration {
+        method: AuthenticationMethod::Basic,
+        realm: "Registry".to_string(),
+        service: None,
+        htpasswd: Some(htpasswd),
+        token: None,
+        acl: Vec::new(),
+        namespace_ownership: false,
+        opa: None,
+        api_keys: None,
+        lockout: None,
+        request_signing: None,
+        admins: Vec::new()
+    }
+}
+
+#[tokio::test]
+async fn basic_authentication() {
+    let storage = TestStorage::new();
+    let router = storage.server(Some(basic_authentication_configuration(&storage))).await.router();
+
+    let response = call(&router, Method::GET, "/v2/", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(header(&response, "WWW-Authenticate").starts_with("Basic"));
+
+    let request = Request::builder()
+        .uri("/v2/")
+        .header("Authorization", format!("Basic {}", base64::encode("alice:secret")))
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = Request::builder()
+        .uri("/v2/")
+        .header("Authorization", format!("Basic {}", base64::encode("alice:wrong")))
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_server_left_out() {
+    let storage = TestStorage::new();
+    let router = storage.server(Some(basic_authentication_configuration(&storage))).await.router();
+
+    let response = call(&router, Method::GET, "/token?service=registry", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(String::from_utf8(body(response).await).unwrap().contains("UNSUPPORTED"));
+}
+
+#[tokio::test]
+async fn images_api_needs_authentication() {
+    let storage = TestStorage::new();
+    let router = storage.server(Some(basic_authentication_configuration(&storage))).await.router();
+
+    // The digest reaches the handlers decoded, however the client encoded it
+    let digest = digest(b"image");
+    for (method, resource) in [(Method::GET, "scan"), (Method::PUT, "sbom")] {
+        for encoded_digest in [digest.clone(), digest.replace(':', "%3A")] {
+            let uri = format!("/api/images/team/app/{}/{}", encoded_digest, resource);
+            let response = call(&router, method.clone(), &uri, Some("application/spdx+json"), b"{}".to_vec()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {}", method, uri);
+        }
+    }
+
+    // Paths naming no image are refused rather than left to the handlers
+    let response = call(&router, Method::GET, "/api/images/team/app/latest/scan", None, Vec::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn whole_ranges_of_cached_blobs_are_verified() {
+    let storage = TestStorage::new();
+    let configuration = Configuration {
+        cache: CacheConfiguration {
+            verification: vec![CacheVerificationRule {
+                repository: "registry.example.com/*".to_string(),
+                mode: CacheVerificationMode::Full,
+                sample_rate: 1.0
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let router = RegistryServer::builder()
+        .configuration(configuration)
+        .registry_storage(storage.0.join("registry"))
+        .temporary_registry_storage(storage.0.join("tmp"))
+        .proxy_storage(storage.0.join("proxy"))
+        .build()
+        .await
+        .unwrap()
+        .router();
+
+    // A cached blob that went bad on disk
+    let digest = digest(b"layer");
+    let blob_path = storage.0.join("proxy/registry.example.com/team/app/_repository/blobs").join(&digest);
+    let get_range = |range: &'static str| {
+        let request = Request::builder()
+            .uri(format!("/v2/proxy/registry.example.com/team/app/blobs/{}", digest))
+            .header("Range", range)
+            // Keeps the cache misses from going upstream
+            .header("X-Proxy-Peer-Request", "1")
+            .body(Body::empty())
+            .unwrap();
+        router.clone().oneshot(request)
+    };
+
+    // Only part of the blob, left to the verification of the whole reads
+    std::fs::create_dir_all(blob_path.parent().unwrap()).unwrap();
+    std::fs::write(&blob_path, b"rotten").unwrap();
+    let response = get_range("bytes=0-2").await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+
+    // The whole blob, asked as a range
+    let response = get_range("bytes=0-").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(!blob_path.exists());
+}